@@ -0,0 +1,93 @@
+//! Checksum cost of a whole firmware image, frame by frame as the flasher
+//! computes it, for each frame checksum kind.
+//!
+//! ```bash
+//! cargo bench --bench crc
+//! ```
+
+use std::hint::black_box;
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use feeflash::crc::{crc16_buypass, crc16_ccitt, crc16_ccitt_table, crc32};
+use feeflash::frame::ChecksumKind;
+
+/// Header and data of one frame: what the trailer checksum covers.
+const FRAME_HEAD: usize = 67;
+
+const SIZES: [usize; 3] = [1024, 64 * 1024, 256 * 1024];
+
+fn image(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn frame_checksums(c: &mut Criterion) {
+    let kinds = [
+        ("crc16", ChecksumKind::Crc16Ccitt),
+        ("crc32", ChecksumKind::Crc32),
+        ("sum8", ChecksumKind::Sum8),
+        ("sum16", ChecksumKind::Sum16),
+    ];
+    let mut group = c.benchmark_group("frame_checksums");
+    for len in SIZES {
+        let data = image(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        for (name, kind) in kinds {
+            group.bench_with_input(BenchmarkId::new(name, len), &data, |b, data| {
+                b.iter(|| {
+                    for head in data.chunks(FRAME_HEAD) {
+                        black_box(kind.checksum(black_box(head)));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The frame CRC-16, bit by bit as the flasher computes it against the
+/// table-driven variant.
+fn crc16_implementations(c: &mut Criterion) {
+    let implementations = [
+        ("bitwise", crc16_ccitt as fn(&[u8]) -> u16),
+        ("table", crc16_ccitt_table),
+    ];
+    let mut group = c.benchmark_group("crc16_ccitt");
+    for len in SIZES {
+        let data = image(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        for (name, crc) in implementations {
+            group.bench_with_input(BenchmarkId::new(name, len), &data, |b, data| {
+                b.iter(|| {
+                    for frame in data.chunks(64) {
+                        black_box(crc(black_box(frame)));
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+/// The CRCs that cover their whole input, over one buffer.
+fn whole_buffer(c: &mut Criterion) {
+    let mut group = c.benchmark_group("whole_buffer");
+    for len in SIZES {
+        let data = image(len);
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("crc32", len), &data, |b, data| {
+            b.iter(|| crc32(black_box(data)))
+        });
+        group.bench_with_input(BenchmarkId::new("crc16_buypass", len), &data, |b, data| {
+            b.iter(|| crc16_buypass(black_box(data)))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    frame_checksums,
+    crc16_implementations,
+    whole_buffer
+);
+criterion_main!(benches);
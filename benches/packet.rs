@@ -0,0 +1,188 @@
+//! Cost of building protocol 1 packets and of a loop of pings, with the
+//! heap allocations each makes, and the allocations of an ID scan.
+//!
+//! ```bash
+//! cargo bench --bench packet
+//! ```
+//!
+//! Allocation counts are printed before the timings.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use criterion::{Criterion, criterion_group, criterion_main};
+use feeflash::deadline::Deadline;
+use feeflash::dynamixel::{
+    Instruction, MAX_PACKET_LEN, PacketReader, ProtocolVersion, ScanOptions, build_dyn_packet,
+    build_dyn_packet_into, ping, ping_reusing, probe_id, scan_ids,
+};
+use feeflash::transport::Transport;
+
+/// System allocator counting allocations.
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        unsafe { System.alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Allocations made by `f`.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    f();
+    ALLOCATIONS.load(Ordering::Relaxed) - before
+}
+
+/// IDs a scan pings one by one.
+const IDS: u8 = 254;
+
+/// Bus where every pinged servo answers at once, without allocating. The
+/// broadcast ping goes unanswered, so a scan sweeps every ID.
+struct Answering {
+    reply: [u8; 6],
+    pending: bool,
+}
+
+impl Answering {
+    fn new() -> Self {
+        Answering {
+            reply: [0; 6],
+            pending: false,
+        }
+    }
+}
+
+impl Transport for Answering {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let id = buf[2];
+        let sum = id as u32 + 2;
+        self.reply = [0xFF, 0xFF, id, 0x02, 0x00, !sum as u8];
+        self.pending = id != 0xFE;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.pending {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.pending = false;
+        buf[..6].copy_from_slice(&self.reply);
+        Ok(6)
+    }
+
+    fn set_timeout(&mut self, _timeout: Duration) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn ping_all(port: &mut Answering) {
+    for id in 0..IDS {
+        black_box(ping(port, id).unwrap());
+    }
+}
+
+fn ping_all_reusing(port: &mut Answering, reader: &mut PacketReader) {
+    for id in 0..IDS {
+        black_box(ping_reusing(port, id, reader).unwrap());
+    }
+}
+
+fn build_packet(c: &mut Criterion) {
+    let params = [0x24, 0x02];
+    let mut buf = [0u8; MAX_PACKET_LEN];
+    println!(
+        "build_dyn_packet: {} allocations, build_dyn_packet_into: {}",
+        allocations(|| drop(build_dyn_packet(1, Instruction::ReadData, &params))),
+        allocations(|| drop(build_dyn_packet_into(
+            &mut buf,
+            1,
+            Instruction::ReadData,
+            &params
+        ))),
+    );
+
+    let mut group = c.benchmark_group("build_packet");
+    group.bench_function("vec", |b| {
+        b.iter(|| build_dyn_packet(black_box(1), Instruction::ReadData, black_box(&params)))
+    });
+    group.bench_function("into", |b| {
+        b.iter(|| {
+            build_dyn_packet_into(
+                &mut buf,
+                black_box(1),
+                Instruction::ReadData,
+                black_box(&params),
+            )
+        })
+    });
+    group.finish();
+}
+
+fn ping_loop(c: &mut Criterion) {
+    let mut port = Answering::new();
+    let mut reader = PacketReader::new();
+    // Grow the reader's buffer first, as the first ping of a loop does.
+    ping_all_reusing(&mut port, &mut reader);
+    println!(
+        "{IDS} pings: {} allocations with ping, {} with ping_reusing",
+        allocations(|| ping_all(&mut port)),
+        allocations(|| ping_all_reusing(&mut port, &mut reader)),
+    );
+
+    let mut group = c.benchmark_group("ping_loop");
+    group.bench_function("ping", |b| b.iter(|| ping_all(&mut port)));
+    group.bench_function("ping_reusing", |b| {
+        b.iter(|| ping_all_reusing(&mut port, &mut reader))
+    });
+    group.finish();
+}
+
+/// Allocations of a protocol 1 scan sweeping every ID, against probing
+/// each ID on its own. Only counted: the sweep's progress line makes it a
+/// poor timing target.
+fn id_scan(_c: &mut Criterion) {
+    let mut port = Answering::new();
+    let options = ScanOptions {
+        protocol: Some(ProtocolVersion::V1),
+        ..ScanOptions::default()
+    };
+    let scan = allocations(|| {
+        let found = scan_ids(&mut port, Deadline::NONE, &options).unwrap();
+        assert_eq!(found.len(), usize::from(IDS));
+    });
+    let probes = allocations(|| {
+        for id in 0..IDS {
+            black_box(probe_id(&mut port, id, options.protocol).unwrap());
+        }
+    });
+    println!("{IDS}-ID scan: {scan} allocations with scan_ids, {probes} with probe_id per ID");
+}
+
+criterion_group!(benches, build_packet, ping_loop, id_scan);
+criterion_main!(benches);
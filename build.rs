@@ -0,0 +1,25 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "ffi")]
+    write_header();
+}
+
+/// Generate the C header of the `ffi` module into `OUT_DIR`. The copy in
+/// `include/` is checked in and kept in sync by tests/ffi.rs, so the build
+/// never writes to the source tree.
+#[cfg(feature = "ffi")]
+fn write_header() {
+    use std::path::PathBuf;
+
+    let dir = PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out = PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    let config = cbindgen::Config::from_file(dir.join("cbindgen.toml")).expect("bad cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(dir.join("src/ffi.rs"))
+        .generate()
+        .expect("failed to generate feeflash.h")
+        .write_to_file(out.join("feeflash.h"));
+}
@@ -0,0 +1,85 @@
+/*
+ * Flash a firmware file through the feeflash C API.
+ *
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ *   cc -Iinclude examples/ffi/flash.c -Ltarget/release -lfeeflash -o flash
+ *   LD_LIBRARY_PATH=target/release ./flash /dev/ttyUSB0 1 fw.bin
+ *
+ * An optional fourth argument sets the bootloader baud rate.
+ */
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "feeflash.h"
+
+static void progress(void *user, FeeflashEventKind kind, uint32_t frame,
+                     uint32_t total, const char *message) {
+  const char *port = user;
+  switch (kind) {
+  case FEEFLASH_EVENT_KIND_MESSAGE:
+    printf("%s: %s\n", port, message);
+    break;
+  case FEEFLASH_EVENT_KIND_WARNING:
+    fprintf(stderr, "%s: warning: %s\n", port, message);
+    break;
+  case FEEFLASH_EVENT_KIND_FRAME:
+    if (frame == total || frame % 16 == 0) {
+      printf("%s: frame %u/%u\n", port, frame, total);
+    }
+    break;
+  }
+}
+
+static unsigned char *read_file(const char *path, size_t *len) {
+  FILE *file = fopen(path, "rb");
+  if (!file) {
+    return NULL;
+  }
+  size_t capacity = 4096;
+  unsigned char *data = malloc(capacity);
+  *len = 0;
+  size_t n;
+  while (data && (n = fread(data + *len, 1, capacity - *len, file)) > 0) {
+    *len += n;
+    if (*len == capacity) {
+      capacity *= 2;
+      unsigned char *bigger = realloc(data, capacity);
+      if (!bigger) {
+        free(data);
+      }
+      data = bigger;
+    }
+  }
+  fclose(file);
+  return data;
+}
+
+int main(int argc, char **argv) {
+  if (argc < 4 || argc > 5) {
+    fprintf(stderr, "usage: %s PORT ID FIRMWARE [BOOTLOADER_BAUD]\n", argv[0]);
+    return 2;
+  }
+  size_t len;
+  unsigned char *firmware = read_file(argv[3], &len);
+  if (!firmware) {
+    perror(argv[3]);
+    return 2;
+  }
+
+  FeeflashOptions options = feeflash_options_default();
+  if (argc == 5) {
+    options.bootloader_baud = (uint32_t)strtoul(argv[4], NULL, 10);
+  }
+  FeeflashResult result =
+      feeflash_flash(argv[1], (uint8_t)atoi(argv[2]), firmware, len, &options,
+                     progress, argv[1]);
+  free(firmware);
+
+  if (result != FEEFLASH_RESULT_OK) {
+    fprintf(stderr, "flash failed (%d): %s\n", (int)result,
+            feeflash_last_error_message());
+    return 1;
+  }
+  printf("done\n");
+  return 0;
+}
@@ -0,0 +1,82 @@
+//! Browser flasher over Web Serial: scan a bus and flash a servo. The page
+//! is index.html; README.md says how to build and serve it.
+
+use std::time::Duration;
+
+use feeflash::flasher::{self, DriverError, Flasher, FlasherConfig, FlasherError, FlasherEvent};
+use feeflash::web::{SerialPort, WebSerialTransport};
+use js_sys::{Function, Object, Reflect};
+use wasm_bindgen::prelude::*;
+
+/// Ping timeout of a scan, as in `feeflash scan`.
+const SCAN_TIMEOUT: Duration = Duration::from_millis(30);
+
+fn flasher_error(e: FlasherError) -> JsValue {
+    JsError::new(&e.to_string()).into()
+}
+
+/// `{stage}`, `{model}`, `{warning}`, `{frame, total}`, `{nak, attempt}` or
+/// `{noAck, attempt}` for the page.
+fn event_object(event: FlasherEvent) -> Object {
+    let object = Object::new();
+    let set = |key: &str, value: JsValue| {
+        let _ = Reflect::set(&object, &key.into(), &value);
+    };
+    match event {
+        FlasherEvent::Stage(stage) => set("stage", stage.to_string().into()),
+        FlasherEvent::Model(model) => set("model", model.into()),
+        FlasherEvent::TorqueNotDisabled => set("warning", "could not disable torque".into()),
+        FlasherEvent::Frame { frame, total, .. } => {
+            set("frame", (frame as u32).into());
+            set("total", (total as u32).into());
+        }
+        FlasherEvent::Nak { index, attempt } => {
+            set("nak", index.into());
+            set("attempt", attempt.into());
+        }
+        FlasherEvent::NoAck { index, attempt } => {
+            set("noAck", index.into());
+            set("attempt", attempt.into());
+        }
+    }
+    object
+}
+
+/// Ping IDs 0 to 253 on `port` at `baud`; resolves to those that answered.
+#[wasm_bindgen]
+pub async fn scan(port: SerialPort, baud: u32) -> Result<Vec<u8>, JsValue> {
+    let mut transport = WebSerialTransport::open(port, baud).await?;
+    let found = flasher::scan(&mut transport, 0..=253, SCAN_TIMEOUT).await;
+    let closed = transport.close().await;
+    let found = found?;
+    closed?;
+    Ok(found)
+}
+
+/// Flash `firmware` onto device `id` on `port`, whose application runs at
+/// `baud`, calling `on_event` with each step.
+#[wasm_bindgen]
+pub async fn flash(
+    port: SerialPort,
+    id: u8,
+    firmware: Vec<u8>,
+    baud: u32,
+    on_event: Function,
+) -> Result<(), JsValue> {
+    let config = FlasherConfig {
+        app_baud: baud,
+        ..FlasherConfig::default()
+    };
+    let mut flasher = Flasher::new(id, &firmware, config).map_err(flasher_error)?;
+    let mut transport = WebSerialTransport::open(port, baud).await?;
+    let result = flasher::flash(&mut transport, &mut flasher, |event| {
+        let _ = on_event.call1(&JsValue::NULL, &event_object(event));
+    })
+    .await;
+    let closed = transport.close().await;
+    result.map_err(|e| match e {
+        DriverError::Transport(e) => e,
+        DriverError::Flasher(e) => flasher_error(e),
+    })?;
+    closed
+}
@@ -0,0 +1,26 @@
+//! Arbitrary bytes into `BootloaderFrame::from_bytes`: it must never panic,
+//! and every frame it accepts must encode back to the same bytes.
+//!
+//! The first input byte picks the checksum kind, the rest is the frame.
+
+#![no_main]
+
+use feeflash::frame::{BootloaderFrame, ChecksumKind};
+use libfuzzer_sys::fuzz_target;
+
+const KINDS: [ChecksumKind; 4] = [
+    ChecksumKind::Crc16Ccitt,
+    ChecksumKind::Crc32,
+    ChecksumKind::Sum8,
+    ChecksumKind::Sum16,
+];
+
+fuzz_target!(|input: &[u8]| {
+    let Some((&selector, bytes)) = input.split_first() else {
+        return;
+    };
+    let checksum = KINDS[selector as usize % KINDS.len()];
+    if let Ok(frame) = BootloaderFrame::from_bytes(bytes, checksum) {
+        assert_eq!(frame.to_bytes_with(checksum), bytes);
+    }
+});
@@ -0,0 +1,106 @@
+/* C API of feeflash. Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#ifndef FEEFLASH_H
+#define FEEFLASH_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Outcome of a call. Details of a failure are available from
+// `feeflash_last_error_message`.
+typedef enum FeeflashResult {
+  FEEFLASH_RESULT_OK = 0,
+  // A NULL pointer or a port name that isn't UTF-8.
+  FEEFLASH_RESULT_INVALID_ARGUMENT = 1,
+  // The port could not be opened or failed.
+  FEEFLASH_RESULT_IO = 2,
+  // The device stopped answering, or the deadline passed.
+  FEEFLASH_RESULT_TIMEOUT = 3,
+  // The device answered with something unexpected, or refused the
+  // image.
+  FEEFLASH_RESULT_PROTOCOL = 4,
+  // A bug in feeflash; the port may be left in the bootloader.
+  FEEFLASH_RESULT_PANIC = 5,
+} FeeflashResult;
+
+// What a progress callback reports.
+typedef enum FeeflashEventKind {
+  // A status line in `message`.
+  FEEFLASH_EVENT_KIND_MESSAGE = 0,
+  // A warning in `message`.
+  FEEFLASH_EVENT_KIND_WARNING = 1,
+  // Frame `frame` of `total` was acknowledged; `message` is NULL.
+  FEEFLASH_EVENT_KIND_FRAME = 2,
+} FeeflashEventKind;
+
+// Flash parameters. Start from `feeflash_options_default()`.
+typedef struct FeeflashOptions {
+  // Baud rate of the application; the port is opened at it.
+  uint32_t baud;
+  // Baud rate of the bootloader.
+  uint32_t bootloader_baud;
+  // Resends of a NAKed frame before giving up.
+  uint8_t max_retries;
+  // Time limit of the whole call in milliseconds, 0 for none.
+  uint32_t max_duration_ms;
+} FeeflashOptions;
+
+// Called with the `user` pointer given to `feeflash_flash`, on the thread
+// that called it. `message` is only valid during the call. Must not
+// throw or longjmp.
+typedef void (*FeeflashProgressCb)(void *user,
+                                   enum FeeflashEventKind kind,
+                                   uint32_t frame,
+                                   uint32_t total,
+                                   const char *message);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Defaults of the CLI: 1 Mbaud application, 500 kbaud bootloader, five
+// resends per frame, no time limit.
+struct FeeflashOptions feeflash_options_default(void);
+
+// Flash `len` bytes of `firmware` to device `id` on `port`, then start
+// the new application. `port` is a serial port path or a `tcp://` or
+// `rfc2217://` address. `options` may be NULL for the defaults. Without a
+// `progress` callback, status lines are printed to stdout.
+//
+// # Safety
+//
+// `port` is a NUL-terminated string, `firmware` points to `len` readable
+// bytes, `options` is NULL or valid, and `progress` is NULL or safe to
+// call with `user` until this function returns.
+enum FeeflashResult feeflash_flash(const char *port,
+                                   uint8_t id,
+                                   const uint8_t *firmware,
+                                   size_t len,
+                                   const struct FeeflashOptions *options,
+                                   FeeflashProgressCb progress,
+                                   void *user);
+
+// Ping every ID on `port` at `baud`. The IDs that answered are written to
+// `ids` in ascending order, at most `capacity` of them; `found` receives
+// how many answered, which may be more than `capacity`.
+//
+// # Safety
+//
+// `port` is a NUL-terminated string, `ids` is NULL or points to
+// `capacity` writable bytes, and `found` is a valid pointer.
+enum FeeflashResult feeflash_scan(const char *port,
+                                  uint32_t baud,
+                                  uint8_t *ids,
+                                  size_t capacity,
+                                  size_t *found);
+
+// Message of the last call on this thread that failed, or NULL if none
+// did. Valid until the next failing call on this thread.
+const char *feeflash_last_error_message(void);
+
+#ifdef __cplusplus
+}  // extern "C"
+#endif  // __cplusplus
+
+#endif  /* FEEFLASH_H */
@@ -0,0 +1,194 @@
+//! Async variants of the firmware transfer, for applications running on
+//! tokio, where a blocking read would stall a runtime thread.
+//!
+//! They mirror their blocking counterparts in [`crate::bootloader`] and
+//! drive the same [`Flasher`]; only the I/O is `.await`ed. Any
+//! `AsyncRead + AsyncWrite` port works; [`open_port_async`] opens a
+//! `tokio-serial` one. Async ports have no read timeout, so replies are
+//! awaited for [`ACK_TIMEOUT`] (the transfer: the frame timeout of its
+//! options, the recovery loop: its `interval`). The
+//! baud rate of a [`SerialStream`] is changed through its
+//! `tokio_serial::SerialPort` impl.
+
+use std::io;
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::bootloader::{
+    BOOTLOADER_ACK, BOOTLOADER_MAGIC, FlashOptions, Report, TransferStats, resend_warning,
+};
+use crate::deadline::Deadline;
+use crate::error::Phase;
+use crate::flasher::{Flasher, FrameReply, ReplyBuffer, Step, judge_frame_reply};
+use crate::transport::CLEAR_INPUT_TIMEOUT;
+
+/// How long a frame ACK is awaited, like the port timeout the CLI flashes
+/// with.
+pub const ACK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Open serial port `name` at `baud` for the functions of this module.
+pub fn open_port_async(name: &str, baud: u32) -> io::Result<SerialStream> {
+    Ok(tokio_serial::new(name, baud).open_native_async()?)
+}
+
+/// Read into `buf`, failing with `TimedOut` after `limit`.
+async fn read_within<P: AsyncRead + Unpin>(
+    port: &mut P,
+    buf: &mut [u8],
+    limit: Duration,
+) -> io::Result<usize> {
+    match tokio::time::timeout(limit, port.read(buf)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out")),
+    }
+}
+
+/// Drop bytes received but not read yet, until the line is quiet for
+/// [`CLEAR_INPUT_TIMEOUT`], like [`clear_input`](crate::transport::clear_input).
+async fn clear_input<P: AsyncRead + Unpin>(port: &mut P) -> io::Result<()> {
+    let mut buf = [0u8; 256];
+    loop {
+        match read_within(port, &mut buf, CLEAR_INPUT_TIMEOUT).await {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(()),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Clear the input if `clear` is set, write `data` and read up to `reply`
+/// bytes, stopping early when nothing arrives for `timeout`.
+async fn exchange<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    data: &[u8],
+    reply: usize,
+    timeout: Duration,
+    clear: bool,
+) -> io::Result<Vec<u8>> {
+    if clear {
+        clear_input(port).await?;
+    }
+    port.write_all(data).await?;
+    port.flush().await?;
+
+    let mut reply = ReplyBuffer::new(reply);
+    while let Some(buf) = reply.unfilled() {
+        match read_within(port, buf, timeout).await {
+            Ok(n) => reply.advance(n),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => reply.advance(0),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(reply.finish())
+}
+
+/// [`send_frame_with_retry`](crate::bootloader::send_frame_with_retry),
+/// awaiting each ACK for [`ACK_TIMEOUT`]. Returns the attempt that got
+/// the ACK.
+pub async fn send_frame_with_retry_async<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    frame_bytes: &[u8],
+    max_retries: u8,
+    deadline: Deadline,
+) -> io::Result<u16> {
+    let mut attempt: u16 = 1;
+    loop {
+        deadline.check(Phase::Transfer)?;
+        let reply = exchange(port, frame_bytes, 1, ACK_TIMEOUT, false).await?;
+        match judge_frame_reply(&reply, frame_bytes[0], attempt, max_retries, None)? {
+            FrameReply::Ack => return Ok(attempt),
+            FrameReply::Resend { missed } => {
+                diag!(
+                    "{}",
+                    resend_warning(missed, attempt, max_retries, ACK_TIMEOUT)
+                );
+                attempt += 1;
+            }
+        }
+    }
+}
+
+/// [`send_firmware`](crate::bootloader::send_firmware), sleeping the
+/// inter-frame delay on the tokio timer instead of `options.clock`. ACKs
+/// are awaited for `options.frame_timeout`, or [`ACK_TIMEOUT`] without
+/// one.
+pub async fn send_firmware_bytes_async<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    data: &[u8],
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let mut flasher = Flasher::transfer(data, options.flasher_config(ACK_TIMEOUT))?;
+    let mut report = Report::new(options, data.len());
+    loop {
+        report.before(&flasher)?;
+        let state = flasher.state();
+        let reply = match flasher.step() {
+            Step::Done => return Ok(report.finish()),
+            Step::Sleep(duration) => {
+                tokio::time::sleep(duration).await;
+                Vec::new()
+            }
+            Step::SetBaud(_) => unreachable!("a transfer keeps the baud rate"),
+            Step::Exchange {
+                data,
+                reply,
+                timeout,
+                clear,
+            } => exchange(port, data, reply, timeout, clear).await?,
+        };
+        let event = flasher.complete(&reply)?;
+        report.after(&flasher, state, event);
+    }
+}
+
+/// [`wait_for_bootloader_magic_ack`](crate::bootloader::wait_for_bootloader_magic_ack):
+/// send the magic every `interval` until the bootloader ACKs it. The port
+/// must already be at the bootloader baud.
+pub async fn wait_for_bootloader_magic_ack_async<P: AsyncRead + AsyncWrite + Unpin>(
+    port: &mut P,
+    interval: Duration,
+    max_wait: Option<Duration>,
+    options: &FlashOptions,
+) -> io::Result<()> {
+    options.say("Recovery mode: power the device now. Spamming magic...".to_string());
+
+    let clock = &options.clock;
+    let start = clock.now();
+    let mut buf = [0u8; 1];
+
+    loop {
+        options.check_deadline(Phase::Recovery)?;
+
+        port.write_all(BOOTLOADER_MAGIC).await?;
+        port.flush().await?;
+
+        match read_within(port, &mut buf, interval).await {
+            Ok(1) if buf[0] == BOOTLOADER_ACK => {
+                if options.progress.is_none() {
+                    out!();
+                }
+                options.say("Bootloader ACK received.".to_string());
+                return Ok(());
+            }
+            // Lightweight progress indicator, left to the sink if any.
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && options.progress.is_none() => {
+                out_inline!(".");
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e),
+            _ => {}
+        }
+
+        if let Some(limit) = max_wait
+            && clock.now() - start >= limit
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for bootloader magic ACK",
+            ));
+        }
+    }
+}
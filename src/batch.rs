@@ -0,0 +1,297 @@
+//! Flashing several servos from a manifest.
+//!
+//! A manifest is a TOML file assigning a firmware file to each bus ID:
+//!
+//! ```toml
+//! entries = [
+//!     { id = 3, firmware = "sts3215_v3.bin", expect_model = 777 },
+//!     { id = 4, firmware = "sts3250_v2.ffw" },
+//! ]
+//! ```
+//!
+//! Relative firmware paths are resolved against the manifest's directory.
+//! [`Manifest::prepare`] reads and checks every file before anything is
+//! sent, then [`run_batch`] flashes the entries one after the other.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::bootloader::{
+    FlashOptions, TransferStats, flash_device, jump_to_application, read_firmware,
+};
+use crate::firmware::{Container, decompress_firmware, validate_firmware_image};
+use crate::transport::{BaudGuard, Transport};
+
+/// One `id = firmware` assignment.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestEntry {
+    pub id: u8,
+    pub firmware: PathBuf,
+    /// Model number the device must have. Defaults to the models of a
+    /// `.ffw` container.
+    pub expect_model: Option<u16>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// A manifest entry with its firmware loaded and checked, ready to flash.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchJob {
+    pub id: u8,
+    pub firmware_path: PathBuf,
+    /// The image as sent to the bootloader (a container's payload).
+    pub image: Vec<u8>,
+    /// Models accepted by the model check; `None` skips it.
+    pub expected_models: Option<Vec<u16>>,
+}
+
+/// What happened to one [`BatchJob`].
+#[derive(Debug)]
+pub struct BatchResult {
+    pub id: u8,
+    pub firmware_path: PathBuf,
+    pub result: io::Result<TransferStats>,
+}
+
+impl Manifest {
+    /// Parse manifest text. Relative firmware paths are taken relative to
+    /// `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> io::Result<Manifest> {
+        let mut manifest: Manifest = toml::from_str(text)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+        for entry in &mut manifest.entries {
+            entry.firmware = base_dir.join(&entry.firmware);
+        }
+        Ok(manifest)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Manifest> {
+        let base_dir = path.parent().unwrap_or(Path::new(""));
+        Manifest::parse(&fs::read_to_string(path)?, base_dir)
+    }
+
+    /// Read and check every firmware file, so a bad entry is found before
+    /// any servo is touched. Fails on the first bad entry, naming it.
+    ///
+    /// Unless `force` is set, every image must pass
+    /// [`validate_firmware_image`] and every entry needs a model to check
+    /// against: `expect_model` or the models of a `.ffw` container. With
+    /// `force` neither is checked.
+    pub fn prepare(&self, max_firmware_size: usize, force: bool) -> io::Result<Vec<BatchJob>> {
+        if self.entries.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Manifest has no entries",
+            ));
+        }
+        let mut jobs: Vec<BatchJob> = Vec::with_capacity(self.entries.len());
+        for entry in &self.entries {
+            if jobs.iter().any(|job| job.id == entry.id) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("Manifest lists id {} more than once", entry.id),
+                ));
+            }
+            let job = entry.prepare(max_firmware_size, force).map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Entry id {} ({}): {e}", entry.id, entry.firmware.display()),
+                )
+            })?;
+            jobs.push(job);
+        }
+        Ok(jobs)
+    }
+}
+
+impl ManifestEntry {
+    fn prepare(&self, max_firmware_size: usize, force: bool) -> io::Result<BatchJob> {
+        let bytes = read_firmware(fs::File::open(&self.firmware)?, max_firmware_size)?;
+        let bytes = decompress_firmware(bytes, max_firmware_size)?;
+        let (image, container_models) = if Container::is_container(&bytes) {
+            let container = Container::parse(&bytes)?;
+            (container.payload, Some(container.models))
+        } else {
+            (bytes, None)
+        };
+        if !force {
+            validate_firmware_image(&image)?;
+        }
+
+        let expected_models = match (self.expect_model, container_models) {
+            _ if force => None,
+            (Some(model), _) => Some(vec![model]),
+            (None, Some(models)) => Some(models),
+            (None, None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "no expect_model for a raw image; set one or use --force",
+                ));
+            }
+        };
+        Ok(BatchJob {
+            id: self.id,
+            firmware_path: self.firmware.clone(),
+            image,
+            expected_models,
+        })
+    }
+}
+
+/// Flash each job in turn with [`flash_device`], then confirm the device
+/// boots at `app_baud` before moving on. A failed entry doesn't stop the
+/// later ones unless `fail_fast` is set. Returns one result per attempted
+/// job, in order.
+///
+/// The port timeout should already be set to the normal protocol timeout.
+pub fn run_batch(
+    port: &mut dyn Transport,
+    jobs: &[BatchJob],
+    app_baud: u32,
+    options: &FlashOptions,
+    fail_fast: bool,
+) -> Vec<BatchResult> {
+    let mut results = Vec::with_capacity(jobs.len());
+    for job in jobs {
+        out!(
+            "=== Flashing device id {} with {} ===",
+            job.id,
+            job.firmware_path.display()
+        );
+        let options = FlashOptions {
+            expected_models: job.expected_models.clone(),
+            ..options.clone()
+        };
+        let result = flash_job(port, job, app_baud, &options);
+        if let Err(e) = &result {
+            diag!("Device id {} failed: {e}", job.id);
+        }
+        let failed = result.is_err();
+        results.push(BatchResult {
+            id: job.id,
+            firmware_path: job.firmware_path.clone(),
+            result,
+        });
+        if failed && fail_fast {
+            break;
+        }
+    }
+    results
+}
+
+fn flash_job(
+    port: &mut dyn Transport,
+    job: &BatchJob,
+    app_baud: u32,
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    // Back at the application baud for the next entry even if this one
+    // fails at the bootloader baud.
+    let mut port = BaudGuard::new(port, app_baud);
+    let timeout = port.timeout();
+    let result = flash_device(&mut port, job.id, &job.image, options).and_then(|stats| {
+        jump_to_application(&mut port, job.id, app_baud, options)?;
+        Ok(stats)
+    });
+    // Likewise the timeout, which the flash changes along the way.
+    port.set_timeout(timeout)?;
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_resolves_paths_and_rejects_typos() {
+        let text = r#"
+            entries = [
+                { id = 3, firmware = "sts3215_v3.bin", expect_model = 777 },
+                { id = 4, firmware = "/abs/sts3250.ffw" },
+            ]
+        "#;
+        let manifest = Manifest::parse(text, Path::new("robot")).unwrap();
+        assert_eq!(
+            manifest.entries,
+            [
+                ManifestEntry {
+                    id: 3,
+                    firmware: PathBuf::from("robot/sts3215_v3.bin"),
+                    expect_model: Some(777),
+                },
+                ManifestEntry {
+                    id: 4,
+                    firmware: PathBuf::from("/abs/sts3250.ffw"),
+                    expect_model: None,
+                },
+            ]
+        );
+
+        let typo = r#"entries = [{ id = 3, firmware = "a.bin", expect_modle = 777 }]"#;
+        assert!(Manifest::parse(typo, Path::new("")).is_err());
+        let bad_id = r#"entries = [{ id = 300, firmware = "a.bin" }]"#;
+        assert!(Manifest::parse(bad_id, Path::new("")).is_err());
+    }
+
+    #[test]
+    fn prepare_checks_every_entry_first() {
+        let dir = std::env::temp_dir().join(format!("feeflash-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("a.bin"), [1u8; 100]).unwrap();
+        let container = Container {
+            models: vec![2825],
+            version: "1.0".to_string(),
+            payload: vec![2u8; 70],
+            signature: None,
+        };
+        fs::write(dir.join("b.ffw"), container.pack().unwrap()).unwrap();
+
+        let manifest = |text: &str| Manifest::parse(text, &dir).unwrap();
+        let jobs = manifest(
+            r#"entries = [
+                { id = 1, firmware = "a.bin", expect_model = 777 },
+                { id = 2, firmware = "b.ffw" },
+            ]"#,
+        )
+        .prepare(1024, false)
+        .unwrap();
+        assert_eq!(jobs[0].expected_models, Some(vec![777]));
+        assert_eq!(jobs[1].image, [2u8; 70]);
+        assert_eq!(jobs[1].expected_models, Some(vec![2825]));
+
+        let err = manifest(
+            r#"entries = [
+                { id = 1, firmware = "a.bin", expect_model = 777 },
+                { id = 2, firmware = "missing.bin", expect_model = 777 },
+            ]"#,
+        )
+        .prepare(1024, false)
+        .unwrap_err();
+        assert!(err.to_string().starts_with("Entry id 2 ("), "{err}");
+
+        let unchecked = manifest(r#"entries = [{ id = 1, firmware = "a.bin" }]"#);
+        assert!(unchecked.prepare(1024, false).is_err());
+        assert_eq!(
+            unchecked.prepare(1024, true).unwrap()[0].expected_models,
+            None
+        );
+
+        let twice = manifest(
+            r#"entries = [
+                { id = 1, firmware = "a.bin", expect_model = 777 },
+                { id = 1, firmware = "b.ffw" },
+            ]"#,
+        );
+        assert!(twice.prepare(1024, false).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,161 +1,1390 @@
+use std::fmt::Debug;
 use std::fs;
 use std::io;
-use std::io::Write;
+use std::io::Read;
+use std::ops::Range;
 use std::path::Path;
-use std::time::Duration;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use crate::frame::BootloaderFrame;
+use crate::clock::{Clock, SystemClock};
+use crate::deadline::Deadline;
+use crate::dynamixel::registers::{describe_model, read_firmware_version, read_model};
+use crate::dynamixel::{PING_TIMEOUT_MS, ProtocolVersion, ping_with};
+use crate::error::{FeeflashError, Phase};
+use crate::firmware::{FirmwareVersion, decompress_firmware};
+use crate::flasher::{
+    Flasher, FlasherConfig, FlasherEvent, FrameReply, ReplyBuffer, State, Step, Until,
+    judge_frame_reply,
+};
+use crate::frame::{CHUNK_SIZE, ChecksumKind};
+use crate::models::lookup_model;
+use crate::transport::{BaudGuard, HalfDuplexTransport, Transport, clear_input};
 
-pub const BOOTLOADER_MAGIC: &[u8] = b"1fBVA";
+pub use crate::flasher::{
+    BOOT_CONFIRM_TIMEOUT, BOOTLOADER_ACK, BOOTLOADER_BAUD, BOOTLOADER_INIT, BOOTLOADER_MAGIC,
+    BOOTLOADER_NAK, DEFAULT_FRAME_TIMEOUT, DEFAULT_MAX_FIRMWARE_SIZE, REBOOT_DELAY,
+};
+
+/// One step of a flash, passed to [`FlashOptions::progress`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlashEvent {
+    /// A status line, printed to stdout when no progress sink is set.
+    Message(String),
+    /// A warning, printed to stderr when no progress sink is set.
+    Warning(String),
+    /// Frame `frame` of `total`, with bootloader index `index`, was ACKed
+    /// after `retries` resends.
+    Frame {
+        frame: usize,
+        total: usize,
+        index: u8,
+        retries: u32,
+    },
+}
+
+/// Receives the [`FlashEvent`]s of a flash in place of stdout and stderr,
+/// e.g. to tell apart several flashes running at once.
+pub trait Progress: Debug + Send + Sync {
+    fn event(&self, event: FlashEvent);
+}
+
+/// Options controlling the firmware transfer.
+/// With the `serde` feature, fields missing from the input take their
+/// defaults; `deadline`, `clock` and `progress` aren't data and are always
+/// left at theirs.
+#[derive(Debug, Clone)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FlashOptions {
+    /// Number of times a NAKed frame is resent before giving up.
+    pub max_retries: u8,
+    /// Resends allowed across the whole transfer; `None` for no limit.
+    /// A marginal link then fails early instead of crawling through.
+    pub max_total_retries: Option<u32>,
+    /// Overall deadline for the operation, checked by every blocking loop.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub deadline: Deadline,
+    /// Pause after each ACKed frame before sending the next one. Some
+    /// servos NAK back-to-back frames while still committing the last one.
+    pub inter_frame_delay: Duration,
+    /// How long the ACK of each frame is awaited, whatever the port
+    /// timeout. A frame whose ACK doesn't come is resent like a NAKed one,
+    /// within `max_retries`. `None` waits the port timeout and fails on the
+    /// first missed ACK.
+    pub frame_timeout: Option<Duration>,
+    /// Time source for delays, timeouts and the deadline.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub clock: Arc<dyn Clock>,
+    /// The line echoes transmitted bytes (single-wire TTL adapters). Applied
+    /// by [`FlashOptions::wrap_transport`].
+    pub half_duplex: bool,
+    /// Disable torque before rebooting into the bootloader, so a loaded
+    /// joint isn't left holding position through the reset.
+    pub torque_off: bool,
+    /// Models the firmware is built for. [`flash_device`] refuses any other
+    /// device model; `None` skips the check.
+    pub expected_models: Option<Vec<u16>>,
+    /// Dynamixel protocol of the reboot instruction and of the pings while
+    /// waiting for the application. The bootloader framing is the same for
+    /// both; model check, torque and EEPROM access use protocol 1.
+    pub protocol: ProtocolVersion,
+    /// Checksum the bootloader expects in each firmware frame.
+    pub checksum: ChecksumKind,
+    /// Baud rate of the bootloader. Device-specific: servo families whose
+    /// bootloader runs at another rate need it changed.
+    pub bootloader_baud: u32,
+    /// Baud rate of the application. [`flash_device`] switches the port
+    /// back to it when it returns, also after failing at `bootloader_baud`;
+    /// `None` leaves the port at whatever rate the failure left it.
+    pub app_baud: Option<u32>,
+    /// Pause after the reboot instruction before the magic is sent.
+    /// Device-specific, like `bootloader_baud`.
+    pub reboot_delay: Duration,
+    /// Pause after a reset before [`wait_for_application`] starts pinging.
+    pub boot_settle: Duration,
+    /// How long [`wait_for_application`] pings before giving up.
+    pub boot_confirm_timeout: Duration,
+    /// Largest firmware image accepted. Bigger images are refused before
+    /// anything is sent, which catches a wrong file passed by mistake.
+    pub max_firmware_size: usize,
+    /// Flash page size of the device. When set, the image is padded with
+    /// 0xFF to a whole number of pages before framing, for controllers
+    /// that would keep stale bytes in a partly written last page. The
+    /// 64-byte frame padding applies on top.
+    pub flash_page_size: Option<usize>,
+    /// Send only the image from this byte on, e.g. one slot of an A/B
+    /// layout. See [`firmware_region`].
+    pub region_offset: usize,
+    /// Send only this many bytes from `region_offset`; `None` for the rest
+    /// of the image.
+    pub region_length: Option<usize>,
+    /// Where status lines and frame progress go. `None` prints status lines
+    /// to stdout (warnings to stderr) and announces every frame.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub progress: Option<Arc<dyn Progress>>,
+}
+
+impl Default for FlashOptions {
+    fn default() -> Self {
+        FlashOptions {
+            max_retries: 5,
+            max_total_retries: None,
+            deadline: Deadline::NONE,
+            inter_frame_delay: Duration::ZERO,
+            frame_timeout: Some(DEFAULT_FRAME_TIMEOUT),
+            clock: Arc::new(SystemClock),
+            half_duplex: false,
+            torque_off: true,
+            expected_models: None,
+            protocol: ProtocolVersion::V1,
+            checksum: ChecksumKind::Crc16Ccitt,
+            bootloader_baud: BOOTLOADER_BAUD,
+            app_baud: None,
+            reboot_delay: REBOOT_DELAY,
+            boot_settle: REBOOT_DELAY,
+            boot_confirm_timeout: BOOT_CONFIRM_TIMEOUT,
+            max_firmware_size: DEFAULT_MAX_FIRMWARE_SIZE,
+            flash_page_size: None,
+            region_offset: 0,
+            region_length: None,
+            progress: None,
+        }
+    }
+}
+
+impl FlashOptions {
+    /// Check the deadline against this options' clock.
+    pub fn check_deadline(&self, phase: Phase) -> io::Result<()> {
+        self.deadline.check_at(self.clock.now(), phase)
+    }
+
+    /// Report a status line to `progress`, or print it.
+    pub(crate) fn say(&self, message: String) {
+        match &self.progress {
+            Some(progress) => progress.event(FlashEvent::Message(message)),
+            None => out!("{message}"),
+        }
+    }
+
+    /// Report a warning to `progress`, or print it to stderr.
+    pub(crate) fn warn(&self, message: String) {
+        match &self.progress {
+            Some(progress) => progress.event(FlashEvent::Warning(message)),
+            None => diag!("{message}"),
+        }
+    }
+
+    /// The protocol settings of these options, for a [`Flasher`] whose
+    /// bootloader replies are awaited for `reply_timeout`.
+    pub(crate) fn flasher_config(&self, reply_timeout: Duration) -> FlasherConfig {
+        FlasherConfig {
+            bootloader_baud: self.bootloader_baud,
+            reboot_delay: self.reboot_delay,
+            boot_settle: self.boot_settle,
+            boot_confirm_timeout: self.boot_confirm_timeout,
+            reply_timeout,
+            frame_timeout: self.frame_timeout,
+            ping_timeout: Duration::from_millis(PING_TIMEOUT_MS),
+            max_retries: self.max_retries,
+            max_total_retries: self.max_total_retries,
+            inter_frame_delay: self.inter_frame_delay,
+            checksum: self.checksum,
+            protocol: self.protocol,
+            expected_models: self.expected_models.clone(),
+            torque_off: self.torque_off,
+            max_firmware_size: self.max_firmware_size,
+            flash_page_size: self.flash_page_size,
+            region_offset: self.region_offset,
+            region_length: self.region_length,
+            ..FlasherConfig::default()
+        }
+    }
+
+    /// Wrap `port` in a [`HalfDuplexTransport`] when `half_duplex` is set,
+    /// so every write, from pings to firmware frames, discards its echo.
+    pub fn wrap_transport<'a>(&self, port: Box<dyn Transport + 'a>) -> Box<dyn Transport + 'a> {
+        if self.half_duplex {
+            Box::new(HalfDuplexTransport::new(port))
+        } else {
+            port
+        }
+    }
+}
+
+/// Summary of a completed firmware transfer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TransferStats {
+    /// Frames ACKed by the bootloader.
+    pub frames: usize,
+    /// Resends caused by NAKs across the whole transfer.
+    pub total_retries: u32,
+    /// Time spent in deliberate inter-frame delays.
+    pub delay_time: Duration,
+    /// Wall-clock duration of the transfer.
+    pub elapsed: Duration,
+}
+
+impl std::fmt::Display for TransferStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} frames, {} retries, {:.2}s elapsed ({:.2}s in inter-frame delays)",
+            self.frames,
+            self.total_retries,
+            self.elapsed.as_secs_f64(),
+            self.delay_time.as_secs_f64()
+        )
+    }
+}
 
 pub fn wait_for_bootloader_magic_ack(
-    port: &mut dyn serialport::SerialPort,
+    port: &mut dyn Transport,
     interval: Duration,
     max_wait: Option<Duration>,
+    options: &FlashOptions,
 ) -> io::Result<()> {
-    println!("Recovery mode: power the device now. Spamming magic...");
+    options.say("Recovery mode: power the device now. Spamming magic...".to_string());
     port.set_timeout(interval)?;
+    clear_input(port)?;
 
-    let start = std::time::Instant::now();
+    let clock = &options.clock;
+    let start = clock.now();
     let magic = BOOTLOADER_MAGIC;
     let mut buf = [0u8; 1];
 
     loop {
+        options.check_deadline(Phase::Recovery)?;
+
         port.write_all(magic)?;
         port.flush()?;
 
         match port.read(&mut buf) {
-            Ok(n) if n == 1 => {
-                if buf[0] == 0x06 {
-                    println!("\nBootloader ACK received.");
-                    return Ok(());
+            Ok(1) if buf[0] == BOOTLOADER_ACK => {
+                if options.progress.is_none() {
+                    out!();
                 }
+                options.say("Bootloader ACK received.".to_string());
+                return Ok(());
             }
-            Err(e) if e.kind() == io::ErrorKind::TimedOut => {
-                // Lightweight progress indicator
-                print!(".");
-                let _ = std::io::stdout().flush();
+            // Lightweight progress indicator, left to the sink if any.
+            Err(e) if e.kind() == io::ErrorKind::TimedOut && options.progress.is_none() => {
+                out_inline!(".");
             }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
             Err(e) => return Err(e),
             _ => {}
         }
 
-        if let Some(limit) = max_wait {
-            if start.elapsed() >= limit {
-                return Err(io::Error::new(
-                    io::ErrorKind::TimedOut,
-                    "Timed out waiting for bootloader magic ACK",
-                ));
-            }
+        if let Some(limit) = max_wait
+            && clock.now() - start >= limit
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Timed out waiting for bootloader magic ACK",
+            ));
         }
     }
 }
 
-pub fn send_frame_with_retry(
-    port: &mut dyn serialport::SerialPort,
-    frame_bytes: &[u8; 70],
-    max_retries: u8,
+/// Flash `firmware` onto device `id`, which must be running its
+/// application: check the model, prepare the servo, reboot it into the
+/// bootloader, complete the handshake and stream the image.
+///
+/// The port timeout should already be set to the normal protocol timeout.
+pub fn flash_device(
+    port: &mut dyn Transport,
+    id: u8,
+    firmware: &[u8],
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let config = options.flasher_config(port.timeout());
+    let flasher = Flasher::to_transfer(id, firmware, config)?;
+    let report = Report::new(options, firmware.len());
+    match options.app_baud {
+        Some(baud) => drive(&mut BaudGuard::new(port, baud), flasher, report),
+        None => drive(port, flasher, report),
+    }
+}
+
+/// Read the model number of `id` and fail with `IncompatibleModel` unless
+/// it is one of `expected_models`. Returns the model.
+pub fn check_model(port: &mut dyn Transport, id: u8, expected_models: &[u16]) -> io::Result<u16> {
+    let previous = port.timeout();
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))?;
+    let model = read_model(port, id);
+    port.set_timeout(previous)?;
+    let model = model?;
+
+    if !expected_models.contains(&model) {
+        return Err(FeeflashError::IncompatibleModel {
+            device: model,
+            expected: expected_models.to_vec(),
+        }
+        .into());
+    }
+    Ok(model)
+}
+
+/// Read the firmware version of `id` and fail with `VersionMismatch`
+/// unless it is `expected`. Returns the version read. Uses protocol 1 and
+/// the ping timeout, like [`check_model`].
+pub fn check_version(
+    port: &mut dyn Transport,
+    id: u8,
+    expected: FirmwareVersion,
+) -> io::Result<FirmwareVersion> {
+    let previous = port.timeout();
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))?;
+    let version = read_firmware_version(port, id);
+    port.set_timeout(previous)?;
+    let actual = FirmwareVersion::from(version?);
+
+    if actual != expected {
+        return Err(FeeflashError::VersionMismatch { expected, actual }.into());
+    }
+    Ok(actual)
+}
+
+/// Reboot device `id` into the bootloader and complete the handshake:
+/// reboot instruction, baud switch, settle delay, magic and init. The
+/// [`BootloaderSession`](crate::session::BootloaderSession) steps of
+/// [`enter`](crate::session::BootloaderSession::enter) and
+/// [`SessionReady::init`](crate::session::SessionReady::init) in one call.
+///
+/// The port timeout should already be set to the normal protocol timeout.
+pub fn enter_bootloader(
+    port: &mut dyn Transport,
+    id: u8,
+    options: &FlashOptions,
 ) -> io::Result<()> {
-    let mut attempt: u8 = 0;
+    handshake(port, id, State::Reboot, Until::Init, options)
+}
 
-    loop {
-        attempt = attempt.wrapping_add(1);
+/// Catch the bootloader right after a reboot: switch to
+/// `options.bootloader_baud`, wait `options.reboot_delay` for the device to
+/// come up and send the magic sequence.
+/// Fails with "Device is not in bootloader mode" if the magic is not ACKed.
+pub fn magic_handshake(port: &mut dyn Transport, options: &FlashOptions) -> io::Result<()> {
+    // The bootloader has no identity/version query; the magic ACK is the
+    // only sign that we are talking to it.
+    handshake(port, 0, State::BootloaderBaud, Until::Magic, options)
+}
 
-        port.write_all(frame_bytes)?;
-        port.flush()?;
+/// Tell the bootloader to initialize by sending 0x01 and wait for another
+/// 0x06 before starting the firmware transfer. Must follow the magic ACK,
+/// either from [`enter_bootloader`] or the recovery loop.
+pub fn init_bootloader(port: &mut dyn Transport, options: &FlashOptions) -> io::Result<()> {
+    handshake(port, 0, State::Init, Until::Init, options)
+}
+
+/// Run the handshake with device `id` from `start` to `until`.
+pub(crate) fn handshake(
+    port: &mut dyn Transport,
+    id: u8,
+    start: State,
+    until: Until,
+    options: &FlashOptions,
+) -> io::Result<()> {
+    let config = options.flasher_config(port.timeout());
+    let flasher = Flasher::handshake(id, config, start, until);
+    drive(port, flasher, Report::new(options, 0)).map(drop)
+}
+
+/// Erase the application flash.
+///
+/// The Feetech bootloader has no dedicated erase opcode: the flash region is
+/// erased implicitly when the first frame after init is programmed. This is
+/// therefore a no-op, kept so callers can state the step explicitly and pick
+/// up a real erase if a bootloader revision ever adds one.
+pub fn erase_flash(_port: &mut dyn Transport) -> io::Result<()> {
+    Ok(())
+}
+
+/// Confirm the device left the bootloader and runs the new firmware.
+///
+/// The bootloader has no execute/jump opcode: ACKing the final frame (stop
+/// byte 4) already makes it jump to the application. This verifies the
+/// jump instead, by switching back to `app_baud` and pinging `id` until the
+/// application answers. Leaves the port at `app_baud` with the ping timeout.
+///
+/// Fails with `BootNotConfirmed` if the device never answers: the image is
+/// written, so the device needs a power cycle rather than another flash.
+pub fn jump_to_application(
+    port: &mut dyn Transport,
+    id: u8,
+    app_baud: u32,
+    options: &FlashOptions,
+) -> io::Result<()> {
+    options.say(format!("Setting baud rate back to {}...", app_baud));
+    port.set_baud_rate(app_baud)?;
+
+    match wait_for_application(port, id, options) {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut && FeeflashError::from_io(&e).is_none() => {
+            return Err(FeeflashError::BootNotConfirmed { id }.into());
+        }
+        result => result?,
+    }
+    options.say(format!("Device id {} is running the new firmware.", id));
+    Ok(())
+}
+
+/// Wait for the application on device `id` to come up after a reset: sleep
+/// `options.boot_settle`, then ping at the current baud until it answers or
+/// `options.boot_confirm_timeout` elapses. Leaves the port with the ping
+/// timeout.
+pub fn wait_for_application(
+    port: &mut dyn Transport,
+    id: u8,
+    options: &FlashOptions,
+) -> io::Result<()> {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))?;
 
-        let mut resp = [0u8; 1];
-        let read_bytes = port.read(&mut resp)?;
+    let clock = &options.clock;
+    clock.sleep(options.boot_settle);
 
-        if read_bytes != 1 {
+    let start = clock.now();
+    loop {
+        options.check_deadline(Phase::Verify)?;
+
+        if ping_with(port, id, options.protocol).is_ok() {
+            return Ok(());
+        }
+
+        if clock.now() - start >= options.boot_confirm_timeout {
             return Err(io::Error::new(
-                io::ErrorKind::UnexpectedEof,
+                io::ErrorKind::TimedOut,
                 format!(
-                    "Expected 1-byte response from bootloader, got {}",
-                    read_bytes
+                    "Device id {id} did not answer within {:.1}s after reset",
+                    options.boot_confirm_timeout.as_secs_f64()
                 ),
             ));
         }
+    }
+}
 
-        match resp[0] {
-            0x06 => {
-                // ACK
-                return Ok(());
+/// Send one frame, resending it on NAK up to `max_retries` times, and
+/// return the attempt that got the ACK: 1 when it went through at once,
+/// so the NAKs it drew are one less. Each NAK is logged to stderr. The ACK
+/// is awaited for the port timeout; [`send_firmware`] uses
+/// [`FlashOptions::frame_timeout`] instead.
+pub fn send_frame_with_retry(
+    port: &mut dyn Transport,
+    frame_bytes: &[u8],
+    max_retries: u8,
+    deadline: Deadline,
+) -> io::Result<u16> {
+    send_frame_counting(port, frame_bytes, max_retries, deadline, None, &|message| {
+        diag!("{message}")
+    })
+}
+
+/// [`send_frame_with_retry`] awaiting each ACK for `frame_timeout` rather
+/// than the port timeout. A missed ACK is retried like a NAK, after
+/// draining the line so that the late ACK of the previous attempt is not
+/// taken for the answer to the resend.
+pub fn send_frame_with_retry_timeout(
+    port: &mut dyn Transport,
+    frame_bytes: &[u8],
+    max_retries: u8,
+    deadline: Deadline,
+    frame_timeout: Duration,
+) -> io::Result<u16> {
+    send_frame_counting(
+        port,
+        frame_bytes,
+        max_retries,
+        deadline,
+        Some(frame_timeout),
+        &|message| diag!("{message}"),
+    )
+}
+
+/// [`send_frame_with_retry`] passing each resend to `warn`. With
+/// `frame_timeout`, the ACK is awaited that long and a missed one is
+/// retried after [`clear_input`].
+fn send_frame_counting(
+    port: &mut dyn Transport,
+    frame_bytes: &[u8],
+    max_retries: u8,
+    deadline: Deadline,
+    frame_timeout: Option<Duration>,
+    warn: &dyn Fn(String),
+) -> io::Result<u16> {
+    let timeout = frame_timeout.unwrap_or(port.timeout());
+    let mut clear = false;
+    let mut attempt: u16 = 1;
+    loop {
+        deadline.check(Phase::Transfer)?;
+        let reply = exchange(port, frame_bytes, 1, timeout, clear)?;
+        match judge_frame_reply(&reply, frame_bytes[0], attempt, max_retries, frame_timeout)? {
+            FrameReply::Ack => return Ok(attempt),
+            FrameReply::Resend { missed } => {
+                warn(resend_warning(missed, attempt, max_retries, timeout));
+                clear = missed;
+                attempt += 1;
             }
-            0x15 => {
-                // NAK, retry if we still have attempts left
-                if attempt > max_retries {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        format!("Bootloader NAK after {} attempts", attempt - 1),
-                    ));
+        }
+    }
+}
+
+/// Warning for send `attempt` of a frame, which is resent after a NAK or,
+/// when `missed`, after no ACK came within `timeout`. The resend is the
+/// `attempt`th of at most `limit`.
+pub(crate) fn resend_warning(missed: bool, attempt: u16, limit: u8, timeout: Duration) -> String {
+    if missed {
+        format!(
+            "No ACK within {} ms, resending frame (resend {} / {})",
+            timeout.as_millis(),
+            attempt,
+            limit
+        )
+    } else {
+        format!(
+            "Bootloader NAK, resending frame (resend {} / {})",
+            attempt, limit
+        )
+    }
+}
+
+/// Fail with `EmptyFirmware` or `FirmwareTooLarge` unless `data` is a
+/// plausible image of at most `max` bytes.
+pub fn check_firmware(data: &[u8], max: usize) -> io::Result<()> {
+    if data.is_empty() {
+        return Err(FeeflashError::EmptyFirmware.into());
+    }
+    if data.len() > max {
+        return Err(FeeflashError::FirmwareTooLarge {
+            len: Some(data.len()),
+            max,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Read a whole firmware image from `reader`, e.g. stdin, reading at most
+/// `max + 1` bytes. Checked like [`check_firmware`].
+pub fn read_firmware(reader: impl Read, max: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.take(max as u64 + 1).read_to_end(&mut data)?;
+    if data.len() > max {
+        return Err(FeeflashError::FirmwareTooLarge { len: None, max }.into());
+    }
+    check_firmware(&data, max)?;
+    Ok(data)
+}
+
+/// Read a firmware image from a file and stream it with [`send_firmware`].
+pub fn send_firmware_file(
+    port: &mut dyn Transport,
+    firmware_path: &Path,
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let file = fs::File::open(firmware_path)?;
+    send_firmware_reader(port, file, options)
+}
+
+/// Read a firmware image from `reader` to its end, then stream it with
+/// [`send_firmware`]. A gzip-compressed image is decompressed first, see
+/// [`decompress_firmware`]. Nothing is sent if reading fails.
+pub fn send_firmware_reader(
+    port: &mut dyn Transport,
+    reader: impl Read,
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let data = read_firmware(reader, options.max_firmware_size)?;
+    let data = decompress_firmware(data, options.max_firmware_size)?;
+    send_firmware(port, &data, options)
+}
+
+/// [`send_firmware`] under its former name.
+#[deprecated(note = "renamed to send_firmware")]
+pub fn send_firmware_bytes(
+    port: &mut dyn Transport,
+    data: &[u8],
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    send_firmware(port, data, options)
+}
+
+/// Stream an in-memory firmware image as bootloader frames. The image is
+/// checked with [`check_firmware`] before the first frame goes out, cut to
+/// its [`firmware_region`], then padded to
+/// [`FlashOptions::flash_page_size`]. Frame indices start at 1 for a region
+/// too.
+pub fn send_firmware(
+    port: &mut dyn Transport,
+    data: &[u8],
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let config = options.flasher_config(port.timeout());
+    let flasher = Flasher::transfer(data, config)?;
+    drive(port, flasher, Report::new(options, data.len()))
+}
+
+/// Bytes of a `len`-byte image that [`send_firmware`] sends, from
+/// [`FlashOptions::region_offset`] and [`FlashOptions::region_length`]; the
+/// whole image without them. See [`crate::flasher::firmware_region`].
+pub fn firmware_region(len: usize, options: &FlashOptions) -> io::Result<Range<usize>> {
+    Ok(crate::flasher::firmware_region(
+        len,
+        options.region_offset,
+        options.region_length,
+    )?)
+}
+
+/// Run `flasher` to its end over `port`, sleeping on the clock of the
+/// options of `report`.
+fn drive(
+    port: &mut dyn Transport,
+    mut flasher: Flasher,
+    mut report: Report,
+) -> io::Result<TransferStats> {
+    loop {
+        report.before(&flasher)?;
+        let state = flasher.state();
+        let reply = match flasher.step() {
+            Step::Done => return Ok(report.finish()),
+            Step::SetBaud(baud) => {
+                port.set_baud_rate(baud)?;
+                Vec::new()
+            }
+            Step::Sleep(duration) => {
+                report.options.clock.sleep(duration);
+                Vec::new()
+            }
+            Step::Exchange {
+                data,
+                reply,
+                timeout,
+                clear,
+            } => exchange(port, data, reply, timeout, clear)?,
+        };
+        let event = flasher.complete(&reply)?;
+        report.after(&flasher, state, event);
+    }
+}
+
+/// Clear the input if `clear` is set, write `data` and read up to `reply`
+/// bytes with the port timeout set to `timeout`, stopping early when a
+/// read times out.
+fn exchange(
+    port: &mut dyn Transport,
+    data: &[u8],
+    reply: usize,
+    timeout: Duration,
+    clear: bool,
+) -> io::Result<Vec<u8>> {
+    if clear {
+        clear_input(port)?;
+    }
+    port.write_all(data)?;
+    port.flush()?;
+
+    let mut reply = ReplyBuffer::new(reply);
+    if reply.unfilled().is_none() {
+        return Ok(reply.finish());
+    }
+    let previous = port.timeout();
+    port.set_timeout(timeout)?;
+    let result = loop {
+        let Some(buf) = reply.unfilled() else {
+            break Ok(());
+        };
+        match port.read(buf) {
+            Ok(n) => reply.advance(n),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => reply.advance(0),
+            Err(e) => break Err(e),
+        }
+    };
+    port.set_timeout(previous)?;
+    result?;
+    Ok(reply.finish())
+}
+
+/// What a flash reports on the way, whichever driver runs the [`Flasher`]:
+/// status lines, warnings and frame progress through the options, the
+/// deadline, and the statistics of the transfer.
+pub(crate) struct Report<'o> {
+    pub(crate) options: &'o FlashOptions,
+    /// Length of the whole image, for the region announcement.
+    image_len: usize,
+    /// When the first frame went out.
+    start: Option<Instant>,
+    stats: TransferStats,
+}
+
+impl<'o> Report<'o> {
+    pub(crate) fn new(options: &'o FlashOptions, image_len: usize) -> Self {
+        Report {
+            options,
+            image_len,
+            start: None,
+            stats: TransferStats::default(),
+        }
+    }
+
+    /// Announce the step `flasher` is about to take, and check the deadline
+    /// before it writes.
+    pub(crate) fn before(&mut self, flasher: &Flasher) -> io::Result<()> {
+        let options = self.options;
+        let id = flasher.id();
+        match flasher.state() {
+            State::Torque => options.say(format!("Disabling torque on device id {}...", id)),
+            State::Reboot => options.say(format!("Rebooting device id {} into bootloader...", id)),
+            State::BootloaderBaud => options.say(format!(
+                "Setting baud rate to {}...",
+                flasher.config().bootloader_baud
+            )),
+            State::RebootDelay => options.say(format!(
+                "Sleeping for {}ms to allow device to reboot...",
+                flasher.config().reboot_delay.as_millis()
+            )),
+            State::Magic => {
+                options.check_deadline(Phase::Handshake)?;
+                options.say("Sending magic sequence to enter bootloader...".to_string());
+            }
+            State::Init => {
+                options.check_deadline(Phase::Handshake)?;
+                options.say("Sending init byte 0x01 to bootloader...".to_string());
+            }
+            State::Frame { attempt, .. } => {
+                if self.start.is_none() {
+                    self.start_transfer(flasher);
                 }
-                eprintln!(
-                    "Bootloader NAK, retrying frame (attempt {} / {})",
-                    attempt, max_retries
-                );
-                continue;
+                let (index, is_last) = flasher.frame();
+                // With a progress sink, frames are reported once ACKed instead.
+                if attempt == 1 && options.progress.is_none() {
+                    out!(
+                        "Sending frame index={} (chunk {}/{}) , last={}...",
+                        index,
+                        flasher.progress().0 + 1,
+                        flasher.progress().1,
+                        is_last
+                    );
+                }
+                options.check_deadline(Phase::Transfer)?;
             }
-            other => {
-                return Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    format!("Unexpected bootloader response 0x{other:02X} (expected 0x06 or 0x15)"),
+            State::FrameDelay => self.stats.delay_time += flasher.config().inter_frame_delay,
+            State::AppBaud => options.say(format!(
+                "Setting baud rate back to {}...",
+                flasher.config().app_baud
+            )),
+            State::Confirm { .. } => options.check_deadline(Phase::Verify)?,
+            State::Ping | State::Model | State::Settle | State::Done => {}
+        }
+        Ok(())
+    }
+
+    /// Announce the region, the padding and the size of the transfer.
+    fn start_transfer(&mut self, flasher: &Flasher) {
+        let options = self.options;
+        let (requested, region) = (flasher.requested_region(), flasher.region());
+        if region != requested {
+            options.warn(format!(
+                "Warning: region 0x{:X}..0x{:X} is not aligned to {CHUNK_SIZE}-byte frames; \
+                 sending 0x{:X}..0x{:X}.",
+                requested.start, requested.end, region.start, region.end
+            ));
+        }
+        if region.len() < self.image_len {
+            options.say(format!(
+                "Sending region 0x{:X}..0x{:X} ({} of {} bytes).",
+                region.start,
+                region.end,
+                region.len(),
+                self.image_len
+            ));
+        }
+        let len = flasher.data().len();
+        if let (true, Some(page_size)) = (len > region.len(), flasher.config().flash_page_size) {
+            options.say(format!(
+                "Padded firmware to {len} bytes ({page_size}-byte pages)."
+            ));
+        }
+        options.say(format!(
+            "Sending firmware ({} bytes) in {} chunks...",
+            len,
+            flasher.progress().1
+        ));
+        self.start = Some(options.clock.now());
+    }
+
+    /// Report `event`, what completing step `done` of `flasher` produced.
+    pub(crate) fn after(&mut self, flasher: &Flasher, done: State, event: Option<FlasherEvent>) {
+        let options = self.options;
+        match (done, event) {
+            (State::Magic, _) => options.say("Bootloader acknowledged magic with 0x06".to_string()),
+            (State::Init, _) => options.say("Bootloader acknowledged init with 0x06".to_string()),
+            (_, Some(FlasherEvent::Model(model))) => {
+                options.say(format!(
+                    "Device id {} is {}.",
+                    flasher.id(),
+                    describe_model(model)
                 ));
+                if !lookup_model(model).is_some_and(|info| info.flashable) {
+                    options.warn(format!(
+                        "Warning: {} is not known to use the bootloader this tool drives.",
+                        describe_model(model)
+                    ));
+                }
+            }
+            (_, Some(FlasherEvent::TorqueNotDisabled)) => options.warn(format!(
+                "Warning: could not disable torque on device id {}",
+                flasher.id()
+            )),
+            (_, Some(FlasherEvent::Nak { attempt, .. })) => options.warn(resend_warning(
+                false,
+                attempt,
+                flasher.retry_limit(),
+                Duration::ZERO,
+            )),
+            (_, Some(FlasherEvent::NoAck { attempt, .. })) => {
+                let timeout = flasher.config().frame_timeout.unwrap_or_default();
+                options.warn(resend_warning(
+                    true,
+                    attempt,
+                    flasher.retry_limit(),
+                    timeout,
+                ))
             }
+            (
+                _,
+                Some(FlasherEvent::Frame {
+                    frame,
+                    total,
+                    index,
+                    retries,
+                }),
+            ) => {
+                self.stats.frames += 1;
+                self.stats.total_retries += retries;
+                if let Some(progress) = &options.progress {
+                    progress.event(FlashEvent::Frame {
+                        frame,
+                        total,
+                        index,
+                        retries,
+                    });
+                }
+                if frame == total {
+                    options.say("Firmware transfer complete.".to_string());
+                }
+            }
+            _ => {}
         }
     }
+
+    /// The statistics of the transfer, if there was one.
+    pub(crate) fn finish(mut self) -> TransferStats {
+        if let Some(start) = self.start {
+            self.stats.elapsed = self.options.clock.now() - start;
+        }
+        self.stats
+    }
 }
 
-pub fn send_firmware_file(
-    port: &mut dyn serialport::SerialPort,
-    firmware_path: &Path,
-) -> io::Result<()> {
-    let data = fs::read(firmware_path)?;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::VirtualClock;
+    use crate::frame::{FirmwareFrames, pad_to_page};
+    use crate::transport::MockTransport;
 
-    if data.is_empty() {
-        return Err(io::Error::new(
-            io::ErrorKind::InvalidInput,
-            "Firmware file is empty",
-        ));
+    /// A line where nothing ever answers: every read blocks for the port
+    /// timeout in virtual time, then times out.
+    #[derive(Debug)]
+    struct SilentLine {
+        clock: Arc<VirtualClock>,
+        timeout: Duration,
+        writes: usize,
+    }
+
+    impl Transport for SilentLine {
+        fn write_all(&mut self, _buf: &[u8]) -> io::Result<()> {
+            self.writes += 1;
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            self.clock.advance(self.timeout);
+            Err(io::Error::new(io::ErrorKind::TimedOut, "silent"))
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    const FRAME: [u8; 70] = [0xAB; 70];
+
+    #[test]
+    fn frame_acked_first_try() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x06]);
+
+        assert_eq!(
+            send_frame_with_retry(&mut mock, &FRAME, 5, Deadline::NONE).unwrap(),
+            1
+        );
+        assert_eq!(mock.writes().len(), 1);
+        assert_eq!(mock.writes()[0], FRAME);
+    }
+
+    #[test]
+    fn frame_resent_after_nak() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x15]).push_read(&[0x06]);
+
+        assert_eq!(
+            send_frame_with_retry(&mut mock, &FRAME, 5, Deadline::NONE).unwrap(),
+            2
+        );
+        assert_eq!(mock.writes().len(), 2);
+    }
+
+    #[test]
+    fn frame_counts_past_255_attempts() {
+        let mut mock = MockTransport::new();
+        for _ in 0..255 {
+            mock.push_read(&[0x15]);
+        }
+        mock.push_read(&[0x06]);
+        assert_eq!(
+            send_frame_with_retry(&mut mock, &FRAME, 255, Deadline::NONE).unwrap(),
+            256
+        );
+
+        let mut mock = MockTransport::new();
+        for _ in 0..256 {
+            mock.push_read(&[0x15]);
+        }
+        assert!(send_frame_with_retry(&mut mock, &FRAME, 255, Deadline::NONE).is_err());
+        assert_eq!(mock.writes().len(), 256);
+    }
+
+    #[test]
+    fn frame_fails_after_retries_exhausted() {
+        let mut mock = MockTransport::new();
+        for _ in 0..10 {
+            mock.push_read(&[0x15]);
+        }
+
+        let err = send_frame_with_retry(&mut mock, &FRAME, 2, Deadline::NONE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        // Initial attempt plus two retries.
+        assert_eq!(mock.writes().len(), 3);
+    }
+
+    #[test]
+    fn frame_resent_after_missed_ack() {
+        let mut mock = MockTransport::new();
+        mock.set_timeout(Duration::from_secs(10)).unwrap();
+        mock.push_timeout().push_timeout().push_read(&[0x06]);
+
+        let timeout = Some(Duration::from_millis(50));
+        let attempt =
+            send_frame_counting(&mut mock, &FRAME, 5, Deadline::NONE, timeout, &drop).unwrap();
+        assert_eq!(attempt, 2);
+        assert_eq!(mock.writes().len(), 2);
+        assert_eq!(mock.timeout(), Some(Duration::from_secs(10)));
+
+        let mut mock = MockTransport::new();
+        let err =
+            send_frame_counting(&mut mock, &FRAME, 2, Deadline::NONE, timeout, &drop).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(mock.writes().len(), 3);
+    }
+
+    #[test]
+    fn late_ack_is_drained_before_the_resend() {
+        let mut mock = MockTransport::new();
+        // The first ACK arrives after the frame timeout: it must be drained,
+        // not read as the answer to the resend.
+        mock.push_timeout()
+            .push_read(&[0x06])
+            .push_timeout()
+            .push_read(&[0x06]);
+
+        let attempt = send_frame_with_retry_timeout(
+            &mut mock,
+            &FRAME,
+            5,
+            Deadline::NONE,
+            Duration::from_millis(50),
+        )
+        .unwrap();
+        assert_eq!(attempt, 2);
+        assert_eq!(mock.writes().len(), 2);
+        assert_eq!(mock.pending_reads(), 0);
+    }
+
+    #[test]
+    fn frame_rejects_unexpected_response() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x42]);
+
+        let err = send_frame_with_retry(&mut mock, &FRAME, 5, Deadline::NONE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(mock.writes().len(), 1);
+    }
+
+    #[test]
+    fn recovery_gives_up_exactly_at_max_wait() {
+        let clock = Arc::new(VirtualClock::new());
+        let options = FlashOptions {
+            clock: clock.clone(),
+            ..FlashOptions::default()
+        };
+        let mut line = SilentLine {
+            clock: clock.clone(),
+            timeout: Duration::ZERO,
+            writes: 0,
+        };
+
+        let interval = Duration::from_millis(100);
+        let err = wait_for_bootloader_magic_ack(
+            &mut line,
+            interval,
+            Some(Duration::from_secs(1)),
+            &options,
+        )
+        .unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(line.writes, 10);
+        // One short read drains the line before the magic spam starts.
+        assert_eq!(
+            clock.elapsed(),
+            Duration::from_secs(1) + crate::transport::CLEAR_INPUT_TIMEOUT
+        );
     }
 
-    let total_chunks = (data.len() + 63) / 64;
-    println!(
-        "Sending firmware ({} bytes) in {} chunks...",
-        data.len(),
-        total_chunks
-    );
+    #[test]
+    fn recovery_stops_at_deadline() {
+        let clock = Arc::new(VirtualClock::new());
+        let options = FlashOptions {
+            clock: clock.clone(),
+            deadline: Deadline::after(Duration::from_millis(250)),
+            ..FlashOptions::default()
+        };
+        let mut line = SilentLine {
+            clock: clock.clone(),
+            timeout: Duration::ZERO,
+            writes: 0,
+        };
 
-    let mut index: u8 = 1;
+        let err =
+            wait_for_bootloader_magic_ack(&mut line, Duration::from_millis(100), None, &options)
+                .unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::DeadlineExceeded {
+                phase: Phase::Recovery
+            })
+        );
+        assert_eq!(line.writes, 3);
+    }
 
-    for (chunk_idx, chunk) in data.chunks(64).enumerate() {
-        let is_last = (chunk_idx + 1) == total_chunks;
+    #[test]
+    fn frame_retries_stop_at_deadline_on_the_clock() {
+        let clock = Arc::new(VirtualClock::new());
+        let options = FlashOptions {
+            clock: clock.clone(),
+            deadline: Deadline::after(Duration::from_millis(250)),
+            frame_timeout: Some(Duration::from_millis(100)),
+            ..FlashOptions::default()
+        };
+        let mut line = SilentLine {
+            clock: clock.clone(),
+            timeout: Duration::ZERO,
+            writes: 0,
+        };
 
-        let mut frame_data = [0xFFu8; 64];
-        frame_data[..chunk.len()].copy_from_slice(chunk);
+        let err = send_firmware(&mut line, &[0u8; 64], &options).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::DeadlineExceeded {
+                phase: Phase::Transfer
+            })
+        );
+        assert_eq!(line.writes, 3);
+    }
 
-        let frame = BootloaderFrame {
-            index,
-            unknown_byte: 0,
-            data: frame_data,
-            is_last,
+    #[test]
+    fn inter_frame_delay_uses_clock() {
+        let clock = Arc::new(VirtualClock::new());
+        let options = FlashOptions {
+            clock: clock.clone(),
+            inter_frame_delay: Duration::from_millis(15),
+            ..FlashOptions::default()
         };
+        let mut mock = MockTransport::new();
+        for _ in 0..4 {
+            mock.push_read(&[0x06]);
+        }
 
-        let raw = frame.to_bytes();
+        let stats = send_firmware(&mut mock, &[0u8; 64 * 4], &options).unwrap();
+        assert_eq!(stats.delay_time, Duration::from_millis(45));
+        assert_eq!(stats.elapsed, Duration::from_millis(45));
+        assert_eq!(clock.elapsed(), Duration::from_millis(45));
+    }
 
-        println!(
-            "Sending frame index={} (chunk {}/{}) , last={}...",
-            index,
-            chunk_idx + 1,
-            total_chunks,
-            is_last
+    #[test]
+    fn flash_page_size_pads_the_image() {
+        let options = FlashOptions {
+            flash_page_size: Some(256),
+            ..FlashOptions::default()
+        };
+        let mut mock = MockTransport::new();
+        for _ in 0..4 {
+            mock.push_read(&[0x06]);
+        }
+
+        // 100 bytes become one 256-byte page: four frames instead of two.
+        send_firmware(&mut mock, &[0x42; 100], &options).unwrap();
+        let frames: Vec<_> = FirmwareFrames::new(&pad_to_page(&[0x42; 100], 256))
+            .map(|frame| frame.to_bytes().to_vec())
+            .collect();
+        assert_eq!(frames.len(), 4);
+        assert_eq!(mock.writes(), frames);
+    }
+
+    #[test]
+    fn nak_of_the_last_frame_rejects_finalize() {
+        let options = FlashOptions {
+            max_retries: 2,
+            ..FlashOptions::default()
+        };
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x06]).push_read(&[0x06]);
+        for _ in 0..3 {
+            mock.push_read(&[0x15]);
+        }
+
+        let err = send_firmware(&mut mock, &[0x42; 64 * 3], &options).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::FinalizeRejected {
+                index: 3,
+                attempts: 3,
+                chunk: 3,
+                chunks: 3
+            })
         );
+        // Two frames, then the last one sent three times.
+        assert_eq!(mock.writes().len(), 5);
 
-        send_frame_with_retry(port, &raw, 5)?;
+        // A NAKed frame before it stays a transfer failure.
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x06]);
+        for _ in 0..3 {
+            mock.push_read(&[0x15]);
+        }
+        let err = send_firmware(&mut mock, &[0x42; 64 * 3], &options).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::FrameNakExhausted {
+                index: 2,
+                attempts: 3
+            })
+        );
+    }
 
-        index = index.wrapping_add(1);
+    /// Records the warnings of a transfer.
+    #[derive(Debug, Default)]
+    struct Warnings(std::sync::Mutex<Vec<String>>);
+
+    impl Progress for Warnings {
+        fn event(&self, event: FlashEvent) {
+            if let FlashEvent::Warning(message) = event {
+                self.0.lock().unwrap().push(message);
+            }
+        }
     }
 
-    println!("Firmware transfer complete.");
-    Ok(())
+    fn region(offset: usize, length: Option<usize>) -> FlashOptions {
+        FlashOptions {
+            region_offset: offset,
+            region_length: length,
+            ..FlashOptions::default()
+        }
+    }
+
+    #[test]
+    fn firmware_region_checks_and_rounds() {
+        assert_eq!(
+            firmware_region(1000, &FlashOptions::default()).unwrap(),
+            0..1000
+        );
+        assert_eq!(
+            firmware_region(1000, &region(128, Some(256))).unwrap(),
+            128..384
+        );
+        assert_eq!(
+            firmware_region(1000, &region(100, Some(100))).unwrap(),
+            64..256
+        );
+        // The end is rounded no further than the end of the image.
+        assert_eq!(
+            firmware_region(1000, &region(900, None)).unwrap(),
+            896..1000
+        );
+        assert_eq!(
+            firmware_region(1000, &region(960, Some(30))).unwrap(),
+            960..1000
+        );
+
+        let err = firmware_region(1000, &region(1000, None)).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::RegionOutOfRange {
+                offset: 1000,
+                length: None,
+                len: 1000
+            })
+        );
+        let err = firmware_region(1000, &region(512, Some(512))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = firmware_region(1000, &region(1, Some(usize::MAX))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = firmware_region(1000, &region(0, Some(0))).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::EmptyFirmware)
+        );
+    }
+
+    #[test]
+    fn region_is_sent_from_frame_one() {
+        let image: Vec<u8> = (0..=255).cycle().take(64 * 8).collect();
+        let warnings = Arc::new(Warnings::default());
+        let options = FlashOptions {
+            progress: Some(warnings.clone()),
+            ..region(0x80, Some(0x70))
+        };
+        let mut mock = MockTransport::new();
+        for _ in 0..2 {
+            mock.push_read(&[0x06]);
+        }
+
+        let stats = send_firmware(&mut mock, &image, &options).unwrap();
+        assert_eq!(stats.frames, 2);
+        let frames: Vec<_> = FirmwareFrames::new(&image[0x80..0x100])
+            .map(|frame| frame.to_bytes().to_vec())
+            .collect();
+        assert_eq!(frames[0][0], 1);
+        assert_eq!(mock.writes(), frames);
+        assert_eq!(warnings.0.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn region_out_of_range_fails_before_sending() {
+        let mut mock = MockTransport::new();
+        let err = flash_device(&mut mock, 1, &[0u8; 256], &region(256, None)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = send_firmware(&mut mock, &[0u8; 256], &region(64, Some(256))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(mock.writes().is_empty());
+    }
+
+    #[test]
+    fn failed_flash_switches_back_to_app_baud() {
+        let options = FlashOptions {
+            reboot_delay: Duration::ZERO,
+            app_baud: Some(57_600),
+            ..FlashOptions::default()
+        };
+        let mut mock = MockTransport::new();
+        // Nothing answers the magic: the flash fails at the bootloader baud.
+        assert!(flash_device(&mut mock, 1, &[0u8; 64], &options).is_err());
+        assert_eq!(mock.baud_rate(), Some(57_600));
+
+        let mut mock = MockTransport::new();
+        let options = FlashOptions {
+            app_baud: None,
+            ..options
+        };
+        assert!(flash_device(&mut mock, 1, &[0u8; 64], &options).is_err());
+        assert_eq!(mock.baud_rate(), Some(BOOTLOADER_BAUD));
+    }
+
+    #[test]
+    fn bad_image_size_fails_before_sending() {
+        let options = FlashOptions {
+            max_firmware_size: 128,
+            ..FlashOptions::default()
+        };
+        let mut mock = MockTransport::new();
+
+        let err = send_firmware(&mut mock, &[], &options).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::EmptyFirmware)
+        );
+        let err = send_firmware_reader(&mut mock, &[0u8; 200][..], &options).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::FirmwareTooLarge {
+                len: None,
+                max: 128
+            })
+        );
+        let err = flash_device(&mut mock, 1, &[0u8; 129], &options).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(mock.writes().is_empty());
+    }
+
+    /// Status reply of id 1 to a read of the two version registers.
+    fn version_reply(major: u8, minor: u8) -> Vec<u8> {
+        let mut reply = vec![0xFF, 0xFF, 0x01, 0x04, 0x00, major, minor];
+        let sum: u32 = reply[2..].iter().map(|&b| b as u32).sum();
+        reply.push(!sum as u8);
+        reply
+    }
+
+    #[test]
+    fn check_version_compares_register() {
+        let expected = FirmwareVersion::from((3, 10));
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&version_reply(3, 10));
+        assert_eq!(check_version(&mut mock, 1, expected).unwrap(), expected);
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&version_reply(3, 9));
+        let err = check_version(&mut mock, 1, expected).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::VersionMismatch {
+                expected,
+                actual: FirmwareVersion::from((3, 9)),
+            })
+        );
+
+        // Nothing answers the read.
+        let mut mock = MockTransport::new();
+        let err = check_version(&mut mock, 1, expected).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(FeeflashError::from_io(&err).is_none());
+    }
 }
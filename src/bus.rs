@@ -0,0 +1,295 @@
+//! Handles for a servo bus and the servos on it.
+//!
+//! A [`Bus`] owns the transport and the settings every transaction needs;
+//! [`Bus::servo`] hands out a [`Servo`] bound to one ID, so the ID is given
+//! once instead of with every call:
+//!
+//! ```no_run
+//! # use feeflash::bus::Bus;
+//! # use feeflash::dynamixel::registers::MODEL;
+//! # fn run(port: Box<dyn feeflash::transport::Transport>) -> std::io::Result<()> {
+//! let bus = Bus::new(port, 1_000_000);
+//! let servo = bus.servo(3);
+//! servo.ping()?;
+//! let model = servo.read(MODEL, 2)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Each call is one transaction: the bus is borrowed for its whole
+//! duration, so packets of two handles never interleave, and the reply
+//! timeout is set at its start. The free functions of
+//! [`crate::dynamixel`] remain for low-level use, also on a bus through
+//! [`Bus::transaction`].
+//!
+//! A [`Bus`] belongs to one thread. [`SharedBus`] is the handle to clone
+//! into several: it locks the bus for each transaction.
+
+use std::cell::RefCell;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+
+use crate::bootloader::{FlashOptions, TransferStats, flash_device, jump_to_application};
+use crate::deadline::Deadline;
+use crate::dynamixel::{
+    PING_TIMEOUT_MS, ProtocolVersion, ScanOptions, ScanReport, StatusPacket, ping_with,
+    read_register, scan_bus, send_reboot, write_register,
+};
+use crate::dynamixel2;
+use crate::transport::{BaudGuard, Transport};
+
+/// Read timeout while flashing, which covers the bootloader programming a
+/// frame.
+pub const FLASH_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A servo bus: a transport at the application baud rate of its servos.
+#[derive(Debug)]
+pub struct Bus<T: Transport> {
+    port: RefCell<T>,
+    baud: u32,
+    timeout: Duration,
+    protocol: ProtocolVersion,
+}
+
+impl<T: Transport> Bus<T> {
+    /// Bus on `port`, whose servos talk protocol 1 at `baud`. Replies are
+    /// awaited for the ping timeout.
+    pub fn new(port: T, baud: u32) -> Self {
+        Bus {
+            port: RefCell::new(port),
+            baud,
+            timeout: Duration::from_millis(PING_TIMEOUT_MS),
+            protocol: ProtocolVersion::V1,
+        }
+    }
+
+    /// Wait `timeout` for each reply.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Talk `protocol` to the servos.
+    pub fn with_protocol(mut self, protocol: ProtocolVersion) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    pub fn baud(&self) -> u32 {
+        self.baud
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn protocol(&self) -> ProtocolVersion {
+        self.protocol
+    }
+
+    /// Handle for the servo with ID `id`. Nothing is sent.
+    pub fn servo(&self, id: u8) -> Servo<'_, T> {
+        Servo { bus: self, id }
+    }
+
+    /// Find the IDs that answer in the bus protocol, see [`scan_bus`].
+    pub fn scan(&self) -> io::Result<ScanReport> {
+        let options = ScanOptions {
+            protocol: Some(self.protocol),
+            ..ScanOptions::default()
+        };
+        self.transaction(|port| scan_bus(port, Deadline::NONE, &options))
+    }
+
+    /// Run `f` with the port at the bus timeout, as one transaction. The
+    /// timeout is set back afterwards, also when `f` fails, so a change
+    /// made by `f` doesn't leak into the next transaction.
+    ///
+    /// # Panics
+    ///
+    /// If `f` starts another transaction on this bus.
+    pub fn transaction<R>(
+        &self,
+        f: impl FnOnce(&mut dyn Transport) -> io::Result<R>,
+    ) -> io::Result<R> {
+        let mut port = self.port.borrow_mut();
+        port.set_timeout(self.timeout)?;
+        let result = f(&mut *port);
+        let restored = port.set_timeout(self.timeout);
+        let result = result?;
+        restored?;
+        Ok(result)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.port.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.port.into_inner()
+    }
+}
+
+/// One servo on a [`Bus`].
+#[derive(Debug)]
+pub struct Servo<'b, T: Transport> {
+    bus: &'b Bus<T>,
+    id: u8,
+}
+
+impl<T: Transport> Clone for Servo<'_, T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T: Transport> Copy for Servo<'_, T> {}
+
+impl<T: Transport> Servo<'_, T> {
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// Ping the servo and return its status packet.
+    pub fn ping(&self) -> io::Result<StatusPacket> {
+        self.bus
+            .transaction(|port| ping_with(port, self.id, self.bus.protocol))
+    }
+
+    /// Send the reboot instruction. The servo resets without answering it
+    /// and is back once it answers pings again.
+    pub fn reboot(&self) -> io::Result<()> {
+        self.bus.transaction(|port| match self.bus.protocol {
+            ProtocolVersion::V1 => send_reboot(port, self.id, false).map(drop),
+            ProtocolVersion::V2 => dynamixel2::reboot(port, self.id, false).map(drop),
+        })
+    }
+
+    /// Read `len` bytes of the control table, starting at `address`.
+    pub fn read(&self, address: u8, len: u8) -> io::Result<Vec<u8>> {
+        self.bus.transaction(|port| match self.bus.protocol {
+            ProtocolVersion::V1 => read_register(port, self.id, address, len),
+            ProtocolVersion::V2 => {
+                dynamixel2::read(port, self.id, u16::from(address), u16::from(len))
+            }
+        })
+    }
+
+    /// Write `data` to the control table, starting at `address`.
+    pub fn write(&self, address: u8, data: &[u8]) -> io::Result<()> {
+        self.bus.transaction(|port| match self.bus.protocol {
+            ProtocolVersion::V1 => write_register(port, self.id, address, data),
+            ProtocolVersion::V2 => dynamixel2::write(port, self.id, u16::from(address), data),
+        })
+    }
+
+    /// Flash `firmware` with [`flash_device`] and wait for the new
+    /// application with [`jump_to_application`]. The bus is back at its
+    /// baud rate afterwards, also when the flash fails. `options.protocol`
+    /// applies, not the bus protocol.
+    pub fn flash(&self, firmware: &[u8], options: &FlashOptions) -> io::Result<TransferStats> {
+        self.flash_and(firmware, options, true)
+    }
+
+    /// Like [`Servo::flash`], but return once the transfer is done instead
+    /// of waiting for the application, e.g. for a servo that needs a power
+    /// cycle first.
+    pub fn flash_unconfirmed(
+        &self,
+        firmware: &[u8],
+        options: &FlashOptions,
+    ) -> io::Result<TransferStats> {
+        self.flash_and(firmware, options, false)
+    }
+
+    fn flash_and(
+        &self,
+        firmware: &[u8],
+        options: &FlashOptions,
+        confirm: bool,
+    ) -> io::Result<TransferStats> {
+        self.bus.transaction(|port| {
+            let mut port = BaudGuard::new(port, self.bus.baud);
+            port.set_timeout(FLASH_TIMEOUT)?;
+            let stats = flash_device(&mut port, self.id, firmware, options)?;
+            if confirm {
+                jump_to_application(&mut port, self.id, self.bus.baud, options)?;
+            }
+            Ok(stats)
+        })
+    }
+}
+
+/// A [`Bus`] shared between threads, e.g. a telemetry reader and a
+/// firmware updater. Clones share the bus.
+///
+/// Each method locks the bus for one transaction, so transactions of
+/// different threads never interleave. A flash holds the lock until the
+/// new application answers; its baud and timeout changes are undone
+/// before the lock is released, also when it fails, so the next user finds
+/// the port as the bus left it.
+#[derive(Debug)]
+pub struct SharedBus<T: Transport>(Arc<Mutex<Bus<T>>>);
+
+impl<T: Transport> Clone for SharedBus<T> {
+    fn clone(&self) -> Self {
+        SharedBus(Arc::clone(&self.0))
+    }
+}
+
+impl<T: Transport> SharedBus<T> {
+    pub fn new(bus: Bus<T>) -> Self {
+        SharedBus(Arc::new(Mutex::new(bus)))
+    }
+
+    /// Lock the bus, for several transactions in a row. A thread that
+    /// panicked while holding the lock left the port restored, so the
+    /// poisoning is ignored.
+    pub fn lock(&self) -> MutexGuard<'_, Bus<T>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// [`Bus::scan`].
+    pub fn scan(&self) -> io::Result<ScanReport> {
+        self.lock().scan()
+    }
+
+    /// [`Servo::ping`] of `id`.
+    pub fn ping(&self, id: u8) -> io::Result<StatusPacket> {
+        self.lock().servo(id).ping()
+    }
+
+    /// [`Servo::reboot`] of `id`.
+    pub fn reboot(&self, id: u8) -> io::Result<()> {
+        self.lock().servo(id).reboot()
+    }
+
+    /// [`Servo::read`] of `id`.
+    pub fn read(&self, id: u8, address: u8, len: u8) -> io::Result<Vec<u8>> {
+        self.lock().servo(id).read(address, len)
+    }
+
+    /// [`Servo::write`] of `id`.
+    pub fn write(&self, id: u8, address: u8, data: &[u8]) -> io::Result<()> {
+        self.lock().servo(id).write(address, data)
+    }
+
+    /// [`Servo::flash`] of `id`.
+    pub fn flash(
+        &self,
+        id: u8,
+        firmware: &[u8],
+        options: &FlashOptions,
+    ) -> io::Result<TransferStats> {
+        self.lock().servo(id).flash(firmware, options)
+    }
+
+    /// [`Bus::transaction`].
+    pub fn transaction<R>(
+        &self,
+        f: impl FnOnce(&mut dyn Transport) -> io::Result<R>,
+    ) -> io::Result<R> {
+        self.lock().transaction(f)
+    }
+}
@@ -0,0 +1,71 @@
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::error::{FeeflashError, Phase};
+
+/// Absolute point in time after which a whole operation must give up.
+///
+/// Per-read serial timeouts only bound a single `read`; a wedged adapter can
+/// still stall for minutes across retries. Every blocking loop checks the
+/// deadline so the overall operation is bounded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Deadline {
+    at: Option<Instant>,
+}
+
+impl Deadline {
+    /// No deadline: loops only stop on their own conditions.
+    pub const NONE: Deadline = Deadline { at: None };
+
+    /// Deadline `duration` from now.
+    pub fn after(duration: Duration) -> Self {
+        Deadline {
+            at: Some(Instant::now() + duration),
+        }
+    }
+
+    /// Convenience for optional CLI values.
+    pub fn from_max_duration(duration: Option<Duration>) -> Self {
+        duration.map_or(Deadline::NONE, Deadline::after)
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.at.is_some_and(|at| Instant::now() >= at)
+    }
+
+    /// Time left before the deadline, `None` if there is no deadline.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.at
+            .map(|at| at.saturating_duration_since(Instant::now()))
+    }
+
+    /// Return `DeadlineExceeded` for `phase` if the deadline has passed.
+    pub fn check(&self, phase: Phase) -> io::Result<()> {
+        self.check_at(Instant::now(), phase)
+    }
+
+    /// Like [`Deadline::check`], against a caller-supplied `now`.
+    pub fn check_at(&self, now: Instant, phase: Phase) -> io::Result<()> {
+        if self.at.is_some_and(|at| now >= at) {
+            return Err(FeeflashError::DeadlineExceeded { phase }.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expired_deadline_reports_phase() {
+        let deadline = Deadline::after(Duration::ZERO);
+        let err = deadline.check(Phase::Scan).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::DeadlineExceeded { phase: Phase::Scan })
+        );
+        assert!(Deadline::NONE.check(Phase::Scan).is_ok());
+    }
+}
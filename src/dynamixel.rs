@@ -1,75 +1,984 @@
 use std::io;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::deadline::Deadline;
+use crate::dynamixel2;
+use crate::error::{DynamixelError, FeeflashError, Phase};
+use crate::transport::{BaudGuard, Transport, clear_input};
+use crate::util::progress_output;
+
+pub mod packet;
+pub mod registers;
+
+pub use packet::{
+    Instruction, MAX_PACKET_LEN, MAX_PARAMS, PacketReader, ProtocolVersion, build_dyn_packet,
+    build_dyn_packet_into, dyn_checksum, validate_dyn_packet, validate_packet,
+};
+#[allow(deprecated)]
+pub use packet::{build_dyn_packet_instr, build_dyn_packet_raw};
 
 pub const PING_TIMEOUT_MS: u64 = 100;
 pub const SCAN_TIMEOUT_MS: u64 = 30;
+/// How long to listen for status packets after a broadcast ping.
+pub const BROADCAST_WINDOW_MS: u64 = 100;
+/// Broadcast ID: every servo on the bus executes the instruction.
+pub const BROADCAST_ID: u8 = 0xFE;
 
-/// Build a Dynamixel v1-style packet for instructions like Ping or Reboot.
-pub fn build_dyn_packet(id: u8, instruction: u8, params: &[u8]) -> Vec<u8> {
-    let length = (params.len() as u8).saturating_add(2); // instruction + checksum
-    let mut packet = Vec::with_capacity(4 + params.len());
-    packet.push(0xFF);
-    packet.push(0xFF);
-    packet.push(id);
-    packet.push(length);
-    packet.push(instruction);
-    packet.extend_from_slice(params);
-
-    let sum: u16 = packet.iter().skip(2).map(|&b| b as u16).sum();
-    let checksum = (!sum & 0xFF) as u8;
-    packet.push(checksum);
-    packet
-}
-
-pub fn send_ping(port: &mut dyn serialport::SerialPort, id: u8) -> io::Result<Vec<u8>> {
-    let packet = build_dyn_packet(id, 0x01, &[]);
-    port.write_all(&packet)?;
-    port.flush()?;
+/// Status error bit set when the servo doesn't know or can't execute the
+/// instruction (e.g. ACTION without a staged REG_WRITE).
+pub const STATUS_INSTRUCTION_ERROR: u8 = 0x40;
+
+/// Status packet sent by a servo in reply to an instruction:
+/// `FF FF id length error params.. checksum`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct StatusPacket {
+    pub id: u8,
+    /// Error bits reported by the servo; 0 when everything is fine.
+    pub error: u8,
+    pub params: Vec<u8>,
+}
+
+impl StatusPacket {
+    /// Parse exactly one status packet, checking the header, the length
+    /// field and the checksum.
+    pub fn parse(bytes: &[u8]) -> io::Result<StatusPacket> {
+        validate_dyn_packet(bytes).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid status packet {bytes:02X?}: {e}"),
+            )
+        })?;
+        Ok(StatusPacket {
+            id: bytes[2],
+            error: bytes[4],
+            params: bytes[5..bytes.len() - 1].to_vec(),
+        })
+    }
+
+    /// Whether the servo flagged the instruction as unknown or not
+    /// executable ([`STATUS_INSTRUCTION_ERROR`]).
+    pub fn instruction_error(&self) -> bool {
+        self.error & STATUS_INSTRUCTION_ERROR != 0
+    }
+}
+
+/// Split a received byte stream into status packets.
+///
+/// Resyncs on the `FF FF` header; candidates with a bad checksum or a
+/// length running past the end (collisions, line noise) are skipped.
+/// Returns the valid packets and the number of skipped candidates.
+pub fn split_status_packets(bytes: &[u8]) -> (Vec<StatusPacket>, usize) {
+    let mut reader = PacketReader::new();
+    reader.push(bytes);
+    (statuses(reader.finish()), reader.garbled())
+}
+
+/// Parse packets a [`PacketReader`] already validated.
+fn statuses(packets: Vec<Vec<u8>>) -> Vec<StatusPacket> {
+    packets
+        .iter()
+        .filter_map(|p| StatusPacket::parse(p).ok())
+        .collect()
+}
 
-    let mut ping_buf: [u8; 1024] = [0; 1024];
-    let ping_read_bytes = match port.read(&mut ping_buf) {
-        Ok(n) => n,
-        Err(e) if e.kind() == io::ErrorKind::TimedOut => {
-            return Err(io::Error::new(io::ErrorKind::TimedOut, "Ping timed out"));
+/// Ping the broadcast ID and collect every status packet that arrives
+/// within `window`. On protocol 1 buses all servos answer in turn, so one
+/// request finds the whole bus. Garbled replies are skipped with a warning;
+/// each responder is listed once, in arrival order.
+pub fn broadcast_ping(port: &mut dyn Transport, window: Duration) -> io::Result<Vec<StatusPacket>> {
+    let (packets, garbled) = broadcast_ping_counting(port, window)?;
+    if garbled > 0 {
+        diag!("Warning: skipped {garbled} garbled status packet(s) after broadcast ping");
+    }
+    Ok(packets)
+}
+
+fn broadcast_ping_counting(
+    port: &mut dyn Transport,
+    window: Duration,
+) -> io::Result<(Vec<StatusPacket>, usize)> {
+    let previous = port.timeout();
+    send_instruction(port, BROADCAST_ID, Instruction::Ping, &[])?;
+
+    let start = Instant::now();
+    let mut reader = PacketReader::new();
+    let mut packets = Vec::new();
+    let mut buf = [0u8; 256];
+    let result = loop {
+        let Some(remaining) = window.checked_sub(start.elapsed()) else {
+            break Ok(());
+        };
+        if let Err(e) = port.set_timeout(remaining) {
+            break Err(e);
+        }
+        match port.read(&mut buf) {
+            Ok(0) => break Ok(()),
+            Ok(n) => reader.push(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ok(()),
+            Err(e) => break Err(e),
         }
-        Err(e) => return Err(e),
+        packets.extend(statuses(
+            std::iter::from_fn(|| reader.next_packet()).collect(),
+        ));
     };
+    // Restore the timeout even when a read failed.
+    port.set_timeout(previous)?;
+    result?;
+    packets.extend(statuses(reader.finish()));
+    let garbled = reader.garbled();
+
+    let mut seen = Vec::new();
+    packets.retain(|p| {
+        let first = !seen.contains(&p.id);
+        seen.push(p.id);
+        first
+    });
+    Ok((packets, garbled))
+}
+
+/// Clear stale input, then send `instruction` to `id`. The packet is built
+/// on the stack, so this doesn't allocate.
+fn send_instruction(
+    port: &mut dyn Transport,
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> io::Result<()> {
+    let mut packet = [0u8; MAX_PACKET_LEN];
+    let len = build_dyn_packet_into(&mut packet, id, instruction, params)?;
+    clear_input(port)?;
+    port.write_all(&packet[..len])?;
+    port.flush()
+}
+
+/// Ping `id` and return the raw status packet.
+pub fn send_ping(port: &mut dyn Transport, id: u8) -> io::Result<Vec<u8>> {
+    let mut reader = PacketReader::new();
+    send_instruction(port, id, Instruction::Ping, &[])?;
+    read_status_with(port, &mut reader, <[u8]>::to_vec).map_err(ping_error)
+}
+
+/// Ping `id` and return its parsed status packet.
+pub fn ping(port: &mut dyn Transport, id: u8) -> io::Result<StatusPacket> {
+    let status = StatusPacket::parse(&send_ping(port, id)?)?;
+    check_status_id(status, id)
+}
+
+/// [`ping`] reading the reply through `reader`, whose buffer is kept
+/// between calls: a loop of pings reusing one reader doesn't allocate once
+/// the buffer has grown to a reply.
+pub fn ping_reusing(
+    port: &mut dyn Transport,
+    id: u8,
+    reader: &mut PacketReader,
+) -> io::Result<StatusPacket> {
+    reader.clear();
+    send_instruction(port, id, Instruction::Ping, &[])?;
+    let status = read_status_with(port, reader, StatusPacket::parse).map_err(ping_error)??;
+    check_status_id(status, id)
+}
+
+/// Name the ping in a timeout or a reply cut short.
+fn ping_error(e: io::Error) -> io::Error {
+    match e.kind() {
+        io::ErrorKind::TimedOut => io::Error::new(io::ErrorKind::TimedOut, "Ping timed out"),
+        io::ErrorKind::UnexpectedEof => {
+            io::Error::new(io::ErrorKind::UnexpectedEof, "No ping response received")
+        }
+        _ => e,
+    }
+}
+
+/// Find out which protocol `id` speaks.
+///
+/// Sends a protocol 1 ping first. If nothing answers, or the reply starts
+/// with a protocol 2 header, pings again with a protocol 2 packet. Fails
+/// with `TimedOut` when neither ping is answered.
+pub fn detect_protocol(port: &mut dyn Transport, id: u8) -> io::Result<ProtocolVersion> {
+    send_instruction(port, id, Instruction::Ping, &[])?;
+
+    if let Some(bytes) = read_reply_start(port)?
+        && !bytes.windows(4).any(|w| w == dynamixel2::HEADER)
+    {
+        let status = StatusPacket::parse(&read_status_bytes_from(port, bytes)?)?;
+        check_status_id(status, id)?;
+        return Ok(ProtocolVersion::V1);
+    }
+
+    match dynamixel2::ping(port, id) {
+        Ok(_) => Ok(ProtocolVersion::V2),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            format!("No response from id {id} to a protocol 1 or 2 ping"),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+/// Ping `id` with `protocol`.
+pub fn ping_with(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: ProtocolVersion,
+) -> io::Result<StatusPacket> {
+    match protocol {
+        ProtocolVersion::V1 => ping(port, id),
+        ProtocolVersion::V2 => dynamixel2::ping(port, id),
+    }
+}
+
+/// Round-trip times of a series of pings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LatencyStats {
+    pub sent: usize,
+    /// Pings answered; the times cover only these.
+    pub received: usize,
+    pub min: Duration,
+    pub max: Duration,
+    pub mean: Duration,
+}
+
+impl LatencyStats {
+    /// Share of pings that went unanswered, 0.0 to 1.0.
+    pub fn loss(&self) -> f64 {
+        (self.sent - self.received) as f64 / self.sent as f64
+    }
+}
+
+/// Ping `id` `samples` times and time each round trip. A steady high
+/// latency points at the adapter; loss or a wide spread at the wiring.
+/// Unanswered pings count as lost; fails with the last error if none is
+/// answered.
+pub fn measure_ping_latency(
+    port: &mut dyn Transport,
+    id: u8,
+    samples: usize,
+) -> io::Result<LatencyStats> {
+    measure_ping_latency_with(port, id, samples, ProtocolVersion::V1)
+}
+
+/// [`measure_ping_latency`] with `protocol`.
+pub fn measure_ping_latency_with(
+    port: &mut dyn Transport,
+    id: u8,
+    samples: usize,
+    protocol: ProtocolVersion,
+) -> io::Result<LatencyStats> {
+    if samples == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Latency measurement needs at least one sample",
+        ));
+    }
+    let mut rtts = Vec::with_capacity(samples);
+    let mut last_error = None;
+    let mut reader = PacketReader::new();
+    for _ in 0..samples {
+        let start = Instant::now();
+        let reply = match protocol {
+            ProtocolVersion::V1 => ping_reusing(port, id, &mut reader),
+            ProtocolVersion::V2 => dynamixel2::ping(port, id),
+        };
+        match reply {
+            Ok(_) => rtts.push(start.elapsed()),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::InvalidData
+                        | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                last_error = Some(e)
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    let (Some(&min), Some(&max)) = (rtts.iter().min(), rtts.iter().max()) else {
+        return Err(last_error.expect("every sample failed"));
+    };
+    Ok(LatencyStats {
+        sent: samples,
+        received: rtts.len(),
+        min,
+        max,
+        mean: rtts.iter().sum::<Duration>() / rtts.len() as u32,
+    })
+}
+
+/// One ping of [`watch_ping`].
+#[derive(Debug)]
+pub enum PingEvent {
+    /// The servo answered after `latency`.
+    Up { latency: Duration },
+    /// No valid reply: timeout, garbled or truncated.
+    Down { error: io::Error },
+}
+
+/// Ping `id` every `interval` until `deadline` passes (forever with
+/// [`Deadline::NONE`]) and pass each result to `on_event`. For catching a
+/// servo that drops off the bus now and then; only port errors end the
+/// watch early.
+pub fn watch_ping(
+    port: &mut dyn Transport,
+    id: u8,
+    interval: Duration,
+    protocol: ProtocolVersion,
+    deadline: Deadline,
+    mut on_event: impl FnMut(PingEvent),
+) -> io::Result<()> {
+    while !deadline.is_expired() {
+        let start = Instant::now();
+        match ping_with(port, id, protocol) {
+            Ok(_) => on_event(PingEvent::Up {
+                latency: start.elapsed(),
+            }),
+            Err(error)
+                if matches!(
+                    error.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::InvalidData
+                        | io::ErrorKind::UnexpectedEof
+                ) =>
+            {
+                on_event(PingEvent::Down { error })
+            }
+            Err(e) => return Err(e),
+        }
+        let pause = interval.saturating_sub(start.elapsed());
+        std::thread::sleep(deadline.remaining().map_or(pause, |left| pause.min(left)));
+    }
+    Ok(())
+}
+
+/// Find the baud rate `id` answers at. Pings it at each of `candidates`
+/// except `current`, which the caller has tried already, and returns the
+/// first rate that gets an answer, leaving the port at it. If none does,
+/// or on any error, the port goes back to `current`.
+pub fn detect_baud(
+    port: &mut dyn Transport,
+    id: u8,
+    candidates: &[u32],
+    current: u32,
+    protocol: ProtocolVersion,
+) -> io::Result<Option<u32>> {
+    let mut port = BaudGuard::new(port, current);
+    for &baud in candidates.iter().filter(|&&baud| baud != current) {
+        port.set_baud_rate(baud)?;
+        match ping_with(&mut port, id, protocol) {
+            Ok(_) => {
+                port.restore_to(baud);
+                return Ok(Some(baud));
+            }
+            // At a wrong rate replies arrive as garbage, if at all.
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    io::ErrorKind::TimedOut
+                        | io::ErrorKind::InvalidData
+                        | io::ErrorKind::UnexpectedEof
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    port.set_baud_rate(current)?;
+    Ok(None)
+}
+
+/// Read until four bytes from the first `FF FF` have arrived, enough to
+/// tell a protocol 1 reply from a protocol 2 one. `None` if the line goes
+/// quiet first.
+fn read_reply_start(port: &mut dyn Transport) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    let mut buf = [0u8; 64];
+    loop {
+        let header = bytes.windows(2).position(|w| w == [0xFF, 0xFF]);
+        if header.is_some_and(|pos| bytes.len() >= pos + 4) || bytes.len() > MAX_STATUS_NOISE {
+            return Ok(Some(bytes));
+        }
+        match port.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(n) => bytes.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Noise skipped while looking for a status header before giving up.
+const MAX_STATUS_NOISE: usize = 256;
+
+/// Read one status packet.
+///
+/// Skips leading noise up to the `FF FF` header, then reads until the
+/// whole packet announced by the length byte has arrived, across as many
+/// reads as the port needs. Each read uses the port timeout.
+pub fn read_status_packet(port: &mut dyn Transport) -> io::Result<StatusPacket> {
+    StatusPacket::parse(&read_status_bytes(port)?)
+}
+
+/// [`read_status_packet`] returning the unparsed packet bytes.
+fn read_status_bytes(port: &mut dyn Transport) -> io::Result<Vec<u8>> {
+    read_status_bytes_from(port, Vec::new())
+}
+
+/// [`read_status_bytes`] continuing after `bytes` already received.
+///
+/// Candidates failing validation are skipped in case a good packet
+/// follows; if the line then goes quiet, the last rejection is reported
+/// instead of the timeout.
+fn read_status_bytes_from(port: &mut dyn Transport, bytes: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut reader = PacketReader::new();
+    reader.push(&bytes);
+    read_status_with(port, &mut reader, <[u8]>::to_vec)
+}
+
+/// [`read_status_bytes_from`] through `reader`, handing the packet to `f`
+/// in place instead of copying it out. When the line goes quiet, the
+/// reader is [finished](PacketReader::finish) first, so a packet behind a
+/// truncated candidate is still returned.
+fn read_status_with<R>(
+    port: &mut dyn Transport,
+    reader: &mut PacketReader,
+    mut f: impl FnMut(&[u8]) -> R,
+) -> io::Result<R> {
+    let mut buf = [0u8; 64];
+    loop {
+        if let Some(result) = reader.with_next_packet(&mut f) {
+            return Ok(result);
+        }
+        if reader.skipped() > MAX_STATUS_NOISE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No status packet header in {} bytes", reader.skipped()),
+            ));
+        }
 
-    if ping_read_bytes == 0 {
+        let quiet = match port.read(&mut buf) {
+            Ok(0) => io::Error::new(io::ErrorKind::UnexpectedEof, "Status packet incomplete"),
+            Ok(n) => {
+                reader.push(&buf[..n]);
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => e,
+            Err(e) => return Err(e),
+        };
+        // A rejection from before the line went quiet explains it better
+        // than the timeout; the one `finish` makes of a truncated
+        // candidate doesn't.
+        let rejected = reader.last_error().map(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid status packet: {e}"),
+            )
+        });
+        if let Some(packet) = reader.finish().first() {
+            return Ok(f(packet));
+        }
+        return Err(rejected.unwrap_or(quiet));
+    }
+}
+
+fn check_status_id(status: StatusPacket, id: u8) -> io::Result<StatusPacket> {
+    if status.id != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Status packet from id {} while talking to id {id}",
+                status.id
+            ),
+        ));
+    }
+    Ok(status)
+}
+
+/// Read the status packet answering `instruction` sent to `id`. An
+/// instruction error bit fails with `InstructionRejected`.
+fn expect_status(
+    port: &mut dyn Transport,
+    id: u8,
+    instruction: Instruction,
+) -> io::Result<StatusPacket> {
+    let status = check_status_id(read_status_packet(port)?, id)?;
+    if status.instruction_error() {
+        return Err(DynamixelError::InstructionRejected { id, instruction }.into());
+    }
+    Ok(status)
+}
+
+/// Read `len` bytes of the control table of `id`, starting at `address`.
+pub fn read_register(
+    port: &mut dyn Transport,
+    id: u8,
+    address: u8,
+    len: u8,
+) -> io::Result<Vec<u8>> {
+    send_instruction(port, id, Instruction::ReadData, &[address, len])?;
+
+    let status = expect_status(port, id, Instruction::ReadData)?;
+    if status.params.len() != len as usize {
         return Err(io::Error::new(
-            io::ErrorKind::UnexpectedEof,
-            "No ping response received",
+            io::ErrorKind::InvalidData,
+            format!(
+                "Read of {len} bytes at 0x{address:02X} from id {id} returned {} bytes",
+                status.params.len()
+            ),
         ));
     }
+    Ok(status.params)
+}
+
+/// Write `data` to the control table of `id`, starting at `address`, and
+/// wait for the status packet. Writes to [`BROADCAST_ID`] get no answer.
+pub fn write_register(
+    port: &mut dyn Transport,
+    id: u8,
+    address: u8,
+    data: &[u8],
+) -> io::Result<()> {
+    let mut params = Vec::with_capacity(1 + data.len());
+    params.push(address);
+    params.extend_from_slice(data);
+    let packet = build_dyn_packet(id, Instruction::WriteData, &params)?;
+    clear_input(port)?;
+    port.write_all(&packet)?;
+    port.flush()?;
+
+    if id != BROADCAST_ID {
+        expect_status(port, id, Instruction::WriteData)?;
+    }
+    Ok(())
+}
+
+/// Stage a write of `data` at `address` on `id` (REG_WRITE, 0x04). The
+/// servo acknowledges it but only applies it on [`send_action`]. Writes to
+/// [`BROADCAST_ID`] get no answer.
+pub fn reg_write(port: &mut dyn Transport, id: u8, address: u8, data: &[u8]) -> io::Result<()> {
+    let mut params = Vec::with_capacity(1 + data.len());
+    params.push(address);
+    params.extend_from_slice(data);
+    let packet = build_dyn_packet(id, Instruction::RegWrite, &params)?;
+    clear_input(port)?;
+    port.write_all(&packet)?;
+    port.flush()?;
+
+    if id != BROADCAST_ID {
+        expect_status(port, id, Instruction::RegWrite)?;
+    }
+    Ok(())
+}
+
+/// Apply every staged [`reg_write`] on the bus at once (ACTION, 0x05, to
+/// the broadcast ID; nobody answers).
+pub fn send_action(port: &mut dyn Transport) -> io::Result<()> {
+    let packet = build_dyn_packet(BROADCAST_ID, Instruction::Action, &[])?;
+    clear_input(port)?;
+    port.write_all(&packet)?;
+    port.flush()
+}
+
+/// Build a SYNC WRITE packet (instruction 0x83) to the broadcast ID:
+/// `address`, the data length, then each ID followed by its data. Every
+/// payload must have the same length and the whole packet must fit the v1
+/// length field.
+pub fn build_sync_write(
+    address: u8,
+    per_id_data: &[(u8, Vec<u8>)],
+) -> Result<Vec<u8>, DynamixelError> {
+    let expected = per_id_data.first().map_or(0, |(_, data)| data.len());
+    let mut params = Vec::with_capacity(2 + per_id_data.len() * (1 + expected));
+    params.push(address);
+    params.push(expected.min(u8::MAX as usize) as u8);
+    for (id, data) in per_id_data {
+        if data.len() != expected {
+            return Err(DynamixelError::SyncWriteLengthMismatch {
+                id: *id,
+                len: data.len(),
+                expected,
+            });
+        }
+        params.push(*id);
+        params.extend_from_slice(data);
+    }
+    build_dyn_packet(BROADCAST_ID, Instruction::SyncWrite, &params)
+}
+
+/// Write `data` at `address` on several servos with one SYNC WRITE
+/// instruction. Sent to the broadcast ID, so no status packets come back.
+/// Does nothing when `per_id_data` is empty.
+pub fn sync_write(
+    port: &mut dyn Transport,
+    address: u8,
+    per_id_data: &[(u8, Vec<u8>)],
+) -> io::Result<()> {
+    if per_id_data.is_empty() {
+        return Ok(());
+    }
+    let packet = build_sync_write(address, per_id_data)?;
+    clear_input(port)?;
+    port.write_all(&packet)?;
+    port.flush()
+}
 
-    Ok(ping_buf[..ping_read_bytes].to_vec())
+/// Disable torque on every servo in `ids` at once.
+pub fn sync_torque_off(port: &mut dyn Transport, ids: &[u8]) -> io::Result<()> {
+    let per_id_data: Vec<(u8, Vec<u8>)> = ids.iter().map(|&id| (id, vec![0])).collect();
+    sync_write(port, registers::TORQUE_ENABLE, &per_id_data)
 }
 
-pub fn send_reboot(port: &mut dyn serialport::SerialPort, id: u8) -> io::Result<()> {
-    let packet = build_dyn_packet(id, 0x08, &[]);
+/// Restore the factory configuration of `id` (instruction 0x06).
+///
+/// This erases every EEPROM setting: the ID goes back to 1, the baud rate
+/// to the factory default, and limits and offsets are lost. Sent to
+/// [`BROADCAST_ID`] it resets every servo on the bus, none of which answer;
+/// otherwise waits for the status packet.
+pub fn factory_reset(port: &mut dyn Transport, id: u8) -> io::Result<()> {
+    let packet = build_dyn_packet(id, Instruction::FactoryReset, &[])?;
+    clear_input(port)?;
     port.write_all(&packet)?;
     port.flush()?;
+
+    if id != BROADCAST_ID {
+        expect_status(port, id, Instruction::FactoryReset)?;
+    }
     Ok(())
 }
 
-pub fn scan_ids(port: &mut dyn serialport::SerialPort) -> io::Result<Vec<u8>> {
+/// Send the reboot instruction to `id`.
+///
+/// Some firmware answers with a status packet before resetting, some resets
+/// straight away. With `read_status` the status packet is read using the
+/// current port timeout and returned; a timeout there is not an error and
+/// yields `None`.
+pub fn send_reboot(
+    port: &mut dyn Transport,
+    id: u8,
+    read_status: bool,
+) -> io::Result<Option<Vec<u8>>> {
+    send_instruction(port, id, Instruction::Reboot, &[])?;
+
+    if !read_status {
+        return Ok(None);
+    }
+
+    let mut buf = [0u8; 64];
+    match port.read(&mut buf) {
+        Ok(0) => Ok(None),
+        Ok(n) => Ok(Some(buf[..n].to_vec())),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Send the reboot instruction and confirm the device actually reset by
+/// pinging until it stops answering. Each ping uses the current port
+/// timeout; fails with `RebootRejected` if the device still answers after
+/// `timeout`, and with `DeadlineExceeded` once `deadline` passes.
+pub fn reboot_and_confirm(
+    port: &mut dyn Transport,
+    id: u8,
+    timeout: Duration,
+    deadline: Deadline,
+) -> io::Result<()> {
+    send_reboot(port, id, false)?;
+
+    let start = Instant::now();
+    loop {
+        deadline.check(Phase::Handshake)?;
+        if send_ping(port, id).is_err() {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(FeeflashError::RebootRejected { id }.into());
+        }
+    }
+}
+
+/// Reboot `id` and wait until it is back: [`reboot_and_confirm`] that it
+/// dropped off the bus, then ping until it answers again. `timeout` covers
+/// both phases; fails with `RebootRejected` if the device never went away
+/// and `NotBackAfterReboot` if it never answered again. `deadline` bounds
+/// both loops as well.
+pub fn reboot_and_wait(
+    port: &mut dyn Transport,
+    id: u8,
+    timeout: Duration,
+    deadline: Deadline,
+) -> io::Result<()> {
+    let start = Instant::now();
+    reboot_and_confirm(port, id, timeout, deadline)?;
+
+    loop {
+        deadline.check(Phase::Verify)?;
+        if send_ping(port, id).is_ok() {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            return Err(FeeflashError::NotBackAfterReboot { id }.into());
+        }
+    }
+}
+
+/// What came back for one ping of a scan.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PingOutcome {
+    Answered(ProtocolVersion),
+    /// The line stayed quiet.
+    NoResponse,
+    /// Bytes arrived but no valid status packet from the ID: usually two
+    /// servos sharing the ID answering on top of each other.
+    Garbled,
+}
+
+/// [`probe_id`] up to `attempts` times (at least once) while the line
+/// stays quiet, so one dropped reply on a busy bus doesn't hide a device.
+/// Stops at the first answer or garbled reply.
+pub fn probe_id_attempts(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: Option<ProtocolVersion>,
+    attempts: u8,
+) -> io::Result<PingOutcome> {
+    probe_id_attempts_reusing(port, id, protocol, attempts, &mut PacketReader::new())
+}
+
+/// [`probe_id_attempts`] reading through `reader`, see
+/// [`probe_id_reusing`].
+fn probe_id_attempts_reusing(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: Option<ProtocolVersion>,
+    attempts: u8,
+    reader: &mut PacketReader,
+) -> io::Result<PingOutcome> {
+    let mut outcome = PingOutcome::NoResponse;
+    for _ in 0..attempts.max(1) {
+        outcome = probe_id_reusing(port, id, protocol, reader)?;
+        if outcome != PingOutcome::NoResponse {
+            break;
+        }
+    }
+    Ok(outcome)
+}
+
+/// Ping `id` once and classify the reply. With `protocol` `None`, a
+/// protocol 2 ping follows if the protocol 1 ping gets no answer or a
+/// protocol 2 header.
+pub fn probe_id(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: Option<ProtocolVersion>,
+) -> io::Result<PingOutcome> {
+    probe_id_reusing(port, id, protocol, &mut PacketReader::new())
+}
+
+/// [`probe_id`] reading protocol 1 replies through `reader`, whose buffer
+/// is kept between calls like for [`ping_reusing`]: a protocol 1 sweep
+/// reusing one reader doesn't allocate per ID.
+pub fn probe_id_reusing(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: Option<ProtocolVersion>,
+    reader: &mut PacketReader,
+) -> io::Result<PingOutcome> {
+    if protocol != Some(ProtocolVersion::V2) {
+        match probe_v1(port, id, reader)? {
+            Some(outcome) => return Ok(outcome),
+            None if protocol == Some(ProtocolVersion::V1) => return Ok(PingOutcome::NoResponse),
+            None => {}
+        }
+    }
+    match dynamixel2::ping(port, id) {
+        Ok(_) => Ok(PingOutcome::Answered(ProtocolVersion::V2)),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(PingOutcome::NoResponse),
+        Err(e) if e.kind() == io::ErrorKind::InvalidData => Ok(PingOutcome::Garbled),
+        Err(e) => Err(e),
+    }
+}
+
+/// Protocol 1 part of [`probe_id`]: reads until a valid status packet from
+/// `id` arrives or the line goes quiet. `None` for silence or a protocol 2
+/// header.
+fn probe_v1(
+    port: &mut dyn Transport,
+    id: u8,
+    reader: &mut PacketReader,
+) -> io::Result<Option<PingOutcome>> {
+    reader.clear();
+    send_instruction(port, id, Instruction::Ping, &[])?;
+
+    let mut received = false;
+    // The last four bytes received, to spot a protocol 2 header split
+    // across reads.
+    let mut last = 0u32;
+    let mut v2_header = false;
+    let mut buf = [0u8; 64];
+    loop {
+        match port.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => {
+                received = true;
+                for &byte in &buf[..n] {
+                    last = last << 8 | u32::from(byte);
+                    v2_header |= last == u32::from_be_bytes(dynamixel2::HEADER);
+                }
+                reader.push(&buf[..n]);
+                let from_id = |bytes: &[u8]| StatusPacket::parse(bytes).is_ok_and(|s| s.id == id);
+                while let Some(answered) = reader.with_next_packet(from_id) {
+                    if answered {
+                        return Ok(Some(PingOutcome::Answered(ProtocolVersion::V1)));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    if !received || v2_header {
+        Ok(None)
+    } else {
+        Ok(Some(PingOutcome::Garbled))
+    }
+}
+
+/// Result of [`scan_bus`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ScanReport {
+    /// IDs that answered with a valid status packet.
+    pub found: Vec<u8>,
+    /// IDs whose replies were garbled: probably several servos sharing the
+    /// ID. Not in `found`.
+    pub collisions: Vec<u8>,
+}
+
+/// How [`scan_bus`] and the scans built on it ping the bus.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScanOptions {
+    /// Restricts the scan to one protocol. With `None` both are tried: a
+    /// broadcast ping of each, so mixed buses are found too. An ID sweep
+    /// then uses the protocol the broadcast answers came in (protocol 1
+    /// if that tells nothing), and sweeps again in the other only if the
+    /// first sweep finds nothing.
+    pub protocol: Option<ProtocolVersion>,
+    /// Ping IDs from 253 down to 0, for kits that ship with high IDs.
+    /// Reports are in ID order either way.
+    pub reverse: bool,
+    /// Pings per ID of the sweep, see [`probe_id_attempts`].
+    pub attempts: u8,
+}
+
+impl Default for ScanOptions {
+    fn default() -> Self {
+        ScanOptions {
+            protocol: None,
+            reverse: false,
+            attempts: 1,
+        }
+    }
+}
+
+/// Find the IDs on the bus. Tries a broadcast ping first and falls back
+/// to pinging every ID when nothing answers it or replies collided, since a
+/// garbled reply may hide a device.
+pub fn scan_bus(
+    port: &mut dyn Transport,
+    deadline: Deadline,
+    options: &ScanOptions,
+) -> io::Result<ScanReport> {
+    let ScanOptions {
+        protocol,
+        reverse,
+        attempts,
+    } = *options;
+    deadline.check(Phase::Scan)?;
+    let window = Duration::from_millis(BROADCAST_WINDOW_MS);
+    let mut responders = Vec::new();
+    let mut garbled = 0;
+    let mut v2_only = protocol == Some(ProtocolVersion::V2);
+    if protocol != Some(ProtocolVersion::V2) {
+        let (packets, skipped) = broadcast_ping_counting(port, window)?;
+        responders.extend(packets);
+        garbled += skipped;
+    }
+    if protocol != Some(ProtocolVersion::V1) {
+        let (packets, skipped) = dynamixel2::broadcast_ping_counting(port, window)?;
+        v2_only |= responders.is_empty() && !packets.is_empty();
+        responders.extend(packets);
+        garbled += skipped;
+    }
+    if !responders.is_empty() && garbled == 0 {
+        let mut found: Vec<u8> = responders.iter().map(|p| p.id).collect();
+        found.sort_unstable();
+        found.dedup();
+        diag!("Responding IDs: {:?}", found);
+        port.set_timeout(Duration::from_secs(10))?;
+        return Ok(ScanReport {
+            found,
+            collisions: Vec::new(),
+        });
+    }
+    if garbled > 0 {
+        diag!("Broadcast ping replies were garbled; scanning IDs one by one.");
+    }
+
     // Use a short timeout to keep scanning quick.
     port.set_timeout(Duration::from_millis(SCAN_TIMEOUT_MS))?;
 
-    let mut found: Vec<u8> = Vec::new();
-    let stdout = std::io::stdout();
-    let mut handle = stdout.lock();
+    // Sweep in one protocol; the other only if that finds nothing.
+    let first = if v2_only {
+        ProtocolVersion::V2
+    } else {
+        ProtocolVersion::V1
+    };
+    let mut report = sweep_ids(port, deadline, first, reverse, attempts)?;
+    if protocol.is_none() && report.found.is_empty() && report.collisions.is_empty() {
+        let second = match first {
+            ProtocolVersion::V1 => ProtocolVersion::V2,
+            ProtocolVersion::V2 => ProtocolVersion::V1,
+        };
+        report = sweep_ids(port, deadline, second, reverse, attempts)?;
+    }
+
+    if !report.found.is_empty() {
+        diag!("Responding IDs: {:?}", report.found);
+    }
+
+    // Restore to a generous timeout for the rest of the protocol.
+    port.set_timeout(Duration::from_secs(10))?;
 
-    // Scan valid unicast IDs only (0..=253). Exclude 254 (0xFE) which is broadcast.
-    let start_id: u8 = 0;
-    let end_id: u8 = 0xFD; // 253
-    let total: u16 = (end_id - start_id + 1) as u16;
+    Ok(report)
+}
 
+/// The ID sweep of [`scan_bus`], pinging every ID in `protocol`. The
+/// report is in ID order.
+fn sweep_ids(
+    port: &mut dyn Transport,
+    deadline: Deadline,
+    protocol: ProtocolVersion,
+    reverse: bool,
+    attempts: u8,
+) -> io::Result<ScanReport> {
     use std::io::Write as _;
 
-    for (idx, id) in (start_id..=end_id).enumerate() {
-        if send_ping(port, id).is_ok() {
-            found.push(id);
+    let mut report = ScanReport::default();
+    // Progress goes to stderr so stdout stays clean for `scan --json`.
+    let mut handle = progress_output();
+
+    let total: u16 = MAX_UNICAST_ID as u16 + 1;
+
+    let mut reader = PacketReader::new();
+    for (idx, id) in unicast_ids(reverse).enumerate() {
+        deadline.check(Phase::Scan)?;
+
+        match probe_id_attempts_reusing(port, id, Some(protocol), attempts, &mut reader)? {
+            PingOutcome::Answered(_) => report.found.push(id),
+            PingOutcome::Garbled => report.collisions.push(id),
+            PingOutcome::NoResponse => {}
         }
 
         let current = (idx as u16) + 1;
@@ -78,35 +987,669 @@ pub fn scan_ids(port: &mut dyn serialport::SerialPort) -> io::Result<Vec<u8>> {
             "\x1b[2K\rScanning IDs ({:3}/{:3}) found: {}",
             current,
             total,
-            found.len()
+            report.found.len()
         )?;
         handle.flush()?;
     }
 
     writeln!(handle)?;
+    if reverse {
+        report.found.reverse();
+        report.collisions.reverse();
+    }
+    Ok(report)
+}
+
+/// Ping IDs upwards from 0 (downwards from 253 with `reverse`) and return
+/// the first that answers, or `None` if none does. Much quicker than
+/// [`scan_bus`] for a single device near the start of the sweep, but says
+/// nothing about other devices on the bus. IDs with garbled replies are
+/// passed over.
+pub fn scan_first(
+    port: &mut dyn Transport,
+    deadline: Deadline,
+    options: &ScanOptions,
+) -> io::Result<Option<u8>> {
+    let previous = port.timeout();
+    port.set_timeout(Duration::from_millis(SCAN_TIMEOUT_MS))?;
+    let mut found = None;
+    for id in unicast_ids(options.reverse) {
+        deadline.check(Phase::Scan)?;
+        if let PingOutcome::Answered(_) =
+            probe_id_attempts(port, id, options.protocol, options.attempts)?
+        {
+            found = Some(id);
+            break;
+        }
+    }
+    port.set_timeout(previous)?;
+    Ok(found)
+}
 
-    if !found.is_empty() {
-        println!("Responding IDs: {:?}", found);
+/// How one ID fared over the repeated pings of [`scan_health`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct IdHealth {
+    pub id: u8,
+    /// Pings answered with a valid status packet.
+    pub responses: u8,
+    /// Pings the line stayed quiet after.
+    pub timeouts: u8,
+    /// Pings answered with bytes that were no valid packet (collisions,
+    /// noise).
+    pub garbled: u8,
+}
+
+impl IdHealth {
+    /// Answered some pings but not all: the "works half the time" device.
+    pub fn is_intermittent(&self) -> bool {
+        self.responses > 0 && (self.timeouts > 0 || self.garbled > 0)
     }
+}
 
-    // Restore to a generous timeout for the rest of the protocol.
-    port.set_timeout(Duration::from_secs(10))?;
+/// Ping every ID `attempts_per_id` times and count how each ping went.
+/// The bus is swept once per attempt, so a burst of noise hits many IDs
+/// once rather than one ID every time. Returns the IDs that produced any
+/// bytes, in ID order; IDs that only timed out are left out.
+///
+/// `protocol` is used as in [`scan_bus`].
+pub fn scan_health(
+    port: &mut dyn Transport,
+    attempts_per_id: u8,
+    protocol: Option<ProtocolVersion>,
+) -> io::Result<Vec<IdHealth>> {
+    use std::io::Write as _;
 
-    Ok(found)
+    let previous = port.timeout();
+    port.set_timeout(Duration::from_millis(SCAN_TIMEOUT_MS))?;
+    let mut health: Vec<IdHealth> = (0..=MAX_UNICAST_ID)
+        .map(|id| IdHealth {
+            id,
+            responses: 0,
+            timeouts: 0,
+            garbled: 0,
+        })
+        .collect();
+
+    let mut handle = progress_output();
+    for attempt in 1..=attempts_per_id {
+        for entry in &mut health {
+            match probe_id(port, entry.id, protocol)? {
+                PingOutcome::Answered(_) => entry.responses += 1,
+                PingOutcome::NoResponse => entry.timeouts += 1,
+                PingOutcome::Garbled => entry.garbled += 1,
+            }
+            write!(
+                handle,
+                "\x1b[2K\rChecking bus health: pass {}/{}, id {:3}",
+                attempt, attempts_per_id, entry.id
+            )?;
+            handle.flush()?;
+        }
+    }
+    writeln!(handle)?;
+
+    port.set_timeout(previous)?;
+    health.retain(|entry| entry.responses > 0 || entry.garbled > 0);
+    Ok(health)
+}
+
+/// IDs found by [`scan_bus`]; suspected collisions are dropped.
+pub fn scan_ids(
+    port: &mut dyn Transport,
+    deadline: Deadline,
+    options: &ScanOptions,
+) -> io::Result<Vec<u8>> {
+    Ok(scan_bus(port, deadline, options)?.found)
+}
+
+/// Highest unicast ID; 254 (0xFE) is broadcast.
+const MAX_UNICAST_ID: u8 = 0xFD;
+
+/// Unicast IDs in scan order.
+fn unicast_ids(reverse: bool) -> impl Iterator<Item = u8> {
+    (0..=MAX_UNICAST_ID).map(move |i| if reverse { MAX_UNICAST_ID - i } else { i })
 }
 
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
+    use crate::transport::{LoopbackTransport, MockTransport};
 
     #[test]
     fn dyn_packet_checksum_matches_examples() {
         // Ping example from original hardcoded packet: FF FF 01 02 01 FB
-        let pkt = build_dyn_packet(0x01, 0x01, &[]);
+        let pkt = build_dyn_packet(0x01, Instruction::Ping, &[]).unwrap();
         assert_eq!(pkt, vec![0xFF, 0xFF, 0x01, 0x02, 0x01, 0xFB]);
 
         // Reboot example: FF FF 01 02 08 F4
-        let pkt = build_dyn_packet(0x01, 0x08, &[]);
+        let pkt = build_dyn_packet(0x01, Instruction::Reboot, &[]).unwrap();
+        assert_eq!(pkt, vec![0xFF, 0xFF, 0x01, 0x02, 0x08, 0xF4]);
+
+        #[allow(deprecated)]
+        let pkt = build_dyn_packet_raw(0x01, 0x08, &[]).unwrap();
+        assert_eq!(pkt, vec![0xFF, 0xFF, 0x01, 0x02, 0x08, 0xF4]);
+
+        #[allow(deprecated)]
+        let pkt = build_dyn_packet_instr(0x01, Instruction::Reboot, &[]).unwrap();
         assert_eq!(pkt, vec![0xFF, 0xFF, 0x01, 0x02, 0x08, 0xF4]);
+        #[allow(deprecated)]
+        let code = Instruction::SyncWrite.as_u8();
+        assert_eq!(code, 0x83);
+    }
+
+    proptest! {
+        #[test]
+        fn dyn_packet_framing_holds(
+            id: u8,
+            code: u8,
+            params in prop::collection::vec(any::<u8>(), 0..=MAX_PARAMS),
+        ) {
+            let packet = build_dyn_packet(id, Instruction::from(code), &params).unwrap();
+            prop_assert_eq!(&packet[..2], &[0xFF, 0xFF]);
+            prop_assert_eq!(packet[2], id);
+            prop_assert_eq!(packet[3] as usize, params.len() + 2);
+            prop_assert_eq!(packet[4], code);
+            prop_assert_eq!(&packet[5..packet.len() - 1], &params[..]);
+            let sum: u32 = packet[2..packet.len() - 1].iter().map(|&b| b as u32).sum();
+            prop_assert_eq!(packet[packet.len() - 1], (!sum & 0xFF) as u8);
+
+            let mut buf = [0u8; MAX_PACKET_LEN];
+            let len = build_dyn_packet_into(&mut buf, id, Instruction::from(code), &params).unwrap();
+            prop_assert_eq!(&buf[..len], &packet[..]);
+        }
+    }
+
+    #[test]
+    fn latency_counts_lost_pings() {
+        let reply = [0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC];
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&reply);
+        mock.push_timeout().push_timeout();
+        mock.push_timeout().push_read(&reply);
+        let stats = measure_ping_latency(&mut mock, 1, 3).unwrap();
+        assert_eq!((stats.sent, stats.received), (3, 2));
+        assert!(stats.min <= stats.mean && stats.mean <= stats.max);
+        assert!((stats.loss() - 1.0 / 3.0).abs() < 1e-9);
+
+        let err = measure_ping_latency(&mut MockTransport::new(), 1, 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn instruction_codes_round_trip() {
+        for code in 0..=u8::MAX {
+            assert_eq!(Instruction::from(code).code(), code);
+        }
+        assert_eq!(Instruction::from(0x83), Instruction::SyncWrite);
+        assert_eq!(Instruction::from(0x10), Instruction::Custom(0x10));
+        assert_eq!(Instruction::RegWrite.to_string(), "REG_WRITE (0x04)");
+    }
+
+    #[test]
+    fn dyn_packet_rejects_oversized_params() {
+        let pkt = build_dyn_packet(0x01, Instruction::WriteData, &[0xFF; 253]).unwrap();
+        assert_eq!(pkt[3], 0xFF);
+        assert_eq!(pkt.len(), 4 + 255);
+
+        assert_eq!(
+            build_dyn_packet(0x01, Instruction::WriteData, &[0u8; 254]),
+            Err(DynamixelError::ParamsTooLong { len: 254, max: 253 })
+        );
+        assert_eq!(
+            build_dyn_packet_into(&mut [0u8; 7], 0x01, Instruction::ReadData, &[0x24, 2]),
+            Err(DynamixelError::BufferTooSmall { len: 7, needed: 8 })
+        );
+    }
+
+    #[test]
+    fn ping_reusing_keeps_reader_between_calls() {
+        let status = [0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC];
+        let mut mock = MockTransport::new();
+        // Noise ahead of the first reply must not count against the second.
+        mock.push_timeout()
+            .push_read(&[0x00; 10])
+            .push_read(&status);
+        mock.push_timeout().push_timeout();
+        mock.push_timeout().push_read(&status);
+
+        let mut reader = PacketReader::new();
+        assert_eq!(ping_reusing(&mut mock, 1, &mut reader).unwrap().id, 1);
+        let err = ping_reusing(&mut mock, 1, &mut reader).unwrap_err();
+        assert_eq!(err.to_string(), "Ping timed out");
+        assert_eq!(ping_reusing(&mut mock, 1, &mut reader).unwrap().id, 1);
+        assert_eq!(reader.skipped(), 0);
+    }
+
+    #[test]
+    fn ping_ignores_stale_input() {
+        let status = [0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC];
+        let mut mock = MockTransport::new();
+        // A late ACK and half a status packet left over from earlier traffic.
+        mock.push_read(&[0x06, 0xFF, 0xFF, 0x03])
+            .push_timeout()
+            .push_read(&status);
+
+        assert_eq!(send_ping(&mut mock, 1).unwrap(), status);
+        assert_eq!(
+            mock.writes(),
+            [build_dyn_packet(1, Instruction::Ping, &[]).unwrap()]
+        );
+    }
+
+    const V2_PING_1: [u8; 10] = [0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x03, 0x00, 0x01, 0x19, 0x4E];
+    /// Protocol 2.0 ping status of id 1: model 1030, firmware 0x26.
+    const V2_STATUS_1: [u8; 14] = [
+        0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x07, 0x00, 0x55, 0x00, 0x06, 0x04, 0x26, 0x65, 0x5D,
+    ];
+
+    #[test]
+    fn detect_protocol_prefers_v1() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x01])
+            .push_read(&[0x02, 0x00, 0xFC]);
+        assert_eq!(detect_protocol(&mut mock, 1).unwrap(), ProtocolVersion::V1);
+        assert_eq!(mock.writes().len(), 1);
+    }
+
+    #[test]
+    fn detect_protocol_falls_back_to_v2() {
+        // No answer to the v1 ping.
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_timeout()
+            .push_timeout()
+            .push_read(&V2_STATUS_1);
+        assert_eq!(detect_protocol(&mut mock, 1).unwrap(), ProtocolVersion::V2);
+        assert_eq!(mock.writes()[1], V2_PING_1);
+
+        // A v2 header in reply to the v1 ping.
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&V2_STATUS_1[..6])
+            .push_timeout()
+            .push_read(&V2_STATUS_1[..5])
+            .push_read(&V2_STATUS_1[5..]);
+        assert_eq!(detect_protocol(&mut mock, 1).unwrap(), ProtocolVersion::V2);
+        assert_eq!(mock.writes().len(), 2);
+    }
+
+    #[test]
+    fn detect_protocol_times_out_without_answer() {
+        let mut mock = MockTransport::new();
+        let err = detect_protocol(&mut mock, 9).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let mut bad_crc = V2_STATUS_1;
+        bad_crc[13] ^= 1;
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_timeout()
+            .push_timeout()
+            .push_read(&bad_crc);
+        let err = detect_protocol(&mut mock, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn status_read_flushes_the_reader_when_the_line_goes_quiet() {
+        // A header announcing 9 more bytes that never come, with a whole
+        // status packet behind it.
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0xFF, 0xFF, 0x01, 0x09])
+            .push_read(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC])
+            .push_timeout();
+        assert_eq!(read_status_packet(&mut mock).unwrap().id, 1);
+    }
+
+    #[test]
+    fn detect_baud_finds_rate_and_restores_on_failure() {
+        // Silent at 500k, answers at 115200; 1M is the current rate.
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_timeout();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC]);
+        let found = detect_baud(
+            &mut mock,
+            1,
+            &[1_000_000, 500_000, 115_200, 57_600],
+            1_000_000,
+            ProtocolVersion::V1,
+        )
+        .unwrap();
+        assert_eq!(found, Some(115_200));
+        assert_eq!(mock.baud_rate(), Some(115_200));
+        assert_eq!(mock.writes().len(), 2);
+
+        let mut mock = MockTransport::new();
+        let found = detect_baud(
+            &mut mock,
+            1,
+            &[500_000, 115_200],
+            1_000_000,
+            ProtocolVersion::V1,
+        )
+        .unwrap();
+        assert_eq!(found, None);
+        assert_eq!(mock.baud_rate(), Some(1_000_000));
+    }
+
+    /// [`MockTransport`] that refuses one baud rate, as an adapter might.
+    struct RejectsBaud {
+        inner: MockTransport,
+        rejected: u32,
+    }
+
+    impl Transport for RejectsBaud {
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.inner.write_all(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.inner.read(buf)
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.inner.set_timeout(timeout)
+        }
+
+        fn timeout(&self) -> Duration {
+            Transport::timeout(&self.inner)
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            if baud_rate == self.rejected {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported"));
+            }
+            self.inner.set_baud_rate(baud_rate)
+        }
+    }
+
+    #[test]
+    fn detect_baud_restores_the_rate_when_switching_fails() {
+        let mut port = RejectsBaud {
+            inner: MockTransport::new(),
+            rejected: 250_000,
+        };
+        let err = detect_baud(
+            &mut port,
+            1,
+            &[500_000, 250_000, 115_200],
+            1_000_000,
+            ProtocolVersion::V1,
+        )
+        .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(port.inner.baud_rate(), Some(1_000_000));
+    }
+
+    #[test]
+    fn reg_write_and_action_packets() {
+        // 01+04+04+28+00 = 0x31, !0x31 = 0xCE
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC]);
+        reg_write(&mut mock, 1, 0x28, &[0]).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x01, 0x04, 0x04, 0x28, 0x00, 0xCE]]
+        );
+
+        // FE+02+05 = 0x105, !0x05 = 0xFA
+        let mut mock = MockTransport::new();
+        send_action(&mut mock).unwrap();
+        assert_eq!(mock.writes(), [[0xFF, 0xFF, 0xFE, 0x02, 0x05, 0xFA]]);
+        assert_eq!(mock.pending_reads(), 0);
+    }
+
+    #[test]
+    fn reg_write_requires_status() {
+        let mut mock = MockTransport::new();
+        let err = reg_write(&mut mock, 1, 0x28, &[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn instruction_error_names_the_instruction() {
+        // 01+02+40 = 0x43, !0x43 = 0xBC
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x01, 0x02, 0x40, 0xBC]);
+        let err = reg_write(&mut mock, 1, 0x28, &[0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(err.to_string(), "Device id 1 rejected REG_WRITE (0x04)");
+    }
+
+    #[test]
+    fn sync_write_matches_hand_computed_packet() {
+        // Goal position (0x2A) 2048 on id 1 and 4095 on id 2. Checksum:
+        // FE+0A+83+2A+02+01+00+08+02+FF+0F = 0x2D0, !0xD0 = 0x2F.
+        let packet =
+            build_sync_write(0x2A, &[(1, vec![0x00, 0x08]), (2, vec![0xFF, 0x0F])]).unwrap();
+        assert_eq!(
+            packet,
+            [
+                0xFF, 0xFF, 0xFE, 0x0A, 0x83, 0x2A, 0x02, 0x01, 0x00, 0x08, 0x02, 0xFF, 0x0F, 0x2F
+            ]
+        );
+
+        // Torque off on ids 1 and 2: sum 0x1B5, !0xB5 = 0x4A.
+        let mut mock = MockTransport::new();
+        sync_torque_off(&mut mock, &[1, 2]).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[
+                0xFF, 0xFF, 0xFE, 0x08, 0x83, 0x28, 0x01, 0x01, 0x00, 0x02, 0x00, 0x4A
+            ]]
+        );
+    }
+
+    #[test]
+    fn sync_write_validates_payloads() {
+        assert_eq!(
+            build_sync_write(0x2A, &[(1, vec![0, 8]), (2, vec![0xFF])]),
+            Err(DynamixelError::SyncWriteLengthMismatch {
+                id: 2,
+                len: 1,
+                expected: 2
+            })
+        );
+        // 84 servos x (1 + 2) bytes + 2 = 254 params: one too many.
+        let many: Vec<(u8, Vec<u8>)> = (0..84).map(|id| (id, vec![0, 0])).collect();
+        assert_eq!(
+            build_sync_write(0x2A, &many),
+            Err(DynamixelError::ParamsTooLong { len: 254, max: 253 })
+        );
+        assert!(build_sync_write(0x2A, &many[..83]).is_ok());
+    }
+
+    #[test]
+    fn reboot_status_is_optional() {
+        let status = [0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC];
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&status);
+        assert_eq!(
+            send_reboot(&mut mock, 1, true).unwrap(),
+            Some(status.to_vec())
+        );
+
+        // Firmware that resets without answering.
+        let mut mock = MockTransport::new();
+        mock.push_timeout();
+        assert_eq!(send_reboot(&mut mock, 1, true).unwrap(), None);
+        assert_eq!(
+            mock.writes(),
+            [build_dyn_packet(1, Instruction::Reboot, &[]).unwrap()]
+        );
+    }
+
+    #[test]
+    fn status_packet_parses_and_validates() {
+        let status = StatusPacket::parse(&[0xFF, 0xFF, 0x01, 0x03, 0x20, 0x07, 0xD4]).unwrap();
+        assert_eq!(
+            status,
+            StatusPacket {
+                id: 1,
+                error: 0x20,
+                params: vec![0x07],
+            }
+        );
+
+        let bad_checksum = [0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFB];
+        assert!(StatusPacket::parse(&bad_checksum).is_err());
+        let truncated = [0xFF, 0xFF, 0x01, 0x03, 0x00, 0xFB];
+        assert!(StatusPacket::parse(&truncated).is_err());
+    }
+
+    fn status(id: u8) -> Vec<u8> {
+        vec![0xFF, 0xFF, id, 0x02, 0x00, !(id.wrapping_add(2))]
+    }
+
+    #[test]
+    fn split_skips_garbled_packets() {
+        let mut stream = vec![0x00, 0xFF];
+        stream.extend(status(1));
+        // Two replies colliding: the second header lands inside the first.
+        stream.extend([0xFF, 0xFF, 0x03, 0x02]);
+        stream.extend(status(4));
+        stream.extend([0xFF, 0xFF, 0x05, 0x02, 0x00, 0x00]); // bad checksum
+        stream.extend(status(7));
+        stream.extend([0xFF, 0xFF, 0x09]); // truncated
+
+        let (packets, garbled) = split_status_packets(&stream);
+        let ids: Vec<u8> = packets.iter().map(|p| p.id).collect();
+        assert_eq!(ids, [1, 4, 7]);
+        assert_eq!(garbled, 3);
+    }
+
+    #[test]
+    fn broadcast_ping_collects_every_responder() {
+        let mut mock = MockTransport::new();
+        let mut replies = status(2);
+        replies.extend(status(5));
+        mock.push_timeout()
+            .push_read(&replies[..8])
+            .push_read(&replies[8..])
+            .push_read(&status(2));
+
+        let packets = broadcast_ping(&mut mock, Duration::from_millis(50)).unwrap();
+        let ids: Vec<u8> = packets.iter().map(|p| p.id).collect();
+        assert_eq!(ids, [2, 5]);
+        assert_eq!(
+            mock.writes(),
+            [build_dyn_packet(BROADCAST_ID, Instruction::Ping, &[]).unwrap()]
+        );
+    }
+
+    #[test]
+    fn broadcast_ping_restores_the_timeout_after_a_failed_read() {
+        let (mut port, mut bus) = LoopbackTransport::pair();
+        port.set_timeout(Duration::from_secs(3)).unwrap();
+        let bus = std::thread::spawn(move || {
+            let mut ping = [0u8; 6];
+            bus.read(&mut ping).unwrap();
+            // Hanging up makes the next read on `port` fail.
+        });
+
+        let err = broadcast_ping(&mut port, Duration::from_secs(5)).unwrap_err();
+        bus.join().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(port.timeout(), Duration::from_secs(3));
+    }
+
+    #[test]
+    fn scan_uses_broadcast_when_it_answers() {
+        let mut mock = MockTransport::new();
+        let mut replies = status(9);
+        replies.extend(status(3));
+        mock.push_timeout().push_read(&replies);
+
+        let options = ScanOptions {
+            protocol: Some(ProtocolVersion::V1),
+            ..ScanOptions::default()
+        };
+        let found = scan_ids(&mut mock, Deadline::NONE, &options).unwrap();
+        assert_eq!(found, [3, 9]);
+        assert_eq!(mock.writes().len(), 1);
+    }
+
+    #[test]
+    fn factory_reset_waits_for_status() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x03, 0x02, 0x00, 0xFA]);
+        factory_reset(&mut mock, 3).unwrap();
+        assert_eq!(mock.writes(), [[0xFF, 0xFF, 0x03, 0x02, 0x06, 0xF4]]);
+
+        let mut mock = MockTransport::new();
+        let err = factory_reset(&mut mock, 3).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        // Broadcast: nobody answers.
+        let mut mock = MockTransport::new();
+        factory_reset(&mut mock, BROADCAST_ID).unwrap();
+        assert_eq!(mock.writes(), [[0xFF, 0xFF, 0xFE, 0x02, 0x06, 0xF9]]);
+    }
+
+    #[test]
+    fn status_reader_resyncs_and_waits_for_pieces() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x00, 0x42, 0xFF])
+            .push_read(&[0xFF, 0xFF, 0x05])
+            .push_read(&[0x03, 0x00])
+            .push_read(&[0x11])
+            .push_read(&[0xE6, 0xAA]);
+
+        let status = read_status_packet(&mut mock).unwrap();
+        assert_eq!(
+            status,
+            StatusPacket {
+                id: 5,
+                error: 0,
+                params: vec![0x11],
+            }
+        );
+    }
+
+    #[test]
+    fn status_reader_times_out_on_partial_packet() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0xFF, 0xFF, 0x05, 0x03]);
+        let err = read_status_packet(&mut mock).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x55; 300]);
+        let err = read_status_packet(&mut mock).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn corrupted_reply_is_reported_over_timeout() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFB]);
+        let err = ping(&mut mock, 1).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("checksum mismatch"), "{err}");
+    }
+
+    #[test]
+    fn ping_parses_slow_response() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0x00, 0xFF, 0xFF])
+            .push_read(&[0x01, 0x02, 0x00])
+            .push_read(&[0xFC]);
+
+        let status = ping(&mut mock, 1).unwrap();
+        assert_eq!((status.id, status.error), (1, 0));
     }
 }
@@ -0,0 +1,540 @@
+//! Building, validation and stream framing of protocol 1 packets.
+//!
+//! [`PacketReader`] takes bytes as the port delivers them, in any split,
+//! and hands out complete packets whose header, length and checksum check
+//! out. Noise before a header and candidates that fail validation (line
+//! noise, colliding replies) are dropped and counted.
+//!
+//! Builds on `core` and `alloc` alone, so it is available without the
+//! `std` feature.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Dynamixel v1 instruction codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Instruction {
+    Ping,
+    ReadData,
+    WriteData,
+    RegWrite,
+    Action,
+    FactoryReset,
+    Reboot,
+    SyncWrite,
+    /// Any other code, e.g. a vendor extension.
+    Custom(u8),
+}
+
+impl Instruction {
+    /// Byte sent in the instruction field.
+    pub fn code(self) -> u8 {
+        match self {
+            Instruction::Ping => 0x01,
+            Instruction::ReadData => 0x02,
+            Instruction::WriteData => 0x03,
+            Instruction::RegWrite => 0x04,
+            Instruction::Action => 0x05,
+            Instruction::FactoryReset => 0x06,
+            Instruction::Reboot => 0x08,
+            Instruction::SyncWrite => 0x83,
+            Instruction::Custom(code) => code,
+        }
+    }
+
+    /// [`Instruction::code`] under its former name.
+    #[deprecated(note = "renamed to Instruction::code")]
+    pub fn as_u8(self) -> u8 {
+        self.code()
+    }
+}
+
+impl From<u8> for Instruction {
+    /// Known codes map to their variant, everything else to `Custom`.
+    fn from(code: u8) -> Self {
+        match code {
+            0x01 => Instruction::Ping,
+            0x02 => Instruction::ReadData,
+            0x03 => Instruction::WriteData,
+            0x04 => Instruction::RegWrite,
+            0x05 => Instruction::Action,
+            0x06 => Instruction::FactoryReset,
+            0x08 => Instruction::Reboot,
+            0x83 => Instruction::SyncWrite,
+            code => Instruction::Custom(code),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Instruction::Ping => "PING",
+            Instruction::ReadData => "READ",
+            Instruction::WriteData => "WRITE",
+            Instruction::RegWrite => "REG_WRITE",
+            Instruction::Action => "ACTION",
+            Instruction::FactoryReset => "FACTORY_RESET",
+            Instruction::Reboot => "REBOOT",
+            Instruction::SyncWrite => "SYNC_WRITE",
+            Instruction::Custom(code) => return write!(f, "instruction 0x{code:02X}"),
+        };
+        write!(f, "{name} (0x{:02X})", self.code())
+    }
+}
+
+/// Dynamixel protocol spoken by a servo.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(any(feature = "std", feature = "serde"), derive(serde::Serialize))]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub enum ProtocolVersion {
+    #[cfg_attr(any(feature = "std", feature = "serde"), serde(rename = "1"))]
+    V1,
+    #[cfg_attr(any(feature = "std", feature = "serde"), serde(rename = "2"))]
+    V2,
+}
+
+/// Most parameter bytes a v1 packet can carry: the length byte also counts
+/// the instruction and the checksum.
+pub const MAX_PARAMS: usize = 253;
+
+/// Longest v1 packet: header, ID, length, instruction, [`MAX_PARAMS`]
+/// parameters and checksum.
+pub const MAX_PACKET_LEN: usize = MIN_PACKET_LEN + MAX_PARAMS;
+
+/// Build a Dynamixel v1-style packet for instructions like Ping or Reboot.
+/// Fails if `params` is longer than [`MAX_PARAMS`].
+pub fn build_dyn_packet(
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> Result<Vec<u8>, DynamixelError> {
+    let mut buf = [0; MAX_PACKET_LEN];
+    let len = build_dyn_packet_into(&mut buf, id, instruction, params)?;
+    Ok(buf[..len].to_vec())
+}
+
+/// [`build_dyn_packet`] into `buf`, without allocating; returns the
+/// packet length. A buffer of [`MAX_PACKET_LEN`] fits any packet. Fails if
+/// `params` is longer than [`MAX_PARAMS`] or the packet doesn't fit `buf`.
+pub fn build_dyn_packet_into(
+    buf: &mut [u8],
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> Result<usize, DynamixelError> {
+    if params.len() > MAX_PARAMS {
+        return Err(DynamixelError::ParamsTooLong {
+            len: params.len(),
+            max: MAX_PARAMS,
+        });
+    }
+    let len = MIN_PACKET_LEN + params.len();
+    if buf.len() < len {
+        return Err(DynamixelError::BufferTooSmall {
+            len: buf.len(),
+            needed: len,
+        });
+    }
+    let length = params.len() as u8 + 2; // instruction + checksum
+    buf[..5].copy_from_slice(&[0xFF, 0xFF, id, length, instruction.code()]);
+    buf[5..len - 1].copy_from_slice(params);
+    buf[len - 1] = dyn_checksum(&buf[2..len - 1]);
+    Ok(len)
+}
+
+/// [`build_dyn_packet`] taking a raw instruction byte.
+#[deprecated(note = "use build_dyn_packet with an Instruction")]
+pub fn build_dyn_packet_raw(
+    id: u8,
+    instruction: u8,
+    params: &[u8],
+) -> Result<Vec<u8>, DynamixelError> {
+    build_dyn_packet(id, Instruction::from(instruction), params)
+}
+
+/// [`build_dyn_packet`] under its former name, from when that took a raw
+/// instruction byte.
+#[deprecated(note = "build_dyn_packet takes an Instruction now")]
+pub fn build_dyn_packet_instr(
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> Result<Vec<u8>, DynamixelError> {
+    build_dyn_packet(id, instruction, params)
+}
+
+/// Errors building Dynamixel packets or reported by the servo.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DynamixelError {
+    /// The parameters don't fit the single-byte v1 length field.
+    ParamsTooLong { len: usize, max: usize },
+    /// The packet is longer than the buffer it is built into.
+    BufferTooSmall { len: usize, needed: usize },
+    /// A SYNC WRITE payload differs in length from the first one.
+    SyncWriteLengthMismatch { id: u8, len: usize, expected: usize },
+    /// The servo answered with the instruction error bit set.
+    InstructionRejected { id: u8, instruction: Instruction },
+}
+
+impl fmt::Display for DynamixelError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DynamixelError::ParamsTooLong { len, max } => write!(
+                f,
+                "Dynamixel packet parameters too long: {len} bytes, at most {max} fit"
+            ),
+            DynamixelError::BufferTooSmall { len, needed } => write!(
+                f,
+                "Dynamixel packet needs {needed} bytes, the buffer has {len}"
+            ),
+            DynamixelError::SyncWriteLengthMismatch { id, len, expected } => write!(
+                f,
+                "SYNC WRITE data for id {id} is {len} bytes, expected {expected} like the others"
+            ),
+            DynamixelError::InstructionRejected { id, instruction } => {
+                write!(f, "Device id {id} rejected {instruction}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for DynamixelError {}
+
+/// Why received bytes are not a valid protocol 1 packet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    /// Fewer bytes than the smallest packet.
+    TooShort {
+        len: usize,
+    },
+    /// Doesn't start with `FF FF`.
+    BadHeader,
+    /// The length field disagrees with the number of bytes.
+    LengthMismatch {
+        length: u8,
+        len: usize,
+    },
+    ChecksumMismatch {
+        expected: u8,
+        actual: u8,
+    },
+}
+
+impl fmt::Display for PacketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PacketError::TooShort { len } => write!(f, "packet too short: {len} bytes"),
+            PacketError::BadHeader => write!(f, "packet does not start with FF FF"),
+            PacketError::LengthMismatch { length, len } => {
+                write!(f, "packet length {length} does not match {len} bytes")
+            }
+            PacketError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "packet checksum mismatch: expected 0x{expected:02X}, got 0x{actual:02X}"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for PacketError {}
+
+/// Header, ID, length, and at least instruction/error and checksum.
+const MIN_PACKET_LEN: usize = 6;
+
+/// Protocol 1 checksum over `body`, the bytes from the ID up to (not
+/// including) the checksum: the inverted low byte of their sum.
+pub fn dyn_checksum(body: &[u8]) -> u8 {
+    let sum: u32 = body.iter().map(|&b| b as u32).sum();
+    (!sum & 0xFF) as u8
+}
+
+/// Whether `packet` is one complete, valid protocol 1 packet. See
+/// [`validate_dyn_packet`] for the reason when it isn't.
+pub fn validate_packet(packet: &[u8]) -> bool {
+    validate_dyn_packet(packet).is_ok()
+}
+
+/// Check the `FF FF` header, the length field and the checksum of one
+/// complete packet. Works for instruction and status packets alike.
+pub fn validate_dyn_packet(bytes: &[u8]) -> Result<(), PacketError> {
+    if bytes.len() < MIN_PACKET_LEN {
+        return Err(PacketError::TooShort { len: bytes.len() });
+    }
+    if bytes[..2] != [0xFF, 0xFF] {
+        return Err(PacketError::BadHeader);
+    }
+    let length = bytes[3];
+    if length < 2 || bytes.len() != 4 + length as usize {
+        return Err(PacketError::LengthMismatch {
+            length,
+            len: bytes.len(),
+        });
+    }
+    let expected = dyn_checksum(&bytes[2..bytes.len() - 1]);
+    let actual = bytes[bytes.len() - 1];
+    if expected != actual {
+        return Err(PacketError::ChecksumMismatch { expected, actual });
+    }
+    Ok(())
+}
+
+/// Incremental packet parser for a byte stream.
+///
+/// [`push`](Self::push) what a read returned, then call
+/// [`next_packet`](Self::next_packet) until it gives `None`. A candidate
+/// that fails validation is dropped and the search resumes right after its
+/// header, so a packet hidden behind a corrupted one is still found.
+#[derive(Debug, Clone, Default)]
+pub struct PacketReader {
+    buf: Vec<u8>,
+    skipped: usize,
+    garbled: usize,
+    last_error: Option<PacketError>,
+}
+
+impl PacketReader {
+    pub fn new() -> Self {
+        PacketReader::default()
+    }
+
+    /// Append received bytes.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Next complete, valid packet, or `None` until more bytes arrive.
+    pub fn next_packet(&mut self) -> Option<Vec<u8>> {
+        self.with_next_packet(<[u8]>::to_vec)
+    }
+
+    /// [`next_packet`](Self::next_packet) without copying the packet out:
+    /// `f` gets it in place and its result is returned.
+    pub fn with_next_packet<R>(&mut self, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+        loop {
+            self.sync();
+            if self.buf.len() < 4 {
+                return None;
+            }
+            let length = self.buf[3] as usize;
+            let end = (4 + length).min(self.buf.len());
+            if length >= 2 && end < 4 + length {
+                return None;
+            }
+            match validate_dyn_packet(&self.buf[..end]) {
+                Ok(()) => {
+                    let result = f(&self.buf[..end]);
+                    self.buf.drain(..end);
+                    return Some(result);
+                }
+                Err(e) => self.reject(e),
+            }
+        }
+    }
+
+    /// End of stream: return the packets still buffered, giving up on a
+    /// candidate whose announced length never arrived.
+    pub fn finish(&mut self) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        loop {
+            if let Some(packet) = self.next_packet() {
+                packets.push(packet);
+            } else if self.buf.len() >= 2 {
+                // `sync` left a header at the front.
+                self.reject(PacketError::TooShort {
+                    len: self.buf.len(),
+                });
+            } else {
+                return packets;
+            }
+        }
+    }
+
+    /// Forget buffered bytes and counts, keeping the buffer's capacity so
+    /// a reader reused across transactions stops allocating.
+    pub fn clear(&mut self) {
+        self.buf.clear();
+        self.skipped = 0;
+        self.garbled = 0;
+        self.last_error = None;
+    }
+
+    /// Bytes dropped so far, as noise or as headers of rejected candidates.
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Candidates rejected so far.
+    pub fn garbled(&self) -> usize {
+        self.garbled
+    }
+
+    /// Why the most recent candidate was rejected.
+    pub fn last_error(&self) -> Option<&PacketError> {
+        self.last_error.as_ref()
+    }
+
+    /// Drop everything before the first `FF FF` header. IDs stop at 0xFE,
+    /// so in a run of 0xFF the header is the last two; a trailing 0xFF may
+    /// start one and is kept.
+    fn sync(&mut self) {
+        let header = match self.buf.windows(2).position(|w| w == [0xFF, 0xFF]) {
+            Some(mut pos) => {
+                while self.buf.get(pos + 2) == Some(&0xFF) {
+                    pos += 1;
+                }
+                pos
+            }
+            None if self.buf.last() == Some(&0xFF) => self.buf.len() - 1,
+            None => self.buf.len(),
+        };
+        self.skipped += header;
+        self.buf.drain(..header);
+    }
+
+    /// Drop the header at the front and resume the search after it.
+    fn reject(&mut self, error: PacketError) {
+        self.garbled += 1;
+        self.skipped += 2;
+        self.last_error = Some(error);
+        self.buf.drain(..2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Status packet from `id` carrying `params`.
+    fn status(id: u8, params: &[u8]) -> Vec<u8> {
+        let mut packet = vec![0xFF, 0xFF, id, params.len() as u8 + 2, 0x00];
+        packet.extend_from_slice(params);
+        let sum: u32 = packet[2..].iter().map(|&b| b as u32).sum();
+        packet.push(!sum as u8);
+        packet
+    }
+
+    /// Deterministic xorshift, so failures reproduce.
+    struct Rng(u32);
+
+    impl Rng {
+        fn next(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 17;
+            self.0 ^= self.0 << 5;
+            self.0
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() as usize % n
+        }
+    }
+
+    #[test]
+    fn validate_reports_each_defect() {
+        assert_eq!(validate_dyn_packet(&status(1, &[])), Ok(()));
+        assert_eq!(
+            validate_dyn_packet(&[0xFF, 0xFF, 0x01]),
+            Err(PacketError::TooShort { len: 3 })
+        );
+        assert_eq!(
+            validate_dyn_packet(&[0xFF, 0x00, 0x01, 0x02, 0x00, 0xFC]),
+            Err(PacketError::BadHeader)
+        );
+        assert_eq!(
+            validate_dyn_packet(&[0xFF, 0xFF, 0x01, 0x03, 0x00, 0xFB]),
+            Err(PacketError::LengthMismatch { length: 3, len: 6 })
+        );
+        assert_eq!(
+            validate_dyn_packet(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFB]),
+            Err(PacketError::ChecksumMismatch {
+                expected: 0xFC,
+                actual: 0xFB
+            })
+        );
+    }
+
+    #[test]
+    fn validate_packet_rejects_flipped_bit() {
+        // Read 2 bytes at 0x2A from id 1, as captured on the bus.
+        let packet = [0xFF, 0xFF, 0x01, 0x04, 0x02, 0x2A, 0x02, 0xCC];
+        assert_eq!(dyn_checksum(&packet[2..7]), 0xCC);
+        assert!(validate_packet(&packet));
+        for i in 2..packet.len() {
+            let mut corrupted = packet;
+            corrupted[i] ^= 0x10;
+            assert!(!validate_packet(&corrupted), "flip in byte {i} accepted");
+        }
+    }
+
+    #[test]
+    fn reader_finds_packet_behind_bogus_length() {
+        // A stray header announcing 0x20 bytes swallows the real reply
+        // until the stream ends.
+        let mut reader = PacketReader::new();
+        reader.push(&[0xFF, 0xFF, 0x01, 0x20]);
+        reader.push(&status(3, &[0x07]));
+        assert_eq!(reader.next_packet(), None);
+        assert_eq!(reader.finish(), [status(3, &[0x07])]);
+        assert_eq!(reader.garbled(), 1);
+    }
+
+    #[test]
+    fn random_bytes_never_panic() {
+        let mut rng = Rng(0x1234_5678);
+        for _ in 0..500 {
+            let mut reader = PacketReader::new();
+            for _ in 0..rng.below(8) {
+                // Bias towards 0xFF so headers and runs show up often.
+                let chunk: Vec<u8> = (0..rng.below(40))
+                    .map(|_| match rng.below(4) {
+                        0 => 0xFF,
+                        _ => rng.next() as u8,
+                    })
+                    .collect();
+                reader.push(&chunk);
+                while let Some(packet) = reader.next_packet() {
+                    assert_eq!(validate_dyn_packet(&packet), Ok(()));
+                }
+            }
+            for packet in reader.finish() {
+                assert_eq!(validate_dyn_packet(&packet), Ok(()));
+            }
+        }
+    }
+
+    #[test]
+    fn recovers_every_packet_from_garbage() {
+        let mut rng = Rng(0xC0FF_EE01);
+        for _ in 0..500 {
+            let mut expected = Vec::new();
+            let mut stream = Vec::new();
+            for _ in 0..1 + rng.below(6) {
+                // Garbage may hold single 0xFF bytes but never a header.
+                for _ in 0..rng.below(12) {
+                    let byte = rng.next() as u8;
+                    if !(byte == 0xFF && stream.last() == Some(&0xFF)) {
+                        stream.push(byte);
+                    }
+                }
+                let params: Vec<u8> = (0..rng.below(6)).map(|_| rng.next() as u8).collect();
+                let packet = status(rng.below(0xFE) as u8, &params);
+                stream.extend(&packet);
+                expected.push(packet);
+            }
+
+            // Deliver in random splits, as reads do.
+            let mut reader = PacketReader::new();
+            let mut found = Vec::new();
+            let mut rest = &stream[..];
+            while !rest.is_empty() {
+                let (chunk, tail) = rest.split_at(1 + rng.below(rest.len().min(16)));
+                reader.push(chunk);
+                found.extend(std::iter::from_fn(|| reader.next_packet()));
+                rest = tail;
+            }
+            found.extend(reader.finish());
+            assert_eq!(found, expected);
+        }
+    }
+}
@@ -0,0 +1,311 @@
+//! Control table of Feetech STS/SCS servos.
+//!
+//! Addresses follow the STS/SMS series memory map. Multi-byte values are
+//! little-endian, low byte at the lower address, as on STS/SMS servos.
+//! SCS servos store them big-endian and need their own decoding.
+
+use std::io;
+
+use super::{read_register, write_register};
+use crate::models::lookup_model;
+use crate::transport::Transport;
+
+/// Firmware major version (EEPROM, read-only).
+pub const FIRMWARE_MAJOR: u8 = 0x00;
+/// Firmware minor version (EEPROM, read-only).
+pub const FIRMWARE_MINOR: u8 = 0x01;
+/// Model number, 2 bytes (EEPROM, read-only).
+pub const MODEL: u8 = 0x03;
+/// Bus ID (EEPROM).
+pub const ID: u8 = 0x05;
+/// Baud rate index (EEPROM): 0 = 1M, 1 = 500k, 2 = 250k, ...
+pub const BAUD_RATE: u8 = 0x06;
+/// Minimum angle limit, 2 bytes (EEPROM).
+pub const MIN_ANGLE_LIMIT: u8 = 0x09;
+/// Maximum angle limit, 2 bytes (EEPROM).
+pub const MAX_ANGLE_LIMIT: u8 = 0x0B;
+/// Maximum temperature limit in °C (EEPROM).
+pub const MAX_TEMPERATURE_LIMIT: u8 = 0x0D;
+/// Torque enable: 0 = off, 1 = on (SRAM).
+pub const TORQUE_ENABLE: u8 = 0x28;
+/// EEPROM write lock: 1 = locked, 0 = writes to EEPROM are kept (SRAM).
+pub const LOCK: u8 = 0x37;
+/// Present position in steps, 2 bytes (SRAM, read-only).
+pub const PRESENT_POSITION: u8 = 0x38;
+/// Present speed in steps/s, 2 bytes, bit 15 = direction (SRAM, read-only).
+pub const PRESENT_SPEED: u8 = 0x3A;
+/// Present load in units of 0.1 %, 2 bytes, bit 10 = direction (SRAM,
+/// read-only).
+pub const PRESENT_LOAD: u8 = 0x3C;
+/// Present voltage in units of 0.1 V (SRAM, read-only).
+pub const PRESENT_VOLTAGE: u8 = 0x3E;
+/// Present temperature in °C (SRAM, read-only).
+pub const PRESENT_TEMPERATURE: u8 = 0x3F;
+
+/// Product name of a model number as read from [`MODEL`], `None` for
+/// models not in [`crate::models`].
+pub fn model_name(model: u16) -> Option<&'static str> {
+    lookup_model(model).map(|info| info.name)
+}
+
+/// `"STS3215 (model 777)"` for known models, `"model 1190"` otherwise.
+pub fn describe_model(model: u16) -> String {
+    match model_name(model) {
+        Some(name) => format!("{name} (model {model})"),
+        None => format!("model {model}"),
+    }
+}
+
+/// Baud rate selected by a [`BAUD_RATE`] index, `None` for unknown
+/// indices.
+pub fn baud_rate_from_index(index: u8) -> Option<u32> {
+    match index {
+        0 => Some(1_000_000),
+        1 => Some(500_000),
+        2 => Some(250_000),
+        3 => Some(128_000),
+        4 => Some(115_200),
+        5 => Some(76_800),
+        6 => Some(57_600),
+        7 => Some(38_400),
+        _ => None,
+    }
+}
+
+pub fn read_u8(port: &mut dyn Transport, id: u8, address: u8) -> io::Result<u8> {
+    Ok(read_register(port, id, address, 1)?[0])
+}
+
+/// Read a little-endian 16-bit value.
+pub fn read_u16(port: &mut dyn Transport, id: u8, address: u8) -> io::Result<u16> {
+    let bytes = read_register(port, id, address, 2)?;
+    Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+}
+
+/// Write a little-endian 16-bit value.
+pub fn write_u16(port: &mut dyn Transport, id: u8, address: u8, value: u16) -> io::Result<()> {
+    write_register(port, id, address, &value.to_le_bytes())
+}
+
+pub fn read_model(port: &mut dyn Transport, id: u8) -> io::Result<u16> {
+    read_u16(port, id, MODEL)
+}
+
+/// Firmware version as `(major, minor)`.
+pub fn read_firmware_version(port: &mut dyn Transport, id: u8) -> io::Result<(u8, u8)> {
+    let bytes = read_register(port, id, FIRMWARE_MAJOR, 2)?;
+    Ok((bytes[0], bytes[1]))
+}
+
+/// Write 1 or 0 to [`TORQUE_ENABLE`] and wait for the acknowledgement.
+pub fn set_torque(port: &mut dyn Transport, id: u8, enable: bool) -> io::Result<()> {
+    set_torque_at(port, id, TORQUE_ENABLE, enable)
+}
+
+/// [`set_torque`] with the torque-enable register at `address`, e.g.
+/// [`torque_enable_address`] of the servo's model.
+pub fn set_torque_at(
+    port: &mut dyn Transport,
+    id: u8,
+    address: u8,
+    enable: bool,
+) -> io::Result<()> {
+    write_register(port, id, address, &[u8::from(enable)])
+}
+
+/// Torque-enable address of `model` from [`crate::models`], the STS
+/// [`TORQUE_ENABLE`] for models not in the table.
+pub fn torque_enable_address(model: u16) -> u8 {
+    lookup_model(model).map_or(TORQUE_ENABLE, |info| info.torque_enable)
+}
+
+pub fn read_lock(port: &mut dyn Transport, id: u8) -> io::Result<bool> {
+    Ok(read_u8(port, id, LOCK)? != 0)
+}
+
+pub fn set_lock(port: &mut dyn Transport, id: u8, locked: bool) -> io::Result<()> {
+    write_register(port, id, LOCK, &[u8::from(locked)])
+}
+
+pub fn read_present_position(port: &mut dyn Transport, id: u8) -> io::Result<u16> {
+    read_u16(port, id, PRESENT_POSITION)
+}
+
+/// Present temperature in °C.
+pub fn read_present_temperature(port: &mut dyn Transport, id: u8) -> io::Result<u8> {
+    read_u8(port, id, PRESENT_TEMPERATURE)
+}
+
+/// Present voltage in units of 0.1 V.
+pub fn read_present_voltage(port: &mut dyn Transport, id: u8) -> io::Result<u8> {
+    read_u8(port, id, PRESENT_VOLTAGE)
+}
+
+/// Run `f` with the EEPROM write lock of `id` released, re-locking
+/// afterwards if it was set, even when `f` fails. Lock access failures are
+/// only warned about, since some firmware states reject register writes;
+/// `f` still runs and reports its own errors.
+pub fn with_eeprom_unlocked<T>(
+    port: &mut dyn Transport,
+    id: u8,
+    f: impl FnOnce(&mut dyn Transport) -> io::Result<T>,
+) -> io::Result<T> {
+    let locked = match read_lock(port, id) {
+        Ok(locked) => locked,
+        Err(e) => {
+            diag!("Warning: could not read EEPROM lock of device id {id}: {e}");
+            false
+        }
+    };
+    if locked && let Err(e) = set_lock(port, id, false) {
+        diag!("Warning: could not unlock EEPROM of device id {id}: {e}");
+    }
+
+    let result = f(port);
+
+    if locked && let Err(e) = set_lock(port, id, true) {
+        diag!("Warning: could not re-lock EEPROM of device id {id}: {e}");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    /// Mock with an idle line for `clear_input`, then `reply`.
+    fn replying(reply: &[u8]) -> MockTransport {
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(reply);
+        mock
+    }
+
+    #[test]
+    fn read_model_decodes_little_endian() {
+        let mut mock = replying(&[0xFF, 0xFF, 0x01, 0x04, 0x00, 0x09, 0x03, 0xEE]);
+        assert_eq!(read_model(&mut mock, 1).unwrap(), 777);
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x01, 0x04, 0x02, 0x03, 0x02, 0xF3]]
+        );
+    }
+
+    #[test]
+    fn read_firmware_version_reads_major_and_minor() {
+        let mut mock = replying(&[0xFF, 0xFF, 0x01, 0x04, 0x00, 0x03, 0x0A, 0xED]);
+        assert_eq!(read_firmware_version(&mut mock, 1).unwrap(), (3, 10));
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x01, 0x04, 0x02, 0x00, 0x02, 0xF6]]
+        );
+    }
+
+    #[test]
+    fn known_models_have_names() {
+        assert_eq!(model_name(777), Some("STS3215"));
+        assert_eq!(describe_model(1284), "SCS0009 (model 1284)");
+        assert_eq!(model_name(1190), None);
+        assert_eq!(describe_model(1190), "model 1190");
+    }
+
+    #[test]
+    fn set_torque_writes_enable_register() {
+        let ack = [0xFF, 0xFF, 0x07, 0x02, 0x00, 0xF6];
+        let mut mock = replying(&ack);
+        set_torque(&mut mock, 7, false).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x07, 0x04, 0x03, 0x28, 0x00, 0xC9]]
+        );
+
+        let mut mock = replying(&ack);
+        set_torque(&mut mock, 7, true).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x07, 0x04, 0x03, 0x28, 0x01, 0xC8]]
+        );
+    }
+
+    #[test]
+    fn set_torque_at_uses_model_address() {
+        assert_eq!(torque_enable_address(777), TORQUE_ENABLE);
+        assert_eq!(torque_enable_address(1190), TORQUE_ENABLE);
+
+        let mut mock = replying(&[0xFF, 0xFF, 0x07, 0x02, 0x00, 0xF6]);
+        set_torque_at(&mut mock, 7, 0x18, true).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x07, 0x04, 0x03, 0x18, 0x01, 0xD8]]
+        );
+    }
+
+    #[test]
+    fn set_lock_writes_lock_register() {
+        let mut mock = replying(&[0xFF, 0xFF, 0x07, 0x02, 0x00, 0xF6]);
+        set_lock(&mut mock, 7, true).unwrap();
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x07, 0x04, 0x03, 0x37, 0x01, 0xB9]]
+        );
+    }
+
+    #[test]
+    fn present_values_read_expected_registers() {
+        // Status packet split across two reads.
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x02])
+            .push_read(&[0x04, 0x00, 0x00, 0x08, 0xF1]);
+        assert_eq!(read_present_position(&mut mock, 2).unwrap(), 2048);
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x02, 0x04, 0x02, 0x38, 0x02, 0xBD]]
+        );
+
+        let mut mock = replying(&[0xFF, 0xFF, 0x02, 0x03, 0x00, 0x28, 0xD2]);
+        assert_eq!(read_present_temperature(&mut mock, 2).unwrap(), 40);
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x02, 0x04, 0x02, 0x3F, 0x01, 0xB7]]
+        );
+
+        let mut mock = replying(&[0xFF, 0xFF, 0x02, 0x03, 0x00, 0x78, 0x82]);
+        assert_eq!(read_present_voltage(&mut mock, 2).unwrap(), 120);
+        assert_eq!(
+            mock.writes(),
+            [[0xFF, 0xFF, 0x02, 0x04, 0x02, 0x3E, 0x01, 0xB8]]
+        );
+    }
+
+    #[test]
+    fn reply_from_wrong_id_is_rejected() {
+        let mut mock = replying(&[0xFF, 0xFF, 0x07, 0x02, 0x00, 0xF6]);
+        let err = set_torque(&mut mock, 3, false).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn eeprom_unlocked_only_while_writing() {
+        let ack = [0xFF, 0xFF, 0x07, 0x02, 0x00, 0xF6];
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x07, 0x03, 0x00, 0x01, 0xF4])
+            .push_timeout()
+            .push_read(&ack)
+            .push_timeout()
+            .push_read(&ack)
+            .push_timeout()
+            .push_read(&ack);
+
+        with_eeprom_unlocked(&mut mock, 7, |port| write_u16(port, 7, MIN_ANGLE_LIMIT, 0)).unwrap();
+
+        let lock_writes: Vec<u8> = mock.writes()[1..]
+            .iter()
+            .filter(|w| w[5] == LOCK)
+            .map(|w| w[6])
+            .collect();
+        assert_eq!(lock_writes, [0, 1]);
+        assert_eq!(mock.writes().len(), 4);
+    }
+}
@@ -0,0 +1,515 @@
+//! Dynamixel protocol 2.0 control plane: ping, reboot, read, sync read
+//! and write.
+//!
+//! Packets are `FF FF FD 00 id len_l len_h instruction params.. crc_l crc_h`.
+//! Inside instruction and params every `FF FF FD` gets an extra `FD`
+//! (byte stuffing) so it can't be mistaken for a header; the length counts
+//! the stuffed bytes plus the CRC. The CRC is CRC-16/BUYPASS over
+//! everything before it. Bootloader framing is the same for both protocols,
+//! so this only covers talking to the application.
+
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::crc::crc16_buypass;
+use crate::dynamixel::{BROADCAST_ID, StatusPacket};
+use crate::transport::{Transport, clear_input};
+
+pub mod packet;
+
+pub use packet::{HEADER, Instruction, STATUS, build_packet, stuff, unstuff};
+
+/// Noise skipped while looking for a status header before giving up.
+const MAX_STATUS_NOISE: usize = 256;
+
+/// Longest status packet [`read_status_packet`] accepts, header and CRC
+/// included. Covers a read of a whole Feetech control table; use
+/// [`read_status_packet_limited`] for bigger responses.
+pub const MAX_RESPONSE_LEN: usize = 1024;
+
+/// Parse exactly one protocol 2.0 status packet, checking the header,
+/// length, CRC and status instruction, and unstuffing the params.
+pub fn parse_status(bytes: &[u8]) -> io::Result<StatusPacket> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+    if bytes.len() < 11 || !bytes.starts_with(&HEADER) {
+        return Err(invalid(format!("Not a status packet: {bytes:02X?}")));
+    }
+    let length = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+    if bytes.len() != 7 + length {
+        return Err(invalid(format!(
+            "Status packet length {length} does not match {} bytes: {bytes:02X?}",
+            bytes.len()
+        )));
+    }
+    let (data, crc) = bytes.split_at(bytes.len() - 2);
+    if crc16_buypass(data).to_le_bytes() != crc {
+        return Err(invalid(format!("Status packet CRC mismatch: {bytes:02X?}")));
+    }
+    let body = unstuff(&data[7..]);
+    if body[0] != STATUS {
+        return Err(invalid(format!("Not a status packet: {bytes:02X?}")));
+    }
+
+    Ok(StatusPacket {
+        id: bytes[4],
+        error: body[1],
+        params: body[2..].to_vec(),
+    })
+}
+
+/// Read one protocol 2.0 status packet, skipping noise before the header.
+/// Each read uses the port timeout.
+pub fn read_status_packet(port: &mut dyn Transport) -> io::Result<StatusPacket> {
+    read_status_packet_limited(port, MAX_RESPONSE_LEN)
+}
+
+/// [`read_status_packet`] accepting packets of up to `max_len` bytes. A
+/// header announcing a longer packet fails with `InvalidData` right away,
+/// without waiting for the rest.
+pub fn read_status_packet_limited(
+    port: &mut dyn Transport,
+    max_len: usize,
+) -> io::Result<StatusPacket> {
+    let mut bytes: Vec<u8> = Vec::new();
+    let mut skipped = 0;
+    let mut buf = [0u8; 64];
+    loop {
+        let header = match bytes.windows(4).position(|w| w == HEADER) {
+            Some(pos) => pos,
+            // Keep a possible partial header at the end.
+            None => bytes.len().saturating_sub(3),
+        };
+        skipped += header;
+        bytes.drain(..header);
+        if skipped > MAX_STATUS_NOISE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("No status packet header in {skipped} bytes"),
+            ));
+        }
+
+        if bytes.starts_with(&HEADER) && bytes.len() >= 7 {
+            let end = 7 + u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+            if end > max_len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "Status packet of {end} bytes exceeds the {max_len}-byte response limit"
+                    ),
+                ));
+            }
+            if bytes.len() >= end {
+                bytes.truncate(end);
+                return parse_status(&bytes);
+            }
+        }
+
+        match port.read(&mut buf)? {
+            0 => {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    "Status packet incomplete",
+                ));
+            }
+            n => bytes.extend_from_slice(&buf[..n]),
+        }
+    }
+}
+
+fn send(
+    port: &mut dyn Transport,
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> io::Result<()> {
+    let packet = build_packet(id, instruction, params)?;
+    clear_input(port)?;
+    port.write_all(&packet)?;
+    port.flush()
+}
+
+/// Read the status packet answering an instruction sent to `id`.
+fn expect_status(port: &mut dyn Transport, id: u8) -> io::Result<StatusPacket> {
+    let status = read_status_packet(port)?;
+    if status.id != id {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Status packet from id {} while talking to id {id}",
+                status.id
+            ),
+        ));
+    }
+    Ok(status)
+}
+
+/// Ping `id`. The status params are the model number (little-endian) and
+/// the firmware version.
+pub fn ping(port: &mut dyn Transport, id: u8) -> io::Result<StatusPacket> {
+    send(port, id, Instruction::Ping, &[])?;
+    expect_status(port, id)
+}
+
+/// Ping the broadcast ID and collect every status packet arriving within
+/// `window`, each responder once, in arrival order. Garbled replies are
+/// skipped with a warning.
+pub fn broadcast_ping(port: &mut dyn Transport, window: Duration) -> io::Result<Vec<StatusPacket>> {
+    let (packets, garbled) = broadcast_ping_counting(port, window)?;
+    if garbled > 0 {
+        diag!("Warning: skipped {garbled} garbled status packet(s) after broadcast ping");
+    }
+    Ok(packets)
+}
+
+/// [`broadcast_ping`] also returning the number of garbled replies.
+pub(crate) fn broadcast_ping_counting(
+    port: &mut dyn Transport,
+    window: Duration,
+) -> io::Result<(Vec<StatusPacket>, usize)> {
+    let previous = port.timeout();
+    send(port, BROADCAST_ID, Instruction::Ping, &[])?;
+
+    let start = Instant::now();
+    let mut bytes = Vec::new();
+    let mut buf = [0u8; 256];
+    while let Some(remaining) = window.checked_sub(start.elapsed()) {
+        port.set_timeout(remaining)?;
+        match port.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => bytes.extend_from_slice(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+            Err(e) => return Err(e),
+        }
+    }
+    port.set_timeout(previous)?;
+
+    let mut packets: Vec<StatusPacket> = Vec::new();
+    let mut garbled = 0;
+    let mut pos = 0;
+    while let Some(offset) = bytes[pos..].windows(4).position(|w| w == HEADER) {
+        let start = pos + offset;
+        let end = match bytes.get(start + 5..start + 7) {
+            Some(len) => start + 7 + u16::from_le_bytes([len[0], len[1]]) as usize,
+            None => bytes.len() + 1,
+        };
+        match bytes.get(start..end).map(parse_status) {
+            Some(Ok(packet)) => {
+                if !packets.iter().any(|p| p.id == packet.id) {
+                    packets.push(packet);
+                }
+                pos = end;
+            }
+            _ => {
+                garbled += 1;
+                pos = start + 4;
+            }
+        }
+    }
+    Ok((packets, garbled))
+}
+
+/// Reboot `id`. With `read_status` the status packet sent before the reset
+/// is awaited; a timeout then yields `None`.
+pub fn reboot(
+    port: &mut dyn Transport,
+    id: u8,
+    read_status: bool,
+) -> io::Result<Option<StatusPacket>> {
+    send(port, id, Instruction::Reboot, &[])?;
+    if !read_status || id == BROADCAST_ID {
+        return Ok(None);
+    }
+    match expect_status(port, id) {
+        Ok(status) => Ok(Some(status)),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read `len` bytes of the control table of `id`, starting at `address`.
+pub fn read(port: &mut dyn Transport, id: u8, address: u16, len: u16) -> io::Result<Vec<u8>> {
+    let [a0, a1] = address.to_le_bytes();
+    let [l0, l1] = len.to_le_bytes();
+    send(port, id, Instruction::Read, &[a0, a1, l0, l1])?;
+
+    let status = expect_status(port, id)?;
+    if status.params.len() != len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "Read of {len} bytes at 0x{address:04X} from id {id} returned {} bytes",
+                status.params.len()
+            ),
+        ));
+    }
+    Ok(status.params)
+}
+
+/// Read `len` bytes at `address` from every servo in `ids` with one
+/// SYNC_READ, e.g. the present position of a whole chain.
+///
+/// The servos answer one after the other, in the order of `ids`, and the
+/// result keeps that order. A servo that doesn't answer within the port
+/// timeout is left out, so the call takes up to one timeout per missing
+/// servo. A status packet from an ID that wasn't asked, or with the wrong
+/// number of bytes, fails with `InvalidData`.
+pub fn sync_read(
+    port: &mut dyn Transport,
+    address: u16,
+    len: u16,
+    ids: &[u8],
+) -> io::Result<Vec<(u8, Vec<u8>)>> {
+    for (i, &id) in ids.iter().enumerate() {
+        if id == BROADCAST_ID || ids[..i].contains(&id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Sync read IDs must be distinct unicast IDs, got {ids:?}"),
+            ));
+        }
+    }
+    if ids.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut params = Vec::with_capacity(4 + ids.len());
+    params.extend(address.to_le_bytes());
+    params.extend(len.to_le_bytes());
+    params.extend_from_slice(ids);
+    send(port, BROADCAST_ID, Instruction::SyncRead, &params)?;
+
+    let mut received: Vec<(u8, Vec<u8>)> = Vec::with_capacity(ids.len());
+    // One read per servo: a timeout is the turn of a missing one.
+    for _ in 0..ids.len() {
+        if received.len() == ids.len() {
+            break;
+        }
+        let status = match read_status_packet(port) {
+            Ok(status) => status,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        if !ids.contains(&status.id) || received.iter().any(|(id, _)| *id == status.id) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Unexpected status packet from id {} in sync read",
+                    status.id
+                ),
+            ));
+        }
+        if status.params.len() != len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Sync read of {len} bytes at 0x{address:04X} from id {} returned {} bytes",
+                    status.id,
+                    status.params.len()
+                ),
+            ));
+        }
+        received.push((status.id, status.params));
+    }
+    received.sort_by_key(|(id, _)| ids.iter().position(|i| i == id));
+    Ok(received)
+}
+
+/// Write `data` to the control table of `id`, starting at `address`, and
+/// wait for the status packet. Writes to [`BROADCAST_ID`] get no answer.
+pub fn write(port: &mut dyn Transport, id: u8, address: u16, data: &[u8]) -> io::Result<()> {
+    let mut params = Vec::with_capacity(2 + data.len());
+    params.extend(address.to_le_bytes());
+    params.extend_from_slice(data);
+    send(port, id, Instruction::Write, &params)?;
+
+    if id != BROADCAST_ID {
+        expect_status(port, id)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    /// Ping status of id 1 from the Dynamixel manual: model 1030, firmware
+    /// 0x26.
+    const PING_STATUS: [u8; 14] = [
+        0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x07, 0x00, 0x55, 0x00, 0x06, 0x04, 0x26, 0x65, 0x5D,
+    ];
+
+    #[test]
+    fn packets_match_manual_examples() {
+        assert_eq!(
+            build_packet(1, Instruction::Ping, &[]).unwrap(),
+            [0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x03, 0x00, 0x01, 0x19, 0x4E]
+        );
+        assert_eq!(
+            build_packet(1, Instruction::Read, &[0x84, 0x00, 0x04, 0x00]).unwrap(),
+            [
+                0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x07, 0x00, 0x02, 0x84, 0x00, 0x04, 0x00, 0x1D, 0x15
+            ]
+        );
+        assert_eq!(
+            build_packet(1, Instruction::Write, &[0x74, 0x00, 0x00, 0x02, 0x00, 0x00]).unwrap(),
+            [
+                0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x09, 0x00, 0x03, 0x74, 0x00, 0x00, 0x02, 0x00, 0x00,
+                0xCA, 0x89
+            ]
+        );
+        assert_eq!(
+            build_packet(1, Instruction::Reboot, &[]).unwrap(),
+            [0xFF, 0xFF, 0xFD, 0x00, 0x01, 0x03, 0x00, 0x08, 0x2F, 0x4E]
+        );
+    }
+
+    #[test]
+    fn header_pattern_in_params_is_stuffed() {
+        assert_eq!(
+            stuff(&[0xFF, 0xFF, 0xFD, 0x01]),
+            [0xFF, 0xFF, 0xFD, 0xFD, 0x01]
+        );
+        assert_eq!(
+            stuff(&[0xFF, 0xFF, 0xFF, 0xFD]),
+            [0xFF, 0xFF, 0xFF, 0xFD, 0xFD]
+        );
+        assert_eq!(
+            unstuff(&[0xFF, 0xFF, 0xFF, 0xFD, 0xFD]),
+            [0xFF, 0xFF, 0xFF, 0xFD]
+        );
+
+        let packet = build_packet(2, Instruction::Write, &[0x10, 0x00, 0xFF, 0xFF, 0xFD]).unwrap();
+        assert_eq!(packet[5..7], [0x09, 0x00]);
+        assert_eq!(packet[7..13], [0x03, 0x10, 0x00, 0xFF, 0xFF, 0xFD]);
+        assert_eq!(packet[13], 0xFD);
+    }
+
+    #[test]
+    fn stuffed_status_params_are_unstuffed() {
+        let mut reply = HEADER.to_vec();
+        reply.extend([0x03, 0x08, 0x00, STATUS, 0x00, 0xFF, 0xFF, 0xFD, 0xFD]);
+        let crc = crc16_buypass(&reply);
+        reply.extend(crc.to_le_bytes());
+
+        let status = parse_status(&reply).unwrap();
+        assert_eq!(status.id, 3);
+        assert_eq!(status.params, [0xFF, 0xFF, 0xFD]);
+    }
+
+    #[test]
+    fn parse_rejects_bad_crc() {
+        let mut reply = PING_STATUS;
+        reply[12] ^= 0x01;
+        let err = parse_status(&reply).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn ping_resyncs_and_reads_across_pieces() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0x00, 0xFF])
+            .push_read(&PING_STATUS[..6])
+            .push_read(&PING_STATUS[6..]);
+        let status = ping(&mut mock, 1).unwrap();
+        assert_eq!(status.params, [0x06, 0x04, 0x26]);
+    }
+
+    #[test]
+    fn oversized_response_is_an_error() {
+        // Announces 2048 bytes; nothing beyond the header is needed to refuse it.
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0xFF, 0xFF, 0xFD, 0x00, 0x01, 0xF9, 0x07, STATUS]);
+        let err = read_status_packet(&mut mock).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(
+            err.to_string(),
+            "Status packet of 2048 bytes exceeds the 1024-byte response limit"
+        );
+
+        let mut mock = MockTransport::new();
+        mock.push_read(&PING_STATUS);
+        assert!(read_status_packet_limited(&mut mock, 13).is_err());
+        let mut mock = MockTransport::new();
+        mock.push_read(&PING_STATUS);
+        assert!(read_status_packet_limited(&mut mock, 14).is_ok());
+    }
+
+    #[test]
+    fn read_and_write_use_16_bit_addresses() {
+        let mut reply = HEADER.to_vec();
+        reply.extend([0x01, 0x06, 0x00, STATUS, 0x00, 0x34, 0x12]);
+        let crc = crc16_buypass(&reply);
+        reply.extend(crc.to_le_bytes());
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&reply);
+        assert_eq!(read(&mut mock, 1, 0x0084, 2).unwrap(), [0x34, 0x12]);
+        assert_eq!(mock.writes()[0][8..12], [0x84, 0x00, 0x02, 0x00]);
+
+        let mut mock = MockTransport::new();
+        write(&mut mock, BROADCAST_ID, 0x0040, &[1]).unwrap();
+        assert_eq!(mock.writes()[0][7..11], [0x03, 0x40, 0x00, 0x01]);
+    }
+
+    fn status(id: u8, params: &[u8]) -> Vec<u8> {
+        let mut reply = HEADER.to_vec();
+        reply.extend([id]);
+        reply.extend((params.len() as u16 + 4).to_le_bytes());
+        reply.extend([STATUS, 0x00]);
+        reply.extend_from_slice(params);
+        let crc = crc16_buypass(&reply);
+        reply.extend(crc.to_le_bytes());
+        reply
+    }
+
+    #[test]
+    fn sync_read_matches_responses_to_ids() {
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&status(1, &[0x00, 0x08]))
+            // id 2 doesn't answer.
+            .push_timeout()
+            .push_read(&status(3, &[0xFF, 0x0F]));
+        let positions = sync_read(&mut mock, 0x0038, 2, &[1, 2, 3]).unwrap();
+        assert_eq!(positions, [(1, vec![0x00, 0x08]), (3, vec![0xFF, 0x0F])]);
+        assert_eq!(
+            mock.writes()[0],
+            build_packet(
+                BROADCAST_ID,
+                Instruction::SyncRead,
+                &[0x38, 0x00, 0x02, 0x00, 1, 2, 3]
+            )
+            .unwrap()
+        );
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&status(4, &[0x00, 0x08]));
+        let err = sync_read(&mut mock, 0x0038, 2, &[1, 2]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&status(1, &[0x00]));
+        let err = sync_read(&mut mock, 0x0038, 2, &[1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let err = sync_read(&mut MockTransport::new(), 0x0038, 2, &[1, 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn broadcast_ping_collects_responders() {
+        let mut other = HEADER.to_vec();
+        other.extend([0x02, 0x07, 0x00, STATUS, 0x00, 0x06, 0x04, 0x26]);
+        let crc = crc16_buypass(&other);
+        other.extend(crc.to_le_bytes());
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&PING_STATUS)
+            .push_read(&[0xFF, 0xFF, 0xFD, 0x00, 0x07])
+            .push_read(&other);
+        let found = broadcast_ping(&mut mock, Duration::from_millis(50)).unwrap();
+        let ids: Vec<u8> = found.iter().map(|p| p.id).collect();
+        assert_eq!(ids, [1, 2]);
+    }
+}
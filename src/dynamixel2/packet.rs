@@ -0,0 +1,104 @@
+//! Building of protocol 2.0 packets.
+//!
+//! Builds on `core` and `alloc` alone, so it is available without the
+//! `std` feature.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::crc::crc16_buypass;
+use crate::dynamixel::packet::DynamixelError;
+
+/// Packet header; the fourth byte is reserved and always zero.
+pub const HEADER: [u8; 4] = [0xFF, 0xFF, 0xFD, 0x00];
+
+/// Instruction byte of status packets.
+pub const STATUS: u8 = 0x55;
+
+/// Most bytes the 16-bit length field can count: stuffed instruction and
+/// params plus the two CRC bytes.
+const MAX_LENGTH: usize = u16::MAX as usize;
+
+/// Dynamixel v2 instruction codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum Instruction {
+    Ping = 0x01,
+    Read = 0x02,
+    Write = 0x03,
+    Reboot = 0x08,
+    SyncRead = 0x82,
+}
+
+impl Instruction {
+    pub fn as_u8(self) -> u8 {
+        self as u8
+    }
+}
+
+/// Insert an `FD` after every `FF FF FD`.
+pub fn stuff(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        out.push(b);
+        if out.ends_with(&[0xFF, 0xFF, 0xFD]) {
+            out.push(0xFD);
+        }
+    }
+    out
+}
+
+/// Undo [`stuff`]: drop the `FD` following every `FF FF FD`.
+pub fn unstuff(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    let mut i = 0;
+    while i < data.len() {
+        if data[i..].starts_with(&[0xFF, 0xFF, 0xFD, 0xFD]) {
+            out.extend([0xFF, 0xFF, 0xFD]);
+            i += 4;
+        } else {
+            out.push(data[i]);
+            i += 1;
+        }
+    }
+    out
+}
+
+/// Build a protocol 2.0 instruction packet. Fails if the stuffed params
+/// don't fit the 16-bit length field.
+pub fn build_packet(
+    id: u8,
+    instruction: Instruction,
+    params: &[u8],
+) -> Result<Vec<u8>, DynamixelError> {
+    let mut body = vec![instruction.as_u8()];
+    body.extend_from_slice(params);
+    let body = stuff(&body);
+    if body.len() + 2 > MAX_LENGTH {
+        return Err(DynamixelError::ParamsTooLong {
+            len: params.len(),
+            max: MAX_LENGTH - 3,
+        });
+    }
+
+    let length = (body.len() + 2) as u16;
+    let mut packet = Vec::with_capacity(7 + body.len() + 2);
+    packet.extend(HEADER);
+    packet.push(id);
+    packet.extend(length.to_le_bytes());
+    packet.extend(body);
+    let crc = crc16_buypass(&packet);
+    packet.extend(crc.to_le_bytes());
+    Ok(packet)
+}
+
+/// Whether `bytes` is exactly one status packet from `id`: header, length,
+/// CRC and status instruction check out.
+pub fn is_status_from(bytes: &[u8], id: u8) -> bool {
+    if bytes.len() < 11 || !bytes.starts_with(&HEADER) || bytes[4] != id {
+        return false;
+    }
+    let length = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+    let (data, crc) = bytes.split_at(bytes.len() - 2);
+    bytes.len() == 7 + length && bytes[7] == STATUS && crc16_buypass(data).to_le_bytes() == crc
+}
@@ -0,0 +1,169 @@
+//! EEPROM backup and restore.
+//!
+//! Flashing firmware can reset calibration such as position offsets and
+//! angle limits on some models. [`EepromBackup`] captures a register range
+//! together with the model number, saves it as JSON and refuses to restore
+//! onto a different model.
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dynamixel::registers::{
+    MIN_ANGLE_LIMIT, TORQUE_ENABLE, read_model, with_eeprom_unlocked,
+};
+use crate::dynamixel::{read_register, write_register};
+use crate::error::FeeflashError;
+use crate::transport::Transport;
+
+/// Calibration part of the EEPROM: angle limits through the operating
+/// mode. ID and baud rate are left out so a restore can't move a servo off
+/// the bus.
+pub const CALIBRATION: Range<u8> = MIN_ANGLE_LIMIT..TORQUE_ENABLE;
+
+/// Bytes per read or write instruction.
+const CHUNK: usize = 16;
+
+/// Read the registers in `range` of `id`, a chunk at a time.
+pub fn backup_eeprom(port: &mut dyn Transport, id: u8, range: Range<u8>) -> io::Result<Vec<u8>> {
+    let mut data = Vec::with_capacity(range.len());
+    let mut address = range.start;
+    while address < range.end {
+        let len = (range.end - address).min(CHUNK as u8);
+        data.extend(read_register(port, id, address, len)?);
+        address += len;
+    }
+    Ok(data)
+}
+
+/// Write `(address, value)` pairs back to `id`, one instruction per run of
+/// consecutive addresses (at most a chunk long). The EEPROM lock is
+/// released for the writes so they persist.
+pub fn restore_eeprom(port: &mut dyn Transport, id: u8, data: &[(u8, u8)]) -> io::Result<()> {
+    let mut runs: Vec<(u8, Vec<u8>)> = Vec::new();
+    for &(address, value) in data {
+        match runs.last_mut() {
+            Some((start, values))
+                if values.len() < CHUNK && *start as usize + values.len() == address as usize =>
+            {
+                values.push(value)
+            }
+            _ => runs.push((address, vec![value])),
+        }
+    }
+
+    with_eeprom_unlocked(port, id, |port| {
+        for (address, values) in &runs {
+            write_register(port, id, *address, values)?;
+        }
+        Ok(())
+    })
+}
+
+/// One saved register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterValue {
+    pub address: u8,
+    pub value: u8,
+}
+
+/// EEPROM contents of one servo, as written to a backup file.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EepromBackup {
+    /// Model number of the servo the backup was taken from.
+    pub model: u16,
+    pub registers: Vec<RegisterValue>,
+}
+
+impl EepromBackup {
+    /// Back up the registers in `range` of `id` along with its model.
+    pub fn read(port: &mut dyn Transport, id: u8, range: Range<u8>) -> io::Result<Self> {
+        let model = read_model(port, id)?;
+        let data = backup_eeprom(port, id, range.clone())?;
+        let registers = range
+            .zip(data)
+            .map(|(address, value)| RegisterValue { address, value })
+            .collect();
+        Ok(EepromBackup { model, registers })
+    }
+
+    /// Restore onto `id`. Fails with `ModelMismatch` before writing
+    /// anything if the device is a different model.
+    pub fn restore(&self, port: &mut dyn Transport, id: u8) -> io::Result<()> {
+        let device = read_model(port, id)?;
+        if device != self.model {
+            return Err(FeeflashError::ModelMismatch {
+                backup: self.model,
+                device,
+            }
+            .into());
+        }
+        let data: Vec<(u8, u8)> = self
+            .registers
+            .iter()
+            .map(|r| (r.address, r.value))
+            .collect();
+        restore_eeprom(port, id, &data)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(io::Error::from)?;
+        fs::write(path, json + "\n")
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        serde_json::from_str(&fs::read_to_string(path)?).map_err(io::Error::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn restore_groups_consecutive_addresses() {
+        let ack = [0xFF, 0xFF, 0x02, 0x02, 0x00, 0xFB];
+        let mut mock = MockTransport::new();
+        // Lock read (unlocked), then one ACK per write.
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x02, 0x03, 0x00, 0x00, 0xFA]);
+        for _ in 0..2 {
+            mock.push_timeout().push_read(&ack);
+        }
+
+        restore_eeprom(&mut mock, 2, &[(0x09, 1), (0x0A, 2), (0x0B, 3), (0x1F, 4)]).unwrap();
+
+        let writes = mock.writes();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[1][3..8], [0x06, 0x03, 0x09, 1, 2]);
+        assert_eq!(writes[1][8], 3);
+        assert_eq!(writes[2][3..7], [0x04, 0x03, 0x1F, 4]);
+    }
+
+    #[test]
+    fn backup_json_round_trips() {
+        let backup = EepromBackup {
+            model: 777,
+            registers: vec![
+                RegisterValue {
+                    address: 0x09,
+                    value: 0,
+                },
+                RegisterValue {
+                    address: 0x0A,
+                    value: 0x10,
+                },
+            ],
+        };
+        let path =
+            std::env::temp_dir().join(format!("feeflash-eeprom-{}.json", std::process::id()));
+        backup.save(&path).unwrap();
+        let loaded = EepromBackup::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), backup);
+    }
+}
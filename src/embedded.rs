@@ -0,0 +1,421 @@
+//! Serial ports of embedded HALs, e.g. a UART driver: [`EmbeddedTransport`]
+//! for `embedded-io` byte streams and [`NbTransport`] for the
+//! word-at-a-time `embedded-hal-nb` serial traits.
+//!
+//! Both are a [`BlockingTransport`], so
+//! [`flasher::flash_blocking`](crate::flasher::flash_blocking) runs a
+//! [`Flasher`](crate::flasher::Flasher) over them on a host without an
+//! OS; this module builds on `core` and `alloc`. With `std` they are a
+//! [`Transport`](crate::transport::Transport) too, for the functions of
+//! `bootloader`.
+//!
+//! Neither trait has read timeouts or a baud rate, so both adapters take
+//! them from the user: reads poll until data arrives or a timer callback
+//! says the timeout has passed, and baud changes go to a callback that
+//! reconfigures the UART.
+
+use alloc::boxed::Box;
+use core::fmt;
+use core::time::Duration;
+
+use embedded_hal_nb::nb;
+use embedded_hal_nb::serial;
+use embedded_io::{Read, ReadReady, Write};
+
+use crate::flasher::BlockingTransport;
+
+type SetBaud<T, E> = Box<dyn FnMut(&mut T, u32) -> Result<(), E> + Send>;
+
+/// Failure of an adapter: the UART's, or a baud rate change without a
+/// callback.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SerialError<E> {
+    Uart(E),
+    /// The baud rate must change, but no callback was given with
+    /// `with_baud_rate`.
+    NoBaudRate,
+}
+
+impl<E: fmt::Debug> fmt::Display for SerialError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SerialError::Uart(e) => write!(f, "UART error: {e:?}"),
+            SerialError::NoBaudRate => write!(f, "No baud rate callback; see with_baud_rate"),
+        }
+    }
+}
+
+impl<E: fmt::Debug> core::error::Error for SerialError<E> {}
+
+/// Wait until `now` says `duration` has passed.
+fn spin_for(now: &mut impl FnMut() -> Duration, duration: Duration) {
+    let start = now();
+    while now().saturating_sub(start) < duration {
+        core::hint::spin_loop();
+    }
+}
+
+/// Adapter from an `embedded_io` stream `T`.
+///
+/// `now` returns the time elapsed since any fixed point, e.g. a hardware
+/// timer's microsecond counter; only differences are used.
+pub struct EmbeddedTransport<T: embedded_io::ErrorType, N> {
+    io: T,
+    now: N,
+    timeout: Duration,
+    set_baud: Option<SetBaud<T, T::Error>>,
+}
+
+impl<T, N> EmbeddedTransport<T, N>
+where
+    T: Read + Write + ReadReady,
+    N: FnMut() -> Duration,
+{
+    /// Wrap `io` with a read timeout of one second, timed by `now`.
+    /// Baud rate changes fail until
+    /// [`with_baud_rate`](Self::with_baud_rate) is set.
+    pub fn new(io: T, now: N) -> Self {
+        EmbeddedTransport {
+            io,
+            now,
+            timeout: Duration::from_secs(1),
+            set_baud: None,
+        }
+    }
+
+    /// Reconfigure the UART with `set_baud` when the flashing flow switches
+    /// baud rates.
+    pub fn with_baud_rate(
+        mut self,
+        set_baud: impl FnMut(&mut T, u32) -> Result<(), T::Error> + Send + 'static,
+    ) -> Self {
+        self.set_baud = Some(Box::new(set_baud));
+        self
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.io
+    }
+
+    pub fn into_inner(self) -> T {
+        self.io
+    }
+
+    /// Read what arrived into `buf`, polling for at most `timeout`;
+    /// `None` when nothing did.
+    fn read_within(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, T::Error> {
+        let start = (self.now)();
+        loop {
+            if self.io.read_ready()? {
+                return self.io.read(buf).map(Some);
+            }
+            if (self.now)().saturating_sub(start) >= timeout {
+                return Ok(None);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    fn change_baud_rate(&mut self, baud: u32) -> Result<(), SerialError<T::Error>> {
+        match &mut self.set_baud {
+            Some(set_baud) => set_baud(&mut self.io, baud).map_err(SerialError::Uart),
+            None => Err(SerialError::NoBaudRate),
+        }
+    }
+}
+
+impl<T: embedded_io::ErrorType, N> fmt::Debug for EmbeddedTransport<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EmbeddedTransport")
+            .field("timeout", &self.timeout)
+            .field("set_baud", &self.set_baud.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, N> BlockingTransport for EmbeddedTransport<T, N>
+where
+    T: Read + Write + ReadReady,
+    N: FnMut() -> Duration,
+{
+    type Error = SerialError<T::Error>;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.io.write_all(data).map_err(SerialError::Uart)?;
+        self.io.flush().map_err(SerialError::Uart)
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Self::Error> {
+        let read = self.read_within(buf, timeout).map_err(SerialError::Uart)?;
+        Ok(read.unwrap_or(0))
+    }
+
+    fn clear_input(&mut self) -> Result<(), Self::Error> {
+        let mut buf = [0u8; 64];
+        while self.io.read_ready().map_err(SerialError::Uart)? {
+            self.io.read(&mut buf).map_err(SerialError::Uart)?;
+        }
+        Ok(())
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<(), Self::Error> {
+        self.change_baud_rate(baud)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        spin_for(&mut self.now, duration);
+    }
+}
+
+/// Adapter from an `embedded_hal_nb` serial port `T`.
+///
+/// Like [`EmbeddedTransport`], with `now` timing the reads. Writes spin on
+/// `WouldBlock` until the UART takes each byte.
+pub struct NbTransport<T: serial::ErrorType, N> {
+    serial: T,
+    now: N,
+    timeout: Duration,
+    set_baud: Option<SetBaud<T, T::Error>>,
+}
+
+impl<T, N> NbTransport<T, N>
+where
+    T: serial::Read + serial::Write,
+    N: FnMut() -> Duration,
+{
+    /// Wrap `serial` with a read timeout of one second, timed by `now`.
+    /// Baud rate changes fail until
+    /// [`with_baud_rate`](Self::with_baud_rate) is set.
+    pub fn new(serial: T, now: N) -> Self {
+        NbTransport {
+            serial,
+            now,
+            timeout: Duration::from_secs(1),
+            set_baud: None,
+        }
+    }
+
+    /// Reconfigure the UART with `set_baud` when the flashing flow switches
+    /// baud rates.
+    pub fn with_baud_rate(
+        mut self,
+        set_baud: impl FnMut(&mut T, u32) -> Result<(), T::Error> + Send + 'static,
+    ) -> Self {
+        self.set_baud = Some(Box::new(set_baud));
+        self
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.serial
+    }
+
+    pub fn into_inner(self) -> T {
+        self.serial
+    }
+
+    fn write_bytes(&mut self, data: &[u8]) -> Result<(), T::Error> {
+        for &byte in data {
+            nb::block!(self.serial.write(byte))?;
+        }
+        nb::block!(self.serial.flush())
+    }
+
+    /// Read what arrived into `buf`, polling for at most `timeout` for the
+    /// first byte; `None` when nothing did.
+    fn read_within(
+        &mut self,
+        buf: &mut [u8],
+        timeout: Duration,
+    ) -> Result<Option<usize>, T::Error> {
+        if buf.is_empty() {
+            return Ok(Some(0));
+        }
+        let start = (self.now)();
+        let mut n = 0;
+        while n < buf.len() {
+            match self.serial.read() {
+                Ok(byte) => {
+                    buf[n] = byte;
+                    n += 1;
+                }
+                // Return what has arrived rather than wait for a full buffer.
+                Err(nb::Error::WouldBlock) if n > 0 => break,
+                Err(nb::Error::WouldBlock) => {
+                    if (self.now)().saturating_sub(start) >= timeout {
+                        return Ok(None);
+                    }
+                    core::hint::spin_loop();
+                }
+                Err(nb::Error::Other(e)) => return Err(e),
+            }
+        }
+        Ok(Some(n))
+    }
+
+    fn change_baud_rate(&mut self, baud: u32) -> Result<(), SerialError<T::Error>> {
+        match &mut self.set_baud {
+            Some(set_baud) => set_baud(&mut self.serial, baud).map_err(SerialError::Uart),
+            None => Err(SerialError::NoBaudRate),
+        }
+    }
+}
+
+impl<T: serial::ErrorType, N> fmt::Debug for NbTransport<T, N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NbTransport")
+            .field("timeout", &self.timeout)
+            .field("set_baud", &self.set_baud.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<T, N> BlockingTransport for NbTransport<T, N>
+where
+    T: serial::Read + serial::Write,
+    N: FnMut() -> Duration,
+{
+    type Error = SerialError<T::Error>;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error> {
+        self.write_bytes(data).map_err(SerialError::Uart)
+    }
+
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Self::Error> {
+        let read = self.read_within(buf, timeout).map_err(SerialError::Uart)?;
+        Ok(read.unwrap_or(0))
+    }
+
+    fn clear_input(&mut self) -> Result<(), Self::Error> {
+        loop {
+            match self.serial.read() {
+                Ok(_) => {}
+                Err(nb::Error::WouldBlock) => return Ok(()),
+                Err(nb::Error::Other(e)) => return Err(SerialError::Uart(e)),
+            }
+        }
+    }
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<(), Self::Error> {
+        self.change_baud_rate(baud)
+    }
+
+    fn sleep(&mut self, duration: Duration) {
+        spin_for(&mut self.now, duration);
+    }
+}
+
+/// [`Transport`](crate::transport::Transport) for both adapters, with the
+/// read timeout set through it.
+#[cfg(feature = "std")]
+mod transport {
+    use std::io;
+    use std::time::Duration;
+
+    use embedded_hal_nb::{nb, serial};
+    use embedded_io::{Read, ReadReady, Write};
+
+    use super::{EmbeddedTransport, NbTransport, SerialError};
+    use crate::transport::Transport;
+
+    fn to_io(err: impl embedded_io::Error) -> io::Error {
+        io::Error::new(err.kind().into(), format!("{err:?}"))
+    }
+
+    fn nb_to_io(err: impl serial::Error) -> io::Error {
+        let kind = match err.kind() {
+            serial::ErrorKind::Other => io::ErrorKind::Other,
+            // Overrun, framing, parity and noise: the bytes on the line are bad.
+            _ => io::ErrorKind::InvalidData,
+        };
+        io::Error::new(kind, format!("{err:?}"))
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "Read timed out")
+    }
+
+    fn baud_error<E>(err: SerialError<E>, to_io: impl FnOnce(E) -> io::Error) -> io::Error {
+        match err {
+            SerialError::Uart(e) => to_io(e),
+            SerialError::NoBaudRate => io::Error::new(
+                io::ErrorKind::Unsupported,
+                "No baud rate callback; see with_baud_rate",
+            ),
+        }
+    }
+
+    impl<T, N> Transport for EmbeddedTransport<T, N>
+    where
+        T: Read + Write + ReadReady,
+        N: FnMut() -> Duration,
+    {
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            self.io.write_all(buf).map_err(to_io)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.io.flush().map_err(to_io)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read_within(buf, self.timeout)
+                .map_err(to_io)?
+                .ok_or_else(timed_out)
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            self.change_baud_rate(baud_rate)
+                .map_err(|e| baud_error(e, to_io))
+        }
+    }
+
+    impl<T, N> Transport for NbTransport<T, N>
+    where
+        T: serial::Read + serial::Write,
+        N: FnMut() -> Duration,
+    {
+        fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+            for &byte in buf {
+                nb::block!(self.serial.write(byte)).map_err(nb_to_io)?;
+            }
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            nb::block!(self.serial.flush()).map_err(nb_to_io)
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read_within(buf, self.timeout)
+                .map_err(nb_to_io)?
+                .ok_or_else(timed_out)
+        }
+
+        fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+            self.timeout = timeout;
+            Ok(())
+        }
+
+        fn timeout(&self) -> Duration {
+            self.timeout
+        }
+
+        fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+            self.change_baud_rate(baud_rate)
+                .map_err(|e| baud_error(e, nb_to_io))
+        }
+    }
+}
@@ -0,0 +1,496 @@
+//! In-process emulation of a Feetech servo and its bootloader.
+//!
+//! [`BootloaderEmulator`] implements [`Transport`] and reacts to writes the
+//! way the device does, so the full ping / reboot / handshake / transfer
+//! sequence can be exercised without hardware. Enabled by the `testing`
+//! feature.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use crate::bootloader::{BOOTLOADER_BAUD, BOOTLOADER_INIT, BOOTLOADER_MAGIC};
+use crate::frame::{BootloaderFrame, ChecksumKind};
+use crate::transport::Transport;
+
+/// How long the bootloader waits for the magic after a reboot before
+/// starting the application again.
+const DEFAULT_BOOT_WINDOW: Duration = Duration::from_millis(800);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Running the application firmware, answering Dynamixel packets.
+    Application,
+    /// Bootloader waiting for the magic sequence.
+    AwaitMagic,
+    /// Magic ACKed, waiting for the init byte.
+    AwaitInit,
+    /// Receiving firmware frames; holds the next expected index.
+    Transfer(u8),
+}
+
+#[derive(Debug)]
+pub struct BootloaderEmulator {
+    ids: Vec<u8>,
+    app_baud: u32,
+    baud: u32,
+    state: State,
+    input: Vec<u8>,
+    output: VecDeque<u8>,
+    image: Vec<u8>,
+    frames_received: usize,
+    frame_times: Vec<Instant>,
+    naks_sent: usize,
+    flashed: bool,
+    timeout: Duration,
+    ready_at: Option<Instant>,
+    nak_frames: HashMap<u8, u32>,
+    drop_acks: HashSet<u8>,
+    magic_responses: VecDeque<u8>,
+    response_delay: Duration,
+    reject_reboot: bool,
+    reboot_status: bool,
+    echo: bool,
+    registers: HashMap<u8, Vec<u8>>,
+    rebooted_at: Option<Instant>,
+    boot_window: Duration,
+    checksum: ChecksumKind,
+    bootloader_baud: u32,
+    /// REG_WRITEs waiting for ACTION: `(id, address, data)`.
+    staged: Vec<(u8, usize, Vec<u8>)>,
+    duplicate_ids: HashSet<u8>,
+    /// Intermittent IDs and the number of unicast pings each got so far.
+    intermittent: HashMap<u8, u32>,
+}
+
+impl BootloaderEmulator {
+    /// Emulate a servo running its application at `app_baud`, answering
+    /// pings and reboots for each of `ids`.
+    pub fn new(ids: &[u8], app_baud: u32) -> Self {
+        BootloaderEmulator {
+            ids: ids.to_vec(),
+            app_baud,
+            baud: app_baud,
+            state: State::Application,
+            input: Vec::new(),
+            output: VecDeque::new(),
+            image: Vec::new(),
+            frames_received: 0,
+            frame_times: Vec::new(),
+            naks_sent: 0,
+            flashed: false,
+            timeout: Duration::from_secs(10),
+            ready_at: None,
+            nak_frames: HashMap::new(),
+            drop_acks: HashSet::new(),
+            magic_responses: VecDeque::new(),
+            response_delay: Duration::ZERO,
+            reject_reboot: false,
+            reboot_status: false,
+            echo: false,
+            registers: HashMap::new(),
+            rebooted_at: None,
+            boot_window: DEFAULT_BOOT_WINDOW,
+            checksum: ChecksumKind::Crc16Ccitt,
+            bootloader_baud: BOOTLOADER_BAUD,
+            staged: Vec::new(),
+            duplicate_ids: HashSet::new(),
+            intermittent: HashMap::new(),
+        }
+    }
+
+    /// Emulate a device that is already sitting in its bootloader, as after
+    /// a power cycle in recovery mode.
+    pub fn in_bootloader() -> Self {
+        let mut emulator = BootloaderEmulator::new(&[], BOOTLOADER_BAUD);
+        emulator.state = State::AwaitMagic;
+        emulator
+    }
+
+    /// NAK the frame with `index` the first `times` it is received.
+    pub fn nak_frame(mut self, index: u8, times: u32) -> Self {
+        self.nak_frames.insert(index, times);
+        self
+    }
+
+    /// Accept the frame with `index` but never send its ACK.
+    pub fn drop_ack(mut self, index: u8) -> Self {
+        self.drop_acks.insert(index);
+        self
+    }
+
+    /// Answer the next `times` magic sequences with `byte` instead of the
+    /// 0x06 ACK, staying in the bootloader's magic-wait state.
+    pub fn magic_response(mut self, byte: u8, times: u32) -> Self {
+        self.magic_responses
+            .extend(std::iter::repeat_n(byte, times as usize));
+        self
+    }
+
+    /// Make every response readable only `delay` after the write that
+    /// triggered it. Reads with a shorter timeout time out and leave the
+    /// late bytes in the buffer, as a real line would.
+    pub fn response_delay(mut self, delay: Duration) -> Self {
+        self.response_delay = delay;
+        self
+    }
+
+    /// Ignore the reboot instruction and keep running the application.
+    pub fn reject_reboot(mut self) -> Self {
+        self.reject_reboot = true;
+        self
+    }
+
+    /// Answer the reboot instruction with a status packet before resetting.
+    pub fn reboot_status(mut self) -> Self {
+        self.reboot_status = true;
+        self
+    }
+
+    /// Preset control table bytes of servo `id`, starting at `address`.
+    /// Unset registers read as 0.
+    pub fn register(mut self, id: u8, address: u8, bytes: &[u8]) -> Self {
+        let start = address as usize;
+        self.table(id)[start..start + bytes.len()].copy_from_slice(bytes);
+        self
+    }
+
+    /// Control table of servo `id` as changed by write instructions.
+    pub fn registers(&self, id: u8) -> &[u8] {
+        self.registers.get(&id).map_or(&[], Vec::as_slice)
+    }
+
+    fn table(&mut self, id: u8) -> &mut Vec<u8> {
+        self.registers.entry(id).or_insert_with(|| vec![0; 256])
+    }
+
+    fn write_table(&mut self, id: u8, address: usize, data: &[u8]) {
+        let end = (address + data.len()).min(256);
+        self.table(id)[address..end].copy_from_slice(&data[..end - address]);
+    }
+
+    /// Pretend a second servo shares `id`: both answer every instruction at
+    /// once, so their status packets arrive byte-interleaved.
+    pub fn duplicate_id(mut self, id: u8) -> Self {
+        self.duplicate_ids.insert(id);
+        self
+    }
+
+    /// Answer only every other unicast ping to `id`, starting with the
+    /// first, like a servo with a loose connector.
+    pub fn intermittent_id(mut self, id: u8) -> Self {
+        self.intermittent.insert(id, 0);
+        self
+    }
+
+    /// Echo every written byte back before any response, like a single-wire
+    /// TTL adapter.
+    pub fn echo(mut self) -> Self {
+        self.echo = true;
+        self
+    }
+
+    /// Time after a reboot during which the bootloader accepts the magic
+    /// (default 800ms). Once it passes, the application answers pings again.
+    /// Does not apply to [`BootloaderEmulator::in_bootloader`].
+    pub fn boot_window(mut self, window: Duration) -> Self {
+        self.boot_window = window;
+        self
+    }
+
+    /// Expect frames with this checksum (default CRC-16).
+    pub fn checksum(mut self, checksum: ChecksumKind) -> Self {
+        self.checksum = checksum;
+        self
+    }
+
+    /// Baud rate the bootloader listens on (default 500000).
+    pub fn bootloader_baud(mut self, baud: u32) -> Self {
+        self.bootloader_baud = baud;
+        self
+    }
+
+    /// Firmware data reassembled from accepted frames, including the 0xFF
+    /// padding of the last frame.
+    pub fn image(&self) -> &[u8] {
+        &self.image
+    }
+
+    /// Number of frames received, including rejected ones and resends.
+    pub fn frames_received(&self) -> usize {
+        self.frames_received
+    }
+
+    /// Arrival time of every frame received, in order.
+    pub fn frame_times(&self) -> &[Instant] {
+        &self.frame_times
+    }
+
+    pub fn naks_sent(&self) -> usize {
+        self.naks_sent
+    }
+
+    /// Whether the final frame (stop byte 4) was accepted.
+    pub fn is_done(&self) -> bool {
+        self.flashed
+    }
+
+    fn process(&mut self) {
+        if self.state == State::AwaitMagic
+            && let Some(at) = self.rebooted_at
+            && at.elapsed() >= self.boot_window
+        {
+            // No magic in time: the bootloader starts the application.
+            self.state = State::Application;
+            self.rebooted_at = None;
+        }
+        loop {
+            let consumed = match self.state {
+                State::Application => self.process_dynamixel(),
+                State::AwaitMagic => self.process_magic(),
+                State::AwaitInit => self.process_init(),
+                State::Transfer(index) => self.process_frame(index),
+            };
+            if consumed == 0 {
+                return;
+            }
+            self.input.drain(..consumed);
+        }
+    }
+
+    fn process_dynamixel(&mut self) -> usize {
+        // Resync on the FF FF header.
+        let Some(start) = self.input.windows(2).position(|w| w == [0xFF, 0xFF]) else {
+            return self.input.len().saturating_sub(1);
+        };
+        if self.input.len() < start + 4 {
+            return start;
+        }
+        let len = self.input[start + 3] as usize;
+        let end = start + 4 + len;
+        if self.input.len() < end {
+            return start;
+        }
+        if self.baud != self.app_baud {
+            return end;
+        }
+
+        let id = self.input[start + 2];
+        let instruction = self.input[start + 4];
+        let sum: u32 = self.input[start + 2..end - 1]
+            .iter()
+            .map(|&b| b as u32)
+            .sum();
+        let valid = (!sum & 0xFF) as u8 == self.input[end - 1];
+
+        if valid && id == 0xFE && instruction == 0x01 {
+            // Broadcast ping: every servo answers, in ID order.
+            let mut ids = self.ids.clone();
+            ids.sort_unstable();
+            for id in ids {
+                self.push_status(id);
+            }
+        } else if valid && id == 0xFE && instruction == 0x05 {
+            // Action: apply every staged write, silently.
+            for (id, address, data) in std::mem::take(&mut self.staged) {
+                self.write_table(id, address, &data);
+            }
+        } else if valid && self.ids.contains(&id) {
+            let params = self.input[start + 5..end - 1].to_vec();
+            match instruction {
+                0x01 => {
+                    let answer = match self.intermittent.get_mut(&id) {
+                        Some(pings) => {
+                            *pings += 1;
+                            *pings % 2 == 1
+                        }
+                        None => true,
+                    };
+                    if answer {
+                        self.push_status(id);
+                    }
+                }
+                0x02 if params.len() == 2 => {
+                    let (address, len) = (params[0] as usize, params[1] as usize);
+                    let data = self.table(id)[address..(address + len).min(256)].to_vec();
+                    self.push_status_params(id, &data);
+                }
+                0x03 if !params.is_empty() => {
+                    self.write_table(id, params[0] as usize, &params[1..]);
+                    self.push_status(id);
+                }
+                0x04 if !params.is_empty() => {
+                    self.staged
+                        .push((id, params[0] as usize, params[1..].to_vec()));
+                    self.push_status(id);
+                }
+                0x08 if !self.reject_reboot => {
+                    if self.reboot_status {
+                        self.push_status(id);
+                    }
+                    self.state = State::AwaitMagic;
+                    self.rebooted_at = Some(Instant::now());
+                }
+                _ => {}
+            }
+        }
+        end
+    }
+
+    fn push_status(&mut self, id: u8) {
+        self.push_status_params(id, &[]);
+    }
+
+    fn push_status_params(&mut self, id: u8, params: &[u8]) {
+        let length = params.len() as u8 + 2; // error + checksum
+        let sum = params
+            .iter()
+            .fold(id.wrapping_add(length), |acc, &b| acc.wrapping_add(b));
+        let mut packet = vec![0xFF, 0xFF, id, length, 0x00];
+        packet.extend(params);
+        packet.push(!sum);
+        if self.duplicate_ids.contains(&id) {
+            self.output.extend(packet.iter().flat_map(|&b| [b, b]));
+        } else {
+            self.output.extend(packet);
+        }
+    }
+
+    fn process_magic(&mut self) -> usize {
+        if self.baud != self.bootloader_baud {
+            return self.input.len();
+        }
+        match self
+            .input
+            .windows(BOOTLOADER_MAGIC.len())
+            .position(|w| w == BOOTLOADER_MAGIC)
+        {
+            Some(pos) => {
+                match self.magic_responses.pop_front() {
+                    Some(byte) => self.output.push_back(byte),
+                    None => {
+                        self.output.push_back(0x06);
+                        self.state = State::AwaitInit;
+                    }
+                }
+                pos + BOOTLOADER_MAGIC.len()
+            }
+            None => self.input.len().saturating_sub(BOOTLOADER_MAGIC.len() - 1),
+        }
+    }
+
+    fn process_init(&mut self) -> usize {
+        match self.input.first() {
+            Some(&BOOTLOADER_INIT) => {
+                self.output.push_back(0x06);
+                self.state = State::Transfer(1);
+                1
+            }
+            // Recovery mode keeps spamming magic until it sees the ACK.
+            Some(_) => 1,
+            None => 0,
+        }
+    }
+
+    fn process_frame(&mut self, expected: u8) -> usize {
+        let len = self.checksum.frame_len();
+        if self.input.len() < len {
+            return 0;
+        }
+        let frame = &self.input[..len];
+        self.frames_received += 1;
+        self.frame_times.push(Instant::now());
+
+        let parsed = BootloaderFrame::from_bytes(frame, self.checksum)
+            .ok()
+            .filter(|frame| frame.index == expected);
+        let forced_nak = match self.nak_frames.get_mut(&expected) {
+            Some(remaining) if *remaining > 0 => {
+                *remaining -= 1;
+                true
+            }
+            _ => false,
+        };
+
+        let frame = match parsed {
+            Some(frame) if !forced_nak => frame,
+            _ => {
+                self.naks_sent += 1;
+                self.output.push_back(0x15);
+                return len;
+            }
+        };
+
+        self.image.extend_from_slice(&frame.data);
+        // The last frame makes the bootloader jump to the application.
+        if frame.is_last {
+            self.flashed = true;
+            self.state = State::Application;
+        } else {
+            self.state = State::Transfer(expected.wrapping_add(1));
+        }
+        if !self.drop_acks.contains(&expected) {
+            self.output.push_back(0x06);
+        }
+        len
+    }
+}
+
+impl Transport for BootloaderEmulator {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        if self.echo {
+            self.output.extend(buf);
+        }
+        let queued = self.output.len();
+        self.input.extend_from_slice(buf);
+        self.process();
+        if self.output.len() > queued && !self.response_delay.is_zero() {
+            self.ready_at = Some(Instant::now() + self.response_delay);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.output.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Emulator has no response",
+            ));
+        }
+        if let Some(ready_at) = self.ready_at {
+            let wait = ready_at.saturating_duration_since(Instant::now());
+            if wait > self.timeout {
+                std::thread::sleep(self.timeout);
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "Emulator response delayed past timeout",
+                ));
+            }
+            std::thread::sleep(wait);
+            self.ready_at = None;
+        }
+        let n = self.output.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.output.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.baud = baud_rate;
+        Ok(())
+    }
+}
@@ -0,0 +1,432 @@
+//! Error types surfaced by the flashing workflow.
+//!
+//! The public API keeps returning `io::Result`; protocol-level failures are
+//! wrapped into an `io::Error` and can be recovered with
+//! [`FeeflashError::from_io`].
+
+use std::fmt;
+use std::io;
+
+pub use crate::dynamixel::packet::{DynamixelError, PacketError};
+use crate::firmware::FirmwareVersion;
+use crate::flasher::FlasherError;
+pub use crate::frame::FrameError;
+
+/// Phase of the flashing workflow an error occurred in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    Recovery,
+    Scan,
+    Handshake,
+    Transfer,
+    Verify,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Phase::Recovery => "recovery",
+            Phase::Scan => "scan",
+            Phase::Handshake => "handshake",
+            Phase::Transfer => "transfer",
+            Phase::Verify => "verify",
+        };
+        f.write_str(name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FeeflashError {
+    /// The overall operation deadline (`--max-duration`) elapsed.
+    DeadlineExceeded { phase: Phase },
+    /// A frame kept being NAKed until its per-frame retries ran out.
+    /// `attempts` counts every send of it, the first and each resend.
+    FrameNakExhausted { index: u8, attempts: u16 },
+    /// The last frame, whose stop byte ends the transfer, kept being NAKed
+    /// after every frame before it was ACKed: the bootloader refused to
+    /// complete programming, e.g. for a corrupt last page. `attempts`
+    /// counts every send of it, `chunk` of `chunks` is its place in the
+    /// transfer.
+    FinalizeRejected {
+        index: u8,
+        attempts: u16,
+        chunk: usize,
+        chunks: usize,
+    },
+    /// The transfer-wide retry budget (`--max-total-retries`) ran out.
+    /// `naks` lists `(chunk number, NAK count)` for every NAKed frame.
+    RetryBudgetExceeded {
+        budget: u32,
+        naks: Vec<(usize, u32)>,
+    },
+    /// The device kept answering pings after the reboot instruction.
+    RebootRejected { id: u8 },
+    /// The device went offline after the reboot instruction but never
+    /// answered a ping again.
+    NotBackAfterReboot { id: u8 },
+    /// The firmware transfer completed but the device never answered at
+    /// the application baud afterwards.
+    BootNotConfirmed { id: u8 },
+    /// An EEPROM backup taken from one model was restored onto another.
+    ModelMismatch { backup: u16, device: u16 },
+    /// The device model is not one the firmware was built for.
+    IncompatibleModel { device: u16, expected: Vec<u16> },
+    /// After flashing, the device reports another firmware version than
+    /// expected.
+    VersionMismatch {
+        expected: FirmwareVersion,
+        actual: FirmwareVersion,
+    },
+    /// The firmware image has no bytes.
+    EmptyFirmware,
+    /// The firmware's SHA-256 differs from the published one.
+    DigestMismatch { expected: String, actual: String },
+    /// The firmware image exceeds `FlashOptions::max_firmware_size`. `len`
+    /// is `None` when reading stopped at the limit, so the full size is
+    /// unknown.
+    FirmwareTooLarge { len: Option<usize>, max: usize },
+    /// The region to flash (`--offset`, `--length`) doesn't lie within the
+    /// `len`-byte image.
+    RegionOutOfRange {
+        offset: usize,
+        length: Option<usize>,
+        len: usize,
+    },
+}
+
+impl FeeflashError {
+    /// Extract a `FeeflashError` wrapped inside an `io::Error`, if any.
+    pub fn from_io(err: &io::Error) -> Option<&FeeflashError> {
+        err.get_ref()
+            .and_then(|e| e.downcast_ref::<FeeflashError>())
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            FeeflashError::DeadlineExceeded { .. }
+            | FeeflashError::NotBackAfterReboot { .. }
+            | FeeflashError::BootNotConfirmed { .. } => io::ErrorKind::TimedOut,
+            FeeflashError::DigestMismatch { .. } => io::ErrorKind::InvalidData,
+            FeeflashError::FrameNakExhausted { .. }
+            | FeeflashError::FinalizeRejected { .. }
+            | FeeflashError::RetryBudgetExceeded { .. }
+            | FeeflashError::RebootRejected { .. }
+            | FeeflashError::VersionMismatch { .. } => io::ErrorKind::Other,
+            FeeflashError::ModelMismatch { .. }
+            | FeeflashError::IncompatibleModel { .. }
+            | FeeflashError::EmptyFirmware
+            | FeeflashError::FirmwareTooLarge { .. }
+            | FeeflashError::RegionOutOfRange { .. } => io::ErrorKind::InvalidInput,
+        }
+    }
+}
+
+impl fmt::Display for FeeflashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FeeflashError::DeadlineExceeded { phase } => {
+                write!(f, "Deadline exceeded during {phase} phase")
+            }
+            FeeflashError::FrameNakExhausted { index, attempts } => {
+                write!(
+                    f,
+                    "Bootloader NAK for frame index {index} on all {attempts} sends"
+                )
+            }
+            FeeflashError::FinalizeRejected {
+                index,
+                attempts,
+                chunk,
+                chunks,
+            } => write!(
+                f,
+                "Bootloader rejected the last frame (index {index}, chunk {chunk}/{chunks}) \
+                 on all {attempts} sends, though it ACKed every frame before it; \
+                 it refused to complete programming, check the end of the image"
+            ),
+            FeeflashError::RetryBudgetExceeded { budget, naks } => {
+                write!(f, "Retry budget of {budget} exhausted; NAKs per chunk:")?;
+                for (chunk, count) in naks {
+                    write!(f, " #{chunk} x{count}")?;
+                }
+                write!(f, ". The link is marginal, check wiring and power")
+            }
+            FeeflashError::RebootRejected { id } => write!(
+                f,
+                "Device id {id} kept answering pings after reboot; the reboot was likely rejected"
+            ),
+            FeeflashError::NotBackAfterReboot { id } => write!(
+                f,
+                "Device id {id} went offline after reboot but did not answer again"
+            ),
+            FeeflashError::BootNotConfirmed { id } => write!(
+                f,
+                "Flashed, but device id {id} did not answer after reboot; power cycle it instead of flashing again"
+            ),
+            FeeflashError::ModelMismatch { backup, device } => write!(
+                f,
+                "EEPROM backup is from model {backup}, but the device is model {device}; refusing to restore"
+            ),
+            FeeflashError::IncompatibleModel { device, expected } => {
+                write!(f, "Device is model {device}, but the firmware is for model")?;
+                for (i, model) in expected.iter().enumerate() {
+                    write!(f, "{}{model}", if i == 0 { " " } else { ", " })?;
+                }
+                write!(f, "; refusing to flash")
+            }
+            FeeflashError::VersionMismatch { expected, actual } => {
+                write!(f, "Device reports firmware {actual}, expected {expected}")
+            }
+            FeeflashError::EmptyFirmware => write!(f, "Firmware image is empty"),
+            FeeflashError::DigestMismatch { expected, actual } => write!(
+                f,
+                "Firmware SHA-256 mismatch: expected {expected}, got {actual}; the file is corrupted or not the published one"
+            ),
+            FeeflashError::FirmwareTooLarge {
+                len: Some(len),
+                max,
+            } => write!(
+                f,
+                "Firmware image is {len} bytes, more than the {max}-byte limit; refusing to flash"
+            ),
+            FeeflashError::FirmwareTooLarge { len: None, max } => write!(
+                f,
+                "Firmware image is more than the {max}-byte limit; refusing to flash"
+            ),
+            FeeflashError::RegionOutOfRange {
+                offset,
+                length: Some(length),
+                len,
+            } => write!(
+                f,
+                "Region of 0x{length:X} bytes at offset 0x{offset:X} doesn't fit the {len}-byte image"
+            ),
+            FeeflashError::RegionOutOfRange {
+                offset,
+                length: None,
+                len,
+            } => write!(
+                f,
+                "Offset 0x{offset:X} is past the end of the {len}-byte image"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FeeflashError {}
+
+impl From<FeeflashError> for io::Error {
+    fn from(err: FeeflashError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// Failures with a [`FeeflashError`] equivalent become that, so callers
+/// can match them whichever driver ran the flash.
+impl From<FlasherError> for io::Error {
+    fn from(err: FlasherError) -> Self {
+        let err = match err {
+            FlasherError::EmptyFirmware => FeeflashError::EmptyFirmware,
+            FlasherError::FirmwareTooLarge { len, max } => FeeflashError::FirmwareTooLarge {
+                len: Some(len),
+                max,
+            },
+            FlasherError::RegionOutOfRange {
+                offset,
+                length,
+                len,
+            } => FeeflashError::RegionOutOfRange {
+                offset,
+                length,
+                len,
+            },
+            FlasherError::IncompatibleModel { device, expected } => {
+                FeeflashError::IncompatibleModel { device, expected }
+            }
+            FlasherError::FrameNakExhausted { index, attempts } => {
+                FeeflashError::FrameNakExhausted { index, attempts }
+            }
+            FlasherError::FinalizeRejected {
+                index,
+                attempts,
+                chunk,
+                chunks,
+            } => FeeflashError::FinalizeRejected {
+                index,
+                attempts,
+                chunk,
+                chunks,
+            },
+            FlasherError::RetryBudgetExceeded { budget, naks } => {
+                FeeflashError::RetryBudgetExceeded { budget, naks }
+            }
+            FlasherError::BootNotConfirmed { id } => FeeflashError::BootNotConfirmed { id },
+            FlasherError::NoDevice { .. }
+            | FlasherError::NotInBootloader { reply: None }
+            | FlasherError::NoReply { .. }
+            | FlasherError::AckTimeout { .. } => {
+                return io::Error::new(io::ErrorKind::TimedOut, err);
+            }
+            FlasherError::NotInBootloader { reply: Some(_) }
+            | FlasherError::UnexpectedReply { .. } => {
+                return io::Error::new(io::ErrorKind::InvalidData, err);
+            }
+        };
+        err.into()
+    }
+}
+
+impl From<DynamixelError> for io::Error {
+    fn from(err: DynamixelError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+impl From<PacketError> for io::Error {
+    fn from(err: PacketError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+impl From<FrameError> for io::Error {
+    fn from(err: FrameError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Why bytes are not a valid `.ffw` firmware container, or can't be packed
+/// into one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerError {
+    /// Doesn't start with the container magic.
+    BadMagic,
+    /// The file ends inside the header.
+    Truncated,
+    /// The version is not valid UTF-8.
+    BadVersion,
+    /// The header announces a different payload length than follows it.
+    LengthMismatch {
+        header: usize,
+        actual: usize,
+    },
+    CrcMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    /// More than 255 models.
+    TooManyModels,
+    /// Version longer than 255 bytes.
+    VersionTooLong,
+    /// Payload of 4 GiB or more.
+    PayloadTooLong,
+    /// Bytes after the payload that are not a signature section.
+    BadTrailer {
+        len: usize,
+    },
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ContainerError::BadMagic => write!(f, "not a firmware container (bad magic)"),
+            ContainerError::Truncated => write!(f, "firmware container header is truncated"),
+            ContainerError::BadVersion => write!(f, "firmware container version is not UTF-8"),
+            ContainerError::LengthMismatch { header, actual } => write!(
+                f,
+                "firmware container announces {header} payload bytes but holds {actual}"
+            ),
+            ContainerError::CrcMismatch { expected, actual } => write!(
+                f,
+                "firmware container payload CRC mismatch: expected 0x{expected:08X}, got 0x{actual:08X}"
+            ),
+            ContainerError::TooManyModels => {
+                write!(f, "a firmware container holds at most 255 models")
+            }
+            ContainerError::VersionTooLong => {
+                write!(f, "firmware container version is longer than 255 bytes")
+            }
+            ContainerError::PayloadTooLong => {
+                write!(f, "firmware container payload must be under 4 GiB")
+            }
+            ContainerError::BadTrailer { len } => write!(
+                f,
+                "firmware container has {len} bytes after the payload that are not a signature section"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {}
+
+impl From<ContainerError> for io::Error {
+    fn from(err: ContainerError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Why a file is not accepted as a firmware image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareError {
+    Empty,
+    TooSmall {
+        len: usize,
+        min: usize,
+    },
+    /// The start of the file is text, not a binary image.
+    LooksLikeText,
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareError::Empty => write!(f, "firmware image is empty"),
+            FirmwareError::TooSmall { len, min } => write!(
+                f,
+                "firmware image is {len} bytes, too small to be firmware (at least {min})"
+            ),
+            FirmwareError::LooksLikeText => {
+                write!(f, "file is text, not a binary firmware image")
+            }
+        }
+    }
+}
+
+impl std::error::Error for FirmwareError {}
+
+impl From<FirmwareError> for io::Error {
+    fn from(err: FirmwareError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Why a firmware signature was not accepted.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigError {
+    /// Not a valid 32-byte Ed25519 public key.
+    BadPublicKey,
+    /// Not a 64-byte Ed25519 signature.
+    BadSignature,
+    /// The signature doesn't match the image and key.
+    Mismatch,
+}
+
+impl fmt::Display for SigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SigError::BadPublicKey => write!(f, "public key is not a 32-byte Ed25519 key"),
+            SigError::BadSignature => write!(f, "signature is not a 64-byte Ed25519 signature"),
+            SigError::Mismatch => write!(
+                f,
+                "firmware signature does not match; the image is modified or signed with another key"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for SigError {}
+
+impl From<SigError> for io::Error {
+    fn from(err: SigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
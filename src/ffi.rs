@@ -0,0 +1,296 @@
+//! C ABI over the flashing flow, for host programs written in C or C++.
+//!
+//! Build the shared library with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`; the
+//! matching header is `include/feeflash.h`, generated by cbindgen from this
+//! file.
+//!
+//! Every function returns a [`FeeflashResult`] and catches Rust panics
+//! before they reach the caller, reporting them as
+//! [`FeeflashResult::Panic`]. The message of the last failed call on a
+//! thread is kept for [`feeflash_last_error_message`].
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString, c_char, c_void};
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::bootloader::{
+    BOOTLOADER_BAUD, FlashEvent, FlashOptions, Progress, flash_device, jump_to_application,
+};
+use crate::deadline::Deadline;
+use crate::dynamixel::{ScanOptions, scan_bus};
+use crate::transport::open_port;
+
+/// Read timeout of ports opened here, as in the CLI.
+const PORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Outcome of a call. Details of a failure are available from
+/// `feeflash_last_error_message`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeflashResult {
+    Ok = 0,
+    /// A NULL pointer or a port name that isn't UTF-8.
+    InvalidArgument = 1,
+    /// The port could not be opened or failed.
+    Io = 2,
+    /// The device stopped answering, or the deadline passed.
+    Timeout = 3,
+    /// The device answered with something unexpected, or refused the
+    /// image.
+    Protocol = 4,
+    /// A bug in feeflash; the port may be left in the bootloader.
+    Panic = 5,
+}
+
+/// What a progress callback reports.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeeflashEventKind {
+    /// A status line in `message`.
+    Message = 0,
+    /// A warning in `message`.
+    Warning = 1,
+    /// Frame `frame` of `total` was acknowledged; `message` is NULL.
+    Frame = 2,
+}
+
+/// Flash parameters. Start from `feeflash_options_default()`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeeflashOptions {
+    /// Baud rate of the application; the port is opened at it.
+    pub baud: u32,
+    /// Baud rate of the bootloader.
+    pub bootloader_baud: u32,
+    /// Resends of a NAKed frame before giving up.
+    pub max_retries: u8,
+    /// Time limit of the whole call in milliseconds, 0 for none.
+    pub max_duration_ms: u32,
+}
+
+impl FeeflashOptions {
+    fn flash_options(&self) -> FlashOptions {
+        let max_duration = (self.max_duration_ms > 0)
+            .then(|| Duration::from_millis(u64::from(self.max_duration_ms)));
+        FlashOptions {
+            max_retries: self.max_retries,
+            bootloader_baud: self.bootloader_baud,
+            deadline: Deadline::from_max_duration(max_duration),
+            ..FlashOptions::default()
+        }
+    }
+}
+
+/// Called with the `user` pointer given to `feeflash_flash`, on the thread
+/// that called it. `message` is only valid during the call. Must not
+/// throw or longjmp.
+pub type FeeflashProgressCb = Option<
+    unsafe extern "C" fn(
+        user: *mut c_void,
+        kind: FeeflashEventKind,
+        frame: u32,
+        total: u32,
+        message: *const c_char,
+    ),
+>;
+
+/// Forwards [`FlashEvent`]s to a C callback.
+struct CallbackProgress {
+    callback: unsafe extern "C" fn(*mut c_void, FeeflashEventKind, u32, u32, *const c_char),
+    user: *mut c_void,
+}
+
+// SAFETY: the flash runs on the calling thread, so the callback and `user`
+// never leave it.
+unsafe impl Send for CallbackProgress {}
+unsafe impl Sync for CallbackProgress {}
+
+impl fmt::Debug for CallbackProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackProgress")
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Progress for CallbackProgress {
+    fn event(&self, event: FlashEvent) {
+        let (kind, frame, total, message) = match event {
+            FlashEvent::Message(message) => (FeeflashEventKind::Message, 0, 0, Some(message)),
+            FlashEvent::Warning(message) => (FeeflashEventKind::Warning, 0, 0, Some(message)),
+            FlashEvent::Frame { frame, total, .. } => {
+                (FeeflashEventKind::Frame, frame, total, None)
+            }
+        };
+        let message = message.map(|m| CString::new(m.replace('\0', "")).unwrap_or_default());
+        let to_u32 = |n: usize| u32::try_from(n).unwrap_or(u32::MAX);
+        // SAFETY: `feeflash_flash` requires the callback to be safe to call
+        // with `user` for the duration of the flash.
+        unsafe {
+            (self.callback)(
+                self.user,
+                kind,
+                to_u32(frame),
+                to_u32(total),
+                message.as_ref().map_or(std::ptr::null(), |m| m.as_ptr()),
+            );
+        }
+    }
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+    let message = CString::new(message.replace('\0', "")).unwrap_or_default();
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Run `f`, turning its error or panic into a result code and the last
+/// error message.
+fn guard(f: impl FnOnce() -> io::Result<()>) -> FeeflashResult {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => FeeflashResult::Ok,
+        Ok(Err(e)) => {
+            let result = match e.kind() {
+                io::ErrorKind::InvalidInput => FeeflashResult::InvalidArgument,
+                io::ErrorKind::TimedOut => FeeflashResult::Timeout,
+                io::ErrorKind::InvalidData => FeeflashResult::Protocol,
+                _ => FeeflashResult::Io,
+            };
+            set_last_error(e.to_string());
+            result
+        }
+        Err(payload) => {
+            let reason = payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            set_last_error(format!("Internal error: {reason}"));
+            FeeflashResult::Panic
+        }
+    }
+}
+
+/// # Safety
+///
+/// `port` is NULL or a NUL-terminated string.
+unsafe fn port_name<'a>(port: *const c_char) -> io::Result<&'a str> {
+    if port.is_null() {
+        return Err(invalid("port is NULL"));
+    }
+    // SAFETY: NUL-terminated per the caller's contract.
+    unsafe { CStr::from_ptr(port) }
+        .to_str()
+        .map_err(|_| invalid("port is not UTF-8"))
+}
+
+/// Defaults of the CLI: 1 Mbaud application, 500 kbaud bootloader, five
+/// resends per frame, no time limit.
+#[unsafe(no_mangle)]
+pub extern "C" fn feeflash_options_default() -> FeeflashOptions {
+    FeeflashOptions {
+        baud: 1_000_000,
+        bootloader_baud: BOOTLOADER_BAUD,
+        max_retries: FlashOptions::default().max_retries,
+        max_duration_ms: 0,
+    }
+}
+
+/// Flash `len` bytes of `firmware` to device `id` on `port`, then start
+/// the new application. `port` is a serial port path or a `tcp://` or
+/// `rfc2217://` address. `options` may be NULL for the defaults. Without a
+/// `progress` callback, status lines are printed to stdout.
+///
+/// # Safety
+///
+/// `port` is a NUL-terminated string, `firmware` points to `len` readable
+/// bytes, `options` is NULL or valid, and `progress` is NULL or safe to
+/// call with `user` until this function returns.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn feeflash_flash(
+    port: *const c_char,
+    id: u8,
+    firmware: *const u8,
+    len: usize,
+    options: *const FeeflashOptions,
+    progress: FeeflashProgressCb,
+    user: *mut c_void,
+) -> FeeflashResult {
+    guard(|| {
+        // SAFETY: per this function's contract.
+        let port = unsafe { port_name(port)? };
+        if firmware.is_null() {
+            return Err(invalid("firmware is NULL"));
+        }
+        // SAFETY: `len` readable bytes per this function's contract.
+        let firmware = unsafe { std::slice::from_raw_parts(firmware, len) };
+        // SAFETY: NULL or valid per this function's contract.
+        let settings = unsafe { options.as_ref() }
+            .copied()
+            .unwrap_or_else(|| feeflash_options_default());
+
+        let mut options = settings.flash_options();
+        options.progress = progress
+            .map(|callback| Arc::new(CallbackProgress { callback, user }) as Arc<dyn Progress>);
+        let mut transport = open_port(port, settings.baud, PORT_TIMEOUT)?;
+        flash_device(&mut transport, id, firmware, &options)?;
+        jump_to_application(&mut transport, id, settings.baud, &options)
+    })
+}
+
+/// Ping every ID on `port` at `baud`. The IDs that answered are written to
+/// `ids` in ascending order, at most `capacity` of them; `found` receives
+/// how many answered, which may be more than `capacity`.
+///
+/// # Safety
+///
+/// `port` is a NUL-terminated string, `ids` is NULL or points to
+/// `capacity` writable bytes, and `found` is a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn feeflash_scan(
+    port: *const c_char,
+    baud: u32,
+    ids: *mut u8,
+    capacity: usize,
+    found: *mut usize,
+) -> FeeflashResult {
+    guard(|| {
+        // SAFETY: per this function's contract.
+        let port = unsafe { port_name(port)? };
+        if found.is_null() || (ids.is_null() && capacity > 0) {
+            return Err(invalid("ids or found is NULL"));
+        }
+        let mut transport = open_port(port, baud, PORT_TIMEOUT)?;
+        let report = scan_bus(&mut transport, Deadline::NONE, &ScanOptions::default())?;
+        for (i, &id) in report.found.iter().take(capacity).enumerate() {
+            // SAFETY: `i < capacity` writable bytes per the contract.
+            unsafe { ids.add(i).write(id) };
+        }
+        // SAFETY: valid per this function's contract.
+        unsafe { found.write(report.found.len()) };
+        Ok(())
+    })
+}
+
+/// Message of the last call on this thread that failed, or NULL if none
+/// did. Valid until the next failing call on this thread.
+#[unsafe(no_mangle)]
+pub extern "C" fn feeflash_last_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| {
+        last.borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |m| m.as_ptr())
+    })
+}
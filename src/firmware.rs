@@ -0,0 +1,585 @@
+//! `.ffw` firmware container.
+//!
+//! A raw image carries nothing that says which servo it is for, so the
+//! wrong file is easy to flash. A [`Container`] puts a header in front of
+//! the image naming the target models and the firmware version, plus the
+//! payload length and a CRC-32 that catch a truncated or corrupted copy.
+//!
+//! Layout, integers little-endian:
+//!
+//! | bytes   | field                                    |
+//! |---------|------------------------------------------|
+//! | 4       | magic `FFW1`                             |
+//! | 1       | number of models `n`                     |
+//! | 2 × `n` | model numbers                            |
+//! | 1       | version length `v`                       |
+//! | `v`     | version, UTF-8                           |
+//! | 4       | payload length                           |
+//! | 4       | CRC-32 of the payload                    |
+//! | ...     | payload: the raw image                   |
+//! | 4 + 64  | optional: tag `SIG1`, Ed25519 signature  |
+//!
+//! The signature covers the payload only, so the same bytes work as a
+//! detached `.sig` file next to a raw image.
+//!
+//! Release files can also come with a detached SHA-256 in a `.sha256`
+//! sidecar, checked with [`verify_digest`] (feature `sha256`). Signatures
+//! are checked with [`verify_signature`] (feature `signing`).
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use crate::crc::crc32;
+#[cfg(any(feature = "sha256", feature = "gzip"))]
+use crate::error::FeeflashError;
+#[cfg(feature = "signing")]
+use crate::error::SigError;
+use crate::error::{ContainerError, FirmwareError};
+
+/// First bytes of every container.
+pub const MAGIC: &[u8; 4] = b"FFW1";
+
+/// Starts the optional signature section after the payload.
+pub const SIGNATURE_TAG: &[u8; 4] = b"SIG1";
+
+/// Length of an Ed25519 signature.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Length of an Ed25519 public key.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// Firmware version as in the servo's version registers, e.g. 3.10.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+impl fmt::Display for FirmwareVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}", self.major, self.minor)
+    }
+}
+
+/// Parses `major.minor`, both decimal bytes.
+impl FromStr for FirmwareVersion {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parsed = s
+            .trim()
+            .split_once('.')
+            .and_then(|(major, minor)| Some((major.parse().ok()?, minor.parse().ok()?)));
+        match parsed {
+            Some((major, minor)) => Ok(FirmwareVersion { major, minor }),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("'{s}' is not a firmware version like 3.10"),
+            )),
+        }
+    }
+}
+
+impl From<(u8, u8)> for FirmwareVersion {
+    fn from((major, minor): (u8, u8)) -> Self {
+        FirmwareVersion { major, minor }
+    }
+}
+
+/// Firmware image with the metadata of a `.ffw` file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Container {
+    /// Model numbers the image is built for.
+    pub models: Vec<u16>,
+    /// Firmware version, e.g. "3.1".
+    pub version: String,
+    /// The raw image, as sent to the bootloader.
+    pub payload: Vec<u8>,
+    /// Ed25519 signature of the payload, if the container is signed.
+    pub signature: Option<[u8; SIGNATURE_LEN]>,
+}
+
+impl Container {
+    /// Whether `bytes` start like a container rather than a raw image.
+    pub fn is_container(bytes: &[u8]) -> bool {
+        bytes.starts_with(MAGIC)
+    }
+
+    /// Serialize into the `.ffw` layout.
+    pub fn pack(&self) -> Result<Vec<u8>, ContainerError> {
+        let models = u8::try_from(self.models.len()).map_err(|_| ContainerError::TooManyModels)?;
+        let version =
+            u8::try_from(self.version.len()).map_err(|_| ContainerError::VersionTooLong)?;
+        let payload_len =
+            u32::try_from(self.payload.len()).map_err(|_| ContainerError::PayloadTooLong)?;
+
+        let mut bytes = Vec::with_capacity(18 + 2 * self.models.len() + self.payload.len());
+        bytes.extend_from_slice(MAGIC);
+        bytes.push(models);
+        for model in &self.models {
+            bytes.extend_from_slice(&model.to_le_bytes());
+        }
+        bytes.push(version);
+        bytes.extend_from_slice(self.version.as_bytes());
+        bytes.extend_from_slice(&payload_len.to_le_bytes());
+        bytes.extend_from_slice(&crc32(&self.payload).to_le_bytes());
+        bytes.extend_from_slice(&self.payload);
+        if let Some(signature) = &self.signature {
+            bytes.extend_from_slice(SIGNATURE_TAG);
+            bytes.extend_from_slice(signature);
+        }
+        Ok(bytes)
+    }
+
+    /// Parse a `.ffw` file, checking the payload length and CRC. The
+    /// signature, if any, is only split off; see [`verify_signature`].
+    pub fn parse(bytes: &[u8]) -> Result<Container, ContainerError> {
+        let mut rest = bytes.strip_prefix(MAGIC).ok_or(ContainerError::BadMagic)?;
+
+        let count = take(&mut rest, 1)?[0] as usize;
+        let models = take(&mut rest, 2 * count)?
+            .chunks(2)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect();
+        let version_len = take(&mut rest, 1)?[0] as usize;
+        let version = String::from_utf8(take(&mut rest, version_len)?.to_vec())
+            .map_err(|_| ContainerError::BadVersion)?;
+        let header_len = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap()) as usize;
+        let expected = u32::from_le_bytes(take(&mut rest, 4)?.try_into().unwrap());
+
+        if rest.len() < header_len {
+            return Err(ContainerError::LengthMismatch {
+                header: header_len,
+                actual: rest.len(),
+            });
+        }
+        let (payload, trailer) = rest.split_at(header_len);
+        let signature = match trailer.strip_prefix(SIGNATURE_TAG) {
+            None if trailer.is_empty() => None,
+            Some(signature) if signature.len() == SIGNATURE_LEN => {
+                Some(signature.try_into().unwrap())
+            }
+            _ => return Err(ContainerError::BadTrailer { len: trailer.len() }),
+        };
+        let actual = crc32(payload);
+        if actual != expected {
+            return Err(ContainerError::CrcMismatch { expected, actual });
+        }
+        Ok(Container {
+            models,
+            version,
+            payload: payload.to_vec(),
+            signature,
+        })
+    }
+}
+
+/// First bytes of a gzip stream.
+pub const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+
+/// Decompress `data` if it is gzip-compressed, detected by
+/// [`GZIP_MAGIC`]; other data is returned as is. At most `max` bytes are
+/// decompressed, like [`read_firmware`](crate::bootloader::read_firmware)
+/// reads. Without the `gzip` feature a gzip file is refused.
+pub fn decompress_firmware(data: Vec<u8>, max: usize) -> io::Result<Vec<u8>> {
+    if !data.starts_with(&GZIP_MAGIC) {
+        return Ok(data);
+    }
+    gunzip(&data, max)
+}
+
+#[cfg(feature = "gzip")]
+fn gunzip(data: &[u8], max: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let bad_gzip = |e: io::Error| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("Failed to decompress gzip firmware: {e}"),
+        )
+    };
+    let decoder = flate2::read::GzDecoder::new(data);
+    let mut image = Vec::new();
+    decoder
+        .take(max as u64 + 1)
+        .read_to_end(&mut image)
+        .map_err(bad_gzip)?;
+    if image.len() > max {
+        // Stop at the limit: a gzip bomb must not cost unbounded time.
+        return Err(FeeflashError::FirmwareTooLarge { len: None, max }.into());
+    }
+    Ok(image)
+}
+
+#[cfg(not(feature = "gzip"))]
+fn gunzip(_data: &[u8], _max: usize) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Firmware is gzip-compressed; decompress it first or build with the gzip feature",
+    ))
+}
+
+pub use crate::frame::{PAD_BYTE, pad_to_page};
+
+/// Smallest raw image [`validate_firmware_image`] accepts: one frame.
+pub const MIN_FIRMWARE_SIZE: usize = 64;
+
+/// Bytes at the start of an image looked at for text.
+const TEXT_SAMPLE_LEN: usize = 1024;
+
+/// Catch files that are clearly not a firmware image, e.g. a text file
+/// picked by mistake, before a flash cycle is spent on them.
+///
+/// Feetech images are raw binaries with no header or magic to check, so
+/// this only rejects an empty file, one under [`MIN_FIRMWARE_SIZE`] bytes,
+/// and one whose first KiB is printable UTF-8 and whitespace only. Intel
+/// HEX and S-record files count as text: convert them to binary first.
+pub fn validate_firmware_image(data: &[u8]) -> Result<(), FirmwareError> {
+    if data.is_empty() {
+        return Err(FirmwareError::Empty);
+    }
+    if data.len() < MIN_FIRMWARE_SIZE {
+        return Err(FirmwareError::TooSmall {
+            len: data.len(),
+            min: MIN_FIRMWARE_SIZE,
+        });
+    }
+    let sample = &data[..data.len().min(TEXT_SAMPLE_LEN)];
+    let text = match std::str::from_utf8(sample) {
+        Ok(text) => text,
+        // Cut in the middle of a character by the sample length.
+        Err(e) if e.error_len().is_none() => {
+            std::str::from_utf8(&sample[..e.valid_up_to()]).unwrap()
+        }
+        Err(_) => return Ok(()),
+    };
+    if text
+        .chars()
+        .all(|c| !c.is_control() || matches!(c, '\t' | '\n' | '\r'))
+    {
+        return Err(FirmwareError::LooksLikeText);
+    }
+    Ok(())
+}
+
+/// Expected digest from the contents of a `.sha256` sidecar, lowercase.
+///
+/// Lines are in `sha256sum` format, `<hash>  <filename>` (or `*<filename>`
+/// for binary mode), or just the hash. With several lines, the one naming
+/// `file_name` is used; a line without a name matches any file.
+pub fn parse_sha256_sidecar(text: &str, file_name: &str) -> io::Result<String> {
+    for line in text.lines() {
+        let mut words = line.split_whitespace();
+        let Some(hash) = words.next() else {
+            continue;
+        };
+        let name = words.next().map(|name| name.trim_start_matches('*'));
+        // Compare base names: the sidecar may list the file with a path.
+        if name.is_none_or(|name| name.rsplit(['/', '\\']).next() == Some(file_name)) {
+            return parse_sha256_hex(hash);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("No SHA-256 for '{file_name}' in sidecar"),
+    ))
+}
+
+/// Check that `hex` is a SHA-256 digest in hex and return it lowercase.
+pub fn parse_sha256_hex(hex: &str) -> io::Result<String> {
+    if hex.len() != 64 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{hex}' is not a SHA-256 digest (64 hex digits)"),
+        ));
+    }
+    Ok(hex.to_ascii_lowercase())
+}
+
+/// SHA-256 of `data` as lowercase hex.
+#[cfg(feature = "sha256")]
+pub fn sha256_hex(data: &[u8]) -> String {
+    use sha2::{Digest, Sha256};
+
+    Sha256::digest(data)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+/// Fail with `DigestMismatch` unless the SHA-256 of `data` is `expected`
+/// (hex, either case).
+#[cfg(feature = "sha256")]
+pub fn verify_digest(data: &[u8], expected: &str) -> io::Result<()> {
+    let expected = parse_sha256_hex(expected)?;
+    let actual = sha256_hex(data);
+    if actual != expected {
+        return Err(FeeflashError::DigestMismatch { expected, actual }.into());
+    }
+    Ok(())
+}
+
+/// Key or signature file contents: hex text (whitespace ignored) is
+/// decoded, anything else is taken as raw bytes.
+pub fn raw_or_hex(bytes: &[u8]) -> Vec<u8> {
+    let digits: Vec<u8> = bytes
+        .iter()
+        .copied()
+        .filter(|b| !b.is_ascii_whitespace())
+        .collect();
+    if digits.is_empty()
+        || !digits.len().is_multiple_of(2)
+        || !digits.iter().all(u8::is_ascii_hexdigit)
+    {
+        return bytes.to_vec();
+    }
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let hex = std::str::from_utf8(pair).unwrap();
+            u8::from_str_radix(hex, 16).unwrap()
+        })
+        .collect()
+}
+
+/// Check the Ed25519 signature `sig` of `image` against `pubkey` (32 raw
+/// bytes).
+#[cfg(feature = "signing")]
+pub fn verify_signature(image: &[u8], sig: &[u8], pubkey: &[u8]) -> Result<(), SigError> {
+    use ed25519_dalek::{Signature, VerifyingKey};
+
+    let pubkey: &[u8; PUBLIC_KEY_LEN] = pubkey.try_into().map_err(|_| SigError::BadPublicKey)?;
+    let key = VerifyingKey::from_bytes(pubkey).map_err(|_| SigError::BadPublicKey)?;
+    let sig = Signature::from_slice(sig).map_err(|_| SigError::BadSignature)?;
+    key.verify_strict(image, &sig)
+        .map_err(|_| SigError::Mismatch)
+}
+
+/// Split `len` bytes off the front of `rest`.
+fn take<'a>(rest: &mut &'a [u8], len: usize) -> Result<&'a [u8], ContainerError> {
+    if rest.len() < len {
+        return Err(ContainerError::Truncated);
+    }
+    let (head, tail) = rest.split_at(len);
+    *rest = tail;
+    Ok(head)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Container {
+        Container {
+            models: vec![777, 2825],
+            version: "3.1".to_string(),
+            payload: (0..200u8).collect(),
+            signature: None,
+        }
+    }
+
+    #[cfg(feature = "gzip")]
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn decompress_firmware_unpacks_gzip_only() {
+        let image: Vec<u8> = (0..=255u8).cycle().take(3000).collect();
+        assert_eq!(decompress_firmware(gzip(&image), 4096).unwrap(), image);
+        assert_eq!(decompress_firmware(image.clone(), 4096).unwrap(), image);
+
+        let err = decompress_firmware(gzip(&image), 1024).unwrap_err();
+        assert_eq!(
+            FeeflashError::from_io(&err),
+            Some(&FeeflashError::FirmwareTooLarge {
+                len: None,
+                max: 1024
+            })
+        );
+
+        let mut truncated = gzip(&image);
+        truncated.truncate(20);
+        let err = decompress_firmware(truncated, 4096).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn validate_firmware_image_rejects_non_images() {
+        // Vector table and code of a small Cortex-M image.
+        let mut image = vec![0x00, 0x50, 0x00, 0x20, 0xC1, 0x00, 0x00, 0x08];
+        image.extend((0..=255u8).cycle().take(2000));
+        assert_eq!(validate_firmware_image(&image), Ok(()));
+
+        assert_eq!(validate_firmware_image(&[]), Err(FirmwareError::Empty));
+        assert_eq!(
+            validate_firmware_image(&[0x00; 10]),
+            Err(FirmwareError::TooSmall { len: 10, min: 64 })
+        );
+
+        let readme = "# Servo firmware\r\n\nFlash with feeflash.\n".repeat(50);
+        assert_eq!(
+            validate_firmware_image(readme.as_bytes()),
+            Err(FirmwareError::LooksLikeText)
+        );
+        let hex = ":10000000005000200D0100080F0100081101000888\n".repeat(40);
+        assert_eq!(
+            validate_firmware_image(hex.as_bytes()),
+            Err(FirmwareError::LooksLikeText)
+        );
+        // Non-ASCII text, cut inside a character by the sample length.
+        let notes = format!("{}ü{}", "=".repeat(1023), "Prüfstand\n".repeat(10));
+        assert_eq!(
+            validate_firmware_image(notes.as_bytes()),
+            Err(FirmwareError::LooksLikeText)
+        );
+    }
+
+    #[test]
+    fn pack_parse_round_trip() {
+        let bytes = sample().pack().unwrap();
+        assert!(Container::is_container(&bytes));
+        assert_eq!(&bytes[..9], b"FFW1\x02\x09\x03\x09\x0b");
+        assert_eq!(Container::parse(&bytes), Ok(sample()));
+    }
+
+    #[test]
+    fn parse_rejects_damaged_files() {
+        let bytes = sample().pack().unwrap();
+        assert_eq!(Container::parse(&bytes[1..]), Err(ContainerError::BadMagic));
+        assert_eq!(
+            Container::parse(&bytes[..12]),
+            Err(ContainerError::Truncated)
+        );
+        assert_eq!(
+            Container::parse(&bytes[..bytes.len() - 1]),
+            Err(ContainerError::LengthMismatch {
+                header: 200,
+                actual: 199
+            })
+        );
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 0x01;
+        assert!(matches!(
+            Container::parse(&corrupted),
+            Err(ContainerError::CrcMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn signature_section_round_trips() {
+        let signed = Container {
+            signature: Some([0x5A; SIGNATURE_LEN]),
+            ..sample()
+        };
+        let bytes = signed.pack().unwrap();
+        assert_eq!(bytes.len(), sample().pack().unwrap().len() + 68);
+        assert_eq!(Container::parse(&bytes), Ok(signed));
+        assert_eq!(
+            Container::parse(&bytes[..bytes.len() - 1]),
+            Err(ContainerError::BadTrailer { len: 67 })
+        );
+    }
+
+    #[test]
+    fn key_files_may_be_hex() {
+        assert_eq!(raw_or_hex(b"0aff\n10 20\n"), [0x0A, 0xFF, 0x10, 0x20]);
+        assert_eq!(raw_or_hex(&[0x0A, 0xFF]), [0x0A, 0xFF]);
+        assert_eq!(raw_or_hex(b"abc"), b"abc");
+    }
+
+    /// Known test keypair: the secret key is 32 bytes of 0x07.
+    #[cfg(feature = "signing")]
+    fn test_key() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&[0x07; 32])
+    }
+
+    #[cfg(feature = "signing")]
+    #[test]
+    fn signature_accepts_image_and_rejects_tampering() {
+        use ed25519_dalek::Signer;
+
+        let image: Vec<u8> = (0..=255u8).collect();
+        let key = test_key();
+        let pubkey = key.verifying_key().to_bytes();
+        let sig = key.sign(&image).to_bytes();
+        assert_eq!(verify_signature(&image, &sig, &pubkey), Ok(()));
+
+        let mut tampered = image.clone();
+        tampered[100] ^= 0x01;
+        assert_eq!(
+            verify_signature(&tampered, &sig, &pubkey),
+            Err(SigError::Mismatch)
+        );
+        let other = ed25519_dalek::SigningKey::from_bytes(&[0x08; 32]).verifying_key();
+        assert_eq!(
+            verify_signature(&image, &sig, other.as_bytes()),
+            Err(SigError::Mismatch)
+        );
+        assert_eq!(
+            verify_signature(&image, &sig, &pubkey[..31]),
+            Err(SigError::BadPublicKey)
+        );
+        assert_eq!(
+            verify_signature(&image, &sig[..63], &pubkey),
+            Err(SigError::BadSignature)
+        );
+    }
+
+    #[test]
+    fn versions_parse_and_display() {
+        let version: FirmwareVersion = "3.10".parse().unwrap();
+        assert_eq!(version, FirmwareVersion::from((3, 10)));
+        assert_eq!(version.to_string(), "3.10");
+        assert!(version > "3.9".parse().unwrap());
+        for bad in ["3", "3.", "v3.1", "3.1.2", "256.0", ""] {
+            assert!(bad.parse::<FirmwareVersion>().is_err(), "{bad}");
+        }
+    }
+
+    /// SHA-256 of "abc".
+    const ABC: &str = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn sidecar_formats() {
+        let upper = ABC.to_ascii_uppercase();
+        assert_eq!(
+            parse_sha256_sidecar(&format!("{ABC}\n"), "fw.bin").unwrap(),
+            ABC
+        );
+        assert_eq!(
+            parse_sha256_sidecar(&format!("{upper}  fw.bin\n"), "fw.bin").unwrap(),
+            ABC
+        );
+
+        let other = "0".repeat(64);
+        let listing = format!("{other}  other.bin\n{ABC} *dist/fw.bin\n");
+        assert_eq!(parse_sha256_sidecar(&listing, "fw.bin").unwrap(), ABC);
+        assert!(parse_sha256_sidecar(&listing, "missing.bin").is_err());
+        assert!(parse_sha256_sidecar("not-a-hash  fw.bin", "fw.bin").is_err());
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn verify_digest_reports_both_digests() {
+        verify_digest(b"abc", &ABC.to_ascii_uppercase()).unwrap();
+
+        let err = verify_digest(b"abd", ABC).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        match FeeflashError::from_io(&err) {
+            Some(FeeflashError::DigestMismatch { expected, actual }) => {
+                assert_eq!(expected, ABC);
+                assert_eq!(actual, &sha256_hex(b"abd"));
+            }
+            other => panic!("unexpected error {other:?}"),
+        }
+    }
+}
@@ -0,0 +1,1690 @@
+//! Sans-IO flashing state machine: the one implementation of the
+//! flashing protocol, whatever drives the port.
+//!
+//! [`Flasher`] knows the protocol: what to write at each step, how many
+//! reply bytes to wait for and what they mean, when to resend a frame and
+//! when to give up. It never touches a port, a clock or a thread.
+//! [`Flasher::step`] says what to do next and [`Flasher::complete`] takes
+//! what was read back. The blocking functions of `bootloader` and the async
+//! ones of `asynchronous` are drivers of it, and so are [`flash`], [`ping`]
+//! and [`scan`] over any [`AsyncTransport`] (browsers, see the `web`
+//! feature) and [`flash_blocking`] over any [`BlockingTransport`] (UARTs
+//! without an OS, see the `embedded` feature).
+//!
+//! [`Flasher::new`] runs the whole sequence: ping, model check, torque off,
+//! reboot, bootloader baud, magic, init, frames, application baud, ping
+//! until the new firmware answers. [`Flasher::transfer`] sends the frames
+//! alone, to a bootloader that ACKed init.
+//!
+//! Builds on `core` and `alloc` alone.
+
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::{Range, RangeInclusive};
+use core::time::Duration;
+
+use crate::dynamixel::packet::{
+    Instruction, ProtocolVersion, build_dyn_packet, validate_dyn_packet,
+};
+use crate::dynamixel2;
+use crate::frame::{CHUNK_SIZE, ChecksumKind, FirmwareFrames, pad_to_page};
+
+/// Magic sequence that makes a freshly rebooted device stay in its
+/// bootloader.
+pub const BOOTLOADER_MAGIC: &[u8] = b"1fBVA";
+/// Byte that tells the bootloader to prepare for a firmware transfer.
+pub const BOOTLOADER_INIT: u8 = 0x01;
+/// Bootloader reply accepting the magic, init or a frame.
+pub const BOOTLOADER_ACK: u8 = 0x06;
+/// Bootloader reply rejecting a frame, which is then resent.
+pub const BOOTLOADER_NAK: u8 = 0x15;
+/// Default bootloader baud rate: the rate the STS/SMS bootloader listens
+/// on after reboot.
+pub const BOOTLOADER_BAUD: u32 = 500_000;
+/// Default time the device needs after the reboot instruction before it
+/// accepts the magic.
+pub const REBOOT_DELAY: Duration = Duration::from_millis(400);
+/// How long the new firmware gets to answer a ping after the transfer.
+pub const BOOT_CONFIRM_TIMEOUT: Duration = Duration::from_secs(3);
+/// Default for [`FlasherConfig::frame_timeout`]: ample for the bootloader
+/// to program a frame, short enough that a lost ACK costs little.
+pub const DEFAULT_FRAME_TIMEOUT: Duration = Duration::from_millis(500);
+/// Default for [`FlasherConfig::max_firmware_size`], well above the flash
+/// of any supported servo.
+pub const DEFAULT_MAX_FIRMWARE_SIZE: usize = 256 * 1024;
+
+/// Control table address of the model number, read by the model check.
+const MODEL_ADDRESS: u8 = 0x03;
+/// Control table address of torque enable, cleared before the reboot.
+const TORQUE_ENABLE_ADDRESS: u8 = 0x28;
+/// Length of a protocol 1 status packet without parameters, the reply to
+/// a ping or a write.
+const STATUS_LEN: usize = 6;
+/// Length of the protocol 2 reply to a ping: model number and firmware
+/// version as parameters.
+const STATUS_LEN_V2: usize = 14;
+
+/// Settings of a [`Flasher`]. The defaults match those of the CLI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(default)
+)]
+pub struct FlasherConfig {
+    /// Baud rate of the application, before and after the flash.
+    pub app_baud: u32,
+    /// Baud rate of the bootloader.
+    pub bootloader_baud: u32,
+    /// Pause after the reboot instruction before the magic is sent.
+    pub reboot_delay: Duration,
+    /// Pause after the transfer before the first confirmation ping.
+    pub boot_settle: Duration,
+    /// How long pings are sent after the transfer before giving up.
+    pub boot_confirm_timeout: Duration,
+    /// How long to wait for the bootloader to ACK the magic and init, and
+    /// a frame without `frame_timeout`.
+    pub reply_timeout: Duration,
+    /// How long the ACK of each frame is awaited. A frame whose ACK doesn't
+    /// come is resent like a NAKed one, after dropping pending input so a
+    /// late ACK isn't taken for the answer. `None` waits `reply_timeout`
+    /// and fails on the first missed ACK.
+    pub frame_timeout: Option<Duration>,
+    /// How long to wait for the application to answer a ping, the model
+    /// read or the torque write.
+    pub ping_timeout: Duration,
+    /// Resends of a frame before giving up.
+    pub max_retries: u8,
+    /// Resends allowed across the whole transfer; `None` for no limit.
+    pub max_total_retries: Option<u32>,
+    /// Pause after each ACKed frame but the last.
+    pub inter_frame_delay: Duration,
+    /// Checksum the bootloader expects in each firmware frame.
+    pub checksum: ChecksumKind,
+    /// Protocol of the reboot instruction and the pings. The model check
+    /// and the torque write use protocol 1.
+    pub protocol: ProtocolVersion,
+    /// Models the firmware is built for; any other device model stops the
+    /// flash before the reboot. `None` skips the check.
+    pub expected_models: Option<Vec<u16>>,
+    /// Disable torque before the reboot, so a loaded joint isn't left
+    /// holding position through the reset.
+    pub torque_off: bool,
+    /// Largest firmware image accepted.
+    pub max_firmware_size: usize,
+    /// Flash page size of the device. When set, the image is padded with
+    /// 0xFF to a whole number of pages before framing.
+    pub flash_page_size: Option<usize>,
+    /// Send only the image from this byte on. See [`firmware_region`].
+    pub region_offset: usize,
+    /// Send only this many bytes from `region_offset`; `None` for the rest
+    /// of the image.
+    pub region_length: Option<usize>,
+}
+
+impl Default for FlasherConfig {
+    fn default() -> Self {
+        FlasherConfig {
+            app_baud: 1_000_000,
+            bootloader_baud: BOOTLOADER_BAUD,
+            reboot_delay: REBOOT_DELAY,
+            boot_settle: REBOOT_DELAY,
+            boot_confirm_timeout: BOOT_CONFIRM_TIMEOUT,
+            reply_timeout: Duration::from_secs(10),
+            frame_timeout: Some(DEFAULT_FRAME_TIMEOUT),
+            ping_timeout: Duration::from_millis(100),
+            max_retries: 5,
+            max_total_retries: None,
+            inter_frame_delay: Duration::ZERO,
+            checksum: ChecksumKind::Crc16Ccitt,
+            protocol: ProtocolVersion::V1,
+            expected_models: None,
+            torque_off: true,
+            max_firmware_size: DEFAULT_MAX_FIRMWARE_SIZE,
+            flash_page_size: None,
+            region_offset: 0,
+            region_length: None,
+        }
+    }
+}
+
+/// Phase of a flash, reported through [`FlasherEvent::Stage`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Stage {
+    /// Checking that the device answers at the application baud rate.
+    Ping,
+    /// Rebooting the device and catching its bootloader.
+    Handshake,
+    /// Sending firmware frames.
+    Transfer,
+    /// Waiting for the new firmware to answer.
+    Confirm,
+    /// The new firmware answered.
+    Done,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Ping => "ping",
+            Stage::Handshake => "handshake",
+            Stage::Transfer => "transfer",
+            Stage::Confirm => "confirm",
+            Stage::Done => "done",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What the driver must do next, from [`Flasher::step`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step<'a> {
+    /// Switch the line to `baud`.
+    SetBaud(u32),
+    /// Wait this long.
+    Sleep(Duration),
+    /// Drop pending input if `clear` is set, write `data`, then read until
+    /// `reply` bytes arrived or nothing arrived for `timeout`.
+    Exchange {
+        data: &'a [u8],
+        reply: usize,
+        timeout: Duration,
+        clear: bool,
+    },
+    /// The flash is complete.
+    Done,
+}
+
+/// Progress of a flash, from [`Flasher::complete`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum FlasherEvent {
+    /// A new phase started.
+    Stage(Stage),
+    /// The device is this model, one the firmware is built for.
+    Model(u16),
+    /// The device didn't confirm the torque-off write; the flash goes on.
+    TorqueNotDisabled,
+    /// Frame `frame` of `total`, with bootloader index `index`, was ACKed
+    /// after `retries` resends.
+    Frame {
+        frame: usize,
+        total: usize,
+        index: u8,
+        retries: u32,
+    },
+    /// The frame with `index` was NAKed and is sent again.
+    Nak { index: u8, attempt: u16 },
+    /// Attempt `attempt` of the frame with `index` got no ACK within the
+    /// frame timeout; it is sent again.
+    NoAck { index: u8, attempt: u16 },
+}
+
+/// Why a [`Flasher`] gave up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlasherError {
+    /// The firmware image has no bytes.
+    EmptyFirmware,
+    /// The `len`-byte image exceeds [`FlasherConfig::max_firmware_size`].
+    FirmwareTooLarge { len: usize, max: usize },
+    /// The region to flash doesn't lie within the `len`-byte image.
+    RegionOutOfRange {
+        offset: usize,
+        length: Option<usize>,
+        len: usize,
+    },
+    /// Device `id` didn't answer a ping or the model read at the
+    /// application baud rate.
+    NoDevice { id: u8 },
+    /// The device model is not one of [`FlasherConfig::expected_models`].
+    IncompatibleModel { device: u16, expected: Vec<u16> },
+    /// The magic was not ACKed; `reply` is the byte received instead.
+    NotInBootloader { reply: Option<u8> },
+    /// No reply during `stage` within the reply timeout.
+    NoReply { stage: Stage },
+    /// A byte other than ACK or NAK during `stage`.
+    UnexpectedReply { stage: Stage, byte: u8 },
+    /// The frame with `index` was NAKed on every one of its `attempts`
+    /// sends, the first and each resend.
+    FrameNakExhausted { index: u8, attempts: u16 },
+    /// The frame with `index` got no ACK within `timeout` on any of its
+    /// `attempts` sends.
+    AckTimeout {
+        index: u8,
+        attempts: u16,
+        timeout: Duration,
+    },
+    /// The last frame, whose stop byte ends the transfer, was NAKed on
+    /// every one of its `attempts` sends after every frame before it was
+    /// ACKed. `chunk` of `chunks` is its place in the transfer.
+    FinalizeRejected {
+        index: u8,
+        attempts: u16,
+        chunk: usize,
+        chunks: usize,
+    },
+    /// [`FlasherConfig::max_total_retries`] ran out. `naks` lists `(chunk
+    /// number, NAK count)` for every NAKed frame.
+    RetryBudgetExceeded {
+        budget: u32,
+        naks: Vec<(usize, u32)>,
+    },
+    /// The image is written, but device `id` never answered afterwards. It
+    /// needs a power cycle rather than another flash.
+    BootNotConfirmed { id: u8 },
+}
+
+impl fmt::Display for FlasherError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlasherError::EmptyFirmware => write!(f, "Firmware image is empty"),
+            FlasherError::FirmwareTooLarge { len, max } => write!(
+                f,
+                "Firmware image is {len} bytes, more than the {max}-byte limit; refusing to flash"
+            ),
+            FlasherError::RegionOutOfRange {
+                offset,
+                length: Some(length),
+                len,
+            } => write!(
+                f,
+                "Region of 0x{length:X} bytes at offset 0x{offset:X} doesn't fit the {len}-byte image"
+            ),
+            FlasherError::RegionOutOfRange {
+                offset,
+                length: None,
+                len,
+            } => write!(
+                f,
+                "Offset 0x{offset:X} is past the end of the {len}-byte image"
+            ),
+            FlasherError::NoDevice { id } => write!(f, "Device id {id} does not answer"),
+            FlasherError::IncompatibleModel { device, expected } => {
+                write!(f, "Device is model {device}, but the firmware is for model")?;
+                for (i, model) in expected.iter().enumerate() {
+                    write!(f, "{}{model}", if i == 0 { " " } else { ", " })?;
+                }
+                write!(f, "; refusing to flash")
+            }
+            FlasherError::NotInBootloader { reply: None } => {
+                write!(f, "Device is not in bootloader mode (no magic ACK)")
+            }
+            FlasherError::NotInBootloader { reply: Some(byte) } => write!(
+                f,
+                "Device is not in bootloader mode (magic answered with 0x{byte:02X})"
+            ),
+            FlasherError::NoReply { stage } => {
+                write!(f, "No reply from the bootloader during {stage}")
+            }
+            FlasherError::UnexpectedReply { stage, byte } => write!(
+                f,
+                "Unexpected bootloader response 0x{byte:02X} during {stage} (expected 0x06 or 0x15)"
+            ),
+            FlasherError::FrameNakExhausted { index, attempts } => write!(
+                f,
+                "Bootloader NAK for frame index {index} on all {attempts} sends"
+            ),
+            FlasherError::AckTimeout {
+                index,
+                attempts,
+                timeout,
+            } => write!(
+                f,
+                "No ACK for frame index {index} within {} ms on any of {attempts} sends",
+                timeout.as_millis()
+            ),
+            FlasherError::FinalizeRejected {
+                index,
+                attempts,
+                chunk,
+                chunks,
+            } => write!(
+                f,
+                "Bootloader rejected the last frame (index {index}, chunk {chunk}/{chunks}) \
+                 on all {attempts} sends, though it ACKed every frame before it; \
+                 it refused to complete programming, check the end of the image"
+            ),
+            FlasherError::RetryBudgetExceeded { budget, naks } => {
+                write!(f, "Retry budget of {budget} exhausted; NAKs per chunk:")?;
+                for (chunk, count) in naks {
+                    write!(f, " #{chunk} x{count}")?;
+                }
+                write!(f, ". The link is marginal, check wiring and power")
+            }
+            FlasherError::BootNotConfirmed { id } => write!(
+                f,
+                "Device id {id} did not answer after the transfer; power cycle it"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for FlasherError {}
+
+/// Step of the sequence a [`Flasher`] is at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum State {
+    Ping,
+    Model,
+    Torque,
+    Reboot,
+    BootloaderBaud,
+    RebootDelay,
+    Magic,
+    Init,
+    /// Attempt `attempt` of the frame in `out`; `clear` after a missed ACK.
+    Frame {
+        attempt: u16,
+        clear: bool,
+    },
+    FrameDelay,
+    AppBaud,
+    Settle,
+    Confirm {
+        attempt: u32,
+    },
+    Done,
+}
+
+/// Where a [`Flasher`] stops: once the magic or init is ACKed, after the
+/// last frame, or once the new firmware answers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Until {
+    #[cfg(feature = "std")]
+    Magic,
+    Init,
+    Transfer,
+    Done,
+}
+
+/// The flashing protocol as a state machine; see the [module
+/// docs](self).
+///
+/// ```
+/// use feeflash::flasher::{Flasher, FlasherConfig, Step};
+///
+/// let firmware = [0u8; 100];
+/// let mut flasher = Flasher::new(1, &firmware, FlasherConfig::default()).unwrap();
+/// // First a ping of the application, which must answer.
+/// assert!(matches!(flasher.step(), Step::Exchange { reply: 6, .. }));
+/// assert!(flasher.complete(&[0xFF, 0xFF, 0x01, 0x02, 0x00, 0xFC]).is_ok());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Flasher<'a> {
+    id: u8,
+    config: FlasherConfig,
+    /// The bytes sent: the region of the image, padded to whole pages.
+    data: Cow<'a, [u8]>,
+    requested: Range<usize>,
+    region: Range<usize>,
+    total: usize,
+    acked: usize,
+    retries: u32,
+    /// `(chunk number, NAK count)` of every NAKed frame, for
+    /// [`FlasherError::RetryBudgetExceeded`].
+    naks: Vec<(usize, u32)>,
+    until: Until,
+    state: State,
+    /// Bytes written by the current exchange.
+    out: Vec<u8>,
+    /// Bootloader index of the frame in `out`.
+    index: u8,
+    is_last: bool,
+    /// Resends allowed for the frame in `out`.
+    limit: u8,
+}
+
+impl<'a> Flasher<'a> {
+    /// Flash `firmware` onto device `id`, which must be running its
+    /// application at `config.app_baud`, and wait for the new firmware.
+    pub fn new(id: u8, firmware: &'a [u8], config: FlasherConfig) -> Result<Self, FlasherError> {
+        let mut flasher = Flasher::prepare(id, firmware, config, Until::Done)?;
+        flasher.enter(State::Ping);
+        Ok(flasher)
+    }
+
+    /// Send the frames of `firmware` to a bootloader that ACKed init, and
+    /// stop after the last one.
+    pub fn transfer(firmware: &'a [u8], config: FlasherConfig) -> Result<Self, FlasherError> {
+        let mut flasher = Flasher::prepare(0, firmware, config, Until::Transfer)?;
+        flasher.next_frame();
+        Ok(flasher)
+    }
+
+    /// [`new`](Self::new) without the first ping, stopping after the last
+    /// frame: what `bootloader::flash_device` does.
+    #[cfg(feature = "std")]
+    pub(crate) fn to_transfer(
+        id: u8,
+        firmware: &'a [u8],
+        config: FlasherConfig,
+    ) -> Result<Self, FlasherError> {
+        let mut flasher = Flasher::prepare(id, firmware, config, Until::Transfer)?;
+        flasher.enter(State::Model);
+        Ok(flasher)
+    }
+
+    /// Part of the handshake with device `id`, from `start` on, for the
+    /// steps of `bootloader` and `session` that stop before the transfer.
+    pub(crate) fn handshake(id: u8, config: FlasherConfig, start: State, until: Until) -> Self {
+        let mut flasher = Flasher {
+            id,
+            config,
+            data: Cow::Borrowed(&[]),
+            requested: 0..0,
+            region: 0..0,
+            total: 0,
+            acked: 0,
+            retries: 0,
+            naks: Vec::new(),
+            until,
+            state: State::Done,
+            out: Vec::new(),
+            index: 0,
+            is_last: false,
+            limit: 0,
+        };
+        flasher.enter(start);
+        flasher
+    }
+
+    /// Check `firmware` and cut it to the region of `config`.
+    fn prepare(
+        id: u8,
+        firmware: &'a [u8],
+        config: FlasherConfig,
+        until: Until,
+    ) -> Result<Self, FlasherError> {
+        let len = firmware.len();
+        if len == 0 {
+            return Err(FlasherError::EmptyFirmware);
+        }
+        if len > config.max_firmware_size {
+            return Err(FlasherError::FirmwareTooLarge {
+                len,
+                max: config.max_firmware_size,
+            });
+        }
+        let requested = requested_region(len, config.region_offset, config.region_length)?;
+        let region = round_region(len, &requested);
+        let data = &firmware[region.clone()];
+        let data = match config.flash_page_size {
+            Some(page_size) => pad_to_page(data, page_size),
+            None => Cow::Borrowed(data),
+        };
+        let mut flasher = Flasher::handshake(id, config, State::Done, until);
+        flasher.total = data.len().div_ceil(CHUNK_SIZE);
+        flasher.data = data;
+        flasher.requested = requested;
+        flasher.region = region;
+        Ok(flasher)
+    }
+
+    /// What to do next. Call [`complete`](Self::complete) once it is done.
+    pub fn step(&self) -> Step<'_> {
+        let config = &self.config;
+        let exchange = |reply, timeout, clear| Step::Exchange {
+            data: &self.out,
+            reply,
+            timeout,
+            clear,
+        };
+        match self.state {
+            State::Ping | State::Confirm { .. } => {
+                exchange(ping_reply_len(config.protocol), config.ping_timeout, true)
+            }
+            // Status with the two bytes of the model number.
+            State::Model => exchange(STATUS_LEN + 2, config.ping_timeout, true),
+            State::Torque => exchange(STATUS_LEN, config.ping_timeout, true),
+            // The device resets without answering.
+            State::Reboot => exchange(0, config.ping_timeout, true),
+            State::BootloaderBaud => Step::SetBaud(config.bootloader_baud),
+            State::RebootDelay => Step::Sleep(config.reboot_delay),
+            State::Magic | State::Init => exchange(1, config.reply_timeout, true),
+            State::Frame { clear, .. } => exchange(
+                1,
+                config.frame_timeout.unwrap_or(config.reply_timeout),
+                clear,
+            ),
+            State::FrameDelay => Step::Sleep(config.inter_frame_delay),
+            State::AppBaud => Step::SetBaud(config.app_baud),
+            State::Settle => Step::Sleep(config.boot_settle),
+            State::Done => Step::Done,
+        }
+    }
+
+    /// Finish the current step. For an exchange, `reply` holds the bytes
+    /// read, fewer than asked for if the read timed out; other steps take
+    /// an empty slice. Returns what to report, if anything.
+    pub fn complete(&mut self, reply: &[u8]) -> Result<Option<FlasherEvent>, FlasherError> {
+        let first = reply.first().copied();
+        match self.state {
+            State::Ping => {
+                if !is_status_from(self.config.protocol, self.id, reply) {
+                    return Err(FlasherError::NoDevice { id: self.id });
+                }
+                self.enter(State::Model);
+                Ok(Some(FlasherEvent::Stage(Stage::Handshake)))
+            }
+            State::Model => {
+                if reply.len() != STATUS_LEN + 2
+                    || !is_status_from(ProtocolVersion::V1, self.id, reply)
+                {
+                    return Err(FlasherError::NoDevice { id: self.id });
+                }
+                let model = u16::from_le_bytes([reply[5], reply[6]]);
+                let expected = self.config.expected_models.as_deref().unwrap_or_default();
+                if !expected.contains(&model) {
+                    return Err(FlasherError::IncompatibleModel {
+                        device: model,
+                        expected: expected.to_vec(),
+                    });
+                }
+                self.enter(State::Torque);
+                Ok(Some(FlasherEvent::Model(model)))
+            }
+            State::Torque => {
+                let confirmed = is_status_from(ProtocolVersion::V1, self.id, reply);
+                self.enter(State::Reboot);
+                Ok((!confirmed).then_some(FlasherEvent::TorqueNotDisabled))
+            }
+            State::Reboot => {
+                self.enter(State::BootloaderBaud);
+                Ok(None)
+            }
+            State::BootloaderBaud => {
+                self.enter(State::RebootDelay);
+                Ok(None)
+            }
+            State::RebootDelay => {
+                self.enter(State::Magic);
+                Ok(None)
+            }
+            State::Magic => {
+                if first != Some(BOOTLOADER_ACK) {
+                    return Err(FlasherError::NotInBootloader { reply: first });
+                }
+                self.enter(match self.until {
+                    #[cfg(feature = "std")]
+                    Until::Magic => State::Done,
+                    _ => State::Init,
+                });
+                Ok(None)
+            }
+            State::Init => {
+                expect_ack(first, Stage::Handshake)?;
+                if self.until == Until::Init {
+                    self.enter(State::Done);
+                    return Ok(None);
+                }
+                self.next_frame();
+                Ok(Some(FlasherEvent::Stage(Stage::Transfer)))
+            }
+            State::Frame { attempt, .. } => {
+                let judged = judge_frame_reply(
+                    reply,
+                    self.index,
+                    attempt,
+                    self.limit,
+                    self.config.frame_timeout,
+                );
+                match judged.map_err(|e| self.failed(e))? {
+                    FrameReply::Resend { missed } => {
+                        self.retries += 1;
+                        self.state = State::Frame {
+                            attempt: attempt + 1,
+                            clear: missed,
+                        };
+                        let (index, attempt) = (self.index, attempt);
+                        Ok(Some(if missed {
+                            FlasherEvent::NoAck { index, attempt }
+                        } else {
+                            FlasherEvent::Nak { index, attempt }
+                        }))
+                    }
+                    FrameReply::Ack => Ok(Some(self.acked(attempt))),
+                }
+            }
+            State::FrameDelay => {
+                self.next_frame();
+                Ok(None)
+            }
+            State::AppBaud => {
+                self.enter(State::Settle);
+                Ok(None)
+            }
+            State::Settle => {
+                self.enter(State::Confirm { attempt: 1 });
+                Ok(Some(FlasherEvent::Stage(Stage::Confirm)))
+            }
+            State::Confirm { attempt } => {
+                if is_status_from(self.config.protocol, self.id, reply) {
+                    self.enter(State::Done);
+                    return Ok(Some(FlasherEvent::Stage(Stage::Done)));
+                }
+                if self.config.ping_timeout * attempt >= self.config.boot_confirm_timeout {
+                    return Err(FlasherError::BootNotConfirmed { id: self.id });
+                }
+                self.state = State::Confirm {
+                    attempt: attempt + 1,
+                };
+                Ok(None)
+            }
+            State::Done => Ok(None),
+        }
+    }
+
+    /// Frames ACKed so far and the total.
+    pub fn progress(&self) -> (usize, usize) {
+        (self.acked, self.total)
+    }
+
+    /// Resends caused by NAKs and missed ACKs so far.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Resends allowed for the frame being sent: `max_retries`, or less
+    /// when the retry budget runs low.
+    pub fn retry_limit(&self) -> u8 {
+        self.limit
+    }
+
+    /// Whether the flash is complete.
+    pub fn is_done(&self) -> bool {
+        self.state == State::Done
+    }
+
+    /// ID of the device.
+    pub fn id(&self) -> u8 {
+        self.id
+    }
+
+    /// The bytes sent: the [`region`](Self::region) of the image, padded
+    /// to [`FlasherConfig::flash_page_size`].
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// The part of the image sent, see [`firmware_region`].
+    pub fn region(&self) -> Range<usize> {
+        self.region.clone()
+    }
+
+    /// The region as configured, before rounding to whole frames.
+    pub fn requested_region(&self) -> Range<usize> {
+        self.requested.clone()
+    }
+
+    /// Settings of the flash.
+    pub fn config(&self) -> &FlasherConfig {
+        &self.config
+    }
+
+    pub(crate) fn state(&self) -> State {
+        self.state
+    }
+
+    /// Bootloader index of the frame being sent, and whether it is the
+    /// last.
+    #[cfg(any(test, feature = "std"))]
+    pub(crate) fn frame(&self) -> (u8, bool) {
+        (self.index, self.is_last)
+    }
+
+    /// Move to `state`, skipping the model check and torque write when
+    /// they are off, and load what it writes.
+    fn enter(&mut self, state: State) {
+        let state = match state {
+            State::Model if self.config.expected_models.is_none() => State::Torque,
+            state => state,
+        };
+        let state = match state {
+            State::Torque if !self.config.torque_off => State::Reboot,
+            state => state,
+        };
+        let (id, protocol) = (self.id, self.config.protocol);
+        match state {
+            State::Ping | State::Confirm { .. } => self.out = packet(protocol, id, Command::Ping),
+            State::Model => {
+                self.out = build_dyn_packet(id, Instruction::ReadData, &[MODEL_ADDRESS, 2])
+                    .expect("two parameters")
+            }
+            State::Torque => {
+                self.out = build_dyn_packet(id, Instruction::WriteData, &[TORQUE_ENABLE_ADDRESS, 0])
+                    .expect("two parameters")
+            }
+            State::Reboot => self.out = packet(protocol, id, Command::Reboot),
+            State::Magic => self.out = BOOTLOADER_MAGIC.to_vec(),
+            State::Init => self.out = alloc::vec![BOOTLOADER_INIT],
+            _ => {}
+        }
+        self.state = state;
+    }
+
+    /// Frame `acked + 1` was ACKed on attempt `attempt`: count it and move
+    /// on to the next frame, after the inter-frame delay.
+    fn acked(&mut self, attempt: u16) -> FlasherEvent {
+        let retries = u32::from(attempt - 1);
+        self.acked += 1;
+        if retries > 0 {
+            self.naks.push((self.acked, retries));
+        }
+        let event = FlasherEvent::Frame {
+            frame: self.acked,
+            total: self.total,
+            index: self.index,
+            retries,
+        };
+        if self.is_last {
+            self.enter(match self.until {
+                Until::Done => State::AppBaud,
+                _ => State::Done,
+            });
+        } else if self.config.inter_frame_delay.is_zero() {
+            self.next_frame();
+        } else {
+            self.state = State::FrameDelay;
+        }
+        event
+    }
+
+    /// Load frame `acked + 1`, with the resends the budget leaves it.
+    fn next_frame(&mut self) {
+        let frame = FirmwareFrames::new(&self.data)
+            .nth(self.acked)
+            .expect("frames left");
+        self.index = frame.index;
+        self.is_last = frame.is_last;
+        self.out = frame.encode(self.config.checksum).to_vec();
+        // Never allow more resends for this frame than the budget has left.
+        let spent: u32 = self.naks.iter().map(|&(_, count)| count).sum();
+        self.limit = match self.config.max_total_retries {
+            Some(budget) => {
+                let left = budget.saturating_sub(spent);
+                self.config
+                    .max_retries
+                    .min(left.min(u32::from(u8::MAX)) as u8)
+            }
+            None => self.config.max_retries,
+        };
+        self.state = State::Frame {
+            attempt: 1,
+            clear: false,
+        };
+    }
+
+    /// The frame in `out` failed with `err`. NAKs past a retry limit the
+    /// budget cut become `RetryBudgetExceeded`, NAKs of the last frame
+    /// `FinalizeRejected`.
+    fn failed(&mut self, err: FlasherError) -> FlasherError {
+        let FlasherError::FrameNakExhausted { index, .. } = err else {
+            return err;
+        };
+        let chunk = self.acked + 1;
+        if let (true, Some(budget)) = (
+            self.limit < self.config.max_retries,
+            self.config.max_total_retries,
+        ) {
+            self.naks.push((chunk, u32::from(self.limit) + 1));
+            return FlasherError::RetryBudgetExceeded {
+                budget,
+                naks: core::mem::take(&mut self.naks),
+            };
+        }
+        if self.is_last {
+            // Every send of it drew a NAK: the first and each resend.
+            return FlasherError::FinalizeRejected {
+                index,
+                attempts: u16::from(self.limit) + 1,
+                chunk,
+                chunks: self.total,
+            };
+        }
+        err
+    }
+}
+
+/// What the reply to a frame calls for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum FrameReply {
+    Ack,
+    /// Send the frame again; `missed` when no reply came in time.
+    Resend {
+        missed: bool,
+    },
+}
+
+/// Judge `reply` to attempt `attempt` of the frame with `index`, which may
+/// be resent `limit` times. An empty reply is a missed ACK, resent like a
+/// NAK when there is a `frame_timeout` and a failure without one.
+pub(crate) fn judge_frame_reply(
+    reply: &[u8],
+    index: u8,
+    attempt: u16,
+    limit: u8,
+    frame_timeout: Option<Duration>,
+) -> Result<FrameReply, FlasherError> {
+    match (reply.first().copied(), frame_timeout) {
+        (Some(BOOTLOADER_ACK), _) => Ok(FrameReply::Ack),
+        (Some(BOOTLOADER_NAK), _) if attempt > u16::from(limit) => {
+            Err(FlasherError::FrameNakExhausted {
+                index,
+                attempts: attempt,
+            })
+        }
+        (Some(BOOTLOADER_NAK), _) => Ok(FrameReply::Resend { missed: false }),
+        (Some(byte), _) => Err(FlasherError::UnexpectedReply {
+            stage: Stage::Transfer,
+            byte,
+        }),
+        (None, Some(timeout)) if attempt > u16::from(limit) => Err(FlasherError::AckTimeout {
+            index,
+            attempts: attempt,
+            timeout,
+        }),
+        (None, Some(_)) => Ok(FrameReply::Resend { missed: true }),
+        (None, None) => Err(FlasherError::NoReply {
+            stage: Stage::Transfer,
+        }),
+    }
+}
+
+fn expect_ack(reply: Option<u8>, stage: Stage) -> Result<(), FlasherError> {
+    match reply {
+        Some(BOOTLOADER_ACK) => Ok(()),
+        Some(byte) => Err(FlasherError::UnexpectedReply { stage, byte }),
+        None => Err(FlasherError::NoReply { stage }),
+    }
+}
+
+/// Bytes of a `len`-byte image that are sent, for a region of `length`
+/// bytes from `offset` (`None` for the rest of the image).
+///
+/// The requested region must lie within the image, else this fails with
+/// `RegionOutOfRange`. Its start is then rounded down and its end up to
+/// whole [`CHUNK_SIZE`] frames, the end no further than the end of the
+/// image.
+pub fn firmware_region(
+    len: usize,
+    offset: usize,
+    length: Option<usize>,
+) -> Result<Range<usize>, FlasherError> {
+    let requested = requested_region(len, offset, length)?;
+    Ok(round_region(len, &requested))
+}
+
+/// The region of a `len`-byte image as given to [`firmware_region`],
+/// checked against the image but not rounded.
+pub fn requested_region(
+    len: usize,
+    offset: usize,
+    length: Option<usize>,
+) -> Result<Range<usize>, FlasherError> {
+    let out_of_range = FlasherError::RegionOutOfRange {
+        offset,
+        length,
+        len,
+    };
+    if offset >= len {
+        return Err(out_of_range);
+    }
+    match length {
+        Some(0) => Err(FlasherError::EmptyFirmware),
+        Some(length) => match offset.checked_add(length) {
+            Some(end) if end <= len => Ok(offset..end),
+            _ => Err(out_of_range),
+        },
+        None => Ok(offset..len),
+    }
+}
+
+fn round_region(len: usize, requested: &Range<usize>) -> Range<usize> {
+    let start = requested.start - requested.start % CHUNK_SIZE;
+    let end = requested.end.next_multiple_of(CHUNK_SIZE).min(len);
+    start..end
+}
+
+/// Instructions of the application side that the flash sends in either
+/// protocol.
+#[derive(Debug, Clone, Copy)]
+enum Command {
+    Ping,
+    Reboot,
+}
+
+/// `command` to `id` in `protocol`.
+fn packet(protocol: ProtocolVersion, id: u8, command: Command) -> Vec<u8> {
+    match protocol {
+        ProtocolVersion::V1 => {
+            let instruction = match command {
+                Command::Ping => Instruction::Ping,
+                Command::Reboot => Instruction::Reboot,
+            };
+            build_dyn_packet(id, instruction, &[]).expect("no parameters")
+        }
+        ProtocolVersion::V2 => {
+            let instruction = match command {
+                Command::Ping => dynamixel2::packet::Instruction::Ping,
+                Command::Reboot => dynamixel2::packet::Instruction::Reboot,
+            };
+            dynamixel2::packet::build_packet(id, instruction, &[]).expect("no parameters")
+        }
+    }
+}
+
+fn ping_reply_len(protocol: ProtocolVersion) -> usize {
+    match protocol {
+        ProtocolVersion::V1 => STATUS_LEN,
+        ProtocolVersion::V2 => STATUS_LEN_V2,
+    }
+}
+
+/// Whether `reply` is a valid status packet from `id`.
+fn is_status_from(protocol: ProtocolVersion, id: u8, reply: &[u8]) -> bool {
+    match protocol {
+        ProtocolVersion::V1 => validate_dyn_packet(reply).is_ok() && reply[2] == id,
+        ProtocolVersion::V2 => dynamixel2::packet::is_status_from(reply, id),
+    }
+}
+
+/// A serial line driven by async I/O, like the Web Serial API. The
+/// futures need not be `Send`: browsers run them on one thread.
+#[allow(async_fn_in_trait)]
+pub trait AsyncTransport {
+    type Error;
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Read what arrived into `buf`, waiting at most `timeout` for the
+    /// first byte. Returns 0 when nothing arrived in time.
+    async fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Self::Error>;
+
+    /// Drop bytes received but not read yet.
+    async fn clear_input(&mut self) -> Result<(), Self::Error>;
+
+    async fn set_baud_rate(&mut self, baud: u32) -> Result<(), Self::Error>;
+
+    /// Wait `duration` on the timer of the platform.
+    async fn sleep(&mut self, duration: Duration);
+}
+
+/// Failure of [`flash`] or [`flash_blocking`]: the transport's, or the
+/// protocol's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DriverError<E> {
+    Transport(E),
+    Flasher(FlasherError),
+}
+
+impl<E> From<FlasherError> for DriverError<E> {
+    fn from(err: FlasherError) -> Self {
+        DriverError::Flasher(err)
+    }
+}
+
+impl<E: fmt::Display> fmt::Display for DriverError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverError::Transport(e) => e.fmt(f),
+            DriverError::Flasher(e) => e.fmt(f),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> core::error::Error for DriverError<E> {}
+
+/// Run `flasher` to the end over `port`, passing every event to
+/// `on_event`.
+pub async fn flash<T: AsyncTransport>(
+    port: &mut T,
+    flasher: &mut Flasher<'_>,
+    mut on_event: impl FnMut(FlasherEvent),
+) -> Result<(), DriverError<T::Error>> {
+    if flasher.state() == State::Ping {
+        on_event(FlasherEvent::Stage(Stage::Ping));
+    }
+    loop {
+        let reply = match flasher.step() {
+            Step::Done => return Ok(()),
+            Step::SetBaud(baud) => {
+                port.set_baud_rate(baud)
+                    .await
+                    .map_err(DriverError::Transport)?;
+                Vec::new()
+            }
+            Step::Sleep(duration) => {
+                port.sleep(duration).await;
+                Vec::new()
+            }
+            Step::Exchange {
+                data,
+                reply,
+                timeout,
+                clear,
+            } => exchange(port, data, reply, timeout, clear)
+                .await
+                .map_err(DriverError::Transport)?,
+        };
+        if let Some(event) = flasher.complete(&reply)? {
+            on_event(event);
+        }
+    }
+}
+
+/// A serial line driven by blocking I/O on a host without an OS, like the
+/// UART of an embedded HAL (see the `embedded` feature): [`AsyncTransport`]
+/// without the `async`.
+pub trait BlockingTransport {
+    type Error;
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), Self::Error>;
+
+    /// Read what arrived into `buf`, waiting at most `timeout` for the
+    /// first byte. Returns 0 when nothing arrived in time.
+    fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, Self::Error>;
+
+    /// Drop bytes received but not read yet.
+    fn clear_input(&mut self) -> Result<(), Self::Error>;
+
+    fn set_baud_rate(&mut self, baud: u32) -> Result<(), Self::Error>;
+
+    /// Wait `duration`, e.g. spinning on a hardware timer.
+    fn sleep(&mut self, duration: Duration);
+}
+
+/// [`flash`] over a [`BlockingTransport`].
+pub fn flash_blocking<T: BlockingTransport>(
+    port: &mut T,
+    flasher: &mut Flasher<'_>,
+    mut on_event: impl FnMut(FlasherEvent),
+) -> Result<(), DriverError<T::Error>> {
+    if flasher.state() == State::Ping {
+        on_event(FlasherEvent::Stage(Stage::Ping));
+    }
+    loop {
+        let reply = match flasher.step() {
+            Step::Done => return Ok(()),
+            Step::SetBaud(baud) => {
+                port.set_baud_rate(baud).map_err(DriverError::Transport)?;
+                Vec::new()
+            }
+            Step::Sleep(duration) => {
+                port.sleep(duration);
+                Vec::new()
+            }
+            Step::Exchange {
+                data,
+                reply,
+                timeout,
+                clear,
+            } => exchange_blocking(port, data, reply, timeout, clear)
+                .map_err(DriverError::Transport)?,
+        };
+        if let Some(event) = flasher.complete(&reply)? {
+            on_event(event);
+        }
+    }
+}
+
+/// Whether device `id` answers a protocol 1 ping within `timeout`.
+pub async fn ping<T: AsyncTransport>(
+    port: &mut T,
+    id: u8,
+    timeout: Duration,
+) -> Result<bool, T::Error> {
+    let packet = packet(ProtocolVersion::V1, id, Command::Ping);
+    let reply = exchange(port, &packet, STATUS_LEN, timeout, true).await?;
+    Ok(is_status_from(ProtocolVersion::V1, id, &reply))
+}
+
+/// Ping every ID in `ids` in turn; returns those that answered.
+pub async fn scan<T: AsyncTransport>(
+    port: &mut T,
+    ids: RangeInclusive<u8>,
+    timeout: Duration,
+) -> Result<Vec<u8>, T::Error> {
+    let mut found = Vec::new();
+    for id in ids {
+        if ping(port, id, timeout).await? {
+            found.push(id);
+        }
+    }
+    Ok(found)
+}
+
+/// Clear the input if `clear` is set, write `data` and read up to `reply`
+/// bytes, stopping early when a read times out.
+async fn exchange<T: AsyncTransport>(
+    port: &mut T,
+    data: &[u8],
+    reply: usize,
+    timeout: Duration,
+    clear: bool,
+) -> Result<Vec<u8>, T::Error> {
+    if clear {
+        port.clear_input().await?;
+    }
+    port.write_all(data).await?;
+    let mut reply = ReplyBuffer::new(reply);
+    while let Some(buf) = reply.unfilled() {
+        let n = port.read(buf, timeout).await?;
+        reply.advance(n);
+    }
+    Ok(reply.finish())
+}
+
+/// [`exchange`] over a [`BlockingTransport`].
+fn exchange_blocking<T: BlockingTransport>(
+    port: &mut T,
+    data: &[u8],
+    reply: usize,
+    timeout: Duration,
+    clear: bool,
+) -> Result<Vec<u8>, T::Error> {
+    if clear {
+        port.clear_input()?;
+    }
+    port.write_all(data)?;
+    let mut reply = ReplyBuffer::new(reply);
+    while let Some(buf) = reply.unfilled() {
+        let n = port.read(buf, timeout)?;
+        reply.advance(n);
+    }
+    Ok(reply.finish())
+}
+
+/// The reply to a [`Step::Exchange`], filled over as many reads as it
+/// takes. Every driver reads through one, so they agree on when a reply
+/// is complete: once `reply` bytes are in, or after a read of none, which
+/// the drivers also report for a read that timed out.
+pub(crate) struct ReplyBuffer {
+    buf: Vec<u8>,
+    got: usize,
+    ended: bool,
+}
+
+impl ReplyBuffer {
+    pub(crate) fn new(len: usize) -> Self {
+        ReplyBuffer {
+            buf: alloc::vec![0u8; len],
+            got: 0,
+            ended: false,
+        }
+    }
+
+    /// Where the next read goes; `None` once the reply is complete.
+    pub(crate) fn unfilled(&mut self) -> Option<&mut [u8]> {
+        if self.ended || self.got == self.buf.len() {
+            return None;
+        }
+        Some(&mut self.buf[self.got..])
+    }
+
+    /// Count a read of `n` bytes into [`ReplyBuffer::unfilled`]; 0 ends the
+    /// reply.
+    pub(crate) fn advance(&mut self, n: usize) {
+        if n == 0 {
+            self.ended = true;
+        }
+        self.got += n;
+    }
+
+    /// The bytes received.
+    pub(crate) fn finish(mut self) -> Vec<u8> {
+        self.buf.truncate(self.got);
+        self.buf
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ACK_REPLY: &[u8] = &[BOOTLOADER_ACK];
+    const NAK_REPLY: &[u8] = &[BOOTLOADER_NAK];
+
+    fn status(id: u8) -> Vec<u8> {
+        let mut packet = alloc::vec![0xFF, 0xFF, id, 0x02, 0x00];
+        packet.push(!(id.wrapping_add(2)));
+        packet
+    }
+
+    /// Complete the steps from the first ping up to the first frame.
+    fn to_transfer(flasher: &mut Flasher) {
+        flasher.complete(&status(1)).unwrap();
+        // Torque off, reboot, bootloader baud and reboot delay.
+        for _ in 0..4 {
+            flasher.complete(&[]).unwrap();
+        }
+        assert_eq!(
+            flasher.step(),
+            Step::Exchange {
+                data: BOOTLOADER_MAGIC,
+                reply: 1,
+                timeout: Duration::from_secs(10),
+                clear: true,
+            }
+        );
+        flasher.complete(ACK_REPLY).unwrap();
+        assert_eq!(
+            flasher.complete(ACK_REPLY).unwrap(),
+            Some(FlasherEvent::Stage(Stage::Transfer))
+        );
+    }
+
+    #[test]
+    fn runs_the_whole_sequence() {
+        let firmware = [0x5Au8; 100];
+        let mut flasher = Flasher::new(1, &firmware, FlasherConfig::default()).unwrap();
+        to_transfer(&mut flasher);
+
+        for frame in 1..=2 {
+            let Step::Exchange { data, clear, .. } = flasher.step() else {
+                panic!("expected a frame");
+            };
+            assert_eq!(data[0], frame as u8);
+            assert!(!clear);
+            assert_eq!(
+                flasher.complete(ACK_REPLY).unwrap(),
+                Some(FlasherEvent::Frame {
+                    frame,
+                    total: 2,
+                    index: frame as u8,
+                    retries: 0,
+                })
+            );
+        }
+        assert_eq!(flasher.step(), Step::SetBaud(1_000_000));
+        flasher.complete(&[]).unwrap();
+        assert_eq!(flasher.step(), Step::Sleep(REBOOT_DELAY));
+        flasher.complete(&[]).unwrap();
+        // A silent first ping is retried.
+        assert_eq!(flasher.complete(&[]).unwrap(), None);
+        assert_eq!(
+            flasher.complete(&status(1)).unwrap(),
+            Some(FlasherEvent::Stage(Stage::Done))
+        );
+        assert!(flasher.is_done());
+        assert_eq!(flasher.step(), Step::Done);
+    }
+
+    #[test]
+    fn resends_naked_frames_up_to_max_retries() {
+        let firmware = [0u8; 128];
+        let config = FlasherConfig {
+            max_retries: 2,
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        to_transfer(&mut flasher);
+
+        for attempt in 1..=2 {
+            assert_eq!(
+                flasher.complete(NAK_REPLY).unwrap(),
+                Some(FlasherEvent::Nak { index: 1, attempt })
+            );
+            // A NAK needs no clearing: the line is quiet.
+            assert!(matches!(
+                flasher.step(),
+                Step::Exchange { clear: false, .. }
+            ));
+        }
+        assert_eq!(
+            flasher.complete(NAK_REPLY),
+            Err(FlasherError::FrameNakExhausted {
+                index: 1,
+                attempts: 3
+            })
+        );
+        assert_eq!(flasher.retries(), 2);
+    }
+
+    #[test]
+    fn reply_buffer_fills_over_reads_and_stops_on_an_empty_one() {
+        let mut reply = ReplyBuffer::new(3);
+        reply.unfilled().unwrap()[..2].copy_from_slice(&[1, 2]);
+        reply.advance(2);
+        assert_eq!(reply.unfilled().unwrap().len(), 1);
+        reply.advance(0);
+        assert!(reply.unfilled().is_none());
+        assert_eq!(reply.finish(), [1, 2]);
+
+        let mut reply = ReplyBuffer::new(1);
+        reply.unfilled().unwrap()[0] = BOOTLOADER_ACK;
+        reply.advance(1);
+        assert!(reply.unfilled().is_none());
+        assert_eq!(reply.finish(), ACK_REPLY);
+        assert!(ReplyBuffer::new(0).unfilled().is_none());
+    }
+
+    #[test]
+    fn max_retries_255_ends_after_256_sends() {
+        let firmware = [0u8; 128];
+        let config = FlasherConfig {
+            max_retries: 255,
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config.clone()).unwrap();
+        to_transfer(&mut flasher);
+        for _ in 0..255 {
+            flasher.complete(NAK_REPLY).unwrap();
+        }
+        assert!(matches!(
+            flasher.complete(ACK_REPLY).unwrap(),
+            Some(FlasherEvent::Frame { retries: 255, .. })
+        ));
+
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        to_transfer(&mut flasher);
+        for _ in 0..255 {
+            flasher.complete(NAK_REPLY).unwrap();
+        }
+        assert!(matches!(
+            flasher.complete(NAK_REPLY),
+            Err(FlasherError::FrameNakExhausted {
+                index: 1,
+                attempts: 256
+            })
+        ));
+    }
+
+    #[test]
+    fn resends_frames_whose_ack_is_missed() {
+        let firmware = [0u8; 128];
+        let config = FlasherConfig {
+            max_retries: 1,
+            frame_timeout: Some(Duration::from_millis(50)),
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        to_transfer(&mut flasher);
+
+        assert!(matches!(
+            flasher.step(),
+            Step::Exchange {
+                timeout,
+                clear: false,
+                ..
+            } if timeout == Duration::from_millis(50)
+        ));
+        assert_eq!(
+            flasher.complete(&[]).unwrap(),
+            Some(FlasherEvent::NoAck {
+                index: 1,
+                attempt: 1
+            })
+        );
+        // The late ACK must not be taken for the answer to the resend.
+        assert!(matches!(flasher.step(), Step::Exchange { clear: true, .. }));
+        assert_eq!(
+            flasher.complete(&[]),
+            Err(FlasherError::AckTimeout {
+                index: 1,
+                attempts: 2,
+                timeout: Duration::from_millis(50),
+            })
+        );
+    }
+
+    #[test]
+    fn checks_the_model_and_disables_torque() {
+        let firmware = [0u8; 64];
+        let config = FlasherConfig {
+            expected_models: Some(alloc::vec![777]),
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config.clone()).unwrap();
+        flasher.complete(&status(1)).unwrap();
+        assert_eq!(
+            flasher.step(),
+            Step::Exchange {
+                data: &[0xFF, 0xFF, 0x01, 0x04, 0x02, 0x03, 0x02, 0xF3],
+                reply: 8,
+                timeout: Duration::from_millis(100),
+                clear: true,
+            }
+        );
+        let model_reply = [0xFF, 0xFF, 0x01, 0x04, 0x00, 0x09, 0x03, 0xEE];
+        assert_eq!(
+            flasher.complete(&model_reply).unwrap(),
+            Some(FlasherEvent::Model(777))
+        );
+        assert!(matches!(
+            flasher.step(),
+            Step::Exchange {
+                data: [0xFF, 0xFF, 0x01, 0x04, 0x03, 0x28, 0x00, 0xCF],
+                ..
+            }
+        ));
+
+        let mut flasher = Flasher::new(
+            1,
+            &firmware,
+            FlasherConfig {
+                expected_models: Some(alloc::vec![1, 2]),
+                ..config
+            },
+        )
+        .unwrap();
+        flasher.complete(&status(1)).unwrap();
+        assert_eq!(
+            flasher.complete(&model_reply),
+            Err(FlasherError::IncompatibleModel {
+                device: 777,
+                expected: alloc::vec![1, 2],
+            })
+        );
+    }
+
+    #[test]
+    fn torque_off_failure_is_only_a_warning() {
+        let firmware = [0u8; 64];
+        let mut flasher = Flasher::new(1, &firmware, FlasherConfig::default()).unwrap();
+        flasher.complete(&status(1)).unwrap();
+        assert_eq!(
+            flasher.complete(&[]).unwrap(),
+            Some(FlasherEvent::TorqueNotDisabled)
+        );
+        assert_eq!(flasher.state(), State::Reboot);
+
+        let config = FlasherConfig {
+            torque_off: false,
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        flasher.complete(&status(1)).unwrap();
+        assert_eq!(flasher.state(), State::Reboot);
+    }
+
+    #[test]
+    fn sends_the_padded_region() {
+        let firmware: Vec<u8> = (0..=255).collect();
+        let config = FlasherConfig {
+            region_offset: 70,
+            region_length: Some(10),
+            flash_page_size: Some(128),
+            ..FlasherConfig::default()
+        };
+        let flasher = Flasher::transfer(&firmware, config).unwrap();
+        assert_eq!(flasher.requested_region(), 70..80);
+        assert_eq!(flasher.region(), 64..128);
+        assert_eq!(flasher.data().len(), 128);
+        assert_eq!(&flasher.data()[..64], &firmware[64..128]);
+        assert!(
+            flasher.data()[64..]
+                .iter()
+                .all(|&b| b == crate::frame::PAD_BYTE)
+        );
+        assert_eq!(flasher.progress(), (0, 2));
+
+        let config = FlasherConfig {
+            region_offset: 300,
+            ..FlasherConfig::default()
+        };
+        assert_eq!(
+            Flasher::transfer(&firmware, config).unwrap_err(),
+            FlasherError::RegionOutOfRange {
+                offset: 300,
+                length: None,
+                len: 256,
+            }
+        );
+        let config = FlasherConfig {
+            max_firmware_size: 100,
+            ..FlasherConfig::default()
+        };
+        assert_eq!(
+            Flasher::transfer(&firmware, config).unwrap_err(),
+            FlasherError::FirmwareTooLarge { len: 256, max: 100 }
+        );
+    }
+
+    #[test]
+    fn rejected_last_frame_is_a_finalize_failure() {
+        let firmware = [0u8; 128];
+        let config = FlasherConfig {
+            max_retries: 2,
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::transfer(&firmware, config).unwrap();
+        flasher.complete(ACK_REPLY).unwrap();
+        assert_eq!(flasher.frame(), (2, true));
+        for _ in 0..2 {
+            flasher.complete(NAK_REPLY).unwrap();
+        }
+        assert_eq!(
+            flasher.complete(NAK_REPLY),
+            Err(FlasherError::FinalizeRejected {
+                index: 2,
+                attempts: 3,
+                chunk: 2,
+                chunks: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn retry_budget_caps_the_resends_of_a_frame() {
+        let firmware = [0u8; 192];
+        let config = FlasherConfig {
+            max_retries: 5,
+            max_total_retries: Some(3),
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::transfer(&firmware, config).unwrap();
+        flasher.complete(NAK_REPLY).unwrap();
+        flasher.complete(NAK_REPLY).unwrap();
+        flasher.complete(ACK_REPLY).unwrap();
+        assert_eq!(flasher.retry_limit(), 1);
+        flasher.complete(NAK_REPLY).unwrap();
+        assert_eq!(
+            flasher.complete(NAK_REPLY),
+            Err(FlasherError::RetryBudgetExceeded {
+                budget: 3,
+                naks: alloc::vec![(1, 2), (2, 2)],
+            })
+        );
+    }
+
+    #[test]
+    fn pauses_between_frames() {
+        let firmware = [0u8; 128];
+        let config = FlasherConfig {
+            inter_frame_delay: Duration::from_millis(15),
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::transfer(&firmware, config).unwrap();
+        flasher.complete(ACK_REPLY).unwrap();
+        assert_eq!(flasher.step(), Step::Sleep(Duration::from_millis(15)));
+        flasher.complete(&[]).unwrap();
+        flasher.complete(ACK_REPLY).unwrap();
+        // Not after the last frame.
+        assert!(flasher.is_done());
+    }
+
+    #[test]
+    fn speaks_protocol_2_to_the_application() {
+        let firmware = [0u8; 64];
+        let config = FlasherConfig {
+            protocol: ProtocolVersion::V2,
+            ..FlasherConfig::default()
+        };
+        let flasher = Flasher::new(1, &firmware, config).unwrap();
+        let Step::Exchange { data, reply, .. } = flasher.step() else {
+            panic!("expected a ping");
+        };
+        assert_eq!(&data[..4], dynamixel2::packet::HEADER);
+        assert_eq!(reply, 14);
+    }
+
+    #[test]
+    fn reports_protocol_failures() {
+        let firmware = [0u8; 64];
+        assert_eq!(
+            Flasher::new(1, &[], FlasherConfig::default()).unwrap_err(),
+            FlasherError::EmptyFirmware
+        );
+
+        let mut flasher = Flasher::new(1, &firmware, FlasherConfig::default()).unwrap();
+        assert_eq!(
+            flasher.complete(&status(2)),
+            Err(FlasherError::NoDevice { id: 1 })
+        );
+
+        let mut flasher = Flasher::new(1, &firmware, FlasherConfig::default()).unwrap();
+        flasher.complete(&status(1)).unwrap();
+        for _ in 0..4 {
+            flasher.complete(&[]).unwrap();
+        }
+        assert_eq!(
+            flasher.complete(NAK_REPLY),
+            Err(FlasherError::NotInBootloader { reply: Some(0x15) })
+        );
+
+        let config = FlasherConfig {
+            frame_timeout: None,
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        to_transfer(&mut flasher);
+        assert_eq!(
+            flasher.complete(&[]),
+            Err(FlasherError::NoReply {
+                stage: Stage::Transfer
+            })
+        );
+    }
+
+    #[test]
+    fn gives_up_confirming_after_the_timeout() {
+        let firmware = [0u8; 64];
+        let config = FlasherConfig {
+            ping_timeout: Duration::from_millis(100),
+            boot_confirm_timeout: Duration::from_millis(300),
+            ..FlasherConfig::default()
+        };
+        let mut flasher = Flasher::new(1, &firmware, config).unwrap();
+        to_transfer(&mut flasher);
+        flasher.complete(ACK_REPLY).unwrap();
+        flasher.complete(&[]).unwrap();
+        flasher.complete(&[]).unwrap();
+
+        assert_eq!(flasher.complete(&[]).unwrap(), None);
+        assert_eq!(flasher.complete(&[]).unwrap(), None);
+        assert_eq!(
+            flasher.complete(&[]),
+            Err(FlasherError::BootNotConfirmed { id: 1 })
+        );
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn control_table_addresses_match_the_registers() {
+        use crate::dynamixel::registers;
+        assert_eq!(MODEL_ADDRESS, registers::MODEL);
+        assert_eq!(TORQUE_ENABLE_ADDRESS, registers::TORQUE_ENABLE);
+    }
+}
@@ -1,5 +1,137 @@
-use crate::crc::crc16_ccitt;
+use alloc::borrow::Cow;
+use alloc::vec::Vec;
+use core::fmt;
 
+use crate::crc::{crc16_ccitt, crc32};
+
+/// Checksum in the trailer of a bootloader frame.
+///
+/// CRC-16 frames are 70 bytes, with the CRC over the first 64 bytes of the
+/// frame (the bootloader's quirk, kept for compatibility). The other kinds
+/// cover index, inverse index, the unknown byte and all 64 data bytes:
+/// CRC-32 frames are 72 bytes, Sum8 frames 69 and Sum16 frames 70.
+/// Multi-byte trailers are big-endian.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ChecksumKind {
+    #[default]
+    Crc16Ccitt,
+    Crc32,
+    /// Byte sum, modulo 256.
+    Sum8,
+    /// Byte sum, modulo 65536.
+    Sum16,
+}
+
+impl ChecksumKind {
+    /// Bytes of checksum in the trailer.
+    pub fn trailer_len(self) -> usize {
+        match self {
+            ChecksumKind::Sum8 => 1,
+            ChecksumKind::Crc16Ccitt | ChecksumKind::Sum16 => 2,
+            ChecksumKind::Crc32 => 4,
+        }
+    }
+
+    /// Total frame size: header, data, checksum and stop byte.
+    pub fn frame_len(self) -> usize {
+        3 + 64 + self.trailer_len() + 1
+    }
+
+    /// Checksum bytes for `head`, the first 67 bytes of a frame (header and
+    /// data).
+    pub fn checksum(self, head: &[u8]) -> Vec<u8> {
+        let (trailer, len) = self.trailer(head);
+        trailer[..len].to_vec()
+    }
+
+    /// [`checksum`](Self::checksum) without allocating: the bytes and how
+    /// many of them are used.
+    fn trailer(self, head: &[u8]) -> ([u8; 4], usize) {
+        let mut trailer = [0u8; 4];
+        let len = self.trailer_len();
+        match self {
+            // crc16_ccitt only looks at the first 64 bytes.
+            ChecksumKind::Crc16Ccitt => {
+                trailer[..2].copy_from_slice(&crc16_ccitt(head).to_be_bytes())
+            }
+            ChecksumKind::Crc32 => trailer.copy_from_slice(&crc32(head).to_be_bytes()),
+            ChecksumKind::Sum8 => trailer[0] = head.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)),
+            ChecksumKind::Sum16 => trailer[..2].copy_from_slice(
+                &head
+                    .iter()
+                    .fold(0u16, |sum, &b| sum.wrapping_add(u16::from(b)))
+                    .to_be_bytes(),
+            ),
+        }
+        (trailer, len)
+    }
+}
+
+/// Longest frame of any [`ChecksumKind`], that of CRC-32.
+pub const MAX_FRAME_LEN: usize = 72;
+
+/// A frame encoded into a fixed buffer by [`BootloaderFrame::encode`];
+/// derefs to the frame bytes.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct FrameBytes {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+}
+
+impl core::ops::Deref for FrameBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl AsRef<[u8]> for FrameBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for FrameBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("FrameBytes")
+            .field(&HexSummary(self))
+            .finish()
+    }
+}
+
+/// Debug rendering of a byte buffer as hex: all of a short one, the first
+/// and last bytes of a long one, with the length.
+struct HexSummary<'a>(&'a [u8]);
+
+impl fmt::Debug for HexSummary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        /// Bytes shown at either end of a long buffer.
+        const EDGE: usize = 4;
+
+        let len = self.0.len();
+        let (head, tail) = if len <= 2 * EDGE {
+            (self.0, &[][..])
+        } else {
+            (&self.0[..EDGE], &self.0[len - EDGE..])
+        };
+        f.write_str("[")?;
+        for (i, b) in head.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{b:02X}")?;
+        }
+        if !tail.is_empty() {
+            f.write_str(" ..")?;
+            tail.iter().try_for_each(|b| write!(f, " {b:02X}"))?;
+        }
+        write!(f, "; {len} bytes]")
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct BootloaderFrame {
     pub index: u8,
     pub unknown_byte: u8,
@@ -7,9 +139,20 @@ pub struct BootloaderFrame {
     pub is_last: bool,
 }
 
+impl fmt::Debug for BootloaderFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BootloaderFrame")
+            .field("index", &self.index)
+            .field("unknown_byte", &self.unknown_byte)
+            .field("data", &HexSummary(&self.data))
+            .field("is_last", &self.is_last)
+            .finish()
+    }
+}
+
 impl BootloaderFrame {
-    /// Build the 70-byte raw frame expected by the bootloader.
-    /// Layout (70 bytes total):
+    /// Build the 70-byte raw frame expected by the bootloader, with its
+    /// CRC-16. Layout (70 bytes total):
     /// [0]   index
     /// [1]   n_index (bitwise inverse of index)
     /// [2]   unknown_byte
@@ -19,31 +162,221 @@ impl BootloaderFrame {
     /// [69]  stop (6 for more data, 4 for last frame)
     pub fn to_bytes(&self) -> [u8; 70] {
         let mut frame = [0u8; 70];
+        frame.copy_from_slice(&self.encode(ChecksumKind::Crc16Ccitt));
+        frame
+    }
+
+    /// The raw frame with a `checksum` trailer:
+    /// [`ChecksumKind::frame_len`] bytes, laid out as in
+    /// [`to_bytes`](Self::to_bytes) with `n` = [`ChecksumKind::trailer_len`]
+    /// big-endian checksum bytes at `[67..67+n)` and the stop byte after.
+    pub fn to_bytes_with(&self, checksum: ChecksumKind) -> Vec<u8> {
+        self.encode(checksum).to_vec()
+    }
 
-        frame[0] = self.index;
-        frame[1] = !self.index; // n_index
-        frame[2] = self.unknown_byte;
-        frame[3..3 + 64].copy_from_slice(&self.data);
+    /// [`to_bytes_with`](Self::to_bytes_with) without allocating, for
+    /// updaters without a heap.
+    pub fn encode(&self, checksum: ChecksumKind) -> FrameBytes {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        buf[0] = self.index;
+        buf[1] = !self.index; // n_index
+        buf[2] = self.unknown_byte;
+        buf[3..67].copy_from_slice(&self.data);
 
-        // CRC is calculated over the first 64 bytes of the frame:
+        // CRC-16 is calculated over the first 64 bytes of the frame:
         // index, n_index, unknown_byte, data[0..=60]. That corresponds to
         // frame[0..64] (64 bytes total).
-        let crc = crc16_ccitt(&frame[0..64]);
-        let crc_high = (crc >> 8) as u8;
-        let crc_low = (crc & 0xFF) as u8;
+        let (trailer, len) = checksum.trailer(&buf[..67]);
+        buf[67..67 + len].copy_from_slice(&trailer[..len]);
+        buf[67 + len] = if self.is_last { 4 } else { 6 };
+
+        FrameBytes {
+            buf,
+            len: checksum.frame_len(),
+        }
+    }
 
-        frame[67] = crc_high;
-        frame[68] = crc_low;
-        frame[69] = if self.is_last { 4 } else { 6 };
+    /// Parse a raw frame as laid out by [`to_bytes_with`](Self::to_bytes_with),
+    /// checking the length, inverse index, checksum and stop byte.
+    pub fn from_bytes(bytes: &[u8], checksum: ChecksumKind) -> Result<Self, FrameError> {
+        let len = checksum.frame_len();
+        if bytes.len() != len {
+            return Err(FrameError::Length {
+                expected: len,
+                actual: bytes.len(),
+            });
+        }
+        if bytes[1] != !bytes[0] {
+            return Err(FrameError::InverseIndex {
+                index: bytes[0],
+                inverse: bytes[1],
+            });
+        }
+        let (trailer, trailer_len) = checksum.trailer(&bytes[..67]);
+        if bytes[67..len - 1] != trailer[..trailer_len] {
+            return Err(FrameError::ChecksumMismatch);
+        }
+        let is_last = match bytes[len - 1] {
+            4 => true,
+            6 => false,
+            stop => return Err(FrameError::StopByte(stop)),
+        };
 
-        frame
+        let mut data = [0u8; 64];
+        data.copy_from_slice(&bytes[3..67]);
+        Ok(BootloaderFrame {
+            index: bytes[0],
+            unknown_byte: bytes[2],
+            data,
+            is_last,
+        })
+    }
+}
+
+/// Firmware bytes carried by one frame.
+pub const CHUNK_SIZE: usize = 64;
+
+/// Value of erased flash, used to fill partial pages. Frames pad their
+/// last chunk with the same byte.
+pub const PAD_BYTE: u8 = 0xFF;
+
+/// `data` filled with [`PAD_BYTE`] up to the next multiple of `page_size`,
+/// for flash controllers that program whole pages and would otherwise
+/// leave stale bytes after the image. Borrowed when no padding is needed,
+/// including for a `page_size` of 0.
+pub fn pad_to_page(data: &[u8], page_size: usize) -> Cow<'_, [u8]> {
+    if page_size == 0 || data.len().is_multiple_of(page_size) {
+        return Cow::Borrowed(data);
+    }
+    let mut padded = data.to_vec();
+    padded.resize(data.len().next_multiple_of(page_size), PAD_BYTE);
+    Cow::Owned(padded)
+}
+
+/// The frames of a firmware image, in transfer order: 64-byte chunks, the
+/// last padded with 0xFF, indices counting up from 1 (wrapping), and the
+/// last frame marked with stop byte 4.
+#[derive(Debug, Clone)]
+pub struct FirmwareFrames<'a> {
+    chunks: core::slice::Chunks<'a, u8>,
+    index: u8,
+}
+
+impl<'a> FirmwareFrames<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        FirmwareFrames {
+            chunks: data.chunks(CHUNK_SIZE),
+            index: 1,
+        }
+    }
+}
+
+impl Iterator for FirmwareFrames<'_> {
+    type Item = BootloaderFrame;
+
+    fn next(&mut self) -> Option<BootloaderFrame> {
+        let chunk = self.chunks.next()?;
+        let mut data = [PAD_BYTE; 64];
+        data[..chunk.len()].copy_from_slice(chunk);
+        let frame = BootloaderFrame {
+            index: self.index,
+            unknown_byte: 0,
+            data,
+            is_last: self.chunks.len() == 0,
+        };
+        self.index = self.index.wrapping_add(1);
+        Some(frame)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.chunks.size_hint()
+    }
+
+    /// Skips the chunks without building their frames.
+    fn nth(&mut self, n: usize) -> Option<BootloaderFrame> {
+        if n > 0 {
+            self.chunks.nth(n - 1)?;
+            self.index = self.index.wrapping_add(n as u8);
+        }
+        self.next()
+    }
+}
+
+impl ExactSizeIterator for FirmwareFrames<'_> {}
+
+/// Split concatenated raw frames, e.g. a file of [`FirmwareFrames`], and
+/// parse each with [`BootloaderFrame::from_bytes`]. A short tail comes last
+/// as a [`FrameError::Length`].
+pub fn split_frames(
+    bytes: &[u8],
+    checksum: ChecksumKind,
+) -> Vec<Result<BootloaderFrame, FrameError>> {
+    bytes
+        .chunks(checksum.frame_len())
+        .map(|raw| BootloaderFrame::from_bytes(raw, checksum))
+        .collect()
+}
+
+/// Why received bytes are not a valid bootloader frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    Length {
+        expected: usize,
+        actual: usize,
+    },
+    /// The second byte is not the bitwise inverse of the index.
+    InverseIndex {
+        index: u8,
+        inverse: u8,
+    },
+    ChecksumMismatch,
+    /// Neither 6 (more frames follow) nor 4 (last frame).
+    StopByte(u8),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Length { expected, actual } => {
+                write!(f, "frame is {actual} bytes, expected {expected}")
+            }
+            FrameError::InverseIndex { index, inverse } => {
+                write!(f, "frame index 0x{index:02X} with inverse 0x{inverse:02X}")
+            }
+            FrameError::ChecksumMismatch => write!(f, "frame checksum mismatch"),
+            FrameError::StopByte(stop) => write!(f, "frame stop byte 0x{stop:02X}"),
+        }
     }
 }
 
+impl core::error::Error for FrameError {}
+
 #[cfg(test)]
 mod tests {
+    use proptest::prelude::*;
+
     use super::*;
 
+    #[test]
+    fn pad_to_page_fills_the_last_page() {
+        let padded = pad_to_page(&[0x42; 100], 256);
+        assert_eq!(padded.len(), 256);
+        assert_eq!(padded[..100], [0x42; 100]);
+        assert!(padded[100..].iter().all(|&b| b == PAD_BYTE));
+
+        assert!(matches!(pad_to_page(&[0x42; 512], 256), Cow::Borrowed(_)));
+        assert_eq!(pad_to_page(&[0x42; 100], 0).len(), 100);
+    }
+
+    #[test]
+    fn nth_frame_matches_iteration() {
+        let data: Vec<u8> = (0..=255).cycle().take(64 * 300 + 10).collect();
+        let frames: Vec<_> = FirmwareFrames::new(&data).collect();
+        for n in [0, 1, 255, 256, 300] {
+            assert_eq!(FirmwareFrames::new(&data).nth(n).as_ref(), frames.get(n));
+        }
+    }
+
     #[test]
     fn frame_has_correct_size_and_inverse_index() {
         let mut data = [0u8; 64];
@@ -64,4 +397,192 @@ mod tests {
         // Stop byte 6 for non-last frame
         assert_eq!(raw[69], 6);
     }
+
+    #[test]
+    fn debug_summarizes_data() {
+        let mut data = [0u8; 64];
+        data[..4].copy_from_slice(&[0x12, 0x34, 0x56, 0x78]);
+        data[63] = 0xEF;
+        let frame = BootloaderFrame {
+            index: 3,
+            unknown_byte: 0,
+            data,
+            is_last: true,
+        };
+        assert_eq!(
+            format!("{frame:?}"),
+            "BootloaderFrame { index: 3, unknown_byte: 0, \
+             data: [12 34 56 78 .. 00 00 00 EF; 64 bytes], is_last: true }"
+        );
+        assert_eq!(
+            format!("{:?}", frame.encode(ChecksumKind::Sum8)),
+            "FrameBytes([03 FC 00 12 .. 00 EF 02 04; 69 bytes])"
+        );
+        assert_eq!(format!("{:?}", HexSummary(&[1, 2])), "[01 02; 2 bytes]");
+    }
+
+    #[test]
+    fn crc32_frame_widens_trailer() {
+        let frame = BootloaderFrame {
+            index: 1,
+            unknown_byte: 0,
+            data: [0xFF; 64],
+            is_last: true,
+        };
+
+        let raw = frame.to_bytes_with(ChecksumKind::Crc32);
+        assert_eq!(raw.len(), 72);
+        assert_eq!(raw[67..71], crc32(&raw[..67]).to_be_bytes());
+        assert_eq!(raw[71], 4);
+
+        // Same header and data as the CRC-16 frame.
+        let crc16 = frame.to_bytes();
+        assert_eq!(crc16[..], frame.to_bytes_with(ChecksumKind::Crc16Ccitt)[..]);
+        assert_eq!(raw[..67], crc16[..67]);
+        assert_eq!(crc16[67..69], crc16_ccitt(&crc16[..64]).to_be_bytes());
+    }
+
+    #[test]
+    fn encode_matches_to_bytes_with_for_every_checksum() {
+        for checksum in [
+            ChecksumKind::Crc16Ccitt,
+            ChecksumKind::Crc32,
+            ChecksumKind::Sum8,
+            ChecksumKind::Sum16,
+        ] {
+            let frame = BootloaderFrame {
+                index: 7,
+                unknown_byte: 0,
+                data: core::array::from_fn(|i| i as u8),
+                is_last: true,
+            };
+            let encoded = frame.encode(checksum);
+            assert_eq!(encoded.len(), checksum.frame_len());
+            assert_eq!(*encoded, frame.to_bytes_with(checksum)[..]);
+            assert_eq!(BootloaderFrame::from_bytes(&encoded, checksum), Ok(frame));
+        }
+    }
+
+    #[test]
+    fn sum_checksums_match_hand_computed_values() {
+        let frame = |data, checksum| {
+            BootloaderFrame {
+                index: 1,
+                unknown_byte: 0,
+                data,
+                is_last: false,
+            }
+            .to_bytes_with(checksum)
+        };
+
+        // 0x01 + 0xFE + 0x00 + 64 * 0x01 = 0x013F
+        let raw = frame([0x01; 64], ChecksumKind::Sum8);
+        assert_eq!(raw.len(), 69);
+        assert_eq!(raw[67..], [0x3F, 6]);
+        let raw = frame([0x01; 64], ChecksumKind::Sum16);
+        assert_eq!(raw.len(), 70);
+        assert_eq!(raw[67..], [0x01, 0x3F, 6]);
+
+        // 0x01 + 0xFE + 0x00 + 64 * 0xFF = 0x40BF
+        let raw = frame([0xFF; 64], ChecksumKind::Sum8);
+        assert_eq!(raw[67..], [0xBF, 6]);
+        let raw = frame([0xFF; 64], ChecksumKind::Sum16);
+        assert_eq!(raw[67..], [0x40, 0xBF, 6]);
+    }
+
+    #[test]
+    fn from_bytes_rejects_each_defect() {
+        let frame = BootloaderFrame {
+            index: 3,
+            unknown_byte: 0,
+            data: [0x42; 64],
+            is_last: false,
+        };
+        let raw = frame.to_bytes();
+        assert_eq!(
+            BootloaderFrame::from_bytes(&raw, ChecksumKind::Crc16Ccitt),
+            Ok(frame)
+        );
+
+        assert_eq!(
+            BootloaderFrame::from_bytes(&raw, ChecksumKind::Crc32),
+            Err(FrameError::Length {
+                expected: 72,
+                actual: 70
+            })
+        );
+        let mut bad = raw;
+        bad[1] = 0;
+        assert_eq!(
+            BootloaderFrame::from_bytes(&bad, ChecksumKind::Crc16Ccitt),
+            Err(FrameError::InverseIndex {
+                index: 3,
+                inverse: 0
+            })
+        );
+        let mut bad = raw;
+        bad[10] ^= 1;
+        assert_eq!(
+            BootloaderFrame::from_bytes(&bad, ChecksumKind::Crc16Ccitt),
+            Err(FrameError::ChecksumMismatch)
+        );
+        let mut bad = raw;
+        bad[69] = 5;
+        assert_eq!(
+            BootloaderFrame::from_bytes(&bad, ChecksumKind::Crc16Ccitt),
+            Err(FrameError::StopByte(5))
+        );
+    }
+
+    #[test]
+    fn firmware_frames_pad_and_mark_last() {
+        let image: Vec<u8> = (0..130).map(|i| i as u8).collect();
+        let frames: Vec<_> = FirmwareFrames::new(&image).collect();
+        let summary: Vec<_> = frames.iter().map(|f| (f.index, f.is_last)).collect();
+        assert_eq!(summary, [(1, false), (2, false), (3, true)]);
+        assert_eq!(frames[2].data[..2], [128, 129]);
+        assert!(frames[2].data[2..].iter().all(|&b| b == 0xFF));
+
+        let mut raw: Vec<u8> = frames
+            .iter()
+            .flat_map(|f| f.to_bytes_with(ChecksumKind::Crc32))
+            .collect();
+        raw.extend_from_slice(&[1, 2, 3]);
+        let parsed = split_frames(&raw, ChecksumKind::Crc32);
+        assert_eq!(parsed.len(), 4);
+        assert_eq!(
+            parsed[..3],
+            frames.into_iter().map(Ok).collect::<Vec<_>>()[..]
+        );
+        assert_eq!(
+            parsed[3],
+            Err(FrameError::Length {
+                expected: 72,
+                actual: 3
+            })
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn frames_round_trip(
+            index: u8,
+            unknown_byte: u8,
+            data: [u8; 64],
+            is_last: bool,
+            kind in 0..4usize,
+        ) {
+            let checksum = [
+                ChecksumKind::Crc16Ccitt,
+                ChecksumKind::Crc32,
+                ChecksumKind::Sum8,
+                ChecksumKind::Sum16,
+            ][kind];
+            let frame = BootloaderFrame { index, unknown_byte, data, is_last };
+            prop_assert_eq!(
+                BootloaderFrame::from_bytes(&frame.to_bytes_with(checksum), checksum),
+                Ok(frame)
+            );
+        }
+    }
 }
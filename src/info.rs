@@ -0,0 +1,423 @@
+//! Decoded register dump of one servo, for `feeflash info`, live
+//! telemetry for `feeflash monitor`, and the device list of `feeflash scan`.
+
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::deadline::Deadline;
+use crate::dynamixel::registers::{
+    self, BAUD_RATE, MAX_ANGLE_LIMIT, MAX_TEMPERATURE_LIMIT, MIN_ANGLE_LIMIT, PRESENT_POSITION,
+    PRESENT_TEMPERATURE,
+};
+use crate::dynamixel::{
+    ProtocolVersion, SCAN_TIMEOUT_MS, ScanOptions, StatusPacket, ping, ping_with, read_register,
+    scan_bus,
+};
+use crate::dynamixel2;
+use crate::models::model_label;
+use crate::transport::Transport;
+
+/// Position steps per full turn on STS servos.
+const STEPS_PER_TURN: f32 = 4096.0;
+
+/// Product name from the model table. An alias so serde doesn't take the
+/// field as borrowed from its input: deserializing looks the name up in
+/// the table instead.
+type ModelName = Option<&'static str>;
+
+/// Registers of one servo in engineering units. `None` marks a register
+/// whose read timed out.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct DeviceInfo {
+    pub id: u8,
+    pub model: Option<u16>,
+    /// Product name of `model`, if known.
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::models::deserialize_model_name")
+    )]
+    pub model_name: ModelName,
+    /// `major.minor`.
+    pub firmware: Option<String>,
+    pub baud_index: Option<u8>,
+    /// Baud rate selected by `baud_index`; `None` for unknown indices too.
+    pub baud_rate: Option<u32>,
+    pub min_angle_limit_steps: Option<u16>,
+    pub max_angle_limit_steps: Option<u16>,
+    pub max_temperature_c: Option<u8>,
+    pub present_temperature_c: Option<u8>,
+    pub present_voltage_v: Option<f32>,
+    pub present_position_steps: Option<u16>,
+}
+
+/// Turn a timed-out read into `None`; other errors still fail.
+fn optional<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+impl DeviceInfo {
+    /// Read every register of the dump from `id`, one instruction each.
+    pub fn read(port: &mut dyn Transport, id: u8) -> io::Result<Self> {
+        let baud_index = optional(registers::read_u8(port, id, BAUD_RATE))?;
+        let model = optional(registers::read_model(port, id))?;
+        Ok(DeviceInfo {
+            id,
+            model,
+            model_name: model.and_then(registers::model_name),
+            firmware: optional(registers::read_firmware_version(port, id))?
+                .map(|(major, minor)| format!("{major}.{minor}")),
+            baud_index,
+            baud_rate: baud_index.and_then(registers::baud_rate_from_index),
+            min_angle_limit_steps: optional(registers::read_u16(port, id, MIN_ANGLE_LIMIT))?,
+            max_angle_limit_steps: optional(registers::read_u16(port, id, MAX_ANGLE_LIMIT))?,
+            max_temperature_c: optional(registers::read_u8(port, id, MAX_TEMPERATURE_LIMIT))?,
+            present_temperature_c: optional(registers::read_present_temperature(port, id))?,
+            present_voltage_v: optional(registers::read_present_voltage(port, id))?
+                .map(|v| f32::from(v) * 0.1),
+            present_position_steps: optional(registers::read_present_position(port, id))?,
+        })
+    }
+}
+
+/// `value` formatted by `f`, or "n/a".
+fn or_na<T>(value: Option<T>, f: impl FnOnce(T) -> String) -> String {
+    value.map_or_else(|| "n/a".to_string(), f)
+}
+
+fn steps(steps: u16) -> String {
+    format!(
+        "{steps} steps ({:.1}°)",
+        f32::from(steps) * 360.0 / STEPS_PER_TURN
+    )
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let baud = match (self.baud_index, self.baud_rate) {
+            (Some(index), Some(rate)) => format!("{rate} (index {index})"),
+            (Some(index), None) => format!("unknown (index {index})"),
+            (None, _) => "n/a".to_string(),
+        };
+        let rows = [
+            ("Model", or_na(self.model, registers::describe_model)),
+            ("Firmware", or_na(self.firmware.clone(), |v| v)),
+            ("Baud rate", baud),
+            ("Min angle limit", or_na(self.min_angle_limit_steps, steps)),
+            ("Max angle limit", or_na(self.max_angle_limit_steps, steps)),
+            (
+                "Max temperature",
+                or_na(self.max_temperature_c, |t| format!("{t} °C")),
+            ),
+            (
+                "Temperature",
+                or_na(self.present_temperature_c, |t| format!("{t} °C")),
+            ),
+            (
+                "Voltage",
+                or_na(self.present_voltage_v, |v| format!("{v:.1} V")),
+            ),
+            ("Position", or_na(self.present_position_steps, steps)),
+        ];
+
+        write!(f, "Device id {}", self.id)?;
+        if let Some(model) = self.model {
+            write!(f, ": {}", model_label(model))?;
+            if let Some(firmware) = &self.firmware {
+                write!(f, " fw {firmware}")?;
+            }
+        }
+        writeln!(f)?;
+        for (i, (name, value)) in rows.iter().enumerate() {
+            write!(f, "  {:<16} {value}", format!("{name}:"))?;
+            if i + 1 < rows.len() {
+                writeln!(f)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Live readings of one servo.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct Telemetry {
+    pub position_steps: u16,
+    /// Negative when turning backwards.
+    pub speed_steps_per_s: i16,
+    /// Share of maximum torque; negative when loaded backwards.
+    pub load_percent: f32,
+    pub voltage_v: f32,
+    pub temperature_c: u8,
+}
+
+/// Decode a sign-magnitude value with its sign in bit `sign_bit`.
+fn sign_magnitude(raw: u16, sign_bit: u8) -> i16 {
+    let magnitude = (raw & ((1 << sign_bit) - 1)) as i16;
+    if raw & (1 << sign_bit) != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+impl Telemetry {
+    /// Read position through temperature of `id` in one instruction.
+    pub fn read(port: &mut dyn Transport, id: u8) -> io::Result<Self> {
+        let len = PRESENT_TEMPERATURE - PRESENT_POSITION + 1;
+        let bytes = read_register(port, id, PRESENT_POSITION, len)?;
+        if bytes.len() != len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Expected {len} telemetry bytes, got {}", bytes.len()),
+            ));
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Ok(Telemetry {
+            position_steps: u16_at(0),
+            speed_steps_per_s: sign_magnitude(u16_at(2), 15),
+            load_percent: f32::from(sign_magnitude(u16_at(4), 10)) * 0.1,
+            voltage_v: f32::from(bytes[6]) * 0.1,
+            temperature_c: bytes[7],
+        })
+    }
+}
+
+impl fmt::Display for Telemetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "pos {:4} ({:5.1}°)  speed {:5} steps/s  load {:6.1} %  {:4.1} V  {:3} °C",
+            self.position_steps,
+            f32::from(self.position_steps) * 360.0 / STEPS_PER_TURN,
+            self.speed_steps_per_s,
+            self.load_percent,
+            self.voltage_v,
+            self.temperature_c
+        )
+    }
+}
+
+/// A device found by [`scan_devices`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct ScannedDevice {
+    pub id: u8,
+    pub protocol: ProtocolVersion,
+    /// Status packet answering the ping.
+    pub status: StatusPacket,
+    /// `None` when the device refused the register read.
+    pub model: Option<u16>,
+    #[cfg_attr(
+        feature = "serde",
+        serde(deserialize_with = "crate::models::deserialize_model_name")
+    )]
+    pub model_name: ModelName,
+    /// `major.minor` on protocol 1, the single version byte on protocol 2.
+    pub firmware: Option<String>,
+}
+
+/// Turn a read the device didn't answer or refused into `None`.
+fn refused<T>(result: io::Result<T>) -> io::Result<Option<T>> {
+    match result {
+        Ok(value) => Ok(Some(value)),
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput
+            ) =>
+        {
+            Ok(None)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Devices found by [`scan_devices`].
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct DeviceScan {
+    pub devices: Vec<ScannedDevice>,
+    /// IDs with garbled replies, probably shared by several servos.
+    pub collisions: Vec<u8>,
+}
+
+/// [`scan_bus`], then ping each device found once more and read its model
+/// and firmware.
+///
+/// The detail reads use the short scan timeout, and firmware is only read
+/// if the model read answered, so a device refusing register reads costs
+/// one scan timeout. Protocol 2 devices report both in their ping reply.
+pub fn scan_devices(
+    port: &mut dyn Transport,
+    deadline: Deadline,
+    options: &ScanOptions,
+) -> io::Result<DeviceScan> {
+    let protocol = options.protocol;
+    let report = scan_bus(port, deadline, options)?;
+    let previous = port.timeout();
+    port.set_timeout(Duration::from_millis(SCAN_TIMEOUT_MS))?;
+    let mut devices = Vec::new();
+    for id in report.found {
+        match scan_device(port, id, protocol) {
+            Ok(Some(device)) => devices.push(device),
+            Ok(None) => {}
+            Err(e) => {
+                port.set_timeout(previous)?;
+                return Err(e);
+            }
+        }
+    }
+    port.set_timeout(previous)?;
+    Ok(DeviceScan {
+        devices,
+        collisions: report.collisions,
+    })
+}
+
+/// Details of one scanned `id`; `None` if it stopped answering.
+fn scan_device(
+    port: &mut dyn Transport,
+    id: u8,
+    protocol: Option<ProtocolVersion>,
+) -> io::Result<Option<ScannedDevice>> {
+    let reply = match protocol {
+        Some(protocol) => refused(ping_with(port, id, protocol))?.map(|s| (protocol, s)),
+        None => match refused(ping(port, id))? {
+            Some(status) => Some((ProtocolVersion::V1, status)),
+            None => refused(dynamixel2::ping(port, id))?.map(|s| (ProtocolVersion::V2, s)),
+        },
+    };
+    let Some((protocol, status)) = reply else {
+        return Ok(None);
+    };
+
+    let (model, firmware) = match protocol {
+        ProtocolVersion::V1 => {
+            let model = refused(registers::read_model(port, id))?;
+            let firmware = match model {
+                Some(_) => refused(registers::read_firmware_version(port, id))?
+                    .map(|(major, minor)| format!("{major}.{minor}")),
+                None => None,
+            };
+            (model, firmware)
+        }
+        ProtocolVersion::V2 => (
+            status
+                .params
+                .get(..2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]])),
+            status.params.get(2).map(|v| v.to_string()),
+        ),
+    };
+    Ok(Some(ScannedDevice {
+        id,
+        protocol,
+        status,
+        model,
+        model_name: model.and_then(registers::model_name),
+        firmware,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn timed_out_registers_show_as_na() {
+        // Baud index 0, then nothing answers.
+        let mut mock = MockTransport::new();
+        mock.push_timeout()
+            .push_read(&[0xFF, 0xFF, 0x03, 0x03, 0x00, 0x00, 0xF9]);
+        let info = DeviceInfo::read(&mut mock, 3).unwrap();
+
+        assert_eq!(info.baud_rate, Some(1_000_000));
+        assert_eq!(info.model, None);
+        let text = info.to_string();
+        assert!(text.contains("Baud rate:       1000000 (index 0)"));
+        assert!(text.contains("Voltage:         n/a"));
+    }
+
+    #[test]
+    fn display_uses_engineering_units() {
+        let info = DeviceInfo {
+            id: 3,
+            model: Some(777),
+            model_name: Some("STS3215"),
+            firmware: Some("3.10".to_string()),
+            baud_index: Some(9),
+            baud_rate: None,
+            min_angle_limit_steps: Some(0),
+            max_angle_limit_steps: Some(4095),
+            max_temperature_c: Some(70),
+            present_temperature_c: Some(31),
+            present_voltage_v: Some(12.1),
+            present_position_steps: Some(2048),
+        };
+        let text = info.to_string();
+        assert!(text.starts_with("Device id 3: STS3215 fw 3.10\n"));
+        assert!(text.contains("STS3215 (model 777)"));
+        assert!(text.contains("unknown (index 9)"));
+        assert!(text.contains("2048 steps (180.0°)"));
+        assert!(text.contains("12.1 V"));
+        assert!(text.contains("31 °C"));
+
+        let json = serde_json::to_value(&info).unwrap();
+        assert_eq!(json["baud_rate"], serde_json::Value::Null);
+        assert_eq!(json["firmware"], "3.10");
+    }
+
+    #[test]
+    fn telemetry_decodes_directions() {
+        // Position 2048, speed -100, load +25.0 %, 12.0 V, 38 °C.
+        let params = [0x00, 0x08, 0x64, 0x80, 0xFA, 0x00, 0x78, 0x26];
+        let mut reply = vec![0xFF, 0xFF, 0x03, 0x0A, 0x00];
+        reply.extend(params);
+        let sum: u32 = reply[2..].iter().map(|&b| b as u32).sum();
+        reply.push(!sum as u8);
+
+        let mut mock = MockTransport::new();
+        mock.push_timeout().push_read(&reply);
+        let telemetry = Telemetry::read(&mut mock, 3).unwrap();
+        assert_eq!(mock.writes()[0][5..7], [PRESENT_POSITION, 8]);
+        assert_eq!(telemetry.position_steps, 2048);
+        assert_eq!(telemetry.speed_steps_per_s, -100);
+        assert_eq!(telemetry.load_percent, 25.0);
+        assert_eq!(telemetry.temperature_c, 38);
+    }
+
+    #[test]
+    fn scan_keeps_devices_refusing_reads() {
+        let status = [0xFF, 0xFF, 0x03, 0x02, 0x00, 0xFA];
+        let mut mock = MockTransport::new();
+        // Broadcast ping, then the per-device ping; the model read times out.
+        mock.push_timeout().push_read(&status).push_timeout();
+        mock.push_timeout().push_read(&status);
+        let options = ScanOptions {
+            protocol: Some(ProtocolVersion::V1),
+            ..ScanOptions::default()
+        };
+        let devices = scan_devices(&mut mock, Deadline::NONE, &options)
+            .unwrap()
+            .devices;
+
+        assert_eq!(devices.len(), 1);
+        assert_eq!(devices[0].id, 3);
+        assert_eq!(devices[0].status.error, 0);
+        assert_eq!(
+            (devices[0].model, devices[0].firmware.as_deref()),
+            (None, None)
+        );
+        // No firmware read after the model read went unanswered.
+        assert_eq!(mock.writes().len(), 3);
+    }
+}
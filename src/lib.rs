@@ -1,8 +1,109 @@
 //! Library for the Feetech Servo bootloader client.
-//! Provides reusable modules for Dynamixel v1 commands, bootloader
+//! Provides reusable modules for Dynamixel v1 and v2 commands, bootloader
 //! handshake and firmware framing.
+//!
+//! Without the default `std` feature only [`crc`], [`frame`],
+//! [`dynamixel::packet`], [`dynamixel2::packet`] and [`flasher`] are built,
+//! on `core` and `alloc`, for updaters running on targets without an OS
+//! and for the browser.
 
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+// The library's own output on stdout and stderr goes through these, so the
+// `silent` feature can compile it out.
+#[cfg(feature = "std")]
+macro_rules! out {
+    ($($arg:tt)*) => {
+        if !cfg!(feature = "silent") {
+            ::std::println!($($arg)*);
+        }
+    };
+}
+
+/// `print!` and flush, for progress on one line.
+#[cfg(feature = "std")]
+macro_rules! out_inline {
+    ($($arg:tt)*) => {
+        if !cfg!(feature = "silent") {
+            ::std::print!($($arg)*);
+            let _ = ::std::io::Write::flush(&mut ::std::io::stdout());
+        }
+    };
+}
+
+#[cfg(feature = "std")]
+macro_rules! diag {
+    ($($arg:tt)*) => {
+        if !cfg!(feature = "silent") {
+            ::std::eprintln!($($arg)*);
+        }
+    };
+}
+
+#[cfg(feature = "tokio")]
+pub mod asynchronous;
+#[cfg(feature = "std")]
+pub mod batch;
+#[cfg(feature = "std")]
 pub mod bootloader;
+#[cfg(feature = "std")]
+pub mod bus;
+#[cfg(feature = "std")]
+pub mod clock;
 pub mod crc;
+#[cfg(feature = "std")]
+pub mod deadline;
+#[cfg(feature = "std")]
 pub mod dynamixel;
+/// Without `std`, only the packet building and parsing of protocol 1.
+#[cfg(not(feature = "std"))]
+pub mod dynamixel {
+    pub mod packet;
+}
+#[cfg(feature = "std")]
+pub mod dynamixel2;
+/// Without `std`, only the packet building of protocol 2.
+#[cfg(not(feature = "std"))]
+pub mod dynamixel2 {
+    pub mod packet;
+}
+#[cfg(feature = "std")]
+pub mod eeprom;
+#[cfg(feature = "embedded")]
+pub mod embedded;
+#[cfg(feature = "testing")]
+pub mod emulator;
+#[cfg(feature = "std")]
+pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod firmware;
+pub mod flasher;
 pub mod frame;
+#[cfg(feature = "std")]
+pub mod info;
+#[cfg(feature = "std")]
+pub mod models;
+#[cfg(feature = "std")]
+pub mod parallel;
+#[cfg(feature = "python")]
+mod python;
+#[cfg(feature = "std")]
+pub mod session;
+#[cfg(feature = "std")]
+pub mod sniff;
+#[cfg(feature = "std")]
+pub mod soak;
+#[cfg(feature = "std")]
+pub mod tcp;
+#[cfg(feature = "std")]
+pub mod trace;
+#[cfg(feature = "std")]
+pub mod transport;
+#[cfg(feature = "std")]
+pub mod util;
+#[cfg(feature = "web")]
+pub mod web;
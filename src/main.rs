@@ -1,15 +1,72 @@
-use clap::Parser;
-use std::io::Read;
-use std::path::Path;
-use std::time::Duration;
+use clap::{Parser, Subcommand, ValueEnum};
+use std::borrow::Cow;
+use std::convert::Infallible;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
-use feeflash::bootloader::{BOOTLOADER_MAGIC, send_firmware_file, wait_for_bootloader_magic_ack};
-use feeflash::dynamixel::{PING_TIMEOUT_MS, scan_ids, send_ping, send_reboot};
+use feeflash::batch::{BatchJob, Manifest, run_batch};
+use feeflash::bootloader::{
+    BOOTLOADER_BAUD, BOOTLOADER_INIT, BOOTLOADER_MAGIC, DEFAULT_FRAME_TIMEOUT,
+    DEFAULT_MAX_FIRMWARE_SIZE, FlashEvent, FlashOptions, Progress, REBOOT_DELAY, check_firmware,
+    check_version, firmware_region, jump_to_application, magic_handshake, read_firmware,
+    wait_for_application,
+};
+use feeflash::bus::Bus;
+use feeflash::deadline::Deadline;
+use feeflash::dynamixel::registers::{
+    TORQUE_ENABLE, describe_model, read_firmware_version, read_model,
+};
+use feeflash::dynamixel::{
+    BROADCAST_WINDOW_MS, PING_TIMEOUT_MS, PingEvent, ProtocolVersion, ScanOptions, broadcast_ping,
+    detect_baud, factory_reset, measure_ping_latency_with, reg_write, scan_bus, scan_first,
+    scan_health, send_action, send_ping, send_reboot, sync_torque_off, watch_ping,
+};
+use feeflash::dynamixel2;
+use feeflash::eeprom::{CALIBRATION, EepromBackup};
+use feeflash::error::FeeflashError;
+#[cfg(feature = "sha256")]
+use feeflash::firmware::verify_digest;
+#[cfg(feature = "signing")]
+use feeflash::firmware::verify_signature;
+use feeflash::firmware::{
+    Container, FirmwareVersion, GZIP_MAGIC, SIGNATURE_LEN, decompress_firmware, pad_to_page,
+    parse_sha256_hex, parse_sha256_sidecar, raw_or_hex, validate_firmware_image,
+};
+use feeflash::frame::{ChecksumKind, FirmwareFrames, split_frames};
+use feeflash::info::{DeviceInfo, Telemetry, scan_devices};
+use feeflash::models::{lookup_model, model_label};
+use feeflash::parallel::{FlashJob, flash_many};
+use feeflash::session::BootloaderSession;
+use feeflash::sniff::{IDLE_GAP, SniffRecord, Sniffer};
+use feeflash::soak::soak;
+use feeflash::trace::{ReplayTransport, TracingTransport, VerboseTransport};
+use feeflash::transport::{BaudGuard, Transport, open_port};
+
+/// Set by --progress-json: stdout then carries the JSON events only, and
+/// the status lines of a flash go to stderr.
+static STATUS_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+/// `println!` for status lines of a flash; see [`STATUS_TO_STDERR`].
+macro_rules! status {
+    ($($arg:tt)*) => {
+        if STATUS_TO_STDERR.load(Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "feeflash", about = "Feetech Servo bootloader client")]
 struct Args {
-    /// Firmware file path
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Firmware file path, or "-" to read the image from stdin
     #[arg(value_name = "FIRMWARE", default_value = "firmware.bin")]
     firmware: String,
 
@@ -17,28 +74,1722 @@ struct Args {
     #[arg(long, value_name = "ID", env = "FEEFLASH_ID")]
     id: Option<u8>,
 
+    /// Without --id, flash the first ID that answers instead of scanning
+    /// the whole bus. Only safe with a single servo connected.
+    #[arg(long, env = "FEEFLASH_FIRST", conflicts_with = "id")]
+    first: bool,
+
+    /// Flash several devices in turn, each with the full reboot, handshake
+    /// and transfer, and print a summary at the end.
+    #[arg(
+        long,
+        value_name = "ID,...",
+        value_delimiter = ',',
+        conflicts_with_all = ["id", "first", "recovery", "preserve_eeprom", "auto_baud", "expect_version"]
+    )]
+    ids: Vec<u8>,
+
+    /// Like --ids, with every ID that answers a bus scan.
+    #[arg(
+        long,
+        conflicts_with_all = ["id", "ids", "first", "recovery", "preserve_eeprom", "auto_baud", "expect_version"]
+    )]
+    all: bool,
+
+    /// With --ids or --all, stop at the first device that fails instead of
+    /// flashing the rest.
+    #[arg(long)]
+    fail_fast: bool,
+
+    /// Scan IDs from 253 down to 0, for servos that ship with high IDs
+    #[arg(long, env = "FEEFLASH_REVERSE_SCAN")]
+    reverse_scan: bool,
+
+    /// Pings per ID before a scan records it as absent; raise on noisy buses
+    #[arg(
+        long,
+        value_name = "N",
+        default_value_t = 1,
+        env = "FEEFLASH_SCAN_ATTEMPTS"
+    )]
+    scan_attempts: u8,
+
     /// Recovery mode: repeatedly send magic and wait for ACK.
     #[arg(long, env = "FEEFLASH_RECOVERY")]
     recovery: bool,
 
-    /// Serial port path
+    /// Serial port path, or `tcp://host:port` for a serial bridge such as
+    /// ser2net (`rfc2217://host:port` if it speaks RFC 2217). Repeat to
+    /// flash the same ID on several ports at once, one thread per port.
     #[arg(
-        long,
+        long = "port",
         value_name = "PORT",
         env = "FEEFLASH_PORT",
+        global = true,
         default_value = "/dev/ttyACM0"
     )]
-    port: String,
+    ports: Vec<String>,
 
     /// Initial baud rate (for normal ping/reboot flow)
     #[arg(
         long,
         value_name = "BAUD",
         env = "FEEFLASH_BAUD",
+        global = true,
         default_value_t = 1_000_000u32
     )]
     baud: u32,
-    // Timeouts are hardcoded; no user configuration needed.
+
+    /// Abort the whole operation after this many seconds.
+    #[arg(
+        long,
+        value_name = "SECS",
+        env = "FEEFLASH_MAX_DURATION",
+        global = true
+    )]
+    max_duration: Option<u64>,
+
+    /// Resends allowed per firmware frame after a NAK.
+    #[arg(
+        long,
+        value_name = "N",
+        env = "FEEFLASH_MAX_RETRIES",
+        default_value_t = 5
+    )]
+    max_retries: u8,
+
+    /// Resends allowed across the whole transfer before aborting.
+    #[arg(long, value_name = "N", env = "FEEFLASH_MAX_TOTAL_RETRIES")]
+    max_total_retries: Option<u32>,
+
+    /// Largest firmware image accepted, in bytes.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        env = "FEEFLASH_MAX_FIRMWARE_SIZE",
+        default_value_t = DEFAULT_MAX_FIRMWARE_SIZE
+    )]
+    max_firmware_size: usize,
+
+    /// Pad the image with 0xFF to a multiple of this many bytes, for
+    /// devices that program flash in whole pages.
+    #[arg(long, value_name = "BYTES", env = "FEEFLASH_FLASH_PAGE_SIZE", value_parser = clap::value_parser!(u32).range(1..))]
+    flash_page_size: Option<u32>,
+
+    /// Send the image only from this byte on, in decimal or 0x hex. Rounded
+    /// down to a whole 64-byte frame.
+    #[arg(long, value_name = "BYTES", default_value_t = 0, value_parser = parse_size)]
+    offset: usize,
+
+    /// Send only this many bytes from --offset, in decimal or 0x hex.
+    /// Rounded up to a whole 64-byte frame.
+    #[arg(long, value_name = "BYTES", value_parser = parse_size)]
+    length: Option<usize>,
+
+    /// Detached Ed25519 signature of the firmware image (raw or hex).
+    /// Overrides a signature embedded in a .ffw container.
+    #[arg(long, value_name = "FILE")]
+    signature: Option<PathBuf>,
+
+    /// Ed25519 public key (raw or hex) firmware must be signed with. When
+    /// set, unsigned or badly signed firmware is refused.
+    #[arg(long, value_name = "FILE", env = "FEEFLASH_PUBKEY")]
+    pubkey: Option<PathBuf>,
+
+    /// Flash even if the firmware is unsigned or its signature doesn't
+    /// verify against --pubkey.
+    #[arg(long)]
+    allow_unsigned: bool,
+
+    /// Only ever flash firmware signed with --pubkey: without a key, or
+    /// with --allow-unsigned, nothing is flashed. Set the environment
+    /// variable on stations that must not flash anything else.
+    #[arg(
+        long,
+        env = "FEEFLASH_REQUIRE_SIGNATURE",
+        requires = "pubkey",
+        conflicts_with = "allow_unsigned"
+    )]
+    require_signature: bool,
+
+    /// Expected SHA-256 of the firmware file, in hex. Without it, a
+    /// "<FIRMWARE>.sha256" sidecar is checked if one exists.
+    #[arg(long, value_name = "HEX", env = "FEEFLASH_SHA256")]
+    sha256: Option<String>,
+
+    /// Delay after each acknowledged firmware frame, in milliseconds.
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "FEEFLASH_FRAME_DELAY_MS",
+        default_value_t = 0
+    )]
+    frame_delay_ms: u64,
+
+    /// How long the ACK of each frame is awaited before the frame is
+    /// resent, in milliseconds. 0 waits the port timeout instead and fails
+    /// on the first missed ACK.
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "FEEFLASH_FRAME_TIMEOUT_MS",
+        default_value_t = DEFAULT_FRAME_TIMEOUT.as_millis() as u64
+    )]
+    frame_timeout_ms: u64,
+
+    /// Checksum in each firmware frame; newer bootloader revisions use
+    /// CRC-32, some frame variants a byte sum.
+    #[arg(
+        long,
+        value_name = "KIND",
+        env = "FEEFLASH_CHECKSUM",
+        default_value = "crc16"
+    )]
+    checksum: ChecksumArg,
+
+    /// Write the firmware frames that would be sent, concatenated, to this
+    /// file instead of flashing. No port is opened.
+    #[arg(long, value_name = "FILE")]
+    emit_frames: Option<PathBuf>,
+
+    /// While flashing, write one JSON object per ACKed frame to stdout,
+    /// then a final "done" object, instead of the per-frame lines. Other
+    /// output goes to stderr.
+    #[arg(
+        long,
+        env = "FEEFLASH_PROGRESS_JSON",
+        conflicts_with_all = ["ids", "all", "emit_frames"]
+    )]
+    progress_json: bool,
+
+    /// Write a byte-level transcript of all serial traffic to this file
+    /// ("-" for stderr).
+    #[arg(
+        long,
+        alias = "trace",
+        value_name = "PATH",
+        env = "FEEFLASH_TRACE_FILE",
+        global = true
+    )]
+    trace_file: Option<PathBuf>,
+
+    /// Print all serial traffic to stderr as hex dumps.
+    #[arg(short, long, env = "FEEFLASH_VERBOSE", global = true)]
+    verbose: bool,
+
+    /// Replay a recorded transcript instead of opening the serial port.
+    #[arg(long, value_name = "PATH", global = true)]
+    replay: Option<PathBuf>,
+
+    /// Keep replaying when written bytes differ from the transcript.
+    #[arg(long, requires = "replay", global = true)]
+    replay_ignore_mismatch: bool,
+
+    /// The adapter echoes transmitted bytes back (single-wire TTL bus);
+    /// read back and check the echo after every write.
+    #[arg(long, env = "FEEFLASH_HALF_DUPLEX", global = true)]
+    half_duplex: bool,
+
+    /// Dynamixel protocol for ping, scan and reboot. Scans try both when
+    /// omitted; everything else defaults to 1.
+    #[arg(long, value_name = "VERSION", env = "FEEFLASH_PROTOCOL", global = true)]
+    protocol: Option<ProtocolArg>,
+
+    /// Leave torque enabled before rebooting into the bootloader.
+    #[arg(long, env = "FEEFLASH_NO_TORQUE_OFF")]
+    no_torque_off: bool,
+
+    /// Model number(s) the firmware is built for; flashing any other model
+    /// is refused. Required unless --force.
+    #[arg(
+        long = "model",
+        value_name = "MODEL",
+        env = "FEEFLASH_MODEL",
+        value_delimiter = ','
+    )]
+    models: Vec<u16>,
+
+    /// Flash without checking the device model or that the file looks
+    /// like a firmware image.
+    #[arg(long, env = "FEEFLASH_FORCE")]
+    force: bool,
+
+    /// Back up the calibration EEPROM before flashing and restore it once
+    /// the new firmware answers pings again.
+    #[arg(long, env = "FEEFLASH_PRESERVE_EEPROM")]
+    preserve_eeprom: bool,
+
+    /// When --id doesn't answer at --baud, continue at whichever of
+    /// --baud-candidates it answers at instead of stopping with a hint.
+    #[arg(long, env = "FEEFLASH_AUTO_BAUD")]
+    auto_baud: bool,
+
+    /// Baud rates tried when --id doesn't answer at --baud.
+    #[arg(
+        long,
+        value_name = "BAUD,...",
+        value_delimiter = ',',
+        env = "FEEFLASH_BAUD_CANDIDATES",
+        default_value = "1000000,500000,250000,128000,115200,76800,57600,38400"
+    )]
+    baud_candidates: Vec<u32>,
+
+    /// After flashing, verify the device starts the new firmware and answers
+    /// at the initial baud rate (the default).
+    #[arg(long, alias = "run", overrides_with = "no_confirm")]
+    confirm_boot: bool,
+
+    /// After the boot confirmation, fail unless the device reports this
+    /// firmware version (e.g. 3.10). Defaults to the version in a .ffw
+    /// container.
+    #[arg(
+        long,
+        value_name = "MAJ.MIN",
+        env = "FEEFLASH_EXPECT_VERSION",
+        conflicts_with = "no_confirm"
+    )]
+    expect_version: Option<FirmwareVersion>,
+
+    /// Skip the post-flash boot confirmation.
+    #[arg(long, env = "FEEFLASH_NO_CONFIRM", overrides_with = "confirm_boot")]
+    no_confirm: bool,
+
+    /// Pause after the transfer before the boot confirmation starts
+    /// pinging, in milliseconds.
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "FEEFLASH_BOOT_SETTLE_MS",
+        default_value_t = REBOOT_DELAY.as_millis() as u64
+    )]
+    boot_settle_ms: u64,
+
+    /// Baud rate of the bootloader. Device-specific; the default suits the
+    /// STS/SMS servos.
+    #[arg(
+        long,
+        value_name = "BAUD",
+        env = "FEEFLASH_BOOTLOADER_BAUD",
+        default_value_t = BOOTLOADER_BAUD
+    )]
+    bootloader_baud: u32,
+
+    /// Pause after the reboot instruction before sending the magic, in
+    /// milliseconds. Device-specific, like --bootloader-baud.
+    #[arg(
+        long,
+        value_name = "MS",
+        env = "FEEFLASH_REBOOT_DELAY_MS",
+        default_value_t = REBOOT_DELAY.as_millis() as u64
+    )]
+    reboot_delay_ms: u64,
+    // Per-read timeouts are hardcoded; no user configuration needed.
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ChecksumArg {
+    Crc16,
+    Crc32,
+    Sum8,
+    Sum16,
+}
+
+impl From<ChecksumArg> for ChecksumKind {
+    fn from(arg: ChecksumArg) -> Self {
+        match arg {
+            ChecksumArg::Crc16 => ChecksumKind::Crc16Ccitt,
+            ChecksumArg::Crc32 => ChecksumKind::Crc32,
+            ChecksumArg::Sum8 => ChecksumKind::Sum8,
+            ChecksumArg::Sum16 => ChecksumKind::Sum16,
+        }
+    }
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum ProtocolArg {
+    #[value(name = "1")]
+    V1,
+    #[value(name = "2")]
+    V2,
+}
+
+impl From<ProtocolArg> for ProtocolVersion {
+    fn from(arg: ProtocolArg) -> Self {
+        match arg {
+            ProtocolArg::V1 => ProtocolVersion::V1,
+            ProtocolArg::V2 => ProtocolVersion::V2,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Reboot a servo without flashing and confirm it comes back.
+    Reboot {
+        /// Device ID to reboot
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Stay in the bootloader: switch to the bootloader baud rate and
+        /// report whether it acknowledges the magic sequence.
+        #[arg(long)]
+        into_bootloader: bool,
+    },
+
+    /// Factory-reset a servo: erases EEPROM settings, the ID becomes 1 and
+    /// the baud rate the factory default.
+    Reset {
+        /// Device ID to reset (254 resets every servo on the bus)
+        #[arg(long, value_name = "ID")]
+        id: u8,
+    },
+
+    /// Ping a servo repeatedly and report round-trip latency and loss.
+    Ping {
+        /// Device ID to ping
+        #[arg(
+            long,
+            value_name = "ID",
+            required_unless_present = "broadcast",
+            conflicts_with = "broadcast"
+        )]
+        id: Option<u8>,
+
+        /// Number of pings to send
+        #[arg(long, value_name = "N", default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..))]
+        count: u32,
+
+        /// Ping the broadcast ID 0xFE once and list every ID that answers.
+        #[arg(long)]
+        broadcast: bool,
+
+        /// Only print the round-trip summary (min/avg/max and loss).
+        #[arg(long, conflicts_with = "broadcast")]
+        latency: bool,
+    },
+
+    /// List every device on the bus with its model and firmware.
+    Scan {
+        /// Print JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+
+        /// Ping every ID N times and report how often each answered,
+        /// to find devices that only respond sometimes.
+        #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(1..))]
+        health: Option<u8>,
+    },
+
+    /// Print the model, firmware, limits and live readings of a servo.
+    Info {
+        /// Device ID to query
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Print JSON instead of a table.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Poll position, speed, load, voltage and temperature until Ctrl+C.
+    Monitor {
+        /// Device ID to watch
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Time between readings, in milliseconds.
+        #[arg(long, value_name = "MS", default_value_t = 100)]
+        interval_ms: u64,
+
+        /// Print one JSON object per reading instead of a refreshing line.
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Flash the same servo over and over and report failure statistics,
+    /// e.g. to qualify a USB adapter and cable harness.
+    Soak {
+        /// Firmware file path, or "-" to read the image from stdin
+        #[arg(value_name = "FIRMWARE")]
+        firmware: String,
+
+        /// Device ID to flash
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Number of flashes
+        #[arg(long, value_name = "N", default_value_t = 10)]
+        iterations: u32,
+
+        /// Print the report as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Ping a servo repeatedly and log when it drops off the bus and comes
+    /// back, until Ctrl+C or --max-duration.
+    Watch {
+        /// Device ID to watch
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Time between pings, in milliseconds.
+        #[arg(long, value_name = "MS", default_value_t = 200)]
+        interval_ms: u64,
+    },
+
+    /// Listen to the bus without ever transmitting and log the decoded
+    /// traffic, until Ctrl+C or --max-duration.
+    Sniff {
+        /// Also write the log to this file
+        #[arg(short, long, value_name = "FILE")]
+        out: Option<PathBuf>,
+    },
+
+    /// Disable torque on several servos at the same moment, e.g. before
+    /// flashing a whole chain.
+    TorqueOff {
+        /// Device IDs, comma-separated or repeated
+        #[arg(long = "id", value_name = "ID", value_delimiter = ',', required = true)]
+        ids: Vec<u8>,
+
+        /// Use one SYNC WRITE instead of staging REG_WRITEs and firing ACTION.
+        /// Faster, but unconfirmed: servos don't answer broadcasts.
+        #[arg(long)]
+        sync: bool,
+    },
+
+    /// Save the calibration EEPROM of a servo to a JSON file.
+    Backup {
+        /// Device ID to back up
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Backup file to write
+        #[arg(long, value_name = "FILE")]
+        out: PathBuf,
+    },
+
+    /// Write a JSON backup back to a servo of the same model.
+    Restore {
+        /// Device ID to restore
+        #[arg(long, value_name = "ID")]
+        id: u8,
+
+        /// Backup file to read
+        #[arg(long = "in", value_name = "FILE")]
+        input: PathBuf,
+    },
+
+    /// Wrap a raw firmware image in a .ffw container naming its target
+    /// models and version.
+    Pack {
+        /// Raw firmware image
+        #[arg(value_name = "FIRMWARE")]
+        input: PathBuf,
+
+        /// Model number(s) the firmware is built for (comma-separated or repeated)
+        #[arg(
+            long = "model",
+            value_name = "MODEL",
+            value_delimiter = ',',
+            required = true
+        )]
+        models: Vec<u16>,
+
+        /// Firmware version recorded in the container, e.g. 3.1
+        #[arg(long, value_name = "VERSION")]
+        version: String,
+
+        /// Container file to write
+        #[arg(short, long, value_name = "FILE")]
+        out: PathBuf,
+
+        /// Detached Ed25519 signature of the image (raw or hex) to embed
+        #[arg(long, value_name = "FILE")]
+        signature: Option<PathBuf>,
+    },
+
+    /// Split a file of raw frames (from --emit-frames or a capture of the TX
+    /// line) into frames and check each one.
+    DecodeFrames {
+        /// Frames file or capture
+        #[arg(value_name = "FILE")]
+        input: PathBuf,
+    },
+
+    /// Flash several servos, each with its own firmware, from a TOML
+    /// manifest of `{ id, firmware, expect_model }` entries.
+    FlashBatch {
+        /// Manifest file
+        #[arg(value_name = "MANIFEST")]
+        manifest: PathBuf,
+
+        /// Stop at the first entry that fails instead of flashing the rest
+        #[arg(long)]
+        fail_fast: bool,
+    },
+}
+
+/// `feeflash reboot`: reboot `id` and verify the result.
+fn run_reboot(port: &mut dyn Transport, id: u8, into_bootloader: bool, options: &FlashOptions) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    println!("Rebooting device id {}...", id);
+    let status = match options.protocol {
+        ProtocolVersion::V1 => send_reboot(port, id, true).map(|s| s.map(|b| format!("{b:02X?}"))),
+        ProtocolVersion::V2 => {
+            dynamixel2::reboot(port, id, true).map(|s| s.map(|p| format!("{p:?}")))
+        }
+    };
+    match status.expect("Failed to send reboot") {
+        Some(status) => println!("Status packet before reset: {}", status),
+        None => println!("No status packet before reset."),
+    }
+
+    if into_bootloader {
+        match magic_handshake(port, options) {
+            Ok(()) => println!("Device id {} is in the bootloader.", id),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        wait_for_application(port, id, options).expect("Device did not come back after reboot");
+        println!("Device id {} is back online.", id);
+    }
+}
+
+/// `feeflash reset`: restore factory settings of `id`.
+fn run_reset(port: &mut dyn Transport, id: u8) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    println!(
+        "Factory-resetting device id {}: EEPROM settings are erased, ID becomes 1.",
+        id
+    );
+    factory_reset(port, id).expect("Factory reset failed");
+    println!("Factory reset done. Reconnect at the factory default baud rate.");
+}
+
+/// Model and firmware of `id` for display, e.g. "STS3215 fw 2.9".
+fn device_label(port: &mut dyn Transport, id: u8) -> String {
+    let Ok(model) = read_model(port, id) else {
+        return "unknown model".to_string();
+    };
+    match read_firmware_version(port, id) {
+        Ok((major, minor)) => format!("{} fw {major}.{minor}", model_label(model)),
+        Err(_) => model_label(model),
+    }
+}
+
+/// Ping `id` once and print the reply.
+fn ping_and_print(port: &mut dyn Transport, id: u8, protocol: ProtocolVersion) -> io::Result<()> {
+    match protocol {
+        ProtocolVersion::V1 => {
+            let ping_resp = send_ping(port, id)?;
+            status!("Ping response received ({} bytes)", ping_resp.len());
+            status!("Response bytes: {:02X?}", ping_resp);
+        }
+        ProtocolVersion::V2 => {
+            let status = dynamixel2::ping(port, id)?;
+            status!("Ping response received: {:?}", status);
+        }
+    }
+    Ok(())
+}
+
+/// `feeflash ping`: ICMP-style ping with latency statistics.
+fn run_ping(bus: &Bus<impl Transport>, id: u8, count: u32) {
+    let servo = bus.servo(id);
+    println!("Pinging device id {} ({} pings)...", id, count);
+    if let Ok(model) = bus.transaction(|port| read_model(port, id)) {
+        println!("Device id {} is {}.", id, describe_model(model));
+    }
+
+    let mut rtts: Vec<Duration> = Vec::new();
+    for seq in 1..=count {
+        let start = Instant::now();
+        let reply = servo.ping();
+        let rtt = start.elapsed();
+        match reply {
+            Ok(status) => {
+                println!(
+                    "Reply from id {}: seq={} error=0x{:02X} time={:.2} ms",
+                    id,
+                    seq,
+                    status.error,
+                    rtt.as_secs_f64() * 1000.0
+                );
+                rtts.push(rtt);
+            }
+            Err(e) => println!("seq={}: {}", seq, e),
+        }
+    }
+
+    let received = rtts.len() as u32;
+    println!("--- id {} ping statistics ---", id);
+    println!(
+        "{} sent, {} received, {:.1}% loss",
+        count,
+        received,
+        100.0 * f64::from(count - received) / f64::from(count)
+    );
+    if received == 0 {
+        std::process::exit(1);
+    }
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let min = rtts.iter().min().copied().unwrap_or_default();
+    let max = rtts.iter().max().copied().unwrap_or_default();
+    let avg = rtts.iter().sum::<Duration>() / received;
+    println!(
+        "rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+        ms(min),
+        ms(avg),
+        ms(max)
+    );
+}
+
+/// `feeflash ping --latency`: round-trip summary of `count` pings.
+fn run_latency(port: &mut dyn Transport, id: u8, count: u32, protocol: ProtocolVersion) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    println!(
+        "Measuring ping latency of device id {} ({} pings)...",
+        id, count
+    );
+    let stats = match measure_ping_latency_with(port, id, count as usize, protocol) {
+        Ok(stats) => stats,
+        Err(e) => {
+            eprintln!("No ping answered: {e}");
+            std::process::exit(1);
+        }
+    };
+
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!(
+        "{} sent, {} received, {:.1}% loss",
+        stats.sent,
+        stats.received,
+        100.0 * stats.loss()
+    );
+    println!(
+        "rtt min/avg/max = {:.2}/{:.2}/{:.2} ms",
+        ms(stats.min),
+        ms(stats.mean),
+        ms(stats.max)
+    );
+}
+
+/// `feeflash ping --broadcast`: one ping to 0xFE, list every ID that answers.
+fn run_broadcast_ping(port: &mut dyn Transport, protocol: ProtocolVersion) {
+    println!("Pinging broadcast id 0xFE...");
+    let window = Duration::from_millis(BROADCAST_WINDOW_MS);
+    let responders = match protocol {
+        ProtocolVersion::V1 => broadcast_ping(port, window),
+        ProtocolVersion::V2 => dynamixel2::broadcast_ping(port, window),
+    }
+    .expect("Broadcast ping failed");
+
+    if responders.is_empty() {
+        eprintln!("No device answered the broadcast ping.");
+        std::process::exit(1);
+    }
+    let ids: Vec<u8> = responders.iter().map(|p| p.id).collect();
+    println!("Responding IDs: {:?}", ids);
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    for status in &responders {
+        // Protocol 2 ping replies carry the model number.
+        let label = match (protocol, status.params.get(..2)) {
+            (ProtocolVersion::V2, Some(&[lo, hi])) => model_label(u16::from_le_bytes([lo, hi])),
+            _ => device_label(port, status.id),
+        };
+        println!("  id {:3}: {}", status.id, label);
+    }
+}
+
+/// `feeflash info`: decoded register dump of `id`.
+fn run_info(bus: &Bus<impl Transport>, id: u8, json: bool) {
+    if let Err(e) = bus.servo(id).ping() {
+        eprintln!("Device id {id} does not answer: {e}");
+        std::process::exit(1);
+    }
+    let info = bus
+        .transaction(|port| DeviceInfo::read(port, id))
+        .expect("Failed to read registers");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&info).expect("Failed to encode JSON")
+        );
+    } else {
+        println!("{info}");
+    }
+}
+
+/// Print a prominent warning for IDs whose replies were garbled.
+fn warn_collisions(collisions: &[u8]) {
+    if collisions.is_empty() {
+        return;
+    }
+    eprintln!("WARNING: garbled replies from ids {:?}.", collisions);
+    eprintln!("WARNING: several servos probably share each of these IDs. Connect them one at");
+    eprintln!("WARNING: a time and give each a unique ID before flashing.");
+}
+
+/// How the ID scans ping the bus: `--protocol`, `--reverse-scan` and
+/// `--scan-attempts`.
+fn scan_options(args: &Args) -> ScanOptions {
+    ScanOptions {
+        protocol: args.protocol.map(ProtocolVersion::from),
+        reverse: args.reverse_scan,
+        attempts: args.scan_attempts,
+    }
+}
+
+/// `feeflash scan`: table of every device that answers a ping.
+fn run_scan(port: &mut dyn Transport, deadline: Deadline, options: &ScanOptions, json: bool) {
+    let scan = scan_devices(port, deadline, options).expect("ID scan failed");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&scan).expect("Failed to encode JSON")
+        );
+        return;
+    }
+    warn_collisions(&scan.collisions);
+    if scan.devices.is_empty() {
+        eprintln!("No devices responded to ping. Please check wiring and baud rate.");
+        std::process::exit(1);
+    }
+
+    println!(
+        "{:>3}  {:<8}  {:<20}  {:<8}  ERROR",
+        "ID", "PROTOCOL", "MODEL", "FIRMWARE"
+    );
+    for device in &scan.devices {
+        let protocol = match device.protocol {
+            ProtocolVersion::V1 => "1",
+            ProtocolVersion::V2 => "2",
+        };
+        println!(
+            "{:>3}  {:<8}  {:<20}  {:<8}  0x{:02X}",
+            device.id,
+            protocol,
+            device
+                .model
+                .map_or_else(|| "n/a".to_string(), describe_model),
+            device.firmware.as_deref().unwrap_or("n/a"),
+            device.status.error
+        );
+    }
+}
+
+/// `feeflash scan --health N`: answer rate of every ID over `attempts` pings.
+fn run_health(
+    port: &mut dyn Transport,
+    attempts: u8,
+    protocol: Option<ProtocolVersion>,
+    json: bool,
+) {
+    let health = scan_health(port, attempts, protocol).expect("Bus health scan failed");
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&health).expect("Failed to encode JSON")
+        );
+        return;
+    }
+    if health.is_empty() {
+        eprintln!("No devices responded to ping. Please check wiring and baud rate.");
+        std::process::exit(1);
+    }
+
+    println!(
+        "{:>3}  {:>9}  {:>8}  {:>7}",
+        "ID", "RESPONSES", "TIMEOUTS", "GARBLED"
+    );
+    for entry in &health {
+        println!(
+            "{:>3}  {:>9}  {:>8}  {:>7}",
+            entry.id, entry.responses, entry.timeouts, entry.garbled
+        );
+    }
+    let intermittent: Vec<u8> = health
+        .iter()
+        .filter(|entry| entry.is_intermittent())
+        .map(|entry| entry.id)
+        .collect();
+    if !intermittent.is_empty() {
+        eprintln!(
+            "Warning: ids {:?} answered only some of {} pings; check their wiring and power.",
+            intermittent, attempts
+        );
+    }
+    let collisions: Vec<u8> = health
+        .iter()
+        .filter(|entry| entry.responses == 0)
+        .map(|entry| entry.id)
+        .collect();
+    warn_collisions(&collisions);
+}
+
+/// Consecutive failed readings before `monitor` reports the servo as gone.
+const MONITOR_MAX_MISSES: u32 = 3;
+
+/// `feeflash monitor`: live telemetry of `id`, until interrupted.
+fn run_monitor(port: &mut dyn Transport, id: u8, interval: Duration, json: bool) -> ! {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    let Err(e) = monitor(port, id, interval, json);
+    // The reader went away, e.g. `feeflash monitor --json | head`.
+    if e.kind() == io::ErrorKind::BrokenPipe {
+        std::process::exit(0);
+    }
+    eprintln!("Failed to write to stdout: {e}");
+    std::process::exit(1);
+}
+
+/// The loop of [`run_monitor`], until stdout fails. With `json`, stdout
+/// carries nothing but the telemetry lines; status text goes to stderr.
+fn monitor(
+    port: &mut dyn Transport,
+    id: u8,
+    interval: Duration,
+    json: bool,
+) -> io::Result<Infallible> {
+    let stdout = std::io::stdout();
+    let mut out = stdout.lock();
+    let header = format!("Monitoring device id {id} every {interval:?} (Ctrl+C to stop)...");
+    if json {
+        eprintln!("{header}");
+    } else {
+        writeln!(out, "{header}")?;
+    }
+    // Past the header, a status line first ends the line of telemetry.
+    let status = |out: &mut io::StdoutLock, message: String| {
+        if json {
+            eprintln!("{message}");
+            Ok(())
+        } else {
+            writeln!(out, "\n{message}")
+        }
+    };
+
+    let mut misses = 0;
+    loop {
+        let started = Instant::now();
+        match Telemetry::read(port, id) {
+            Ok(telemetry) => {
+                if misses >= MONITOR_MAX_MISSES {
+                    status(&mut out, format!("Device id {id} is responding again."))?;
+                }
+                misses = 0;
+                if json {
+                    let line = serde_json::to_string(&telemetry).expect("Failed to encode JSON");
+                    writeln!(out, "{line}")?;
+                } else {
+                    write!(out, "\x1b[2K\r{telemetry}")?;
+                }
+            }
+            Err(e) => {
+                misses += 1;
+                if misses == MONITOR_MAX_MISSES {
+                    status(
+                        &mut out,
+                        format!(
+                            "Device id {id} stopped responding ({misses} readings failed, last: {e})."
+                        ),
+                    )?;
+                }
+            }
+        }
+        out.flush()?;
+        std::thread::sleep(interval.saturating_sub(started.elapsed()));
+    }
+}
+
+/// Drops the step-by-step output of a flash; warnings still go to stderr.
+#[derive(Debug)]
+struct WarningsOnly;
+
+impl Progress for WarningsOnly {
+    fn event(&self, event: FlashEvent) {
+        if let FlashEvent::Warning(message) = event {
+            eprintln!("{message}");
+        }
+    }
+}
+
+/// `--progress-json`: frames as JSON lines on stdout, everything else on
+/// stderr.
+#[derive(Debug)]
+struct JsonProgress;
+
+impl Progress for JsonProgress {
+    fn event(&self, event: FlashEvent) {
+        match event {
+            FlashEvent::Frame {
+                frame,
+                total,
+                index,
+                retries,
+            } => {
+                let line = serde_json::json!({
+                    "event": "frame",
+                    "index": index,
+                    "chunk": frame,
+                    "total": total,
+                    "retries": retries,
+                });
+                println!("{line}");
+            }
+            FlashEvent::Message(message) | FlashEvent::Warning(message) => {
+                eprintln!("{message}")
+            }
+        }
+    }
+}
+
+/// Status lines and frames as the library prints them, for a binary built
+/// with the `silent` feature, which compiles that output out.
+#[derive(Debug)]
+struct ConsoleProgress;
+
+impl Progress for ConsoleProgress {
+    fn event(&self, event: FlashEvent) {
+        match event {
+            FlashEvent::Message(message) => status!("{message}"),
+            FlashEvent::Warning(message) => eprintln!("{message}"),
+            FlashEvent::Frame {
+                frame,
+                total,
+                index,
+                ..
+            } => status!("Frame index={} (chunk {}/{}) ACKed", index, frame, total),
+        }
+    }
+}
+
+/// `feeflash soak`: flash `id` `iterations` times, one line per iteration,
+/// then the statistics. Exits non-zero if any iteration failed.
+fn run_soak(
+    port: &mut dyn Transport,
+    id: u8,
+    firmware: &[u8],
+    app_baud: u32,
+    iterations: u32,
+    options: FlashOptions,
+    json: bool,
+) {
+    let options = FlashOptions {
+        progress: Some(Arc::new(WarningsOnly)),
+        ..options
+    };
+    eprintln!("Flashing device id {id} {iterations} times...");
+    let report = soak(
+        port,
+        id,
+        firmware,
+        app_baud,
+        iterations,
+        &options,
+        |i| match &i.error {
+            None => eprintln!(
+                "[{:>3}/{iterations}] ok      {:6.2}s  {} retries",
+                i.iteration,
+                i.duration.as_secs_f64(),
+                i.retries.unwrap_or(0)
+            ),
+            Some(error) => eprintln!(
+                "[{:>3}/{iterations}] FAILED  {:6.2}s  {error}",
+                i.iteration,
+                i.duration.as_secs_f64()
+            ),
+        },
+    )
+    .expect("Soak test failed");
+
+    let histogram = report.retry_histogram();
+    if json {
+        let summary = serde_json::json!({
+            "iterations": report.iterations,
+            "success_rate": report.success_rate(),
+            "retry_histogram": histogram,
+            "slowest": report.slowest(),
+        });
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&summary).expect("Failed to encode JSON")
+        );
+    } else {
+        println!(
+            "{} of {} iterations succeeded ({:.1}%).",
+            report.successes(),
+            report.iterations.len(),
+            report.success_rate() * 100.0
+        );
+        for (retries, count) in &histogram {
+            println!("  {retries:>3} retries: {count}");
+        }
+        if let Some(slowest) = report.slowest() {
+            println!(
+                "Slowest: iteration {} ({:.2}s).",
+                slowest.iteration,
+                slowest.duration.as_secs_f64()
+            );
+        }
+    }
+    if report.successes() < report.iterations.len() {
+        std::process::exit(1);
+    }
+}
+
+/// `feeflash watch`: one timestamped line per up/down change of `id`, with
+/// seconds since the start as the timestamp.
+fn run_watch(
+    port: &mut dyn Transport,
+    id: u8,
+    interval: Duration,
+    protocol: ProtocolVersion,
+    deadline: Deadline,
+) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    println!(
+        "Watching device id {} every {:?} (Ctrl+C to stop)...",
+        id, interval
+    );
+
+    let start = Instant::now();
+    // Whether the last ping was answered, and since when.
+    let mut state: Option<(bool, Instant)> = None;
+    let (mut up, mut down) = (0u64, 0u64);
+    watch_ping(port, id, interval, protocol, deadline, |event| {
+        let now = Instant::now();
+        let is_up = matches!(event, PingEvent::Up { .. });
+        if is_up {
+            up += 1;
+        } else {
+            down += 1;
+        }
+        if state.is_some_and(|(was_up, _)| was_up == is_up) {
+            return;
+        }
+        let stamp = (now - start).as_secs_f64();
+        let after = state.map_or_else(String::new, |(was_up, since)| {
+            let was = if was_up { "up" } else { "down" };
+            format!(", was {was} for {:.1}s", (now - since).as_secs_f64())
+        });
+        match event {
+            PingEvent::Up { latency } => println!(
+                "{stamp:10.3} id {id} UP   ({:.1} ms{after})",
+                latency.as_secs_f64() * 1000.0
+            ),
+            PingEvent::Down { error } => println!("{stamp:10.3} id {id} DOWN ({error}{after})"),
+        }
+        state = Some((is_up, now));
+    })
+    .expect("Watch failed");
+    println!("{up} ping(s) answered, {down} missed.");
+}
+
+/// `feeflash torque-off`: torque off on all of `ids` at once.
+fn run_torque_off(port: &mut dyn Transport, ids: &[u8], sync: bool) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    if sync {
+        sync_torque_off(port, ids).expect("SYNC WRITE failed");
+        println!("Sent torque off to ids {:?}.", ids);
+        return;
+    }
+
+    // Stage on every servo first, so a missing one aborts before anything
+    // changes, then apply them together.
+    for &id in ids {
+        if let Err(e) = reg_write(port, id, TORQUE_ENABLE, &[0]) {
+            eprintln!("Staging torque off on device id {id} failed: {e}");
+            std::process::exit(1);
+        }
+        println!("Staged torque off on device id {}.", id);
+    }
+    send_action(port).expect("Failed to send ACTION");
+    println!("Torque off applied on ids {:?}.", ids);
+}
+
+/// `feeflash backup`: save the calibration EEPROM of `id` to `out`.
+fn run_backup(port: &mut dyn Transport, id: u8, out: &Path) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    let backup = EepromBackup::read(port, id, CALIBRATION).expect("EEPROM backup failed");
+    backup.save(out).expect("Failed to write backup file");
+    println!(
+        "Saved {} registers of device id {} (model {}) to {}",
+        backup.registers.len(),
+        id,
+        backup.model,
+        out.display()
+    );
+}
+
+/// `feeflash restore`: write the backup in `input` to `id`.
+fn run_restore(port: &mut dyn Transport, id: u8, input: &Path) {
+    port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
+        .expect("Failed to set ping timeout");
+    let backup = EepromBackup::load(input).expect("Failed to read backup file");
+    if let Err(e) = backup.restore(port, id) {
+        eprintln!("EEPROM restore failed: {e}");
+        std::process::exit(1);
+    }
+    println!(
+        "Restored {} registers to device id {}.",
+        backup.registers.len(),
+        id
+    );
+}
+
+/// `feeflash pack`: write `input` with its metadata to the container `out`.
+fn run_pack(input: &Path, models: &[u16], version: &str, out: &Path, signature: Option<&Path>) {
+    let payload = std::fs::read(input).expect("Failed to read firmware file");
+    check_firmware(&payload, DEFAULT_MAX_FIRMWARE_SIZE).expect("Refusing to pack firmware");
+    let signature = signature.map(|path| {
+        let bytes = raw_or_hex(&std::fs::read(path).expect("Failed to read signature file"));
+        <[u8; SIGNATURE_LEN]>::try_from(bytes).unwrap_or_else(|bytes| {
+            eprintln!(
+                "Signature file {} holds {} bytes, expected {SIGNATURE_LEN}",
+                path.display(),
+                bytes.len()
+            );
+            std::process::exit(1);
+        })
+    });
+    let container = Container {
+        models: models.to_vec(),
+        version: version.to_string(),
+        payload,
+        signature,
+    };
+    let bytes = container.pack().expect("Failed to pack firmware");
+    std::fs::write(out, bytes).expect("Failed to write container");
+    println!(
+        "Packed {} ({} bytes, version {}, for {}{}) into {}",
+        input.display(),
+        container.payload.len(),
+        container.version,
+        models_label(&container.models),
+        if container.signature.is_some() {
+            ", signed"
+        } else {
+            ""
+        },
+        out.display()
+    );
+}
+
+/// Enforce the firmware signature when `--pubkey` is set: exit unless the
+/// signature (from `--signature` or `embedded` in the container) verifies,
+/// or `--allow-unsigned` is passed. `--require-signature` makes the key
+/// mandatory and rules out `--allow-unsigned`.
+fn check_signature(image: &[u8], embedded: Option<&[u8]>, args: &Args) {
+    let signature = match &args.signature {
+        Some(path) => Some(raw_or_hex(
+            &std::fs::read(path).expect("Failed to read signature file"),
+        )),
+        None => embedded.map(<[u8]>::to_vec),
+    };
+    let Some(pubkey) = &args.pubkey else {
+        if signature.is_some() {
+            status!("Firmware is signed, but no --pubkey is given; signature not checked.");
+        }
+        return;
+    };
+    let pubkey = raw_or_hex(&std::fs::read(pubkey).expect("Failed to read public key file"));
+
+    let result = match signature {
+        Some(signature) => verify_image_signature(image, &signature, &pubkey),
+        None => Err("Firmware is unsigned".to_string()),
+    };
+    match result {
+        Ok(()) => status!("Firmware signature verified."),
+        Err(e) if args.allow_unsigned => {
+            eprintln!("Warning: {e}; flashing anyway because of --allow-unsigned.")
+        }
+        Err(e) if args.require_signature => {
+            eprintln!("{e}. Refusing to flash because of --require-signature.");
+            std::process::exit(1);
+        }
+        Err(e) => {
+            eprintln!("{e}. Refusing to flash; pass --allow-unsigned to override.");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(feature = "signing")]
+fn verify_image_signature(image: &[u8], signature: &[u8], pubkey: &[u8]) -> Result<(), String> {
+    verify_signature(image, signature, pubkey).map_err(|e| e.to_string())
+}
+
+#[cfg(not(feature = "signing"))]
+fn verify_image_signature(_image: &[u8], _signature: &[u8], _pubkey: &[u8]) -> Result<(), String> {
+    Err("feeflash was built without the signing feature and can't check signatures".to_string())
+}
+
+/// Check `firmware` against `--sha256` or a `<path>.sha256` sidecar, and
+/// exit on a mismatch. Does nothing when neither is there.
+fn check_digest(path: &str, firmware: &[u8], sha256: Option<&str>) {
+    let sidecar = PathBuf::from(format!("{path}.sha256"));
+    let (expected, source) = match sha256 {
+        Some(hex) => (parse_sha256_hex(hex), "--sha256".to_string()),
+        None if path != "-" && sidecar.is_file() => {
+            let name = Path::new(path)
+                .file_name()
+                .map_or_else(String::new, |name| name.to_string_lossy().into_owned());
+            let expected = std::fs::read_to_string(&sidecar)
+                .and_then(|text| parse_sha256_sidecar(&text, &name));
+            (expected, sidecar.display().to_string())
+        }
+        None => return,
+    };
+    let expected = expected.unwrap_or_else(|e| {
+        eprintln!("Bad SHA-256 from {source}: {e}");
+        std::process::exit(1);
+    });
+
+    #[cfg(feature = "sha256")]
+    match verify_digest(firmware, &expected) {
+        Ok(()) => status!("SHA-256 matches {source}."),
+        Err(e) => {
+            eprintln!("{e}");
+            std::process::exit(1);
+        }
+    }
+    #[cfg(not(feature = "sha256"))]
+    {
+        let _ = (firmware, expected);
+        eprintln!(
+            "A SHA-256 is given by {source}, but feeflash was built without the sha256 feature."
+        );
+        std::process::exit(1);
+    }
+}
+
+/// Model names joined for display, e.g. "STS3215 (777), model 1190".
+fn models_label(models: &[u16]) -> String {
+    models
+        .iter()
+        .map(|&model| match lookup_model(model) {
+            Some(info) => format!("{} ({model})", info.name),
+            None => model_label(model),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Load `manifest` and read every firmware it names, exiting on the first
+/// bad entry.
+fn prepare_batch(manifest: &Path, max_firmware_size: usize, force: bool) -> Vec<BatchJob> {
+    let jobs = Manifest::load(manifest)
+        .and_then(|manifest| manifest.prepare(max_firmware_size, force))
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid manifest '{}': {e}", manifest.display());
+            std::process::exit(1);
+        });
+    println!(
+        "Manifest '{}': {} device(s) to flash.",
+        manifest.display(),
+        jobs.len()
+    );
+    jobs
+}
+
+/// `feeflash flash-batch`: flash every job and print one line per device.
+/// Exits non-zero if any device failed.
+fn run_flash_batch(
+    port: &mut dyn Transport,
+    jobs: &[BatchJob],
+    app_baud: u32,
+    options: &FlashOptions,
+    fail_fast: bool,
+) {
+    let results = run_batch(port, jobs, app_baud, options, fail_fast);
+
+    println!();
+    println!("{:>3}  {:<8}  {:<30}  DETAILS", "ID", "RESULT", "FIRMWARE");
+    for result in &results {
+        let (status, details) = match &result.result {
+            Ok(stats) => ("ok", stats.to_string()),
+            Err(e) => ("FAILED", e.to_string()),
+        };
+        println!(
+            "{:>3}  {:<8}  {:<30}  {}",
+            result.id,
+            status,
+            result.firmware_path.display(),
+            details
+        );
+    }
+    for job in &jobs[results.len()..] {
+        println!(
+            "{:>3}  {:<8}  {:<30}  not attempted (--fail-fast)",
+            job.id,
+            "skipped",
+            job.firmware_path.display()
+        );
+    }
+
+    let failed = results.iter().filter(|r| r.result.is_err()).count();
+    if failed > 0 || results.len() < jobs.len() {
+        eprintln!(
+            "{} of {} device(s) flashed.",
+            results.len() - failed,
+            jobs.len()
+        );
+        std::process::exit(1);
+    }
+    println!("All {} device(s) flashed.", jobs.len());
+}
+
+/// Flash the firmware onto each of `--ids`, or onto every ID a scan finds
+/// with `--all`, one after the other. Reports like [`run_flash_batch`].
+fn run_flash_ids(
+    port: &mut dyn Transport,
+    args: &Args,
+    firmware: Vec<u8>,
+    options: &FlashOptions,
+    normal_timeout: Duration,
+) {
+    let ids = if args.all {
+        println!("Scanning all IDs (0..=253)...");
+        let report = scan_bus(port, options.deadline, &scan_options(args)).expect("ID scan failed");
+        if !report.collisions.is_empty() {
+            warn_collisions(&report.collisions);
+            eprintln!("Refusing to flash a bus with shared IDs. Please fix the IDs first.");
+            std::process::exit(1);
+        }
+        if report.found.is_empty() {
+            eprintln!("No devices responded to ping. Please check wiring.");
+            std::process::exit(1);
+        }
+        println!(
+            "Found {} device(s): {}.",
+            report.found.len(),
+            report
+                .found
+                .iter()
+                .map(|id| id.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+        report.found
+    } else {
+        args.ids.clone()
+    };
+    port.set_timeout(normal_timeout)
+        .expect("Failed to restore normal timeout");
+
+    let jobs: Vec<BatchJob> = ids
+        .into_iter()
+        .map(|id| BatchJob {
+            id,
+            firmware_path: PathBuf::from(&args.firmware),
+            image: firmware.clone(),
+            expected_models: options.expected_models.clone(),
+        })
+        .collect();
+    run_flash_batch(port, &jobs, args.baud, options, args.fail_fast);
+}
+
+/// Flash `args.id` on every `--port` at once, printing each port's output
+/// with the port as prefix. Exits non-zero if any port failed.
+fn run_flash_many(args: &Args, options: FlashOptions, firmware: Vec<u8>) {
+    let unsupported = [
+        (args.id.is_none(), "--id is required"),
+        (args.recovery, "--recovery is not supported"),
+        (args.replay.is_some(), "--replay is not supported"),
+        (args.trace_file.is_some(), "--trace-file is not supported"),
+        (args.preserve_eeprom, "--preserve-eeprom is not supported"),
+        (args.auto_baud, "--auto-baud is not supported"),
+        (
+            args.expect_version.is_some(),
+            "--expect-version is not supported",
+        ),
+    ];
+    for (hit, message) in unsupported {
+        if hit {
+            eprintln!("{message} with several --port values.");
+            std::process::exit(1);
+        }
+    }
+    let id = args.id.expect("checked above");
+
+    let firmware: Arc<[u8]> = firmware.into();
+    let jobs: Vec<FlashJob> = args
+        .ports
+        .iter()
+        .map(|port| FlashJob {
+            port_name: port.clone(),
+            app_baud: args.baud,
+            id,
+            firmware: firmware.clone(),
+            options: options.clone(),
+        })
+        .collect();
+    let names = args.ports.clone();
+    println!("Flashing device id {id} on {} ports...", names.len());
+    let outcomes = flash_many(jobs, move |index, event| {
+        let port = &names[index];
+        match event {
+            FlashEvent::Message(message) => println!("[{port}] {message}"),
+            FlashEvent::Warning(message) => eprintln!("[{port}] {message}"),
+            // Every 10%, so four ports don't flood the terminal.
+            FlashEvent::Frame { frame, total, .. } => {
+                if frame == total || (frame * 10 / total) != ((frame - 1) * 10 / total) {
+                    println!("[{port}] {frame}/{total} frames");
+                }
+            }
+        }
+    });
+
+    println!();
+    let mut failed = 0;
+    for outcome in &outcomes {
+        match &outcome.result {
+            Ok(stats) => println!("[{}] ok: {}", outcome.port_name, stats),
+            Err(e) => {
+                failed += 1;
+                eprintln!("[{}] FAILED: {}", outcome.port_name, e);
+            }
+        }
+    }
+    if failed > 0 {
+        eprintln!("{failed} of {} port(s) failed.", outcomes.len());
+        std::process::exit(1);
+    }
+    println!("All {} port(s) flashed.", outcomes.len());
+}
+
+/// `--offset`, `--length`: a byte count in decimal or with a 0x prefix.
+fn parse_size(value: &str) -> Result<usize, String> {
+    let parsed = match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => usize::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.map_err(|e| format!("{e}"))
+}
+
+/// `--emit-frames`: write the frames of `firmware` to `path`, exactly as
+/// the transfer would send them.
+fn emit_frames(firmware: &[u8], options: &FlashOptions, path: &Path) {
+    let region = firmware_region(firmware.len(), options).expect("region checked on load");
+    if region.len() < firmware.len() {
+        println!(
+            "Region 0x{:X}..0x{:X} ({} of {} bytes)",
+            region.start,
+            region.end,
+            region.len(),
+            firmware.len()
+        );
+    }
+    let firmware = &firmware[region];
+    let firmware = match options.flash_page_size {
+        Some(page_size) => pad_to_page(firmware, page_size),
+        None => Cow::Borrowed(firmware),
+    };
+    let frames = FirmwareFrames::new(&firmware);
+    let count = frames.len();
+    let bytes: Vec<u8> = frames
+        .flat_map(|frame| frame.to_bytes_with(options.checksum))
+        .collect();
+    std::fs::write(path, &bytes).expect("Failed to write frames");
+    println!(
+        "Wrote {} frames ({} bytes) to {}",
+        count,
+        bytes.len(),
+        path.display()
+    );
+}
+/// `feeflash sniff`: read the port without writing to it and print one
+/// timestamped line per decoded item, in seconds since the start. Lines also
+/// go to `out` if given.
+fn run_sniff(
+    port: &mut dyn Transport,
+    out: Option<&Path>,
+    checksum: ChecksumKind,
+    deadline: Deadline,
+) {
+    let mut file = out.map(|path| {
+        io::BufWriter::new(std::fs::File::create(path).expect("Failed to create sniff log"))
+    });
+    // Read timeouts double as the idle detection of the decoder.
+    port.set_timeout(IDLE_GAP)
+        .expect("Failed to set read timeout");
+    println!("Sniffing (Ctrl+C to stop)...");
+
+    let start = Instant::now();
+    let mut sniffer = Sniffer::new(checksum);
+    let mut buf = [0u8; 256];
+    let mut log = |records: Vec<SniffRecord>| {
+        for record in records {
+            println!("{record}");
+            if let Some(file) = &mut file {
+                writeln!(file, "{record}").expect("Failed to write sniff log");
+                file.flush().expect("Failed to write sniff log");
+            }
+        }
+    };
+    while !deadline.is_expired() {
+        match port.read(&mut buf) {
+            Ok(n) => log(sniffer.push(start.elapsed(), &buf[..n])),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => log(sniffer.flush()),
+            Err(e) => panic!("Sniff failed: {e}"),
+        }
+    }
+    log(sniffer.flush());
+}
+
+/// `feeflash decode-frames`: one line per frame of `input`, with index,
+/// checksum and stop byte, or why it is invalid. A capture of a whole flash
+/// is cut to what follows the last magic and init byte. Exits non-zero if
+/// any frame is invalid.
+fn run_decode_frames(input: &Path, checksum: ChecksumKind) {
+    let bytes = std::fs::read(input).expect("Failed to read frames file");
+    let start = bytes
+        .windows(BOOTLOADER_MAGIC.len())
+        .rposition(|w| w == BOOTLOADER_MAGIC)
+        .map_or(0, |at| {
+            let after = at + BOOTLOADER_MAGIC.len();
+            after + usize::from(bytes.get(after) == Some(&BOOTLOADER_INIT))
+        });
+    if start > 0 {
+        println!("Skipping {start} bytes up to the bootloader magic and init.");
+    }
+
+    let len = checksum.frame_len();
+    let results = split_frames(&bytes[start..], checksum);
+    let mut invalid = 0;
+    for (n, result) in results.iter().enumerate() {
+        let offset = start + n * len;
+        match result {
+            Ok(frame) => {
+                let raw = frame.to_bytes_with(checksum);
+                let trailer: String = raw[67..len - 1]
+                    .iter()
+                    .map(|b| format!("{b:02X}"))
+                    .collect();
+                println!(
+                    "frame {:4} @ {:6}: index 0x{:02X}, checksum {}, stop {}{}",
+                    n + 1,
+                    offset,
+                    frame.index,
+                    trailer,
+                    raw[len - 1],
+                    if frame.is_last { " (last)" } else { "" }
+                );
+            }
+            Err(e) => {
+                invalid += 1;
+                println!("frame {:4} @ {:6}: INVALID: {e}", n + 1, offset);
+            }
+        }
+    }
+    println!("{} frames, {} invalid.", results.len(), invalid);
+    if invalid > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// Read the firmware to flash and check it: digest, container metadata and
+/// signature, and the region to flash. Sets the expected models from a
+/// container. Returns the image and the version to expect after flashing.
+fn load_firmware(
+    args: &Args,
+    firmware_path: &str,
+    options: &mut FlashOptions,
+) -> (Vec<u8>, Option<FirmwareVersion>) {
+    let firmware = if firmware_path == "-" {
+        read_firmware(io::stdin().lock(), options.max_firmware_size)
+    } else {
+        std::fs::File::open(firmware_path)
+            .and_then(|file| read_firmware(file, options.max_firmware_size))
+    };
+    let firmware = firmware.unwrap_or_else(|e| {
+        eprintln!("Failed to read firmware '{firmware_path}': {e}");
+        std::process::exit(1);
+    });
+    // The digest is of the file as stored, compressed or not.
+    check_digest(firmware_path, &firmware, args.sha256.as_deref());
+    let (compressed_len, gzipped) = (firmware.len(), firmware.starts_with(&GZIP_MAGIC));
+    let firmware = decompress_firmware(firmware, options.max_firmware_size).unwrap_or_else(|e| {
+        eprintln!("Failed to read firmware '{firmware_path}': {e}");
+        std::process::exit(1);
+    });
+    if gzipped {
+        status!(
+            "Decompressed gzip firmware '{}': {} -> {} bytes",
+            firmware_path,
+            compressed_len,
+            firmware.len()
+        );
+    }
+
+    let mut expected_version = args.expect_version;
+    let (firmware, embedded_signature) = if Container::is_container(&firmware) {
+        let container = Container::parse(&firmware).unwrap_or_else(|e| {
+            eprintln!("Invalid firmware container '{firmware_path}': {e}");
+            std::process::exit(1);
+        });
+        status!(
+            "Firmware container '{}': version {}, for {}, {} bytes, CRC OK",
+            firmware_path,
+            container.version,
+            models_label(&container.models),
+            container.payload.len()
+        );
+        if !args.models.is_empty() {
+            status!("Checking the device against the container's models; --model is ignored.");
+        }
+        if !args.force {
+            options.expected_models = Some(container.models.clone());
+        }
+        if expected_version.is_none() {
+            match container.version.parse() {
+                Ok(version) => expected_version = Some(version),
+                Err(_) => status!(
+                    "Container version '{}' isn't MAJ.MIN; the version after flashing is not checked.",
+                    container.version
+                ),
+            }
+        }
+        (container.payload, container.signature)
+    } else {
+        if !args.recovery && !args.force && args.models.is_empty() {
+            eprintln!(
+                "Pass --model with the model number(s) this firmware is built for, or --force to skip the check."
+            );
+            std::process::exit(1);
+        }
+        status!("Firmware '{}' ({} bytes)", firmware_path, firmware.len());
+        (firmware, None)
+    };
+    if !args.force
+        && let Err(e) = validate_firmware_image(&firmware)
+    {
+        eprintln!("Refusing to flash '{firmware_path}': {e}. Use --force to flash anyway.");
+        std::process::exit(1);
+    }
+    check_signature(&firmware, embedded_signature.as_ref().map(|s| &s[..]), args);
+    if let Err(e) = firmware_region(firmware.len(), options) {
+        eprintln!("Can't flash a region of '{firmware_path}': {e}");
+        std::process::exit(1);
+    }
+    (firmware, expected_version)
 }
 
 fn main() {
@@ -48,28 +1799,306 @@ fn main() {
     // }
 
     let args = Args::parse();
-    let firmware_path = args.firmware.clone();
-    let maybe_id = args.id;
-    let recovery = args.recovery;
+    if args.progress_json {
+        if args.command.is_some() || args.ports.len() > 1 {
+            eprintln!("--progress-json is only supported when flashing a single device.");
+            std::process::exit(1);
+        }
+        STATUS_TO_STDERR.store(true, Ordering::Relaxed);
+    }
+    if let Some(Command::Pack {
+        input,
+        models,
+        version,
+        out,
+        signature,
+    }) = &args.command
+    {
+        // Offline: no port needed.
+        run_pack(input, models, version, out, signature.as_deref());
+        return;
+    }
+    if let Some(Command::DecodeFrames { input }) = &args.command {
+        run_decode_frames(input, args.checksum.into());
+        return;
+    }
+    // Check every manifest entry before touching the bus.
+    let batch_jobs = match &args.command {
+        // Refused rather than flashing every entry unchecked.
+        Some(Command::FlashBatch { .. })
+            if args.require_signature || args.pubkey.is_some() || args.signature.is_some() =>
+        {
+            eprintln!(
+                "flash-batch doesn't check signatures, so it can't run with --pubkey, --signature or --require-signature."
+            );
+            std::process::exit(1);
+        }
+        Some(Command::FlashBatch { manifest, .. }) => {
+            Some(prepare_batch(manifest, args.max_firmware_size, args.force))
+        }
+        _ => None,
+    };
+    let deadline = Deadline::from_max_duration(args.max_duration.map(Duration::from_secs));
+    let mut options = FlashOptions {
+        deadline,
+        max_retries: args.max_retries,
+        max_total_retries: args.max_total_retries,
+        max_firmware_size: args.max_firmware_size,
+        flash_page_size: args.flash_page_size.map(|size| size as usize),
+        region_offset: args.offset,
+        region_length: args.length,
+        inter_frame_delay: Duration::from_millis(args.frame_delay_ms),
+        frame_timeout: (args.frame_timeout_ms > 0)
+            .then(|| Duration::from_millis(args.frame_timeout_ms)),
+        boot_settle: Duration::from_millis(args.boot_settle_ms),
+        bootloader_baud: args.bootloader_baud,
+        reboot_delay: Duration::from_millis(args.reboot_delay_ms),
+        half_duplex: args.half_duplex,
+        torque_off: !args.no_torque_off,
+        expected_models: (!args.force).then(|| args.models.clone()),
+        protocol: args
+            .protocol
+            .map_or(ProtocolVersion::V1, ProtocolVersion::from),
+        progress: if args.progress_json {
+            Some(Arc::new(JsonProgress) as Arc<dyn Progress>)
+        } else if cfg!(feature = "silent") {
+            Some(Arc::new(ConsoleProgress))
+        } else {
+            None
+        },
+        ..FlashOptions::default()
+    };
+
+    // The image is checked before any port is opened.
+    let prepared = match &args.command {
+        None => Some(load_firmware(&args, &args.firmware, &mut options)),
+        Some(Command::Soak { firmware, .. }) => Some(load_firmware(&args, firmware, &mut options)),
+        _ => None,
+    };
+    if let (None, Some(path), Some((firmware, _))) = (&args.command, &args.emit_frames, &prepared) {
+        emit_frames(firmware, &options, path);
+        return;
+    }
+    if args.ports.len() > 1 {
+        if args.command.is_some() {
+            eprintln!("Several --port values are only supported when flashing.");
+            std::process::exit(1);
+        }
+        let (firmware, _) = prepared.expect("loaded for flashing");
+        run_flash_many(&args, options, firmware);
+        return;
+    }
 
     let normal_timeout = Duration::from_secs(10);
 
-    let mut port = serialport::new(&args.port, args.baud)
-        .timeout(normal_timeout)
-        .open()
-        .expect("Failed to open port");
+    let base: Box<dyn Transport> = match &args.replay {
+        Some(path) => Box::new(
+            ReplayTransport::open(path)
+                .expect("Failed to load replay transcript")
+                .ignore_mismatches(args.replay_ignore_mismatch),
+        ),
+        None => {
+            if args.ports[0].starts_with("tcp://") {
+                eprintln!(
+                    "Warning: {} is a raw TCP bridge, which can't change baud rates. \
+                     Set it to the rate of each step yourself, or use rfc2217://.",
+                    args.ports[0]
+                );
+            }
+            open_port(&args.ports[0], args.baud, normal_timeout).expect("Failed to open port")
+        }
+    };
 
-    if recovery {
-        // Recovery: skip ping/reboot. Assume user will power cycle.
-        println!("Recovery mode enabled: skipping ping/reboot.");
-        println!("Setting baud rate to 500_000...");
-        port.set_baud_rate(500_000)
-            .expect("Not able to set baudrate to 500_000");
+    let traced: Box<dyn Transport> = match &args.trace_file {
+        Some(path) if path.as_os_str() == "-" => {
+            Box::new(TracingTransport::new(base, io::stderr()))
+        }
+        Some(path) => {
+            Box::new(TracingTransport::create(base, path).expect("Failed to create trace file"))
+        }
+        None => base,
+    };
+    let traced: Box<dyn Transport> = if args.verbose {
+        Box::new(VerboseTransport::stderr(traced))
+    } else {
+        traced
+    };
+    let mut port = options.wrap_transport(traced);
 
+    match args.command {
+        Some(Command::Reboot {
+            id,
+            into_bootloader,
+        }) => {
+            run_reboot(&mut port, id, into_bootloader, &options);
+            return;
+        }
+        Some(Command::Reset { id }) => {
+            run_reset(&mut port, id);
+            return;
+        }
+        Some(Command::Ping {
+            broadcast: true, ..
+        }) => {
+            run_broadcast_ping(&mut port, options.protocol);
+            return;
+        }
+        Some(Command::Ping {
+            id,
+            count,
+            latency: true,
+            ..
+        }) => {
+            run_latency(
+                &mut port,
+                id.expect("clap requires --id"),
+                count,
+                options.protocol,
+            );
+            return;
+        }
+        Some(Command::Ping { id, count, .. }) => {
+            let bus = Bus::new(&mut port, args.baud).with_protocol(options.protocol);
+            run_ping(&bus, id.expect("clap requires --id"), count);
+            return;
+        }
+        Some(Command::Scan {
+            json,
+            health: Some(attempts),
+        }) => {
+            run_health(
+                &mut port,
+                attempts,
+                args.protocol.map(ProtocolVersion::from),
+                json,
+            );
+            return;
+        }
+        Some(Command::Scan { json, .. }) => {
+            run_scan(&mut port, deadline, &scan_options(&args), json);
+            return;
+        }
+        Some(Command::Info { id, json }) => {
+            run_info(&Bus::new(&mut port, args.baud), id, json);
+            return;
+        }
+        Some(Command::Monitor {
+            id,
+            interval_ms,
+            json,
+        }) => run_monitor(&mut port, id, Duration::from_millis(interval_ms), json),
+        Some(Command::Soak {
+            id,
+            iterations,
+            json,
+            ..
+        }) => {
+            let (firmware, _) = prepared.expect("firmware is loaded before opening the port");
+            run_soak(
+                &mut port, id, &firmware, args.baud, iterations, options, json,
+            );
+            return;
+        }
+        Some(Command::Watch { id, interval_ms }) => {
+            run_watch(
+                &mut port,
+                id,
+                Duration::from_millis(interval_ms),
+                options.protocol,
+                deadline,
+            );
+            return;
+        }
+        Some(Command::Sniff { out }) => {
+            run_sniff(&mut port, out.as_deref(), options.checksum, deadline);
+            return;
+        }
+        Some(Command::TorqueOff { ids, sync }) => {
+            run_torque_off(&mut port, &ids, sync);
+            return;
+        }
+        Some(Command::Backup { id, out }) => {
+            run_backup(&mut port, id, &out);
+            return;
+        }
+        Some(Command::Restore { id, input }) => {
+            run_restore(&mut port, id, &input);
+            return;
+        }
+        Some(Command::FlashBatch { fail_fast, .. }) => {
+            let jobs = batch_jobs.expect("prepared before opening the port");
+            run_flash_batch(&mut port, &jobs, args.baud, &options, fail_fast);
+            return;
+        }
+        Some(Command::Pack { .. } | Command::DecodeFrames { .. }) => {
+            unreachable!("handled before opening the port")
+        }
+        None => {}
+    }
+
+    let (firmware, expected_version) =
+        prepared.expect("firmware is loaded before opening the port");
+    if args.all || !args.ids.is_empty() {
+        run_flash_ids(&mut port, &args, firmware, &options, normal_timeout);
+        return;
+    }
+    if let Err(failure) = run_flash(&mut port, &args, firmware, expected_version, options) {
+        eprintln!("{}", failure.message);
+        if args.progress_json {
+            let error = serde_json::json!({
+                "event": "error",
+                "code": failure.code,
+                "message": failure.message,
+            });
+            println!("{error}");
+        }
+        // Only now, with the port back at its baud rate.
+        std::process::exit(failure.code);
+    }
+}
+
+/// Flash the single device selected by `args`, then confirm it boots.
+/// Returns the failure instead of exiting, so that `port` is put back at
+/// its baud rate before the process exits.
+fn run_flash(
+    port: &mut dyn Transport,
+    args: &Args,
+    firmware: Vec<u8>,
+    expected_version: Option<FirmwareVersion>,
+    options: FlashOptions,
+) -> Result<(), Failure> {
+    let maybe_id = args.id;
+    let recovery = args.recovery;
+    let deadline = options.deadline;
+
+    // Leave the port at the baud the device talks at, also when a step
+    // below fails at the bootloader baud.
+    let mut port = BaudGuard::new(port, args.baud);
+
+    let mut eeprom_backup = None;
+    let mut old_version = None;
+    // Rate the application talks at; --auto-baud may change it.
+    let mut app_baud = args.baud;
+    // The EEPROM backup is only restored into a running application.
+    let confirm = !args.no_confirm || (args.preserve_eeprom && !recovery);
+    let (device_id, stats) = if recovery {
+        // Recovery: skip ping/reboot. Assume user will power cycle.
+        status!("Recovery mode enabled: skipping ping/reboot.");
+        if args.preserve_eeprom {
+            status!("--preserve-eeprom has no effect in recovery mode.");
+        }
         // Spam magic and wait for ACK.
-        let interval = Duration::from_millis(100);
-        wait_for_bootloader_magic_ack(&mut *port, interval, None)
+        let session = BootloaderSession::connect(&mut port, &options)
+            .recover()
             .expect("Failed to receive bootloader ACK in recovery mode");
+
+        // After magic ACK, avoid re-setting baud or extra delay; go straight to init.
+        let session = session.init().expect("Bootloader init failed");
+
+        let session = session
+            .send_firmware(&firmware)
+            .expect("Failed to send firmware");
+        (maybe_id, session.stats().clone())
     } else {
         // Determine device ID:
         // - If user provided --id, use it and require ping to succeed.
@@ -78,107 +2107,257 @@ fn main() {
             // Use a short timeout while probing a specific ID.
             port.set_timeout(Duration::from_millis(PING_TIMEOUT_MS))
                 .expect("Failed to set ping timeout");
-            println!("Pinging device id {}...", id);
-            let ping_resp = send_ping(&mut *port, id).expect("Ping failed!");
-            println!("Ping response received ({} bytes)", ping_resp.len());
-            println!("Response bytes: {:02X?}", ping_resp);
+            status!("Pinging device id {}...", id);
+            if let Err(e) = ping_and_print(&mut port, id, options.protocol) {
+                if e.kind() != io::ErrorKind::TimedOut {
+                    return Err(Failure::new(1, format!("Ping failed: {e}")));
+                }
+                status!("No answer at {} baud; trying other baud rates...", app_baud);
+                let found = detect_baud(
+                    &mut port,
+                    id,
+                    &args.baud_candidates,
+                    app_baud,
+                    options.protocol,
+                )
+                .expect("Baud rate detection failed");
+                match found {
+                    Some(baud) if args.auto_baud => {
+                        status!("Device id {id} answers at {baud} baud; continuing at {baud}.");
+                        app_baud = baud;
+                        port.restore_to(baud);
+                    }
+                    Some(baud) => {
+                        return Err(Failure::new(
+                            1,
+                            format!(
+                                "Device id {id} responds at {baud} baud, re-run with --baud {baud} (or pass --auto-baud)."
+                            ),
+                        ));
+                    }
+                    None => {
+                        return Err(Failure::new(
+                            1,
+                            format!(
+                                "Ping failed: {e}. Device id {id} doesn't answer at any of {:?} baud either.",
+                                args.baud_candidates
+                            ),
+                        ));
+                    }
+                }
+            }
             id
+        } else if args.first {
+            status!("No --id provided. Looking for the first ID that answers...");
+            match scan_first(&mut port, deadline, &scan_options(args)).expect("ID scan failed") {
+                Some(id) => {
+                    status!(
+                        "Found device with id {} ({}). Using this ID.",
+                        id,
+                        device_label(&mut port, id)
+                    );
+                    id
+                }
+                None => {
+                    return Err(Failure::new(
+                        1,
+                        "No devices responded to ping. Please check wiring or use --id.",
+                    ));
+                }
+            }
         } else {
-            println!("No --id provided. Scanning all IDs (0..=253)...");
-            let found = scan_ids(&mut *port).expect("ID scan failed");
+            status!("No --id provided. Scanning all IDs (0..=253)...");
+            let report =
+                scan_bus(&mut port, deadline, &scan_options(args)).expect("ID scan failed");
+            if !report.collisions.is_empty() {
+                warn_collisions(&report.collisions);
+                return Err(Failure::new(
+                    1,
+                    "Refusing to pick a device automatically. Please re-run with --id.",
+                ));
+            }
+            let found = report.found;
 
             match found.len() {
                 0 => {
-                    eprintln!("No devices responded to ping. Please check wiring or use --id.");
-                    std::process::exit(1);
+                    return Err(Failure::new(
+                        1,
+                        "No devices responded to ping. Please check wiring or use --id.",
+                    ));
                 }
                 1 => {
                     let id = found[0];
-                    println!("Found single device with id {}. Using this ID.", id);
+                    status!(
+                        "Found single device with id {} ({}). Using this ID.",
+                        id,
+                        device_label(&mut port, id)
+                    );
                     id
                 }
                 _ => {
-                    eprintln!("Multiple devices found: {:?}", found);
-                    eprintln!(
-                        "Please re-run with --id <one of: {}>",
-                        found
-                            .iter()
-                            .map(|id| id.to_string())
-                            .collect::<Vec<_>>()
-                            .join(", ")
-                    );
-                    std::process::exit(1);
+                    eprintln!("Multiple devices found:");
+                    for &id in &found {
+                        eprintln!("  id {:3}: {}", id, device_label(&mut port, id));
+                    }
+                    return Err(Failure::new(
+                        1,
+                        format!(
+                            "Please re-run with --id <one of: {}>",
+                            found
+                                .iter()
+                                .map(|id| id.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        ),
+                    ));
                 }
             }
         };
 
-        // Restore the normal timeout for the rest of the protocol.
-        port.set_timeout(normal_timeout)
-            .expect("Failed to restore normal timeout");
-
-        // FF FF 01 02 08 F4
-        println!("Rebooting device id {} into bootloader...", device_id);
-        send_reboot(&mut *port, device_id).expect("Reboot failed!");
-
-        println!("Setting baud rate to 500_000...");
-        port.set_baud_rate(500_000)
-            .expect("Not able to set baudrate to 500_000");
+        // Still at the ping timeout, which also suits the register reads.
+        eeprom_backup = if args.preserve_eeprom {
+            let backup = EepromBackup::read(&mut port, device_id, CALIBRATION)
+                .expect("EEPROM backup failed");
+            let path = std::env::temp_dir().join(format!("feeflash-eeprom-id{}.json", device_id));
+            backup.save(&path).expect("Failed to write EEPROM backup");
+            status!("EEPROM backed up to {}", path.display());
+            Some(backup)
+        } else {
+            None
+        };
 
-        // sleep to allow the device to reboot
-        println!("Sleeping for 400ms to allow device to reboot...");
-        std::thread::sleep(Duration::from_millis(400));
+        // Version before flashing, to report next to a mismatch after.
+        old_version = expected_version
+            .and_then(|_| read_firmware_version(&mut port, device_id).ok())
+            .map(FirmwareVersion::from);
 
-        println!("Sending magic sequence to enter bootloader...");
-        // magic sequence "1fBVA"
-        port.write(BOOTLOADER_MAGIC)
-            .expect("Failed to write magic sequence");
+        // The servo handle puts the port back at `app_baud` and waits for
+        // the new application unless told not to.
+        let bus = Bus::new(&mut port, app_baud);
+        let servo = bus.servo(device_id);
+        let flashed = if confirm {
+            servo.flash(&firmware, &options)
+        } else {
+            servo.flash_unconfirmed(&firmware, &options)
+        };
+        let stats = flashed.map_err(|e| match FeeflashError::from_io(&e) {
+            Some(FeeflashError::IncompatibleModel { .. }) => {
+                Failure::new(1, format!("{e}. Use --force to flash anyway."))
+            }
+            Some(FeeflashError::BootNotConfirmed { .. }) => {
+                Failure::new(EXIT_BOOT_NOT_CONFIRMED, e.to_string())
+            }
+            _ => Failure::new(1, format!("Failed to flash device: {e}")),
+        })?;
+        (Some(device_id), stats)
+    };
 
-        let mut buf: [u8; 1024] = [0; 1024];
-        let read_bytes = port.read(&mut buf).expect("Failed to read from port");
+    if !args.progress_json {
+        println!("Transfer report: {}", stats);
+    }
 
-        if read_bytes != 1 {
-            panic!("Expected to read 1 byte, got {}", read_bytes);
+    let confirmed = confirm && device_id.is_some();
+    match device_id {
+        Some(id) if confirm => {
+            // Otherwise the servo handle has waited for the application.
+            if recovery {
+                confirm_boot(&mut port, id, app_baud, &options)?;
+            }
+            match expected_version {
+                Some(expected) => confirm_version(&mut port, id, expected, old_version)?,
+                None => {
+                    if options.protocol == ProtocolVersion::V1
+                        && let Ok((major, minor)) = read_firmware_version(&mut port, id)
+                    {
+                        status!("Device id {id} reports firmware {major}.{minor}.");
+                    }
+                }
+            }
         }
-
-        if buf[0] != 0x06 {
-            panic!("Expected to read byte 0x06, got 0x{:02X}", buf[0]);
+        None if confirm => {
+            status!("No --id given in recovery mode; skipping the boot confirmation.")
         }
-
-        println!("Bootloader acknowledged magic with 0x06");
+        _ => {}
     }
 
-    // After magic ACK, avoid re-setting baud or extra delay; go straight to init.
-
-    // At this point, bootloader has acknowledged magic (either via recovery loop or normal flow).
-    let mut buf: [u8; 1024] = [0; 1024];
-
-    // Tell the bootloader to initialize by sending 0x01 and
-    // wait for another 0x06 before starting firmware transfer.
-    println!("Sending init byte 0x01 to bootloader...");
-    port.write(&[0x01]).expect("Failed to write init byte 0x01");
-
-    let read_bytes = port
-        .read(&mut buf)
-        .expect("Failed to read init ACK from port");
+    if let (Some(backup), Some(id)) = (&eeprom_backup, device_id) {
+        if let Err(e) = backup.restore(&mut port, id) {
+            return Err(Failure::new(1, format!("EEPROM restore failed: {e}")));
+        }
+        status!("EEPROM restored to device id {}.", id);
+    }
 
-    if read_bytes != 1 {
-        panic!("Expected to read 1 byte for init ACK, got {}", read_bytes);
+    // Only now: a dashboard takes "done" to mean the device is usable.
+    if args.progress_json {
+        let done = serde_json::json!({
+            "event": "done",
+            "frames": stats.frames,
+            "retries": stats.total_retries,
+            "elapsed": stats.elapsed.as_secs_f64(),
+            "confirmed": confirmed,
+        });
+        println!("{done}");
     }
+    Ok(())
+}
 
-    if buf[0] != 0x06 {
-        panic!(
-            "Expected to read byte 0x06 after init, got 0x{:02X}",
-            buf[0]
-        );
+/// Why [`run_flash`] failed, and the process exit code that says so.
+struct Failure {
+    code: i32,
+    message: String,
+}
+
+impl Failure {
+    fn new(code: i32, message: impl Into<String>) -> Self {
+        Failure {
+            code,
+            message: message.into(),
+        }
     }
+}
 
-    println!("Bootloader acknowledged init with 0x06");
+/// Exit code when the transfer completed but the device never came back:
+/// it wants a power cycle, not another flash.
+const EXIT_BOOT_NOT_CONFIRMED: i32 = 3;
 
-    // Handshake is complete at this point. Now send the firmware frames.
+/// Switch back to `app_baud` and wait for `id` to run the new firmware.
+fn confirm_boot(
+    port: &mut dyn Transport,
+    id: u8,
+    app_baud: u32,
+    options: &FlashOptions,
+) -> Result<(), Failure> {
+    jump_to_application(port, id, app_baud, options).map_err(|e| {
+        let code = match FeeflashError::from_io(&e) {
+            Some(FeeflashError::BootNotConfirmed { .. }) => EXIT_BOOT_NOT_CONFIRMED,
+            _ => 1,
+        };
+        Failure::new(code, e.to_string())
+    })
+}
 
-    println!("Sending firmware from '{}'...", firmware_path);
+/// Exit code when the device runs another firmware version than expected,
+/// or its version can't be read.
+const EXIT_VERSION_MISMATCH: i32 = 4;
 
-    send_firmware_file(&mut *port, Path::new(&firmware_path)).expect("Failed to send firmware");
+/// Fail with [`EXIT_VERSION_MISMATCH`] unless `id` reports `expected`.
+fn confirm_version(
+    port: &mut dyn Transport,
+    id: u8,
+    expected: FirmwareVersion,
+    old: Option<FirmwareVersion>,
+) -> Result<(), Failure> {
+    let old = old.map_or_else(|| "unknown".to_string(), |v| v.to_string());
+    match check_version(port, id, expected) {
+        Ok(version) => {
+            status!("Device id {id} runs firmware {version} (was {old}).");
+            Ok(())
+        }
+        Err(e) => Err(Failure::new(
+            EXIT_VERSION_MISMATCH,
+            format!("{e} (before flashing: {old})."),
+        )),
+    }
 }
 
 // Tests moved into library modules: see `frame` and `dynamixel`.
@@ -0,0 +1,83 @@
+//! Known Feetech servo models.
+//!
+//! Maps the number in the [`MODEL`](crate::dynamixel::registers::MODEL)
+//! register to a product name and what this tool can do with it. Models
+//! missing here still work everywhere; they are shown by number and the
+//! flash pre-check warns about them.
+
+use crate::dynamixel::ProtocolVersion;
+use crate::dynamixel::registers::TORQUE_ENABLE;
+
+/// One entry of the model table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelInfo {
+    pub number: u16,
+    pub name: &'static str,
+    /// Protocol the factory firmware speaks.
+    pub protocol: ProtocolVersion,
+    /// Uses the STS bootloader this tool drives.
+    pub flashable: bool,
+    /// Address of the torque-enable register. 40 on every model so far;
+    /// SCS servos share it with STS ones.
+    pub torque_enable: u8,
+}
+
+const fn model(number: u16, name: &'static str, flashable: bool) -> ModelInfo {
+    ModelInfo {
+        number,
+        name,
+        protocol: ProtocolVersion::V1,
+        flashable,
+        torque_enable: TORQUE_ENABLE,
+    }
+}
+
+const MODELS: &[ModelInfo] = &[
+    model(777, "STS3215", true),
+    model(2825, "STS3250", true),
+    // SCS servos store multi-byte registers big-endian and ship another
+    // bootloader.
+    model(1284, "SCS0009", false),
+    model(11272, "SM8512BL", false),
+];
+
+/// Table entry for `number`, `None` for unknown models.
+pub fn lookup_model(number: u16) -> Option<ModelInfo> {
+    MODELS.iter().find(|m| m.number == number).copied()
+}
+
+/// Read a product name back as the table's `&'static str`, for the
+/// `model_name` fields of the device types. Names missing from the table
+/// become `None`.
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_model_name<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<&'static str>, D::Error> {
+    let name = <Option<String> as serde::Deserialize>::deserialize(deserializer)?;
+    Ok(name.and_then(|name| MODELS.iter().find(|m| m.name == name).map(|m| m.name)))
+}
+
+/// Product name, or `"model 1190"` for unknown models.
+pub fn model_label(number: u16) -> String {
+    match lookup_model(number) {
+        Some(info) => info.name.to_string(),
+        None => format!("model {number}"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_knows_table_entries_only() {
+        let sts = lookup_model(777).unwrap();
+        assert_eq!(sts.name, "STS3215");
+        assert!(sts.flashable);
+        assert!(!lookup_model(1284).unwrap().flashable);
+        assert_eq!(lookup_model(1190), None);
+
+        assert_eq!(model_label(2825), "STS3250");
+        assert_eq!(model_label(1190), "model 1190");
+    }
+}
@@ -0,0 +1,147 @@
+//! Flashing on several serial ports at once.
+//!
+//! Each [`FlashJob`] runs on its own thread with its own port, so a
+//! fixture with one adapter per programming pod flashes all pods in the
+//! time of one. Jobs share nothing mutable; the image is shared read-only
+//! through an `Arc<[u8]>`.
+
+use std::any::Any;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+
+use crate::bootloader::{
+    FlashEvent, FlashOptions, Progress, TransferStats, flash_device, jump_to_application,
+};
+use crate::transport::Transport;
+
+/// One device to flash: `id` on the serial port `port_name`, which may
+/// also be a network bridge (see [`open_port`](crate::transport::open_port)).
+#[derive(Debug, Clone)]
+pub struct FlashJob {
+    pub port_name: String,
+    /// Baud rate the application talks at; the port is opened at it.
+    pub app_baud: u32,
+    pub id: u8,
+    pub firmware: Arc<[u8]>,
+    /// Options of this job. `progress` is replaced by the reporter given to
+    /// [`flash_many`].
+    pub options: FlashOptions,
+}
+
+/// Result of one [`FlashJob`], in job order.
+#[derive(Debug)]
+pub struct FlashOutcome {
+    pub port_name: String,
+    pub id: u8,
+    pub result: io::Result<TransferStats>,
+}
+
+/// Flash every job on its own thread, opening each job's serial port. Each
+/// flash is [`flash_device`] followed by [`jump_to_application`].
+///
+/// `report` gets every [`FlashEvent`] tagged with the index of its job, and
+/// is called from the job threads. Returns one outcome per job, in order,
+/// once all of them finished; a job whose thread panicked gets an error
+/// outcome and leaves the others alone.
+#[cfg(feature = "serial")]
+pub fn flash_many<F>(jobs: Vec<FlashJob>, report: F) -> Vec<FlashOutcome>
+where
+    F: Fn(usize, FlashEvent) + Send + Sync + 'static,
+{
+    flash_many_with(jobs, open_serial, report)
+}
+
+/// [`flash_many`] with `open` creating each job's transport, e.g. to run
+/// the jobs against emulators.
+pub fn flash_many_with<O, F>(jobs: Vec<FlashJob>, open: O, report: F) -> Vec<FlashOutcome>
+where
+    O: Fn(&FlashJob) -> io::Result<Box<dyn Transport>> + Sync,
+    F: Fn(usize, FlashEvent) + Send + Sync + 'static,
+{
+    let report: Arc<dyn Fn(usize, FlashEvent) + Send + Sync> = Arc::new(report);
+    let open = &open;
+    thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .into_iter()
+            .enumerate()
+            .map(|(index, mut job)| {
+                job.options.progress = Some(Arc::new(JobProgress {
+                    index,
+                    report: report.clone(),
+                }));
+                let (port_name, id) = (job.port_name.clone(), job.id);
+                let handle = scope.spawn(move || {
+                    let result = open(&job).and_then(|port| run_job(port, &job));
+                    FlashOutcome {
+                        port_name: job.port_name,
+                        id: job.id,
+                        result,
+                    }
+                });
+                (port_name, id, handle)
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|(port_name, id, handle)| {
+                handle.join().unwrap_or_else(|panic| FlashOutcome {
+                    port_name,
+                    id,
+                    result: Err(io::Error::other(format!(
+                        "Flash thread panicked: {}",
+                        panic_message(panic.as_ref())
+                    ))),
+                })
+            })
+            .collect()
+    })
+}
+
+/// Text of a panic payload, as passed to `panic!`.
+fn panic_message(payload: &(dyn Any + Send)) -> &str {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message
+    } else {
+        "no message"
+    }
+}
+
+fn run_job(port: Box<dyn Transport>, job: &FlashJob) -> io::Result<TransferStats> {
+    let mut port = job.options.wrap_transport(port);
+    let stats = flash_device(&mut port, job.id, &job.firmware, &job.options)?;
+    jump_to_application(&mut port, job.id, job.app_baud, &job.options)?;
+    Ok(stats)
+}
+
+#[cfg(feature = "serial")]
+fn open_serial(job: &FlashJob) -> io::Result<Box<dyn Transport>> {
+    crate::transport::open_port(
+        &job.port_name,
+        job.app_baud,
+        std::time::Duration::from_secs(10),
+    )
+}
+
+/// Forwards the events of job `index` to the shared reporter.
+struct JobProgress {
+    index: usize,
+    report: Arc<dyn Fn(usize, FlashEvent) + Send + Sync>,
+}
+
+impl fmt::Debug for JobProgress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobProgress")
+            .field("index", &self.index)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Progress for JobProgress {
+    fn event(&self, event: FlashEvent) {
+        (self.report)(self.index, event);
+    }
+}
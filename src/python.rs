@@ -0,0 +1,381 @@
+//! Python module `feeflash`, built with PyO3.
+//!
+//! Build a wheel with `maturin build --release` (see `pyproject.toml`).
+//! Ports are serial port paths or `tcp://` and `rfc2217://` addresses; with
+//! the `testing` feature they can also be an `Emulator`, the in-crate
+//! bootloader emulator. Serial I/O runs with the GIL released.
+//!
+//! Every [`FeeflashError`] variant is raised as its own exception class,
+//! all subclasses of `feeflash.FeeflashError`. Other I/O errors are raised
+//! as the matching `OSError` subclass, e.g. `TimeoutError`.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyList};
+
+use crate::bootloader::{
+    BOOTLOADER_BAUD, FlashEvent, FlashOptions, Progress, TransferStats, flash_device,
+    jump_to_application,
+};
+use crate::deadline::Deadline;
+use crate::dynamixel::{ScanOptions, ping as ping_id, scan_bus};
+#[cfg(feature = "testing")]
+use crate::emulator::BootloaderEmulator;
+use crate::error::FeeflashError;
+use crate::transport::{Transport, open_port};
+
+/// Read timeout of ports opened here, as in the CLI.
+const PORT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// One exception class per [`FeeflashError`] variant.
+mod exceptions {
+    use pyo3::create_exception;
+    use pyo3::exceptions::PyException;
+
+    create_exception!(
+        feeflash,
+        FeeflashError,
+        PyException,
+        "Base of feeflash's errors."
+    );
+    create_exception!(feeflash, DeadlineExceeded, FeeflashError);
+    create_exception!(feeflash, FrameNakExhausted, FeeflashError);
+    create_exception!(feeflash, FinalizeRejected, FeeflashError);
+    create_exception!(feeflash, RetryBudgetExceeded, FeeflashError);
+    create_exception!(feeflash, RebootRejected, FeeflashError);
+    create_exception!(feeflash, NotBackAfterReboot, FeeflashError);
+    create_exception!(feeflash, BootNotConfirmed, FeeflashError);
+    create_exception!(feeflash, ModelMismatch, FeeflashError);
+    create_exception!(feeflash, IncompatibleModel, FeeflashError);
+    create_exception!(feeflash, VersionMismatch, FeeflashError);
+    create_exception!(feeflash, EmptyFirmware, FeeflashError);
+    create_exception!(feeflash, DigestMismatch, FeeflashError);
+    create_exception!(feeflash, FirmwareTooLarge, FeeflashError);
+    create_exception!(feeflash, RegionOutOfRange, FeeflashError);
+}
+
+/// Raise `err` as the exception class of its [`FeeflashError`] variant, or
+/// as an `OSError`.
+fn to_py_err(err: io::Error) -> PyErr {
+    let Some(feeflash) = FeeflashError::from_io(&err) else {
+        return err.into();
+    };
+    let message = feeflash.to_string();
+    match feeflash {
+        FeeflashError::DeadlineExceeded { .. } => exceptions::DeadlineExceeded::new_err(message),
+        FeeflashError::FrameNakExhausted { .. } => exceptions::FrameNakExhausted::new_err(message),
+        FeeflashError::FinalizeRejected { .. } => exceptions::FinalizeRejected::new_err(message),
+        FeeflashError::RetryBudgetExceeded { .. } => {
+            exceptions::RetryBudgetExceeded::new_err(message)
+        }
+        FeeflashError::RebootRejected { .. } => exceptions::RebootRejected::new_err(message),
+        FeeflashError::NotBackAfterReboot { .. } => {
+            exceptions::NotBackAfterReboot::new_err(message)
+        }
+        FeeflashError::BootNotConfirmed { .. } => exceptions::BootNotConfirmed::new_err(message),
+        FeeflashError::ModelMismatch { .. } => exceptions::ModelMismatch::new_err(message),
+        FeeflashError::IncompatibleModel { .. } => exceptions::IncompatibleModel::new_err(message),
+        FeeflashError::VersionMismatch { .. } => exceptions::VersionMismatch::new_err(message),
+        FeeflashError::EmptyFirmware => exceptions::EmptyFirmware::new_err(message),
+        FeeflashError::DigestMismatch { .. } => exceptions::DigestMismatch::new_err(message),
+        FeeflashError::FirmwareTooLarge { .. } => exceptions::FirmwareTooLarge::new_err(message),
+        FeeflashError::RegionOutOfRange { .. } => exceptions::RegionOutOfRange::new_err(message),
+    }
+}
+
+/// The in-crate bootloader emulator, usable as a port.
+#[cfg(feature = "testing")]
+#[pyclass(name = "Emulator", module = "feeflash", frozen)]
+struct PyEmulator {
+    servo: Arc<Mutex<BootloaderEmulator>>,
+}
+
+#[cfg(feature = "testing")]
+#[pymethods]
+impl PyEmulator {
+    /// Servos `ids` answering at `baud`, with their bootloader at
+    /// `bootloader_baud`.
+    #[new]
+    #[pyo3(signature = (ids, baud = 1_000_000, bootloader_baud = BOOTLOADER_BAUD))]
+    fn new(ids: Vec<u8>, baud: u32, bootloader_baud: u32) -> Self {
+        let servo = BootloaderEmulator::new(&ids, baud).bootloader_baud(bootloader_baud);
+        PyEmulator {
+            servo: Arc::new(Mutex::new(servo)),
+        }
+    }
+
+    /// Bytes written by the firmware frames so far.
+    fn image(&self) -> Vec<u8> {
+        self.servo.lock().unwrap().image().to_vec()
+    }
+
+    /// Whether the last frame was received and the application started.
+    fn is_done(&self) -> bool {
+        self.servo.lock().unwrap().is_done()
+    }
+}
+
+/// [`Transport`] over an emulator shared with Python.
+#[cfg(feature = "testing")]
+struct SharedEmulator(Arc<Mutex<BootloaderEmulator>>);
+
+#[cfg(feature = "testing")]
+impl Transport for SharedEmulator {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.0.lock().unwrap().write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.lock().unwrap().flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.0.lock().unwrap().set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.0.lock().unwrap().timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.0.lock().unwrap().set_baud_rate(baud_rate)
+    }
+}
+
+/// A `port` argument: a name to open, or an emulator.
+#[derive(FromPyObject)]
+enum Port {
+    Name(String),
+    #[cfg(feature = "testing")]
+    Emulator(Py<PyEmulator>),
+}
+
+/// What [`Port`] turns into before the GIL is released.
+enum OpenPort {
+    Name(String),
+    #[cfg(feature = "testing")]
+    Emulator(Arc<Mutex<BootloaderEmulator>>),
+}
+
+impl Port {
+    fn prepare(&self) -> OpenPort {
+        match self {
+            Port::Name(name) => OpenPort::Name(name.clone()),
+            #[cfg(feature = "testing")]
+            Port::Emulator(emulator) => OpenPort::Emulator(emulator.get().servo.clone()),
+        }
+    }
+}
+
+impl OpenPort {
+    fn open(self, baud: u32) -> io::Result<Box<dyn Transport>> {
+        match self {
+            OpenPort::Name(name) => open_port(&name, baud, PORT_TIMEOUT),
+            #[cfg(feature = "testing")]
+            OpenPort::Emulator(servo) => {
+                let mut port = SharedEmulator(servo);
+                port.set_baud_rate(baud)?;
+                Ok(Box::new(port))
+            }
+        }
+    }
+}
+
+/// Passes frames to the Python `progress` callable and status lines to
+/// the `feeflash` logger. The first exception raised by either is kept
+/// and raised once the flash is over.
+#[derive(Debug)]
+struct PyProgress {
+    callback: Option<Py<PyAny>>,
+    error: Mutex<Option<PyErr>>,
+}
+
+impl PyProgress {
+    fn report(&self, py: Python<'_>, event: FlashEvent) -> PyResult<()> {
+        match event {
+            FlashEvent::Frame { frame, total, .. } => match &self.callback {
+                Some(callback) => callback.call1(py, (frame, total)).map(drop),
+                None => Ok(()),
+            },
+            FlashEvent::Message(message) => log(py, "info", message),
+            FlashEvent::Warning(message) => log(py, "warning", message),
+        }
+    }
+}
+
+fn log(py: Python<'_>, level: &str, message: String) -> PyResult<()> {
+    py.import("logging")?
+        .call_method1("getLogger", ("feeflash",))?
+        .call_method1(level, (message,))
+        .map(drop)
+}
+
+impl Progress for PyProgress {
+    fn event(&self, event: FlashEvent) {
+        Python::attach(|py| {
+            if let Err(e) = self.report(py, event) {
+                self.error.lock().unwrap().get_or_insert(e);
+            }
+        });
+    }
+}
+
+/// IDs answering on `port` at `baud`, ascending.
+#[pyfunction]
+#[pyo3(signature = (port, baud = 1_000_000))]
+fn scan<'py>(py: Python<'py>, port: Port, baud: u32) -> PyResult<Bound<'py, PyList>> {
+    let port = port.prepare();
+    let found = py
+        .detach(|| {
+            let mut port = port.open(baud)?;
+            Ok(scan_bus(&mut port, Deadline::NONE, &ScanOptions::default())?.found)
+        })
+        .map_err(to_py_err)?;
+    // A list of ints rather than the `bytes` a Vec<u8> converts to.
+    PyList::new(py, found)
+}
+
+/// Whether `id` answers a ping on `port` at `baud`.
+#[pyfunction]
+#[pyo3(signature = (port, id, baud = 1_000_000))]
+fn ping(py: Python<'_>, port: Port, id: u8, baud: u32) -> PyResult<bool> {
+    let port = port.prepare();
+    py.detach(|| {
+        let mut port = port.open(baud)?;
+        match ping_id(&mut port, id) {
+            Ok(_) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(false),
+            Err(e) => Err(e),
+        }
+    })
+    .map_err(to_py_err)
+}
+
+/// Flash `firmware` to `id` on `port` and start it. `progress(frame,
+/// total)` is called after every acknowledged frame. Returns the transfer
+/// statistics as a dict.
+#[pyfunction]
+#[pyo3(signature = (
+    port,
+    id,
+    firmware,
+    progress = None,
+    *,
+    baud = 1_000_000,
+    bootloader_baud = BOOTLOADER_BAUD,
+    models = None,
+    max_retries = 5,
+    max_duration = None,
+))]
+#[allow(clippy::too_many_arguments)]
+fn flash<'py>(
+    py: Python<'py>,
+    port: Port,
+    id: u8,
+    firmware: Vec<u8>,
+    progress: Option<Py<PyAny>>,
+    baud: u32,
+    bootloader_baud: u32,
+    models: Option<Vec<u16>>,
+    max_retries: u8,
+    max_duration: Option<f64>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let reporter = Arc::new(PyProgress {
+        callback: progress,
+        error: Mutex::new(None),
+    });
+    let options = FlashOptions {
+        bootloader_baud,
+        expected_models: models,
+        max_retries,
+        deadline: Deadline::from_max_duration(max_duration.map(Duration::from_secs_f64)),
+        progress: Some(reporter.clone()),
+        ..FlashOptions::default()
+    };
+    let port = port.prepare();
+    let result = py.detach(|| -> io::Result<TransferStats> {
+        let mut port = port.open(baud)?;
+        let stats = flash_device(&mut port, id, &firmware, &options)?;
+        jump_to_application(&mut port, id, baud, &options)?;
+        Ok(stats)
+    });
+    if let Some(e) = reporter.error.lock().unwrap().take() {
+        return Err(e);
+    }
+    let stats = result.map_err(to_py_err)?;
+
+    let dict = PyDict::new(py);
+    dict.set_item("frames", stats.frames)?;
+    dict.set_item("retries", stats.total_retries)?;
+    dict.set_item("elapsed", stats.elapsed.as_secs_f64())?;
+    Ok(dict)
+}
+
+#[pymodule]
+fn feeflash(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    let py = m.py();
+    m.add_function(wrap_pyfunction!(scan, m)?)?;
+    m.add_function(wrap_pyfunction!(ping, m)?)?;
+    m.add_function(wrap_pyfunction!(flash, m)?)?;
+    #[cfg(feature = "testing")]
+    m.add_class::<PyEmulator>()?;
+    m.add("FeeflashError", py.get_type::<exceptions::FeeflashError>())?;
+    m.add(
+        "DeadlineExceeded",
+        py.get_type::<exceptions::DeadlineExceeded>(),
+    )?;
+    m.add(
+        "FrameNakExhausted",
+        py.get_type::<exceptions::FrameNakExhausted>(),
+    )?;
+    m.add(
+        "FinalizeRejected",
+        py.get_type::<exceptions::FinalizeRejected>(),
+    )?;
+    m.add(
+        "RetryBudgetExceeded",
+        py.get_type::<exceptions::RetryBudgetExceeded>(),
+    )?;
+    m.add(
+        "RebootRejected",
+        py.get_type::<exceptions::RebootRejected>(),
+    )?;
+    m.add(
+        "NotBackAfterReboot",
+        py.get_type::<exceptions::NotBackAfterReboot>(),
+    )?;
+    m.add(
+        "BootNotConfirmed",
+        py.get_type::<exceptions::BootNotConfirmed>(),
+    )?;
+    m.add("ModelMismatch", py.get_type::<exceptions::ModelMismatch>())?;
+    m.add(
+        "IncompatibleModel",
+        py.get_type::<exceptions::IncompatibleModel>(),
+    )?;
+    m.add(
+        "VersionMismatch",
+        py.get_type::<exceptions::VersionMismatch>(),
+    )?;
+    m.add("EmptyFirmware", py.get_type::<exceptions::EmptyFirmware>())?;
+    m.add(
+        "DigestMismatch",
+        py.get_type::<exceptions::DigestMismatch>(),
+    )?;
+    m.add(
+        "FirmwareTooLarge",
+        py.get_type::<exceptions::FirmwareTooLarge>(),
+    )?;
+    m.add(
+        "RegionOutOfRange",
+        py.get_type::<exceptions::RegionOutOfRange>(),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+//! Typestate API over the bootloader handshake, so the steps of a flash
+//! can only be taken in order.
+//!
+//! ```no_run
+//! # use feeflash::bootloader::FlashOptions;
+//! # use feeflash::session::BootloaderSession;
+//! # fn run(port: &mut dyn feeflash::transport::Transport, image: &[u8]) -> std::io::Result<()> {
+//! let options = FlashOptions::default();
+//! let session = BootloaderSession::connect(port, &options)
+//!     .enter(1)?
+//!     .init()?
+//!     .send_firmware(image)?;
+//! println!("{} frames", session.stats().frames);
+//! session.finish(1_000_000)?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`BootloaderSession`] starts at the application; [`SessionReady`] has
+//! the magic ACKed and [`SessionInitialized`] the init byte, and only the
+//! latter sends firmware. Sending it moves on to [`SessionTransferred`],
+//! which alone finishes the session. The free functions of [`crate::bootloader`]
+//! remain for callers that need a step on its own.
+
+use std::io;
+use std::time::Duration;
+
+use crate::bootloader::{
+    FlashOptions, TransferStats, handshake, init_bootloader, jump_to_application, send_firmware,
+    wait_for_bootloader_magic_ack,
+};
+use crate::flasher::{State, Until};
+use crate::transport::Transport;
+
+/// Pause between magic sequences while [`BootloaderSession::recover`]
+/// waits for the device to power up.
+pub const RECOVERY_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A port to a device that isn't in its bootloader yet.
+#[derive(Debug)]
+pub struct BootloaderSession<'o, T: Transport> {
+    port: T,
+    options: &'o FlashOptions,
+}
+
+impl<'o, T: Transport> BootloaderSession<'o, T> {
+    /// Start a session on `port`, at the application baud rate and with
+    /// the normal protocol timeout.
+    pub fn connect(port: T, options: &'o FlashOptions) -> Self {
+        BootloaderSession { port, options }
+    }
+
+    /// Reboot device `id` into the bootloader and complete the magic
+    /// handshake, see [`magic_handshake`].
+    pub fn enter(mut self, id: u8) -> io::Result<SessionReady<'o, T>> {
+        // FF FF 01 02 08 F4
+        handshake(
+            &mut self.port,
+            id,
+            State::Reboot,
+            Until::Magic,
+            self.options,
+        )?;
+        Ok(SessionReady {
+            port: self.port,
+            options: self.options,
+            id: Some(id),
+        })
+    }
+
+    /// Recovery for a device whose application doesn't answer: switch to
+    /// the bootloader baud and repeat the magic every
+    /// [`RECOVERY_INTERVAL`] until the device is powered up and ACKs it.
+    /// Only `options.deadline` bounds the wait.
+    pub fn recover(mut self) -> io::Result<SessionReady<'o, T>> {
+        self.options.say(format!(
+            "Setting baud rate to {}...",
+            self.options.bootloader_baud
+        ));
+        self.port.set_baud_rate(self.options.bootloader_baud)?;
+        wait_for_bootloader_magic_ack(&mut self.port, RECOVERY_INTERVAL, None, self.options)?;
+        Ok(SessionReady {
+            port: self.port,
+            options: self.options,
+            id: None,
+        })
+    }
+
+    /// Give the port back.
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+}
+
+/// A bootloader that ACKed the magic and waits for the init byte.
+#[derive(Debug)]
+pub struct SessionReady<'o, T: Transport> {
+    port: T,
+    options: &'o FlashOptions,
+    id: Option<u8>,
+}
+
+impl<'o, T: Transport> SessionReady<'o, T> {
+    /// Send the init byte, see [`init_bootloader`].
+    pub fn init(mut self) -> io::Result<SessionInitialized<'o, T>> {
+        init_bootloader(&mut self.port, self.options)?;
+        Ok(SessionInitialized {
+            port: self.port,
+            options: self.options,
+            id: self.id,
+        })
+    }
+
+    /// ID of the device, unknown after [`BootloaderSession::recover`].
+    pub fn id(&self) -> Option<u8> {
+        self.id
+    }
+
+    /// Give the port back, left at the bootloader baud.
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+}
+
+/// A bootloader ready for the firmware frames.
+#[derive(Debug)]
+pub struct SessionInitialized<'o, T: Transport> {
+    port: T,
+    options: &'o FlashOptions,
+    id: Option<u8>,
+}
+
+impl<'o, T: Transport> SessionInitialized<'o, T> {
+    /// Stream `firmware`, see [`send_firmware`]. Once the last frame is
+    /// ACKed the bootloader has left for the application, so the session
+    /// moves on and can't send again.
+    pub fn send_firmware(mut self, firmware: &[u8]) -> io::Result<SessionTransferred<'o, T>> {
+        let stats = send_firmware(&mut self.port, firmware, self.options)?;
+        Ok(SessionTransferred {
+            port: self.port,
+            options: self.options,
+            id: self.id,
+            stats,
+        })
+    }
+
+    /// ID of the device, unknown after [`BootloaderSession::recover`].
+    pub fn id(&self) -> Option<u8> {
+        self.id
+    }
+
+    /// Give the port back, left at the bootloader baud.
+    pub fn into_inner(self) -> T {
+        self.port
+    }
+}
+
+/// A completed transfer: the bootloader ACKed every frame and started the
+/// new application.
+#[derive(Debug)]
+pub struct SessionTransferred<'o, T: Transport> {
+    port: T,
+    options: &'o FlashOptions,
+    id: Option<u8>,
+    stats: TransferStats,
+}
+
+impl<T: Transport> SessionTransferred<'_, T> {
+    /// How the transfer went.
+    pub fn stats(&self) -> &TransferStats {
+        &self.stats
+    }
+
+    /// ID of the device, unknown after [`BootloaderSession::recover`].
+    pub fn id(&self) -> Option<u8> {
+        self.id
+    }
+
+    /// End the session at `app_baud` and give the port back. For a known
+    /// ID, wait for the new application as [`jump_to_application`] does.
+    pub fn finish(mut self, app_baud: u32) -> io::Result<T> {
+        match self.id {
+            Some(id) => jump_to_application(&mut self.port, id, app_baud, self.options)?,
+            None => {
+                self.options
+                    .say(format!("Setting baud rate back to {}...", app_baud));
+                self.port.set_baud_rate(app_baud)?;
+            }
+        }
+        Ok(self.port)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn finish_after_recovery_only_restores_baud() {
+        let options = FlashOptions::default();
+        let session = SessionTransferred {
+            port: MockTransport::new(),
+            options: &options,
+            id: None,
+            stats: TransferStats::default(),
+        };
+        let port = session.finish(115_200).unwrap();
+        assert_eq!(port.baud_rate(), Some(115_200));
+        assert!(port.writes().is_empty());
+    }
+}
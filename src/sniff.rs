@@ -0,0 +1,359 @@
+//! Best-effort decoding of captured serial traffic.
+//!
+//! [`Sniffer`] takes bytes with the time they arrived, from a live port or
+//! a saved capture, and splits them into Dynamixel packets (protocol 1 and
+//! 2), bootloader frames, the bootloader magic, init, ACK and NAK bytes.
+//! Whatever doesn't decode is kept as runs of unknown bytes, so nothing is
+//! lost from the log.
+
+use std::fmt;
+use std::time::Duration;
+
+use crate::bootloader::{BOOTLOADER_INIT, BOOTLOADER_MAGIC};
+use crate::crc::crc16_buypass;
+use crate::dynamixel::validate_packet;
+use crate::dynamixel2;
+use crate::frame::{BootloaderFrame, ChecksumKind};
+
+/// A pause this long between bytes ends whatever was being received, so an
+/// incomplete candidate is given up and a lone byte can be an ACK.
+pub const IDLE_GAP: Duration = Duration::from_millis(20);
+
+/// Largest protocol 2 packet considered; longer announced lengths are noise.
+const MAX_DXL2_LEN: usize = dynamixel2::MAX_RESPONSE_LEN;
+
+/// What a run of bytes was decoded as.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decoded {
+    /// A valid protocol 1 packet, instruction or status.
+    Dynamixel1(Vec<u8>),
+    /// A protocol 2 packet with a valid CRC.
+    Dynamixel2(Vec<u8>),
+    Frame(BootloaderFrame),
+    Magic,
+    /// The init byte after the magic and its ACK.
+    Init,
+    Ack,
+    Nak,
+    /// Bytes that decode as none of the above.
+    Unknown(Vec<u8>),
+}
+
+/// A decoded item and when its first byte arrived.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SniffRecord {
+    pub at: Duration,
+    pub decoded: Decoded,
+}
+
+impl fmt::Display for SniffRecord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:10.6}  ", self.at.as_secs_f64())?;
+        match &self.decoded {
+            Decoded::Dynamixel1(bytes) => write!(f, "DXL1   id {:3}: {}", bytes[2], hex(bytes)),
+            Decoded::Dynamixel2(bytes) => write!(f, "DXL2   id {:3}: {}", bytes[4], hex(bytes)),
+            Decoded::Frame(frame) => write!(
+                f,
+                "FRAME  index 0x{:02X}{}",
+                frame.index,
+                if frame.is_last { ", last" } else { "" }
+            ),
+            Decoded::Magic => write!(f, "MAGIC  \"1fBVA\""),
+            Decoded::Init => write!(f, "INIT"),
+            Decoded::Ack => write!(f, "ACK"),
+            Decoded::Nak => write!(f, "NAK"),
+            Decoded::Unknown(bytes) => write!(f, "??     {}", hex(bytes)),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// How the bytes at the front of the buffer look to one decoder.
+enum Match {
+    Item(Decoded, usize),
+    /// Could still become an item once more bytes arrive.
+    Incomplete,
+    No,
+}
+
+/// Incremental decoder. Feed it with [`push`](Self::push); call
+/// [`flush`](Self::flush) when the line goes idle or the capture ends.
+#[derive(Debug)]
+pub struct Sniffer {
+    checksum: ChecksumKind,
+    buf: Vec<u8>,
+    /// Arrival time of each byte in `buf`.
+    stamps: Vec<Duration>,
+    unknown: Vec<u8>,
+    unknown_at: Duration,
+    last_at: Option<Duration>,
+    after_magic: bool,
+}
+
+impl Sniffer {
+    /// Decode bootloader frames with `checksum`.
+    pub fn new(checksum: ChecksumKind) -> Self {
+        Sniffer {
+            checksum,
+            buf: Vec::new(),
+            stamps: Vec::new(),
+            unknown: Vec::new(),
+            unknown_at: Duration::ZERO,
+            last_at: None,
+            after_magic: false,
+        }
+    }
+
+    /// Add `bytes` received at `at` (time since the capture started) and
+    /// return what can be decoded for certain. A gap of [`IDLE_GAP`] since
+    /// the previous bytes flushes first.
+    pub fn push(&mut self, at: Duration, bytes: &[u8]) -> Vec<SniffRecord> {
+        let mut records = Vec::new();
+        if self
+            .last_at
+            .is_some_and(|last| at.saturating_sub(last) >= IDLE_GAP)
+        {
+            records.extend(self.flush());
+        }
+        if !bytes.is_empty() {
+            self.last_at = Some(at);
+        }
+        self.buf.extend_from_slice(bytes);
+        self.stamps.extend(std::iter::repeat_n(at, bytes.len()));
+        self.decode(false, &mut records);
+        records
+    }
+
+    /// The line went idle or the capture ended: decode everything buffered,
+    /// giving up on candidates that never completed.
+    pub fn flush(&mut self) -> Vec<SniffRecord> {
+        let mut records = Vec::new();
+        self.decode(true, &mut records);
+        self.end_unknown(&mut records);
+        records
+    }
+
+    fn decode(&mut self, idle: bool, records: &mut Vec<SniffRecord>) {
+        while !self.buf.is_empty() {
+            let at = self.stamps[0];
+            let matched = [
+                self.match_magic(),
+                self.match_dxl2(),
+                self.match_dxl1(),
+                self.match_frame(),
+            ]
+            .into_iter()
+            .find(|m| !matches!(m, Match::No));
+            let (decoded, len) = match matched {
+                Some(Match::Item(decoded, len)) => (decoded, len),
+                Some(Match::Incomplete) if !idle => return,
+                _ => match self.buf[0] {
+                    0x06 => (Decoded::Ack, 1),
+                    0x15 => (Decoded::Nak, 1),
+                    BOOTLOADER_INIT if self.after_magic => (Decoded::Init, 1),
+                    byte => {
+                        if self.unknown.is_empty() {
+                            self.unknown_at = at;
+                        }
+                        self.unknown.push(byte);
+                        self.consume(1);
+                        self.after_magic = false;
+                        continue;
+                    }
+                },
+            };
+            self.end_unknown(records);
+            // The init byte follows the magic's ACK.
+            self.after_magic = match decoded {
+                Decoded::Magic => true,
+                Decoded::Ack => self.after_magic,
+                _ => false,
+            };
+            self.consume(len);
+            records.push(SniffRecord { at, decoded });
+        }
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.drain(..len);
+        self.stamps.drain(..len);
+    }
+
+    fn end_unknown(&mut self, records: &mut Vec<SniffRecord>) {
+        if !self.unknown.is_empty() {
+            records.push(SniffRecord {
+                at: self.unknown_at,
+                decoded: Decoded::Unknown(std::mem::take(&mut self.unknown)),
+            });
+        }
+    }
+
+    fn match_magic(&self) -> Match {
+        let n = self.buf.len().min(BOOTLOADER_MAGIC.len());
+        if self.buf[..n] != BOOTLOADER_MAGIC[..n] {
+            Match::No
+        } else if n < BOOTLOADER_MAGIC.len() {
+            Match::Incomplete
+        } else {
+            Match::Item(Decoded::Magic, n)
+        }
+    }
+
+    fn match_dxl2(&self) -> Match {
+        let header = &dynamixel2::HEADER;
+        let n = self.buf.len().min(header.len());
+        if self.buf[..n] != header[..n] {
+            return Match::No;
+        }
+        if self.buf.len() < 7 {
+            return Match::Incomplete;
+        }
+        let len = 7 + u16::from_le_bytes([self.buf[5], self.buf[6]]) as usize;
+        if !(10..=MAX_DXL2_LEN).contains(&len) {
+            return Match::No;
+        }
+        if self.buf.len() < len {
+            return Match::Incomplete;
+        }
+        let (data, crc) = self.buf[..len].split_at(len - 2);
+        if crc16_buypass(data).to_le_bytes() == crc {
+            Match::Item(Decoded::Dynamixel2(self.buf[..len].to_vec()), len)
+        } else {
+            Match::No
+        }
+    }
+
+    fn match_dxl1(&self) -> Match {
+        let n = self.buf.len().min(2);
+        if self.buf[..n] != [0xFF, 0xFF][..n] {
+            return Match::No;
+        }
+        if self.buf.len() < 4 {
+            return Match::Incomplete;
+        }
+        let len = 4 + self.buf[3] as usize;
+        if self.buf.len() < len {
+            return Match::Incomplete;
+        }
+        if validate_packet(&self.buf[..len]) {
+            Match::Item(Decoded::Dynamixel1(self.buf[..len].to_vec()), len)
+        } else {
+            Match::No
+        }
+    }
+
+    fn match_frame(&self) -> Match {
+        let len = self.checksum.frame_len();
+        if self.buf.len() < 2 {
+            return Match::Incomplete;
+        }
+        if self.buf[1] != !self.buf[0] {
+            return Match::No;
+        }
+        if self.buf.len() < len {
+            return Match::Incomplete;
+        }
+        match BootloaderFrame::from_bytes(&self.buf[..len], self.checksum) {
+            Ok(frame) => Match::Item(Decoded::Frame(frame), len),
+            Err(_) => Match::No,
+        }
+    }
+}
+
+/// Decode a whole capture of `(arrival time, bytes)` chunks with a
+/// [`Sniffer`].
+pub fn decode_capture(
+    chunks: impl IntoIterator<Item = (Duration, Vec<u8>)>,
+    checksum: ChecksumKind,
+) -> Vec<SniffRecord> {
+    let mut sniffer = Sniffer::new(checksum);
+    let mut records = Vec::new();
+    for (at, bytes) in chunks {
+        records.extend(sniffer.push(at, &bytes));
+    }
+    records.extend(sniffer.flush());
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dynamixel::{Instruction, build_dyn_packet};
+    use crate::frame::FirmwareFrames;
+
+    fn ms(ms: u64) -> Duration {
+        Duration::from_millis(ms)
+    }
+
+    #[test]
+    fn decodes_a_flash_session() {
+        let ping = build_dyn_packet(3, Instruction::Ping, &[]).unwrap();
+        let frames: Vec<_> = FirmwareFrames::new(&[0x42; 100]).collect();
+        let mut burst = vec![0x00, 0x13];
+        burst.extend(BOOTLOADER_MAGIC);
+
+        let chunks = vec![
+            // A ping split across reads.
+            (ms(0), ping[..3].to_vec()),
+            (ms(1), ping[3..].to_vec()),
+            // Noise, then the magic right behind it.
+            (ms(100), burst),
+            (ms(150), vec![0x06]),
+            (ms(160), vec![BOOTLOADER_INIT]),
+            (ms(170), vec![0x06]),
+            (ms(180), frames[0].to_bytes().to_vec()),
+            (ms(190), vec![0x15]),
+            (ms(200), frames[0].to_bytes().to_vec()),
+            (ms(210), vec![0x06]),
+            // Truncated second frame.
+            (ms(220), frames[1].to_bytes()[..10].to_vec()),
+        ];
+        let records = decode_capture(chunks, ChecksumKind::Crc16Ccitt);
+        let summary: Vec<_> = records.iter().map(|r| (r.at, r.decoded.clone())).collect();
+        assert_eq!(
+            summary,
+            [
+                (ms(0), Decoded::Dynamixel1(ping)),
+                (ms(100), Decoded::Unknown(vec![0x00, 0x13])),
+                (ms(100), Decoded::Magic),
+                (ms(150), Decoded::Ack),
+                (ms(160), Decoded::Init),
+                (ms(170), Decoded::Ack),
+                (ms(180), Decoded::Frame(frames[0].clone())),
+                (ms(190), Decoded::Nak),
+                (ms(200), Decoded::Frame(frames[0].clone())),
+                (ms(210), Decoded::Ack),
+                (
+                    ms(220),
+                    Decoded::Unknown(frames[1].to_bytes()[..10].to_vec())
+                ),
+            ]
+        );
+        assert_eq!(
+            records[0].to_string(),
+            "  0.000000  DXL1   id   3: FF FF 03 02 01 F9"
+        );
+    }
+
+    #[test]
+    fn waits_for_incomplete_candidates_until_idle() {
+        let mut sniffer = Sniffer::new(ChecksumKind::Crc16Ccitt);
+        // 0x06 0xF9 could still start frame 6.
+        assert!(sniffer.push(ms(0), &[0x06]).is_empty());
+        assert_eq!(sniffer.push(ms(5), &[0xF9]), []);
+        let decoded: Vec<_> = sniffer.flush().into_iter().map(|r| r.decoded).collect();
+        assert_eq!(decoded, [Decoded::Ack, Decoded::Unknown(vec![0xF9])]);
+
+        // The gap alone ends the previous burst.
+        assert!(sniffer.push(ms(10), &[0x15]).is_empty());
+        let records = sniffer.push(ms(40), &[0x00]);
+        assert_eq!(records[0].decoded, Decoded::Nak);
+    }
+}
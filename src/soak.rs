@@ -0,0 +1,184 @@
+//! Soak testing: flash the same device over and over and collect failure
+//! statistics, e.g. to qualify a USB adapter and cable harness overnight.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::time::{Duration, Instant};
+
+use serde::{Serialize, Serializer};
+
+use crate::bootloader::{
+    FlashOptions, TransferStats, flash_device, jump_to_application, wait_for_application,
+};
+use crate::transport::{BaudGuard, Transport};
+
+/// Outcome of one [`soak`] iteration.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct SoakIteration {
+    /// 1-based.
+    pub iteration: u32,
+    /// Flash and boot confirmation, in seconds in JSON.
+    #[serde(serialize_with = "as_secs")]
+    #[cfg_attr(feature = "serde", serde(deserialize_with = "from_secs"))]
+    pub duration: Duration,
+    /// NAK resends of the transfer; `None` if the iteration failed.
+    pub retries: Option<u32>,
+    /// Why the iteration failed; `None` on success.
+    pub error: Option<String>,
+}
+
+impl SoakIteration {
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Every iteration of a [`soak`] run, with summary statistics.
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize))]
+pub struct SoakReport {
+    pub iterations: Vec<SoakIteration>,
+}
+
+impl SoakReport {
+    pub fn successes(&self) -> usize {
+        self.iterations.iter().filter(|i| i.succeeded()).count()
+    }
+
+    /// Share of iterations that succeeded, 0.0 to 1.0.
+    pub fn success_rate(&self) -> f64 {
+        if self.iterations.is_empty() {
+            return 0.0;
+        }
+        self.successes() as f64 / self.iterations.len() as f64
+    }
+
+    /// Number of successful iterations per retry count.
+    pub fn retry_histogram(&self) -> BTreeMap<u32, usize> {
+        let mut histogram = BTreeMap::new();
+        for retries in self.iterations.iter().filter_map(|i| i.retries) {
+            *histogram.entry(retries).or_insert(0) += 1;
+        }
+        histogram
+    }
+
+    /// The iteration that took longest, failed or not.
+    pub fn slowest(&self) -> Option<&SoakIteration> {
+        self.iterations.iter().max_by_key(|i| i.duration)
+    }
+}
+
+/// Flash `firmware` onto `id` `iterations` times, each time with
+/// [`flash_device`] and [`jump_to_application`], and record how each went.
+/// A failed iteration doesn't end the run. Before each iteration the port
+/// is put back to `app_baud` and its original timeout, and after a failed
+/// one the device gets [`FlashOptions::boot_confirm_timeout`] to come back
+/// to its application. A transfer that broke off mid-image leaves the
+/// bootloader waiting for frames until a power cycle, so the iterations
+/// after it fail too.
+///
+/// `on_iteration` sees each iteration as it completes. Only errors of the
+/// port itself end the run early.
+pub fn soak(
+    port: &mut dyn Transport,
+    id: u8,
+    firmware: &[u8],
+    app_baud: u32,
+    iterations: u32,
+    options: &FlashOptions,
+    mut on_iteration: impl FnMut(&SoakIteration),
+) -> io::Result<SoakReport> {
+    let timeout = port.timeout();
+    let mut report = SoakReport::default();
+    for iteration in 1..=iterations {
+        port.set_baud_rate(app_baud)?;
+        port.set_timeout(timeout)?;
+
+        let start = Instant::now();
+        let result = flash_once(port, id, firmware, app_baud, options);
+        let duration = start.elapsed();
+        let (retries, error) = match result {
+            Ok(stats) => (Some(stats.total_retries), None),
+            Err(e) => {
+                // Give an interrupted device the chance to come back before
+                // the next iteration pings it. The port is back at
+                // `app_baud` already.
+                let _ = wait_for_application(port, id, options);
+                (None, Some(e.to_string()))
+            }
+        };
+        let iteration = SoakIteration {
+            iteration,
+            duration,
+            retries,
+            error,
+        };
+        on_iteration(&iteration);
+        report.iterations.push(iteration);
+    }
+    port.set_baud_rate(app_baud)?;
+    port.set_timeout(timeout)?;
+    Ok(report)
+}
+
+fn flash_once(
+    port: &mut dyn Transport,
+    id: u8,
+    firmware: &[u8],
+    app_baud: u32,
+    options: &FlashOptions,
+) -> io::Result<TransferStats> {
+    let mut port = BaudGuard::new(port, app_baud);
+    let stats = flash_device(&mut port, id, firmware, options)?;
+    jump_to_application(&mut port, id, app_baud, options)?;
+    Ok(stats)
+}
+
+fn as_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[cfg(feature = "serde")]
+fn from_secs<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = <f64 as serde::Deserialize>::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iteration(iteration: u32, ms: u64, retries: Option<u32>) -> SoakIteration {
+        SoakIteration {
+            iteration,
+            duration: Duration::from_millis(ms),
+            retries,
+            error: retries.is_none().then(|| "Operation timed out".to_string()),
+        }
+    }
+
+    #[test]
+    fn report_statistics() {
+        let report = SoakReport {
+            iterations: vec![
+                iteration(1, 900, Some(0)),
+                iteration(2, 3000, None),
+                iteration(3, 1100, Some(2)),
+                iteration(4, 950, Some(0)),
+            ],
+        };
+        assert_eq!(report.successes(), 3);
+        assert_eq!(report.success_rate(), 0.75);
+        assert_eq!(
+            report.retry_histogram().into_iter().collect::<Vec<_>>(),
+            [(0, 2), (2, 1)]
+        );
+        assert_eq!(report.slowest().unwrap().iteration, 2);
+        assert_eq!(SoakReport::default().success_rate(), 0.0);
+
+        let json = serde_json::to_value(&report.iterations[1]).unwrap();
+        assert_eq!(json["duration"], 3.0);
+        assert_eq!(json["retries"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,430 @@
+//! [`Transport`] over TCP, for serial ports shared on the network by a
+//! bridge such as ser2net.
+//!
+//! In raw mode the bridge passes bytes through unchanged and has no way to
+//! receive a baud rate, so it must already run at whatever rate the flow
+//! needs: flashing only works if the servo's application and its
+//! bootloader use the same rate, or if the bridge is reconfigured in
+//! between. With the `rfc2217` feature, [`TcpTransport::connect_rfc2217`]
+//! talks Telnet with the RFC 2217 COM port option instead, and baud rate
+//! changes are sent to the bridge.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use crate::transport::Transport;
+
+/// Shortest read timeout set on the socket; a zero timeout means "block
+/// forever" to the OS.
+const MIN_TIMEOUT: Duration = Duration::from_micros(1);
+
+/// TCP connection to a serial bridge.
+#[derive(Debug)]
+pub struct TcpTransport {
+    stream: TcpStream,
+    timeout: Duration,
+    /// Rate the bridge runs at, as far as we know.
+    baud_rate: u32,
+    #[cfg(feature = "rfc2217")]
+    telnet: Option<rfc2217::Telnet>,
+}
+
+impl TcpTransport {
+    /// Connect to a bridge in raw mode that runs its serial port at
+    /// `baud_rate`. Later baud rate changes can't be sent and only print a
+    /// warning.
+    pub fn connect(addr: impl ToSocketAddrs, baud_rate: u32) -> io::Result<Self> {
+        TcpTransport::from_stream(TcpStream::connect(addr)?, baud_rate)
+    }
+
+    /// Use an already connected raw `stream`, as [`connect`](Self::connect)
+    /// does.
+    pub fn from_stream(stream: TcpStream, baud_rate: u32) -> io::Result<Self> {
+        stream.set_nodelay(true)?;
+        let mut transport = TcpTransport {
+            stream,
+            timeout: Duration::ZERO,
+            baud_rate,
+            #[cfg(feature = "rfc2217")]
+            telnet: None,
+        };
+        transport.set_timeout(Duration::from_secs(1))?;
+        Ok(transport)
+    }
+
+    /// Connect to a bridge speaking RFC 2217 (e.g. ser2net in `telnet` mode
+    /// with `remctl`) and set its serial port to `baud_rate`.
+    #[cfg(feature = "rfc2217")]
+    pub fn connect_rfc2217(addr: impl ToSocketAddrs, baud_rate: u32) -> io::Result<Self> {
+        let mut transport = TcpTransport::connect(addr, baud_rate)?;
+        transport.stream.write_all(&rfc2217::NEGOTIATION)?;
+        transport.stream.write_all(&rfc2217::request_signature())?;
+        transport.telnet = Some(rfc2217::Telnet::default());
+        transport.set_baud_rate(baud_rate)?;
+        Ok(transport)
+    }
+
+    /// Signature the RFC 2217 bridge sent about itself, if any so far.
+    #[cfg(feature = "rfc2217")]
+    pub fn bridge_signature(&self) -> Option<&str> {
+        self.telnet.as_ref()?.signature.as_deref()
+    }
+
+    /// One read from the socket, with the timeout reported as `TimedOut`
+    /// and a closed connection as an error.
+    fn read_socket(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.stream.read(buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Serial bridge closed the connection",
+            )),
+            // Unix reports an expired socket timeout as WouldBlock.
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "Read timed out"))
+            }
+            result => result,
+        }
+    }
+
+    /// Read from the socket into the Telnet decoder, and send the replies
+    /// it asks for.
+    #[cfg(feature = "rfc2217")]
+    fn receive_telnet(&mut self) -> io::Result<()> {
+        let mut chunk = [0u8; 256];
+        let n = self.read_socket(&mut chunk)?;
+        let Some(telnet) = &mut self.telnet else {
+            return Ok(());
+        };
+        telnet.decode(&chunk[..n]);
+        if !telnet.replies.is_empty() {
+            let replies = std::mem::take(&mut telnet.replies);
+            self.stream.write_all(&replies)?;
+        }
+        Ok(())
+    }
+
+    /// Send SET-BAUDRATE, wait for the bridge to confirm it, then purge
+    /// what the bridge received from the line at the old rate.
+    #[cfg(feature = "rfc2217")]
+    fn set_remote_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        if let Some(telnet) = &mut self.telnet {
+            telnet.baud_rate = None;
+        }
+        self.stream.write_all(&rfc2217::set_baud_rate(baud_rate))?;
+        let confirmed = loop {
+            if let Some(confirmed) = self.telnet.as_ref().and_then(|t| t.baud_rate) {
+                break confirmed;
+            }
+            self.receive_telnet().map_err(|e| {
+                if e.kind() == io::ErrorKind::TimedOut {
+                    io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        format!("Serial bridge did not confirm {baud_rate} baud"),
+                    )
+                } else {
+                    e
+                }
+            })?;
+        };
+        if confirmed != baud_rate {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Serial bridge set {confirmed} baud instead of {baud_rate}"),
+            ));
+        }
+        self.stream
+            .write_all(&rfc2217::purge(rfc2217::PURGE_RECEIVE))?;
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+}
+
+impl Transport for TcpTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        #[cfg(feature = "rfc2217")]
+        if self.telnet.is_some() {
+            return self.stream.write_all(&rfc2217::escape(buf));
+        }
+        self.stream.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        #[cfg(feature = "rfc2217")]
+        if self.telnet.is_some() {
+            loop {
+                if let Some(telnet) = &mut self.telnet {
+                    let n = telnet.take_data(buf);
+                    if n > 0 {
+                        return Ok(n);
+                    }
+                }
+                self.receive_telnet()?;
+            }
+        }
+        self.read_socket(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.stream
+            .set_read_timeout(Some(timeout.max(MIN_TIMEOUT)))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        #[cfg(feature = "rfc2217")]
+        if self.telnet.is_some() {
+            return self.set_remote_baud_rate(baud_rate);
+        }
+        if baud_rate != self.baud_rate {
+            diag!(
+                "Warning: can't switch the serial bridge from {} to {baud_rate} baud over raw TCP. \
+                 It must already run at {baud_rate} baud for the next step to work.",
+                self.baud_rate
+            );
+            self.baud_rate = baud_rate;
+        }
+        Ok(())
+    }
+}
+
+/// The client side of Telnet (RFC 854) with the COM port option (RFC
+/// 2217), as far as flashing needs it: 8-bit data, baud rate, purge and
+/// signature.
+#[cfg(feature = "rfc2217")]
+mod rfc2217 {
+    use std::collections::VecDeque;
+
+    const IAC: u8 = 0xFF;
+    const WILL: u8 = 0xFB;
+    const WONT: u8 = 0xFC;
+    const DO: u8 = 0xFD;
+    const DONT: u8 = 0xFE;
+    const SB: u8 = 0xFA;
+    const SE: u8 = 0xF0;
+    const BINARY: u8 = 0x00;
+    const SUPPRESS_GO_AHEAD: u8 = 0x03;
+    const COM_PORT_OPTION: u8 = 0x2C;
+
+    // Client-to-server commands; the server answers with the same command
+    // plus 100.
+    const SIGNATURE: u8 = 0;
+    const SET_BAUDRATE: u8 = 1;
+    const PURGE_DATA: u8 = 12;
+    const SERVER_OFFSET: u8 = 100;
+
+    /// PURGE-DATA value: the bridge's buffer of bytes received from the
+    /// serial line.
+    pub(super) const PURGE_RECEIVE: u8 = 1;
+
+    /// Sent on connect: 8-bit data both ways, and the COM port option.
+    pub(super) const NEGOTIATION: [u8; 9] = [
+        IAC,
+        WILL,
+        BINARY,
+        IAC,
+        DO,
+        BINARY,
+        IAC,
+        WILL,
+        COM_PORT_OPTION,
+    ];
+
+    /// Double every IAC byte of `data`.
+    pub(super) fn escape(data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len());
+        for &b in data {
+            out.push(b);
+            if b == IAC {
+                out.push(IAC);
+            }
+        }
+        out
+    }
+
+    fn com_port(command: u8, value: &[u8]) -> Vec<u8> {
+        let mut out = vec![IAC, SB, COM_PORT_OPTION, command];
+        out.extend(escape(value));
+        out.extend([IAC, SE]);
+        out
+    }
+
+    pub(super) fn set_baud_rate(baud_rate: u32) -> Vec<u8> {
+        com_port(SET_BAUDRATE, &baud_rate.to_be_bytes())
+    }
+
+    pub(super) fn purge(what: u8) -> Vec<u8> {
+        com_port(PURGE_DATA, &[what])
+    }
+
+    /// An empty SIGNATURE asks the server for its own.
+    pub(super) fn request_signature() -> Vec<u8> {
+        com_port(SIGNATURE, &[])
+    }
+
+    fn signature() -> Vec<u8> {
+        com_port(
+            SIGNATURE,
+            concat!("feeflash ", env!("CARGO_PKG_VERSION")).as_bytes(),
+        )
+    }
+
+    #[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+    enum State {
+        #[default]
+        Data,
+        Iac,
+        /// After WILL, WONT, DO or DONT: the option byte follows.
+        Option(u8),
+        Sub,
+        SubIac,
+    }
+
+    /// Receiving side of the Telnet connection: separates data from
+    /// commands and collects what the commands say.
+    #[derive(Debug, Default)]
+    pub(super) struct Telnet {
+        state: State,
+        data: VecDeque<u8>,
+        /// Subnegotiation being received, unescaped, without IAC SB.
+        sub: Vec<u8>,
+        /// Answers to send to the server.
+        pub(super) replies: Vec<u8>,
+        /// Rate last confirmed by the server.
+        pub(super) baud_rate: Option<u32>,
+        pub(super) signature: Option<String>,
+    }
+
+    impl Telnet {
+        /// Decode bytes received from the server, keeping state across
+        /// calls.
+        pub(super) fn decode(&mut self, input: &[u8]) {
+            for &b in input {
+                self.state = match (self.state, b) {
+                    (State::Data, IAC) => State::Iac,
+                    (State::Data, _) | (State::Iac, IAC) => {
+                        self.data.push_back(b);
+                        State::Data
+                    }
+                    (State::Iac, SB) => {
+                        self.sub.clear();
+                        State::Sub
+                    }
+                    (State::Iac, WILL..=DONT) => State::Option(b),
+                    (State::Iac, _) => State::Data,
+                    (State::Option(verb), _) => {
+                        self.negotiate(verb, b);
+                        State::Data
+                    }
+                    (State::Sub, IAC) => State::SubIac,
+                    (State::Sub, _) | (State::SubIac, IAC) => {
+                        self.sub.push(b);
+                        State::Sub
+                    }
+                    (State::SubIac, SE) => {
+                        self.subnegotiation();
+                        State::Data
+                    }
+                    (State::SubIac, _) => State::Sub,
+                };
+            }
+        }
+
+        /// Move received data to the start of `buf`.
+        pub(super) fn take_data(&mut self, buf: &mut [u8]) -> usize {
+            let n = self.data.len().min(buf.len());
+            for (dst, src) in buf.iter_mut().zip(self.data.drain(..n)) {
+                *dst = src;
+            }
+            n
+        }
+
+        /// Answers to what we asked for in [`NEGOTIATION`] need no reply;
+        /// anything else is refused.
+        fn negotiate(&mut self, verb: u8, option: u8) {
+            let reply = match (verb, option) {
+                (WILL, BINARY | SUPPRESS_GO_AHEAD) | (DO, BINARY | COM_PORT_OPTION) => return,
+                (WONT | DONT, _) => return,
+                (WILL, _) => DONT,
+                _ => WONT,
+            };
+            self.replies.extend([IAC, reply, option]);
+        }
+
+        fn subnegotiation(&mut self) {
+            let [COM_PORT_OPTION, command, value @ ..] = self.sub.as_slice() else {
+                return;
+            };
+            match (command.wrapping_sub(SERVER_OFFSET), value) {
+                (SIGNATURE, []) => self.replies.extend(signature()),
+                (SIGNATURE, text) => {
+                    self.signature = Some(String::from_utf8_lossy(text).into_owned());
+                }
+                (SET_BAUDRATE, &[a, b, c, d]) => {
+                    self.baud_rate = Some(u32::from_be_bytes([a, b, c, d]));
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn escapes_iac_and_strips_commands() {
+            assert_eq!(escape(&[0x01, 0xFF, 0x02]), [0x01, 0xFF, 0xFF, 0x02]);
+            assert_eq!(
+                set_baud_rate(500_000),
+                [IAC, SB, COM_PORT_OPTION, 1, 0x00, 0x07, 0xA1, 0x20, IAC, SE]
+            );
+
+            let mut telnet = Telnet::default();
+            // DO COM-PORT, data with an escaped 0xFF, and the baud rate
+            // confirmation split across two reads.
+            telnet.decode(&[
+                IAC,
+                DO,
+                COM_PORT_OPTION,
+                0x06,
+                IAC,
+                IAC,
+                0x15,
+                IAC,
+                SB,
+                0x2C,
+            ]);
+            telnet.decode(&[101, 0x00, 0x07, 0xA1, 0x20, IAC, SE, 0x01]);
+            let mut buf = [0u8; 8];
+            let n = telnet.take_data(&mut buf);
+            assert_eq!(buf[..n], [0x06, 0xFF, 0x15, 0x01]);
+            assert_eq!(telnet.baud_rate, Some(500_000));
+            assert!(telnet.replies.is_empty());
+        }
+
+        #[test]
+        fn refuses_unknown_options_and_answers_signature_requests() {
+            let mut telnet = Telnet::default();
+            // WILL ECHO, DO NAWS, a signature request and the server's own
+            // signature.
+            telnet.decode(&[IAC, WILL, 0x01, IAC, DO, 0x1F]);
+            telnet.decode(&[IAC, SB, COM_PORT_OPTION, 100, IAC, SE]);
+            telnet.decode(&[IAC, SB, COM_PORT_OPTION, 100, b'r', b'i', b'g', IAC, SE]);
+
+            let mut expected = vec![IAC, DONT, 0x01, IAC, WONT, 0x1F];
+            expected.extend(signature());
+            assert_eq!(telnet.replies, expected);
+            assert_eq!(telnet.signature.as_deref(), Some("rig"));
+        }
+    }
+}
@@ -0,0 +1,453 @@
+//! Byte-level transcript of serial traffic.
+//!
+//! [`TracingTransport`] wraps any transport and records every write and
+//! successful read as one line:
+//!
+//! ```text
+//! 12.345 TX 6 FF FF 01 02 01 FB
+//! 12.352 RX 1 06
+//! ```
+//!
+//! Fields are seconds since the tracer was created, direction, byte count
+//! and the bytes in hex. Lines go through a buffered writer so tracing does
+//! not add blocking file I/O between a write and the read of its response.
+//!
+//! [`VerboseTransport`] prints the same traffic as [`hexdump`]s for
+//! reading along live.
+//!
+//! [`ReplayTransport`] plays a transcript back so a field failure can be
+//! reproduced offline.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::transport::Transport;
+use crate::util::hexdump;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Direction::Tx => "TX",
+            Direction::Rx => "RX",
+        })
+    }
+}
+
+/// One line of a transcript.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub elapsed: Duration,
+    pub direction: Direction,
+    pub bytes: Vec<u8>,
+}
+
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.3} {} {}",
+            self.elapsed.as_secs_f64(),
+            self.direction,
+            self.bytes.len()
+        )?;
+        for b in &self.bytes {
+            write!(f, " {b:02X}")?;
+        }
+        Ok(())
+    }
+}
+
+impl TraceEntry {
+    /// Parse a single transcript line.
+    pub fn parse(line: &str) -> io::Result<TraceEntry> {
+        let invalid = |msg: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Invalid trace line '{line}': {msg}"),
+            )
+        };
+
+        let mut fields = line.split_whitespace();
+        let elapsed = fields
+            .next()
+            .and_then(|t| t.parse::<f64>().ok())
+            .filter(|t| t.is_finite() && *t >= 0.0)
+            .ok_or_else(|| invalid("bad timestamp"))?;
+        let direction = match fields.next() {
+            Some("TX") => Direction::Tx,
+            Some("RX") => Direction::Rx,
+            _ => return Err(invalid("expected TX or RX")),
+        };
+        let count = fields
+            .next()
+            .and_then(|c| c.parse::<usize>().ok())
+            .ok_or_else(|| invalid("bad byte count"))?;
+        let bytes = fields
+            .map(|b| u8::from_str_radix(b, 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| invalid("bad hex byte"))?;
+        if bytes.len() != count {
+            return Err(invalid("byte count does not match"));
+        }
+
+        Ok(TraceEntry {
+            elapsed: Duration::from_secs_f64(elapsed),
+            direction,
+            bytes,
+        })
+    }
+}
+
+/// Parse a whole transcript, skipping blank lines.
+pub fn parse_transcript(text: &str) -> io::Result<Vec<TraceEntry>> {
+    Ok(parse_numbered(text)?
+        .into_iter()
+        .map(|(_, entry)| entry)
+        .collect())
+}
+
+/// Parse a transcript keeping the 1-based line number of each entry.
+fn parse_numbered(text: &str) -> io::Result<Vec<(usize, TraceEntry)>> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(idx, line)| TraceEntry::parse(line).map(|entry| (idx + 1, entry)))
+        .collect()
+}
+
+/// Transport wrapper that logs all traffic to `W`.
+pub struct TracingTransport<T, W: Write = BufWriter<File>> {
+    inner: T,
+    out: W,
+    start: Instant,
+}
+
+impl<T: Transport> TracingTransport<T> {
+    /// Trace into a newly created file at `path`.
+    pub fn create(inner: T, path: &Path) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(TracingTransport::new(inner, BufWriter::new(file)))
+    }
+}
+
+impl<T: Transport, W: Write> TracingTransport<T, W> {
+    pub fn new(inner: T, out: W) -> Self {
+        TracingTransport {
+            inner,
+            out,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Flush the transcript and return the wrapped transport and writer.
+    pub fn into_parts(mut self) -> io::Result<(T, W)> {
+        self.out.flush()?;
+        Ok((self.inner, self.out))
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        let entry = TraceEntry {
+            elapsed: self.start.elapsed(),
+            direction,
+            bytes: bytes.to_vec(),
+        };
+        writeln!(self.out, "{entry}")
+    }
+}
+
+impl<T: Transport, W: Write> Transport for TracingTransport<T, W> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.record(Direction::Tx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.record(Direction::Rx, &buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+}
+
+/// Transport wrapper that prints every write and successful read to `W`
+/// (stderr by default) as a direction header followed by a [`hexdump`].
+pub struct VerboseTransport<T, W: Write = io::Stderr> {
+    inner: T,
+    out: W,
+}
+
+impl<T: Transport> VerboseTransport<T> {
+    pub fn stderr(inner: T) -> Self {
+        VerboseTransport::new(inner, io::stderr())
+    }
+}
+
+impl<T: Transport, W: Write> VerboseTransport<T, W> {
+    pub fn new(inner: T, out: W) -> Self {
+        VerboseTransport { inner, out }
+    }
+
+    pub fn into_parts(self) -> (T, W) {
+        (self.inner, self.out)
+    }
+
+    fn record(&mut self, direction: Direction, bytes: &[u8]) -> io::Result<()> {
+        writeln!(self.out, "{direction} {} bytes", bytes.len())?;
+        writeln!(self.out, "{}", hexdump(bytes))
+    }
+}
+
+impl<T: Transport, W: Write> Transport for VerboseTransport<T, W> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.record(Direction::Tx, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n > 0 {
+            self.record(Direction::Rx, &buf[..n])?;
+        }
+        Ok(n)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+}
+
+/// Transport that replays a recorded transcript.
+///
+/// Each write is checked against the next recorded TX entry and each read
+/// serves the next recorded RX entry. A read with a TX entry next means the
+/// original read returned nothing, so it times out again; the protocol code
+/// thus fails at the same point as the recorded run.
+#[derive(Debug)]
+pub struct ReplayTransport {
+    entries: VecDeque<(usize, TraceEntry)>,
+    ignore_mismatches: bool,
+    timeout: Duration,
+}
+
+impl ReplayTransport {
+    pub fn from_transcript(text: &str) -> io::Result<Self> {
+        Ok(ReplayTransport {
+            entries: parse_numbered(text)?.into(),
+            ignore_mismatches: false,
+            timeout: Duration::ZERO,
+        })
+    }
+
+    pub fn open(path: &Path) -> io::Result<Self> {
+        ReplayTransport::from_transcript(&fs::read_to_string(path)?)
+    }
+
+    /// Accept writes whose bytes differ from the recorded TX entry.
+    pub fn ignore_mismatches(mut self, ignore: bool) -> Self {
+        self.ignore_mismatches = ignore;
+        self
+    }
+
+    /// Number of transcript entries not replayed yet.
+    pub fn remaining(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        let Some((line, entry)) = self.entries.pop_front() else {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Replay transcript exhausted, got TX {buf:02X?}"),
+            ));
+        };
+
+        if entry.direction != Direction::Tx {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Replay mismatch at transcript line {line}: expected RX, got TX {buf:02X?}"
+                ),
+            ));
+        }
+
+        if entry.bytes != buf && !self.ignore_mismatches {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Replay mismatch at transcript line {line}: expected TX {:02X?}, got TX {buf:02X?}",
+                    entry.bytes
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.entries.front_mut() {
+            Some((_, entry)) if entry.direction == Direction::Rx => {
+                let n = entry.bytes.len().min(buf.len());
+                buf[..n].copy_from_slice(&entry.bytes[..n]);
+                entry.bytes.drain(..n);
+                if entry.bytes.is_empty() {
+                    self.entries.pop_front();
+                }
+                Ok(n)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Replay read timed out",
+            )),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        // Recorded only so `timeout()` round-trips; replay never waits.
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, _baud_rate: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::MockTransport;
+
+    #[test]
+    fn verbose_prints_hexdumps() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x06]);
+
+        let mut verbose = VerboseTransport::new(mock, Vec::new());
+        verbose.write_all(b"1fBVA").unwrap();
+        let mut buf = [0u8; 8];
+        verbose.read(&mut buf).unwrap();
+        let (_, out) = verbose.into_parts();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "TX 5 bytes");
+        assert!(lines[1].starts_with("00000000  31 66 42 56 41 "));
+        assert!(lines[1].ends_with("|1fBVA|"));
+        assert_eq!(lines[2], "RX 1 bytes");
+    }
+
+    #[test]
+    fn transcript_round_trips() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x06]);
+
+        let mut tracer = TracingTransport::new(mock, Vec::new());
+        tracer.write_all(b"1fBVA").unwrap();
+        let mut buf = [0u8; 8];
+        tracer.read(&mut buf).unwrap();
+        let (_, out) = tracer.into_parts().unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let entries = parse_transcript(&text).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].direction, Direction::Tx);
+        assert_eq!(entries[0].bytes, b"1fBVA");
+        assert_eq!(entries[1].direction, Direction::Rx);
+        assert_eq!(entries[1].bytes, [0x06]);
+
+        let rendered: String = entries.iter().map(|e| format!("{e}\n")).collect();
+        assert_eq!(rendered, text);
+    }
+
+    #[test]
+    fn parse_rejects_count_mismatch() {
+        assert!(TraceEntry::parse("0.000 TX 2 FF").is_err());
+        assert!(TraceEntry::parse("0.000 XX 1 FF").is_err());
+    }
+
+    #[test]
+    fn replay_serves_recorded_responses() {
+        let text = "0.000 TX 5 31 66 42 56 41\n0.010 RX 1 06\n";
+        let mut replay = ReplayTransport::from_transcript(text).unwrap();
+
+        replay.write_all(b"1fBVA").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(replay.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x06);
+        assert_eq!(replay.remaining(), 0);
+        assert_eq!(
+            replay.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::TimedOut
+        );
+    }
+
+    #[test]
+    fn replay_reports_mismatch_with_line_number() {
+        let text = "\n0.000 TX 1 01\n";
+        let mut replay = ReplayTransport::from_transcript(text).unwrap();
+
+        let err = replay.write_all(&[0x02]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let msg = err.to_string();
+        assert!(msg.contains("line 2"), "{msg}");
+        assert!(msg.contains("[01]") && msg.contains("[02]"), "{msg}");
+
+        let mut lenient = ReplayTransport::from_transcript(text)
+            .unwrap()
+            .ignore_mismatches(true);
+        lenient.write_all(&[0x02]).unwrap();
+    }
+}
@@ -0,0 +1,574 @@
+//! Byte transport used by the protocol functions.
+//!
+//! The protocol code only needs a handful of operations from the serial
+//! port. Abstracting them lets the whole flow be driven by a scripted
+//! [`MockTransport`] in tests, without a servo attached.
+
+use std::collections::VecDeque;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+pub trait Transport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()>;
+    fn flush(&mut self) -> io::Result<()>;
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()>;
+    fn timeout(&self) -> Duration;
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()>;
+}
+
+/// Read timeout used while draining stale input.
+pub const CLEAR_INPUT_TIMEOUT: Duration = Duration::from_millis(2);
+
+/// Upper bound on bytes discarded by one [`clear_input`] call, so a line
+/// that never goes quiet can't stall the caller.
+const CLEAR_INPUT_MAX_BYTES: usize = 4096;
+
+/// Drain bytes already waiting in the receive buffer.
+///
+/// On a half-duplex bus, or behind a USB adapter with its own buffering,
+/// a late status packet from an earlier command or line noise from a
+/// power cycle can still be queued when the next command goes out. Without
+/// draining, the first byte of that garbage is read as the response. Reads
+/// with a short timeout until the line is quiet, then restores the previous
+/// timeout. Returns the number of bytes discarded.
+pub fn clear_input(port: &mut dyn Transport) -> io::Result<usize> {
+    let previous = port.timeout();
+    port.set_timeout(CLEAR_INPUT_TIMEOUT)?;
+
+    let mut buf = [0u8; 256];
+    let mut discarded = 0;
+    let result = loop {
+        if discarded >= CLEAR_INPUT_MAX_BYTES {
+            break Ok(discarded);
+        }
+        match port.read(&mut buf) {
+            Ok(0) => break Ok(discarded),
+            Ok(n) => discarded += n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => break Ok(discarded),
+            Err(e) => break Err(e),
+        }
+    };
+
+    port.set_timeout(previous)?;
+    result
+}
+
+/// Open `name` at `baud_rate` with the read `timeout`: `tcp://host:port`
+/// connects to a raw serial bridge, `rfc2217://host:port` to an RFC 2217
+/// one (with the `rfc2217` feature), anything else is a local serial port.
+#[cfg(feature = "serial")]
+pub fn open_port(name: &str, baud_rate: u32, timeout: Duration) -> io::Result<Box<dyn Transport>> {
+    let mut port: Box<dyn Transport> = if let Some(addr) = name.strip_prefix("tcp://") {
+        Box::new(crate::tcp::TcpTransport::connect(addr, baud_rate)?)
+    } else if let Some(addr) = name.strip_prefix("rfc2217://") {
+        #[cfg(feature = "rfc2217")]
+        {
+            Box::new(crate::tcp::TcpTransport::connect_rfc2217(addr, baud_rate)?)
+        }
+        #[cfg(not(feature = "rfc2217"))]
+        {
+            let _ = addr;
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "rfc2217:// ports need the rfc2217 feature",
+            ));
+        }
+    } else {
+        Box::new(serialport::new(name, baud_rate).open()?)
+    };
+    port.set_timeout(timeout)?;
+    Ok(port)
+}
+
+/// Covers every port returned by `serialport::new(..).open()`.
+#[cfg(feature = "serial")]
+impl Transport for dyn serialport::SerialPort {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        io::Write::write_all(self, buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::Write::flush(self)
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(self, buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        serialport::SerialPort::set_timeout(self, timeout).map_err(io::Error::from)
+    }
+
+    fn timeout(&self) -> Duration {
+        serialport::SerialPort::timeout(self)
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        serialport::SerialPort::set_baud_rate(self, baud_rate).map_err(io::Error::from)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for Box<T> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        (**self).set_baud_rate(baud_rate)
+    }
+}
+
+impl<T: Transport + ?Sized> Transport for &mut T {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        (**self).write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (**self).read(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        (**self).set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        (**self).timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        (**self).set_baud_rate(baud_rate)
+    }
+}
+
+/// One scripted response served by [`MockTransport::read`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum MockRead {
+    Data(Vec<u8>),
+    Timeout,
+}
+
+/// Transport that serves canned responses and records everything written.
+///
+/// Reads pop scripted responses in order; a data chunk larger than the read
+/// buffer is served across several reads. Once the script is exhausted,
+/// reads time out like an idle serial line.
+#[derive(Debug, Default)]
+pub struct MockTransport {
+    reads: VecDeque<MockRead>,
+    writes: Vec<Vec<u8>>,
+    timeout: Option<Duration>,
+    baud_rate: Option<u32>,
+}
+
+impl MockTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queue bytes to be returned by the next read.
+    pub fn push_read(&mut self, bytes: &[u8]) -> &mut Self {
+        self.reads.push_back(MockRead::Data(bytes.to_vec()));
+        self
+    }
+
+    /// Queue a read that fails with `TimedOut`.
+    pub fn push_timeout(&mut self) -> &mut Self {
+        self.reads.push_back(MockRead::Timeout);
+        self
+    }
+
+    /// Every `write_all` call, in order.
+    pub fn writes(&self) -> &[Vec<u8>] {
+        &self.writes
+    }
+
+    /// Last timeout set through the transport.
+    pub fn timeout(&self) -> Option<Duration> {
+        self.timeout
+    }
+
+    /// Last baud rate set through the transport.
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.baud_rate
+    }
+
+    /// Number of scripted reads not consumed yet.
+    pub fn pending_reads(&self) -> usize {
+        self.reads.len()
+    }
+}
+
+impl Transport for MockTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writes.push(buf.to_vec());
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.reads.pop_front() {
+            Some(MockRead::Data(mut data)) => {
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                if n < data.len() {
+                    data.drain(..n);
+                    self.reads.push_front(MockRead::Data(data));
+                }
+                Ok(n)
+            }
+            Some(MockRead::Timeout) | None => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "Mock read timed out",
+            )),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = Some(timeout);
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout.unwrap_or_default()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.baud_rate = Some(baud_rate);
+        Ok(())
+    }
+}
+
+/// Sets the port back to a baud rate when dropped.
+///
+/// The flash flow switches the port to the bootloader baud and only switches
+/// back once the transfer succeeded. Wrapping the port in a guard puts it
+/// back at the application baud on every exit, including errors and panics,
+/// so a caller that keeps using the port talks at the rate it expects.
+/// Failing to set the rate while dropping is ignored.
+#[derive(Debug)]
+pub struct BaudGuard<T: Transport> {
+    inner: T,
+    baud_rate: u32,
+}
+
+impl<T: Transport> BaudGuard<T> {
+    /// Guard `inner`, restoring `baud_rate` on drop.
+    pub fn new(inner: T, baud_rate: u32) -> Self {
+        BaudGuard { inner, baud_rate }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Restore `baud_rate` on drop instead, e.g. once the device turned out
+    /// to answer at another rate than the one the guard was made for.
+    pub fn restore_to(&mut self, baud_rate: u32) {
+        self.baud_rate = baud_rate;
+    }
+}
+
+impl<T: Transport> Drop for BaudGuard<T> {
+    fn drop(&mut self) {
+        let _ = self.inner.set_baud_rate(self.baud_rate);
+    }
+}
+
+impl<T: Transport> Transport for BaudGuard<T> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+}
+
+/// Transport for single-wire TTL buses whose adapter echoes every
+/// transmitted byte back on RX.
+///
+/// Each write reads back exactly the bytes just sent before returning, so
+/// the echo is never taken for the device's response. An echo that differs
+/// from what was sent means a bus collision or a wiring fault and fails the
+/// write with `InvalidData`.
+#[derive(Debug)]
+pub struct HalfDuplexTransport<T> {
+    inner: T,
+}
+
+impl<T: Transport> HalfDuplexTransport<T> {
+    pub fn new(inner: T) -> Self {
+        HalfDuplexTransport { inner }
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for HalfDuplexTransport<T> {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.inner.write_all(buf)?;
+        self.inner.flush()?;
+
+        let mut echo = vec![0u8; buf.len()];
+        let mut filled = 0;
+        while filled < echo.len() {
+            match self.inner.read(&mut echo[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if filled < buf.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!(
+                    "Half-duplex echo incomplete: sent {} bytes, read back {:02X?}",
+                    buf.len(),
+                    &echo[..filled]
+                ),
+            ));
+        }
+        if echo != buf {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Half-duplex echo mismatch: sent {buf:02X?}, read back {echo:02X?}"),
+            ));
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.inner.set_baud_rate(baud_rate)
+    }
+}
+
+/// One end of an in-memory, full-duplex byte pipe.
+///
+/// Bytes written on one end are read from the other. Reads honor the
+/// configured timeout, so a thread holding the other end can act as a
+/// device with realistic sequencing.
+#[derive(Debug)]
+pub struct LoopbackTransport {
+    tx: Sender<Vec<u8>>,
+    rx: Receiver<Vec<u8>>,
+    pending: VecDeque<u8>,
+    timeout: Duration,
+    baud_rate: Option<u32>,
+}
+
+impl LoopbackTransport {
+    /// Create two connected ends.
+    pub fn pair() -> (LoopbackTransport, LoopbackTransport) {
+        let (a_tx, b_rx) = mpsc::channel();
+        let (b_tx, a_rx) = mpsc::channel();
+        let end = |tx, rx| LoopbackTransport {
+            tx,
+            rx,
+            pending: VecDeque::new(),
+            timeout: Duration::from_secs(10),
+            baud_rate: None,
+        };
+        (end(a_tx, a_rx), end(b_tx, b_rx))
+    }
+
+    /// Last baud rate set on this end.
+    pub fn baud_rate(&self) -> Option<u32> {
+        self.baud_rate
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.tx
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Loopback peer closed"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() {
+            match self.rx.recv_timeout(self.timeout) {
+                Ok(chunk) => self.pending.extend(chunk),
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::TimedOut,
+                        "Loopback read timed out",
+                    ));
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::BrokenPipe,
+                        "Loopback peer closed",
+                    ));
+                }
+            }
+        }
+
+        let n = self.pending.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> io::Result<()> {
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> io::Result<()> {
+        self.baud_rate = Some(baud_rate);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mock_splits_large_responses_across_reads() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[1, 2, 3]);
+
+        let mut buf = [0u8; 2];
+        assert_eq!(mock.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(mock.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+
+        let err = mock.read(&mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[test]
+    fn baud_guard_restores_on_drop() {
+        let mut mock = MockTransport::new();
+        {
+            let mut port = BaudGuard::new(&mut mock, 1_000_000);
+            port.set_baud_rate(500_000).unwrap();
+            assert_eq!(port.get_mut().baud_rate(), Some(500_000));
+        }
+        assert_eq!(mock.baud_rate(), Some(1_000_000));
+    }
+
+    #[test]
+    fn clear_input_discards_pending_bytes() {
+        let mut mock = MockTransport::new();
+        mock.set_timeout(Duration::from_millis(100)).unwrap();
+        mock.push_read(&[0xFF, 0x00, 0x06])
+            .push_read(&[0x15; 300])
+            .push_timeout()
+            .push_read(&[0x06]);
+
+        assert_eq!(clear_input(&mut mock).unwrap(), 303);
+        assert_eq!(mock.timeout(), Some(Duration::from_millis(100)));
+
+        let mut buf = [0u8; 1];
+        assert_eq!(mock.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf, [0x06]);
+    }
+
+    #[test]
+    fn half_duplex_discards_echo() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x31, 0x66])
+            .push_read(&[0x42])
+            .push_read(&[0x06]);
+        let mut port = HalfDuplexTransport::new(&mut mock);
+
+        port.write_all(&[0x31, 0x66, 0x42]).unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(port.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 0x06);
+    }
+
+    #[test]
+    fn half_duplex_rejects_bad_echo() {
+        let mut mock = MockTransport::new();
+        mock.push_read(&[0x31, 0x00]);
+        let err = HalfDuplexTransport::new(&mut mock)
+            .write_all(&[0x31, 0x66])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // No echo at all: the adapter is probably not half-duplex.
+        let mut mock = MockTransport::new();
+        let err = HalfDuplexTransport::new(&mut mock)
+            .write_all(&[0x31, 0x66])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,84 @@
+//! Small helpers shared by the protocol modules and the CLI.
+
+use std::io;
+
+/// Bytes per [`hexdump`] line.
+const BYTES_PER_LINE: usize = 16;
+
+/// Render `bytes` in the `hexdump -C` layout: offset column, 16 bytes per
+/// line in two groups of 8, and a printable-ASCII gutter:
+///
+/// ```text
+/// 00000000  FF FF 01 02 01 FB                                 |......|
+/// ```
+///
+/// Lines are joined with `\n`, without a trailing newline. Empty input
+/// gives an empty string.
+pub fn hexdump(bytes: &[u8]) -> String {
+    bytes
+        .chunks(BYTES_PER_LINE)
+        .enumerate()
+        .map(|(line, chunk)| {
+            let mut hex = String::with_capacity(3 * BYTES_PER_LINE + 1);
+            for i in 0..BYTES_PER_LINE {
+                if i == BYTES_PER_LINE / 2 {
+                    hex.push(' ');
+                }
+                match chunk.get(i) {
+                    Some(b) => hex.push_str(&format!("{b:02X} ")),
+                    None => hex.push_str("   "),
+                }
+            }
+            let ascii: String = chunk
+                .iter()
+                .map(|&b| {
+                    if b.is_ascii_graphic() || b == b' ' {
+                        b as char
+                    } else {
+                        '.'
+                    }
+                })
+                .collect();
+            format!("{:08X}  {hex} |{ascii}|", line * BYTES_PER_LINE)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Where scans draw their progress line: stderr, or nowhere with the
+/// `silent` feature.
+pub(crate) fn progress_output() -> Box<dyn io::Write> {
+    if cfg!(feature = "silent") {
+        Box::new(io::sink())
+    } else {
+        Box::new(io::stderr().lock())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_input_is_empty() {
+        assert_eq!(hexdump(&[]), "");
+    }
+
+    #[test]
+    fn short_line_is_padded() {
+        assert_eq!(
+            hexdump(&[0xFF, 0xFF, 0x01, 0x02, 0x01, 0xFB]),
+            "00000000  FF FF 01 02 01 FB                                 |......|"
+        );
+    }
+
+    #[test]
+    fn wraps_at_sixteen_bytes() {
+        let bytes: Vec<u8> = (0x41..0x41 + 18).collect();
+        assert_eq!(
+            hexdump(&bytes),
+            "00000000  41 42 43 44 45 46 47 48  49 4A 4B 4C 4D 4E 4F 50  |ABCDEFGHIJKLMNOP|\n\
+             00000010  51 52                                             |QR|"
+        );
+    }
+}
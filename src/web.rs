@@ -0,0 +1,215 @@
+//! Web Serial transport, for flashers running in the browser.
+//!
+//! [`WebSerialTransport`] implements [`AsyncTransport`] over a `SerialPort`
+//! of the Web Serial API (Chromium-based browsers). Drive it with
+//! [`flasher::flash`](crate::flasher::flash) and
+//! [`flasher::scan`](crate::flasher::scan); examples/web is a page that
+//! does. Build for `wasm32-unknown-unknown`: on other targets the calls
+//! into JavaScript panic.
+//!
+//! An open port can't change its rate, so
+//! [`set_baud_rate`](AsyncTransport::set_baud_rate) closes and reopens it.
+
+use alloc::collections::VecDeque;
+use core::time::Duration;
+
+use js_sys::{Array, Function, Object, Promise, Reflect, Uint8Array};
+use wasm_bindgen::JsCast;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    ReadableStream, ReadableStreamDefaultReader, ReadableStreamReadResult, WritableStream,
+    WritableStreamDefaultWriter,
+};
+
+use crate::flasher::AsyncTransport;
+
+// web-sys has the Web Serial types only behind `--cfg web_sys_unstable_apis`,
+// so the few members used here are bound directly.
+#[wasm_bindgen]
+extern "C" {
+    type Serial;
+
+    #[wasm_bindgen(method, js_name = requestPort)]
+    fn request_port(this: &Serial) -> Promise;
+
+    /// A port of the Web Serial API, as returned by
+    /// `navigator.serial.requestPort()`.
+    #[derive(Debug, Clone)]
+    pub type SerialPort;
+
+    #[wasm_bindgen(method)]
+    fn open(this: &SerialPort, options: &Object) -> Promise;
+
+    #[wasm_bindgen(method)]
+    fn close(this: &SerialPort) -> Promise;
+
+    #[wasm_bindgen(method, getter)]
+    fn readable(this: &SerialPort) -> ReadableStream;
+
+    #[wasm_bindgen(method, getter)]
+    fn writable(this: &SerialPort) -> WritableStream;
+
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn set_timeout(handler: &Function, timeout: i32) -> JsValue;
+}
+
+/// `navigator.serial`, in a window or a worker.
+fn serial() -> Result<Serial, JsValue> {
+    let navigator = Reflect::get(&js_sys::global(), &"navigator".into())?;
+    let serial = Reflect::get(&navigator, &"serial".into())?;
+    if serial.is_undefined() {
+        return Err(JsError::new("This browser has no Web Serial API").into());
+    }
+    Ok(serial.unchecked_into())
+}
+
+/// Promise resolving to `undefined` after `duration`.
+fn timer(duration: Duration) -> Promise {
+    let ms = i32::try_from(duration.as_millis()).unwrap_or(i32::MAX);
+    Promise::new(&mut |resolve, _reject| {
+        set_timeout(&resolve, ms);
+    })
+}
+
+async fn open_port(port: &SerialPort, baud: u32) -> Result<(), JsValue> {
+    let options = Object::new();
+    Reflect::set(&options, &"baudRate".into(), &baud.into())?;
+    JsFuture::from(port.open(&options)).await.map(drop)
+}
+
+fn lock_streams(
+    port: &SerialPort,
+) -> Result<(ReadableStreamDefaultReader, WritableStreamDefaultWriter), JsValue> {
+    let reader = port.readable().get_reader().unchecked_into();
+    let writer = port.writable().get_writer()?;
+    Ok((reader, writer))
+}
+
+/// [`AsyncTransport`] over a Web Serial port. Errors are the JavaScript
+/// exceptions of the API.
+#[derive(Debug)]
+pub struct WebSerialTransport {
+    port: SerialPort,
+    baud: u32,
+    reader: ReadableStreamDefaultReader,
+    writer: WritableStreamDefaultWriter,
+    /// Read that outlived a timeout. It can't be cancelled, so the next
+    /// read waits for it instead of starting another.
+    pending_read: Option<Promise>,
+    /// Bytes received but not handed out yet.
+    input: VecDeque<u8>,
+}
+
+impl WebSerialTransport {
+    /// Ask the user to pick a port and open it at `baud`. Must run in
+    /// response to a user gesture, like a click.
+    pub async fn request(baud: u32) -> Result<Self, JsValue> {
+        let port = JsFuture::from(serial()?.request_port()).await?;
+        Self::open(port.unchecked_into(), baud).await
+    }
+
+    /// Open `port` at `baud`.
+    pub async fn open(port: SerialPort, baud: u32) -> Result<Self, JsValue> {
+        open_port(&port, baud).await?;
+        let (reader, writer) = lock_streams(&port)?;
+        Ok(WebSerialTransport {
+            port,
+            baud,
+            reader,
+            writer,
+            pending_read: None,
+            input: VecDeque::new(),
+        })
+    }
+
+    /// Close the port, so it can be opened again.
+    pub async fn close(mut self) -> Result<(), JsValue> {
+        self.unlock().await?;
+        JsFuture::from(self.port.close()).await.map(drop)
+    }
+
+    /// The underlying port.
+    pub fn port(&self) -> &SerialPort {
+        &self.port
+    }
+
+    /// Current baud rate.
+    pub fn baud_rate(&self) -> u32 {
+        self.baud
+    }
+
+    /// Cancel the pending read and release the streams, which `close`
+    /// requires.
+    async fn unlock(&mut self) -> Result<(), JsValue> {
+        JsFuture::from(self.reader.cancel()).await?;
+        self.reader.release_lock();
+        self.writer.release_lock();
+        self.pending_read = None;
+        self.input.clear();
+        Ok(())
+    }
+}
+
+impl AsyncTransport for WebSerialTransport {
+    type Error = JsValue;
+
+    async fn write_all(&mut self, data: &[u8]) -> Result<(), JsValue> {
+        let chunk = Uint8Array::from(data);
+        JsFuture::from(self.writer.write_with_chunk(&chunk))
+            .await
+            .map(drop)
+    }
+
+    async fn read(&mut self, buf: &mut [u8], timeout: Duration) -> Result<usize, JsValue> {
+        if self.input.is_empty() {
+            let read = self
+                .pending_read
+                .take()
+                .unwrap_or_else(|| self.reader.read());
+            let race = Array::new();
+            race.push(&read);
+            race.push(&timer(timeout));
+            let result = JsFuture::from(Promise::race(&race)).await?;
+            if result.is_undefined() {
+                self.pending_read = Some(read);
+                return Ok(0);
+            }
+            let result: ReadableStreamReadResult = result.unchecked_into();
+            if result.get_done() == Some(true) {
+                return Err(JsError::new("Serial port closed").into());
+            }
+            self.input
+                .extend(Uint8Array::new(&result.get_value()).to_vec());
+        }
+        let n = buf.len().min(self.input.len());
+        for (dst, src) in buf.iter_mut().zip(self.input.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    async fn clear_input(&mut self) -> Result<(), JsValue> {
+        self.input.clear();
+        // Reads that already completed win the race against a zero timer.
+        let mut scratch = [0u8; 64];
+        while self.read(&mut scratch, Duration::ZERO).await? > 0 {}
+        Ok(())
+    }
+
+    async fn set_baud_rate(&mut self, baud: u32) -> Result<(), JsValue> {
+        if baud == self.baud {
+            return Ok(());
+        }
+        self.unlock().await?;
+        JsFuture::from(self.port.close()).await?;
+        open_port(&self.port, baud).await?;
+        (self.reader, self.writer) = lock_streams(&self.port)?;
+        self.baud = baud;
+        Ok(())
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        let _ = JsFuture::from(timer(duration)).await;
+    }
+}
@@ -0,0 +1,138 @@
+//! The async transfer functions against the bootloader emulator, through
+//! an `AsyncRead + AsyncWrite` adapter.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use feeflash::asynchronous::{
+    send_firmware_bytes_async, send_frame_with_retry_async, wait_for_bootloader_magic_ack_async,
+};
+use feeflash::bootloader::{FlashOptions, enter_bootloader};
+use feeflash::deadline::Deadline;
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::error::FeeflashError;
+use feeflash::frame::FirmwareFrames;
+use feeflash::transport::Transport;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+const APP_BAUD: u32 = 1_000_000;
+
+/// The emulator answers as soon as it is written to, so a read with
+/// nothing to return stays pending until its timeout.
+struct AsyncEmulator(BootloaderEmulator);
+
+impl AsyncRead for AsyncEmulator {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.0.read(buf.initialize_unfilled()) {
+            Ok(n) => {
+                buf.advance(n);
+                Poll::Ready(Ok(()))
+            }
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Poll::Pending,
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+impl AsyncWrite for AsyncEmulator {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Poll::Ready(self.0.write_all(buf).map(|()| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_time()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Device 4, taken into the bootloader over the blocking API.
+fn in_bootloader(mut emulator: BootloaderEmulator) -> AsyncEmulator {
+    enter_bootloader(&mut emulator, 4, &FlashOptions::default()).unwrap();
+    AsyncEmulator(emulator)
+}
+
+#[test]
+fn sends_firmware() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 3) as u8).collect();
+    let mut port = in_bootloader(BootloaderEmulator::new(&[4], APP_BAUD).nak_frame(2, 1));
+    let options = FlashOptions::default();
+
+    let stats = block_on(send_firmware_bytes_async(&mut port, &firmware, &options)).unwrap();
+
+    assert_eq!(stats.frames, firmware.len().div_ceil(64));
+    assert_eq!(stats.total_retries, 1);
+    assert!(port.0.is_done());
+    assert_eq!(&port.0.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn exhausted_naks_fail_the_frame() {
+    let mut port = in_bootloader(BootloaderEmulator::new(&[4], APP_BAUD).nak_frame(1, 3));
+    let frame = FirmwareFrames::new(&[0; 64]).next().unwrap().to_bytes();
+
+    let err = block_on(send_frame_with_retry_async(
+        &mut port,
+        &frame,
+        2,
+        Deadline::NONE,
+    ))
+    .unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::FrameNakExhausted {
+            index: 1,
+            attempts: 3
+        })
+    );
+}
+
+#[test]
+fn recovery_catches_the_bootloader() {
+    let mut port = AsyncEmulator(BootloaderEmulator::in_bootloader());
+    let options = FlashOptions::default();
+
+    block_on(wait_for_bootloader_magic_ack_async(
+        &mut port,
+        Duration::from_millis(10),
+        Some(Duration::from_secs(1)),
+        &options,
+    ))
+    .unwrap();
+}
+
+#[test]
+fn recovery_gives_up_after_max_wait() {
+    let mut port = AsyncEmulator(BootloaderEmulator::new(&[4], APP_BAUD));
+    let options = FlashOptions::default();
+
+    let err = block_on(wait_for_bootloader_magic_ack_async(
+        &mut port,
+        Duration::from_millis(10),
+        Some(Duration::from_millis(50)),
+        &options,
+    ))
+    .unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
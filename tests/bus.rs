@@ -0,0 +1,132 @@
+//! `Bus`, `Servo` and `SharedBus` handles against the bootloader emulator.
+
+use std::thread;
+use std::time::Duration;
+
+use feeflash::bootloader::FlashOptions;
+use feeflash::bus::{Bus, SharedBus};
+use feeflash::dynamixel::registers::{MIN_ANGLE_LIMIT, MODEL};
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::transport::MockTransport;
+
+const APP_BAUD: u32 = 1_000_000;
+
+#[test]
+fn servos_share_the_bus() {
+    let emulator = BootloaderEmulator::new(&[2, 7], APP_BAUD)
+        .register(2, MODEL, &777u16.to_le_bytes())
+        .register(7, MODEL, &1030u16.to_le_bytes());
+    let bus = Bus::new(emulator, APP_BAUD);
+
+    assert_eq!(bus.scan().unwrap().found, [2, 7]);
+    let (a, b) = (bus.servo(2), bus.servo(7));
+    assert_eq!(a.ping().unwrap().id, 2);
+    assert_eq!(b.ping().unwrap().id, 7);
+    assert_eq!(a.read(MODEL, 2).unwrap(), 777u16.to_le_bytes());
+    assert_eq!(b.read(MODEL, 2).unwrap(), 1030u16.to_le_bytes());
+
+    b.write(MIN_ANGLE_LIMIT, &[1, 2]).unwrap();
+    assert_eq!(b.read(MIN_ANGLE_LIMIT, 2).unwrap(), [1, 2]);
+    assert!(bus.servo(8).ping().is_err());
+
+    let emulator = bus.into_inner();
+    assert_eq!(emulator.registers(2)[MIN_ANGLE_LIMIT as usize], 0);
+}
+
+#[test]
+fn servo_flashes_and_the_bus_stays_usable() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 11) as u8).collect();
+    let bus = Bus::new(BootloaderEmulator::new(&[5], APP_BAUD), APP_BAUD);
+    let options = FlashOptions {
+        boot_settle: Duration::ZERO,
+        ..FlashOptions::default()
+    };
+
+    let servo = bus.servo(5);
+    let stats = servo.flash(&firmware, &options).unwrap();
+    assert_eq!(stats.frames, firmware.len().div_ceil(64));
+    assert!(servo.ping().is_ok());
+
+    let emulator = bus.into_inner();
+    assert!(emulator.is_done());
+    assert_eq!(&emulator.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn unconfirmed_flash_still_restores_the_baud() {
+    let firmware = [0x3C; 300];
+    let bus = Bus::new(BootloaderEmulator::new(&[5], APP_BAUD), APP_BAUD);
+
+    let servo = bus.servo(5);
+    let stats = servo
+        .flash_unconfirmed(&firmware, &FlashOptions::default())
+        .unwrap();
+    assert_eq!(stats.frames, 5);
+    // Not waited for, but answering at the bus baud.
+    assert!(servo.ping().is_ok());
+    assert!(bus.into_inner().is_done());
+}
+
+#[test]
+fn failed_flash_leaves_the_bus_at_its_baud() {
+    let bus = Bus::new(
+        BootloaderEmulator::new(&[5], APP_BAUD).reject_reboot(),
+        APP_BAUD,
+    );
+    let options = FlashOptions {
+        reboot_delay: Duration::from_millis(10),
+        ..FlashOptions::default()
+    };
+
+    assert!(bus.servo(5).flash(&[0; 64], &options).is_err());
+    assert!(bus.servo(5).ping().is_ok());
+}
+
+#[test]
+fn threads_never_interleave_packets() {
+    let emulator = BootloaderEmulator::new(&[2, 7], APP_BAUD)
+        .register(7, MODEL, &1030u16.to_le_bytes())
+        .register(7, MIN_ANGLE_LIMIT, &[0x12, 0x34, 0x56, 0x78]);
+    let bus = SharedBus::new(Bus::new(emulator, APP_BAUD));
+
+    let pinger = {
+        let bus = bus.clone();
+        thread::spawn(move || {
+            for _ in 0..300 {
+                assert_eq!(bus.ping(2).unwrap().id, 2);
+            }
+        })
+    };
+    let reader = {
+        let bus = bus.clone();
+        thread::spawn(move || {
+            for _ in 0..300 {
+                assert_eq!(bus.read(7, MODEL, 2).unwrap(), 1030u16.to_le_bytes());
+                assert_eq!(
+                    bus.read(7, MIN_ANGLE_LIMIT, 4).unwrap(),
+                    [0x12, 0x34, 0x56, 0x78]
+                );
+            }
+        })
+    };
+    pinger.join().unwrap();
+    reader.join().unwrap();
+}
+
+#[test]
+fn failed_flash_restores_baud_and_timeout() {
+    let timeout = Duration::from_millis(30);
+    let bus = SharedBus::new(Bus::new(MockTransport::new(), APP_BAUD).with_timeout(timeout));
+    let options = FlashOptions {
+        reboot_delay: Duration::ZERO,
+        ..FlashOptions::default()
+    };
+
+    // Nothing answers the magic: the flash fails at the bootloader baud.
+    assert!(bus.flash(5, &[0; 64], &options).is_err());
+
+    let mut bus = bus.lock();
+    let port = bus.get_mut();
+    assert_eq!(port.baud_rate(), Some(APP_BAUD));
+    assert_eq!(port.timeout(), Some(timeout));
+}
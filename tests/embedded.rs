@@ -0,0 +1,217 @@
+//! Handshake and transfer through `EmbeddedTransport` and `NbTransport`,
+//! as a `Transport` and as a `BlockingTransport`, over an in-memory UART
+//! whose far end is the bootloader emulator.
+
+use std::collections::VecDeque;
+use std::io;
+use std::time::{Duration, Instant};
+
+use embedded_hal_nb::{nb, serial};
+use embedded_io::{ErrorKind, ErrorType, Read, ReadReady, Write};
+use feeflash::bootloader::{FlashOptions, enter_bootloader, jump_to_application, send_firmware};
+use feeflash::embedded::{EmbeddedTransport, NbTransport, SerialError};
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::flasher::{self, Flasher, FlasherConfig, FlasherEvent, flash_blocking};
+use feeflash::transport::Transport;
+
+const APP_BAUD: u32 = 1_000_000;
+
+/// UART as an embedded HAL would expose it: no timeouts, no baud rate.
+struct Uart {
+    servo: BootloaderEmulator,
+    rx: VecDeque<u8>,
+}
+
+impl Uart {
+    fn poll(&mut self) -> Result<(), ErrorKind> {
+        let mut buf = [0u8; 64];
+        match self.servo.read(&mut buf) {
+            Ok(n) => self.rx.extend(&buf[..n]),
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+            Err(e) => return Err(e.kind().into()),
+        }
+        Ok(())
+    }
+}
+
+impl ErrorType for Uart {
+    type Error = ErrorKind;
+}
+
+impl Read for Uart {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, ErrorKind> {
+        while self.rx.is_empty() {
+            self.poll()?;
+        }
+        let n = self.rx.len().min(buf.len());
+        for (dst, src) in buf.iter_mut().zip(self.rx.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl ReadReady for Uart {
+    fn read_ready(&mut self) -> Result<bool, ErrorKind> {
+        if self.rx.is_empty() {
+            self.poll()?;
+        }
+        Ok(!self.rx.is_empty())
+    }
+}
+
+impl Write for Uart {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, ErrorKind> {
+        self.servo.write_all(buf).map_err(|e| e.kind())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), ErrorKind> {
+        Ok(())
+    }
+}
+
+impl serial::ErrorType for Uart {
+    type Error = serial::ErrorKind;
+}
+
+impl serial::Read for Uart {
+    fn read(&mut self) -> nb::Result<u8, serial::ErrorKind> {
+        if self.rx.is_empty() {
+            self.poll()
+                .map_err(|_| nb::Error::Other(serial::ErrorKind::Other))?;
+        }
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl serial::Write for Uart {
+    fn write(&mut self, byte: u8) -> nb::Result<(), serial::ErrorKind> {
+        self.servo
+            .write_all(&[byte])
+            .map_err(|_| nb::Error::Other(serial::ErrorKind::Other))
+    }
+
+    fn flush(&mut self) -> nb::Result<(), serial::ErrorKind> {
+        Ok(())
+    }
+}
+
+fn uart(ids: &[u8]) -> Uart {
+    Uart {
+        servo: BootloaderEmulator::new(ids, APP_BAUD),
+        rx: VecDeque::new(),
+    }
+}
+
+fn set_baud(uart: &mut Uart, baud: u32) -> Result<(), ErrorKind> {
+    uart.servo.set_baud_rate(baud).map_err(|e| e.kind().into())
+}
+
+fn set_baud_nb(uart: &mut Uart, baud: u32) -> Result<(), serial::ErrorKind> {
+    uart.servo
+        .set_baud_rate(baud)
+        .map_err(|_| serial::ErrorKind::Other)
+}
+
+fn timer() -> impl FnMut() -> Duration {
+    let start = Instant::now();
+    move || start.elapsed()
+}
+
+#[test]
+fn flashes_through_embedded_io() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 13) as u8).collect();
+    let mut port = EmbeddedTransport::new(uart(&[5]), timer()).with_baud_rate(set_baud);
+    let options = FlashOptions::default();
+
+    enter_bootloader(&mut port, 5, &options).unwrap();
+    send_firmware(&mut port, &firmware, &options).unwrap();
+    jump_to_application(&mut port, 5, APP_BAUD, &options).unwrap();
+
+    let servo = &port.get_mut().servo;
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn reads_time_out_and_baud_needs_a_callback() {
+    let mut port = EmbeddedTransport::new(uart(&[5]), timer());
+    port.set_timeout(Duration::from_millis(20)).unwrap();
+
+    let start = Instant::now();
+    let err = port.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    let err = port.set_baud_rate(500_000).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+}
+
+#[test]
+fn flashes_through_embedded_hal_nb() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 13) as u8).collect();
+    let mut port = NbTransport::new(uart(&[5]), timer()).with_baud_rate(set_baud_nb);
+    let options = FlashOptions::default();
+
+    enter_bootloader(&mut port, 5, &options).unwrap();
+    send_firmware(&mut port, &firmware, &options).unwrap();
+    jump_to_application(&mut port, 5, APP_BAUD, &options).unwrap();
+
+    let servo = &port.get_mut().servo;
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+fn flasher_config() -> FlasherConfig {
+    FlasherConfig {
+        app_baud: APP_BAUD,
+        ..FlasherConfig::default()
+    }
+}
+
+#[test]
+fn flasher_runs_over_embedded_io() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let mut port = EmbeddedTransport::new(uart(&[5]), timer()).with_baud_rate(set_baud);
+    let mut flasher = Flasher::new(5, &firmware, flasher_config()).unwrap();
+
+    flash_blocking(&mut port, &mut flasher, drop).unwrap();
+    assert!(flasher.is_done());
+    let servo = &port.get_mut().servo;
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn flasher_runs_over_embedded_hal_nb() {
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let mut port = NbTransport::new(uart(&[5]), timer()).with_baud_rate(set_baud_nb);
+    let mut flasher = Flasher::new(5, &firmware, flasher_config()).unwrap();
+
+    let mut frames = 0;
+    flash_blocking(&mut port, &mut flasher, |event| {
+        if let FlasherEvent::Frame { .. } = event {
+            frames += 1;
+        }
+    })
+    .unwrap();
+    assert_eq!(frames, 16);
+    let servo = &port.get_mut().servo;
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn blocking_reads_return_nothing_after_the_timeout() {
+    let mut port = EmbeddedTransport::new(uart(&[5]), timer());
+    let start = Instant::now();
+    let n = flasher::BlockingTransport::read(&mut port, &mut [0u8; 8], Duration::from_millis(20))
+        .unwrap();
+    assert_eq!(n, 0);
+    assert!(start.elapsed() >= Duration::from_millis(20));
+
+    assert_eq!(
+        flasher::BlockingTransport::set_baud_rate(&mut port, 500_000),
+        Err(SerialError::NoBaudRate)
+    );
+}
@@ -0,0 +1,797 @@
+//! End-to-end flashing against the in-process bootloader emulator.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use feeflash::batch::{BatchJob, run_batch};
+use feeflash::bootloader::{
+    FlashEvent, FlashOptions, enter_bootloader, flash_device, init_bootloader, jump_to_application,
+    magic_handshake, send_firmware, send_firmware_file, send_firmware_reader, wait_for_application,
+    wait_for_bootloader_magic_ack,
+};
+use feeflash::deadline::Deadline;
+use feeflash::dynamixel::registers::{FIRMWARE_MAJOR, MIN_ANGLE_LIMIT, MODEL, TORQUE_ENABLE};
+use feeflash::dynamixel::{
+    PingEvent, PingOutcome, ProtocolVersion, ScanOptions, probe_id, probe_id_attempts,
+    reboot_and_confirm, reboot_and_wait, reg_write, scan_bus, scan_first, scan_health, scan_ids,
+    send_action, send_ping, send_reboot, watch_ping, write_register,
+};
+use feeflash::eeprom::{CALIBRATION, EepromBackup};
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::error::{FeeflashError, Phase};
+use feeflash::frame::ChecksumKind;
+use feeflash::info::scan_devices;
+use feeflash::parallel::{FlashJob, flash_many_with};
+use feeflash::soak::soak;
+use feeflash::trace::{Direction, TracingTransport, parse_transcript};
+use feeflash::transport::Transport;
+
+const APP_BAUD: u32 = 1_000_000;
+
+fn synthetic_firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+fn assert_image_matches(emulator: &BootloaderEmulator, firmware: &[u8]) {
+    let image = emulator.image();
+    assert_eq!(image.len(), firmware.len().div_ceil(64) * 64);
+    assert_eq!(&image[..firmware.len()], firmware);
+    assert!(image[firmware.len()..].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn flashes_10k_image_from_file() {
+    let firmware = synthetic_firmware(10 * 1024 + 17);
+    let path = std::env::temp_dir().join(format!("feeflash-emulator-{}.bin", std::process::id()));
+    std::fs::write(&path, &firmware).unwrap();
+
+    let mut emulator = BootloaderEmulator::new(&[7], APP_BAUD);
+    let options = FlashOptions::default();
+
+    assert!(send_ping(&mut emulator, 7).is_ok());
+    assert!(send_ping(&mut emulator, 8).is_err());
+
+    enter_bootloader(&mut emulator, 7, &options).unwrap();
+    let result = send_firmware_file(&mut emulator, &path, &options);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    assert!(emulator.is_done());
+    assert_eq!(emulator.naks_sent(), 0);
+    assert_image_matches(&emulator, &firmware);
+
+    jump_to_application(&mut emulator, 7, APP_BAUD, &options).unwrap();
+}
+
+#[test]
+fn flashes_gzipped_image_from_file() {
+    use std::io::Write;
+
+    let firmware = synthetic_firmware(5000);
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(&firmware).unwrap();
+    let path =
+        std::env::temp_dir().join(format!("feeflash-emulator-{}.bin.gz", std::process::id()));
+    std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+    let mut emulator = BootloaderEmulator::new(&[7], APP_BAUD);
+    let options = FlashOptions::default();
+    enter_bootloader(&mut emulator, 7, &options).unwrap();
+    let result = send_firmware_file(&mut emulator, &path, &options);
+    std::fs::remove_file(&path).unwrap();
+    result.unwrap();
+
+    assert!(emulator.is_done());
+    assert_image_matches(&emulator, &firmware);
+}
+
+#[test]
+fn silent_device_after_flash_is_boot_not_confirmed() {
+    let firmware = synthetic_firmware(128);
+    let mut emulator = BootloaderEmulator::new(&[7], APP_BAUD);
+    let options = FlashOptions {
+        boot_settle: Duration::ZERO,
+        boot_confirm_timeout: Duration::from_millis(50),
+        ..FlashOptions::default()
+    };
+    enter_bootloader(&mut emulator, 7, &options).unwrap();
+    send_firmware(&mut emulator, &firmware, &options).unwrap();
+
+    // The application comes up, but not as the ID we wait for.
+    let err = jump_to_application(&mut emulator, 8, APP_BAUD, &options).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::BootNotConfirmed { id: 8 })
+    );
+}
+
+#[test]
+fn flashes_from_reader() {
+    let firmware = synthetic_firmware(300);
+    let mut emulator = BootloaderEmulator::new(&[1], APP_BAUD);
+    let options = FlashOptions::default();
+
+    enter_bootloader(&mut emulator, 1, &options).unwrap();
+    send_firmware_reader(&mut emulator, io::Cursor::new(&firmware), &options).unwrap();
+
+    assert!(emulator.is_done());
+    assert_image_matches(&emulator, &firmware);
+}
+
+#[test]
+fn recovers_from_injected_naks() {
+    let firmware = synthetic_firmware(1000);
+    let mut emulator = BootloaderEmulator::new(&[1], APP_BAUD).nak_frame(5, 2);
+    let options = FlashOptions::default();
+
+    enter_bootloader(&mut emulator, 1, &options).unwrap();
+    send_firmware(&mut emulator, &firmware, &options).unwrap();
+
+    assert_eq!(emulator.naks_sent(), 2);
+    assert_eq!(emulator.frames_received(), firmware.len().div_ceil(64) + 2);
+    assert_image_matches(&emulator, &firmware);
+}
+
+#[test]
+fn missing_ack_times_out() {
+    let firmware = synthetic_firmware(500);
+    let mut emulator = BootloaderEmulator::new(&[1], APP_BAUD).drop_ack(3);
+    // Wait the port timeout, without resending.
+    let options = FlashOptions {
+        frame_timeout: None,
+        ..FlashOptions::default()
+    };
+
+    enter_bootloader(&mut emulator, 1, &options).unwrap();
+    let err = send_firmware(&mut emulator, &firmware, &options).unwrap_err();
+
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert!(!emulator.is_done());
+}
+
+#[test]
+fn recovery_mode_catches_bootloader() {
+    let firmware = synthetic_firmware(300);
+    let mut emulator = BootloaderEmulator::in_bootloader();
+    let options = FlashOptions::default();
+
+    wait_for_bootloader_magic_ack(
+        &mut emulator,
+        Duration::from_millis(1),
+        Some(Duration::from_secs(1)),
+        &options,
+    )
+    .unwrap();
+    init_bootloader(&mut emulator, &options).unwrap();
+    send_firmware(&mut emulator, &firmware, &options).unwrap();
+
+    assert_image_matches(&emulator, &firmware);
+}
+
+#[test]
+fn inter_frame_delay_spaces_frames() {
+    let firmware = synthetic_firmware(64 * 4);
+    let mut emulator = BootloaderEmulator::in_bootloader();
+    let delay = Duration::from_millis(20);
+    let options = FlashOptions {
+        inter_frame_delay: delay,
+        ..FlashOptions::default()
+    };
+
+    wait_for_bootloader_magic_ack(&mut emulator, delay, None, &options).unwrap();
+    init_bootloader(&mut emulator, &options).unwrap();
+    let stats = send_firmware(&mut emulator, &firmware, &options).unwrap();
+
+    // No delay after the last frame.
+    assert_eq!(stats.frames, 4);
+    assert_eq!(stats.delay_time, delay * 3);
+    for gap in emulator.frame_times().windows(2) {
+        assert!(gap[1] - gap[0] >= delay);
+    }
+}
+
+#[test]
+fn reboot_and_wait_tells_failures_apart() {
+    let mut emulator =
+        BootloaderEmulator::new(&[4], APP_BAUD).boot_window(Duration::from_millis(20));
+    reboot_and_wait(&mut emulator, 4, Duration::from_secs(2), Deadline::NONE).unwrap();
+    assert!(send_ping(&mut emulator, 4).is_ok());
+
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD).reject_reboot();
+    let err =
+        reboot_and_wait(&mut emulator, 4, Duration::from_millis(50), Deadline::NONE).unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::RebootRejected { id: 4 })
+    );
+
+    // Stays in the bootloader for longer than we wait.
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD).boot_window(Duration::from_secs(10));
+    let err =
+        reboot_and_wait(&mut emulator, 4, Duration::from_millis(50), Deadline::NONE).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::NotBackAfterReboot { id: 4 })
+    );
+}
+
+#[test]
+fn reboot_confirmed_when_device_drops_off() {
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD);
+    reboot_and_confirm(&mut emulator, 4, Duration::from_millis(50), Deadline::NONE).unwrap();
+
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD).reject_reboot();
+    let err = reboot_and_confirm(&mut emulator, 4, Duration::from_millis(50), Deadline::NONE)
+        .unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::RebootRejected { id: 4 })
+    );
+
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD).reject_reboot();
+    let deadline = Deadline::after(Duration::ZERO);
+    let err = reboot_and_confirm(&mut emulator, 4, Duration::from_secs(10), deadline).unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::DeadlineExceeded {
+            phase: Phase::Handshake
+        })
+    );
+}
+
+#[test]
+fn plain_reboot_comes_back_online() {
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD)
+        .reboot_status()
+        .boot_window(Duration::from_millis(100));
+    emulator.set_timeout(Duration::from_millis(10)).unwrap();
+
+    let status = send_reboot(&mut emulator, 3, true).unwrap();
+    assert_eq!(status, Some(vec![0xFF, 0xFF, 0x03, 0x02, 0x00, 0xFA]));
+    // Still in the bootloader window right after the reboot.
+    assert!(send_ping(&mut emulator, 3).is_err());
+
+    wait_for_application(&mut emulator, 3, &FlashOptions::default()).unwrap();
+}
+
+#[test]
+fn reboot_into_bootloader_acks_magic() {
+    // The status packet is left unread and must not be taken for the ACK.
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD).reboot_status();
+    emulator.set_timeout(Duration::from_millis(10)).unwrap();
+
+    assert_eq!(send_reboot(&mut emulator, 3, false).unwrap(), None);
+    magic_handshake(&mut emulator, &FlashOptions::default()).unwrap();
+
+    // Without a reboot first there is no bootloader to answer.
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD);
+    emulator.set_timeout(Duration::from_millis(10)).unwrap();
+    let err = magic_handshake(&mut emulator, &FlashOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("not in bootloader mode"), "{err}");
+}
+
+#[test]
+fn flashes_over_echoing_half_duplex_bus() {
+    let firmware = synthetic_firmware(700);
+    let options = FlashOptions {
+        half_duplex: true,
+        ..FlashOptions::default()
+    };
+    let mut port = options.wrap_transport(Box::new(BootloaderEmulator::new(&[2], APP_BAUD).echo()));
+    port.set_timeout(Duration::from_millis(50)).unwrap();
+
+    assert_eq!(
+        send_ping(&mut port, 2).unwrap(),
+        [0xFF, 0xFF, 0x02, 0x02, 0x00, 0xFB]
+    );
+    enter_bootloader(&mut port, 2, &options).unwrap();
+    send_firmware(&mut port, &firmware, &options).unwrap();
+    jump_to_application(&mut port, 2, APP_BAUD, &options).unwrap();
+}
+
+#[test]
+fn scan_finds_every_servo_with_one_broadcast() {
+    let mut emulator = BootloaderEmulator::new(&[9, 1, 5], APP_BAUD);
+    assert_eq!(
+        scan_ids(&mut emulator, Deadline::NONE, &ScanOptions::default()).unwrap(),
+        [1, 5, 9]
+    );
+}
+
+#[test]
+fn flash_device_disables_torque_first() {
+    let firmware = synthetic_firmware(300);
+    let mut emulator = BootloaderEmulator::new(&[6], APP_BAUD).register(6, TORQUE_ENABLE, &[1]);
+
+    flash_device(&mut emulator, 6, &firmware, &FlashOptions::default()).unwrap();
+    assert_eq!(emulator.registers(6)[TORQUE_ENABLE as usize], 0);
+    assert_image_matches(&emulator, &firmware);
+
+    let options = FlashOptions {
+        torque_off: false,
+        ..FlashOptions::default()
+    };
+    let mut emulator = BootloaderEmulator::new(&[6], APP_BAUD).register(6, TORQUE_ENABLE, &[1]);
+    flash_device(&mut emulator, 6, &firmware, &options).unwrap();
+    assert_eq!(emulator.registers(6)[TORQUE_ENABLE as usize], 1);
+}
+
+#[test]
+fn eeprom_survives_flash_via_backup_and_restore() {
+    let firmware = synthetic_firmware(300);
+    let limits = [0x10, 0x00, 0xF0, 0x0F];
+    let mut emulator = BootloaderEmulator::new(&[4], APP_BAUD)
+        .register(4, MODEL, &777u16.to_le_bytes())
+        .register(4, MIN_ANGLE_LIMIT, &limits);
+    let options = FlashOptions::default();
+
+    let backup = EepromBackup::read(&mut emulator, 4, CALIBRATION).unwrap();
+    assert_eq!(backup.model, 777);
+    assert_eq!(backup.registers.len(), CALIBRATION.len());
+
+    flash_device(&mut emulator, 4, &firmware, &options).unwrap();
+    jump_to_application(&mut emulator, 4, APP_BAUD, &options).unwrap();
+    // The new firmware came up with default limits.
+    write_register(&mut emulator, 4, MIN_ANGLE_LIMIT, &[0; 4]).unwrap();
+
+    backup.restore(&mut emulator, 4).unwrap();
+    let start = MIN_ANGLE_LIMIT as usize;
+    assert_eq!(emulator.registers(4)[start..start + 4], limits);
+}
+
+#[test]
+fn eeprom_restore_refuses_other_model() {
+    let mut emulator =
+        BootloaderEmulator::new(&[4], APP_BAUD).register(4, MODEL, &777u16.to_le_bytes());
+    let backup = EepromBackup::read(&mut emulator, 4, CALIBRATION).unwrap();
+
+    let mut other = BootloaderEmulator::new(&[4], APP_BAUD)
+        .register(4, MODEL, &1030u16.to_le_bytes())
+        .register(4, MIN_ANGLE_LIMIT, &[0x55]);
+    let err = backup.restore(&mut other, 4).unwrap_err();
+    assert!(matches!(
+        FeeflashError::from_io(&err),
+        Some(FeeflashError::ModelMismatch {
+            backup: 777,
+            device: 1030
+        })
+    ));
+    assert_eq!(other.registers(4)[MIN_ANGLE_LIMIT as usize], 0x55);
+}
+
+#[test]
+fn flash_device_refuses_other_model() {
+    let firmware = synthetic_firmware(300);
+    let options = FlashOptions {
+        expected_models: Some(vec![1030, 1031]),
+        ..FlashOptions::default()
+    };
+    let mut emulator =
+        BootloaderEmulator::new(&[2], APP_BAUD).register(2, MODEL, &777u16.to_le_bytes());
+    let err = flash_device(&mut emulator, 2, &firmware, &options).unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::IncompatibleModel {
+            device: 777,
+            expected: vec![1030, 1031]
+        })
+    );
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    // Nothing was sent past the model read: still answering pings.
+    assert_eq!(emulator.frames_received(), 0);
+    send_ping(&mut emulator, 2).unwrap();
+
+    let mut emulator =
+        BootloaderEmulator::new(&[2], APP_BAUD).register(2, MODEL, &1031u16.to_le_bytes());
+    flash_device(&mut emulator, 2, &firmware, &options).unwrap();
+    assert_image_matches(&emulator, &firmware);
+}
+
+#[test]
+fn flashes_with_crc32_frames() {
+    let firmware = synthetic_firmware(1000);
+    let options = FlashOptions {
+        checksum: ChecksumKind::Crc32,
+        ..FlashOptions::default()
+    };
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD).checksum(ChecksumKind::Crc32);
+
+    flash_device(&mut emulator, 3, &firmware, &options).unwrap();
+    assert_image_matches(&emulator, &firmware);
+    assert_eq!(emulator.naks_sent(), 0);
+
+    // A CRC-16 host is NAKed by a CRC-32 bootloader.
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD).checksum(ChecksumKind::Crc32);
+    assert!(flash_device(&mut emulator, 3, &firmware, &FlashOptions::default()).is_err());
+}
+
+#[test]
+fn staged_writes_apply_on_action() {
+    let mut emulator = BootloaderEmulator::new(&[1, 2], APP_BAUD)
+        .register(1, TORQUE_ENABLE, &[1])
+        .register(2, TORQUE_ENABLE, &[1]);
+
+    for id in [1, 2] {
+        reg_write(&mut emulator, id, TORQUE_ENABLE, &[0]).unwrap();
+    }
+    assert_eq!(emulator.registers(1)[TORQUE_ENABLE as usize], 1);
+    assert_eq!(emulator.registers(2)[TORQUE_ENABLE as usize], 1);
+
+    send_action(&mut emulator).unwrap();
+    assert_eq!(emulator.registers(1)[TORQUE_ENABLE as usize], 0);
+    assert_eq!(emulator.registers(2)[TORQUE_ENABLE as usize], 0);
+}
+
+#[test]
+fn scan_devices_reads_model_and_firmware() {
+    let mut emulator = BootloaderEmulator::new(&[2, 5], APP_BAUD)
+        .register(2, MODEL, &777u16.to_le_bytes())
+        .register(2, FIRMWARE_MAJOR, &[3, 10]);
+
+    let v1 = ScanOptions {
+        protocol: Some(ProtocolVersion::V1),
+        ..ScanOptions::default()
+    };
+    let scan = scan_devices(&mut emulator, Deadline::NONE, &v1).unwrap();
+    assert!(scan.collisions.is_empty());
+    let summary: Vec<_> = scan
+        .devices
+        .iter()
+        .map(|d| (d.id, d.model_name, d.firmware.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [(2, Some("STS3215"), Some("3.10")), (5, None, Some("0.0"))]
+    );
+}
+
+#[test]
+fn scan_first_stops_at_lowest_answering_id() {
+    let mut emulator = BootloaderEmulator::new(&[12, 40], APP_BAUD).duplicate_id(3);
+    let v1 = ScanOptions {
+        protocol: Some(ProtocolVersion::V1),
+        ..ScanOptions::default()
+    };
+    assert_eq!(
+        scan_first(&mut emulator, Deadline::NONE, &v1).unwrap(),
+        Some(12)
+    );
+    let reverse = ScanOptions {
+        reverse: true,
+        ..v1
+    };
+    assert_eq!(
+        scan_first(&mut emulator, Deadline::NONE, &reverse).unwrap(),
+        Some(40)
+    );
+
+    let mut emulator = BootloaderEmulator::new(&[], APP_BAUD);
+    assert_eq!(
+        scan_first(&mut emulator, Deadline::NONE, &ScanOptions::default()).unwrap(),
+        None
+    );
+}
+
+#[test]
+fn scan_health_flags_intermittent_ids() {
+    let mut emulator = BootloaderEmulator::new(&[2, 5, 9], APP_BAUD)
+        .intermittent_id(5)
+        .duplicate_id(9);
+    let health = scan_health(&mut emulator, 4, Some(ProtocolVersion::V1)).unwrap();
+
+    let summary: Vec<_> = health
+        .iter()
+        .map(|h| (h.id, h.responses, h.timeouts, h.garbled))
+        .collect();
+    assert_eq!(summary, [(2, 4, 0, 0), (5, 2, 2, 0), (9, 0, 0, 4)]);
+    let intermittent: Vec<u8> = health
+        .iter()
+        .filter(|h| h.is_intermittent())
+        .map(|h| h.id)
+        .collect();
+    assert_eq!(intermittent, [5]);
+}
+
+#[test]
+fn scan_reports_shared_id_as_collision() {
+    // Two servos answer as id 5 at once; their replies interleave.
+    let mut emulator = BootloaderEmulator::new(&[2, 5], APP_BAUD).duplicate_id(5);
+
+    assert_eq!(
+        probe_id(&mut emulator, 5, Some(ProtocolVersion::V1)).unwrap(),
+        PingOutcome::Garbled
+    );
+    assert_eq!(
+        probe_id(&mut emulator, 7, Some(ProtocolVersion::V1)).unwrap(),
+        PingOutcome::NoResponse
+    );
+    assert_eq!(
+        probe_id(&mut emulator, 2, None).unwrap(),
+        PingOutcome::Answered(ProtocolVersion::V1)
+    );
+
+    for (protocol, reverse) in [
+        (Some(ProtocolVersion::V1), false),
+        (Some(ProtocolVersion::V1), true),
+        (None, false),
+    ] {
+        let options = ScanOptions {
+            protocol,
+            reverse,
+            attempts: 1,
+        };
+        let report = scan_bus(&mut emulator, Deadline::NONE, &options).unwrap();
+        assert_eq!(report.found, [2]);
+        assert_eq!(report.collisions, [5]);
+    }
+}
+
+#[test]
+fn run_batch_continues_past_a_failed_entry() {
+    let firmware = synthetic_firmware(300);
+    let jobs = [
+        BatchJob {
+            id: 1,
+            firmware_path: "wrong_model.bin".into(),
+            image: synthetic_firmware(100),
+            expected_models: Some(vec![1030]),
+        },
+        BatchJob {
+            id: 2,
+            firmware_path: "right_model.bin".into(),
+            image: firmware.clone(),
+            expected_models: Some(vec![777]),
+        },
+    ];
+    let emulator = || {
+        BootloaderEmulator::new(&[1, 2], APP_BAUD)
+            .register(1, MODEL, &777u16.to_le_bytes())
+            .register(2, MODEL, &777u16.to_le_bytes())
+    };
+    let options = FlashOptions::default();
+
+    let mut bus = emulator();
+    let results = run_batch(&mut bus, &jobs, APP_BAUD, &options, false);
+    assert_eq!(results.len(), 2);
+    assert_eq!(
+        FeeflashError::from_io(results[0].result.as_ref().unwrap_err()),
+        Some(&FeeflashError::IncompatibleModel {
+            device: 777,
+            expected: vec![1030]
+        })
+    );
+    assert!(results[1].result.is_ok());
+    assert!(bus.is_done());
+    assert_image_matches(&bus, &firmware);
+
+    let mut bus = emulator();
+    bus.set_timeout(Duration::from_millis(700)).unwrap();
+    let results = run_batch(&mut bus, &jobs, APP_BAUD, &options, true);
+    assert_eq!(results.len(), 1);
+    assert!(!bus.is_done());
+    // The failed entry leaves the port as it found it.
+    assert_eq!(bus.timeout(), Duration::from_millis(700));
+}
+
+/// Unicast pings written through `recorder` in protocol 1 and protocol 2.
+fn unicast_pings(recorder: TracingTransport<BootloaderEmulator, Vec<u8>>) -> (usize, usize) {
+    let (_, transcript) = recorder.into_parts().unwrap();
+    let entries = parse_transcript(&String::from_utf8(transcript).unwrap()).unwrap();
+    let (mut v1, mut v2) = (0, 0);
+    for entry in entries.iter().filter(|e| e.direction == Direction::Tx) {
+        match entry.bytes[..] {
+            [0xFF, 0xFF, 0xFD, 0x00, id, ..] if id != 0xFE => v2 += 1,
+            [0xFF, 0xFF, 0xFD, 0x00, ..] => {}
+            [0xFF, 0xFF, id, ..] if id != 0xFE => v1 += 1,
+            _ => {}
+        }
+    }
+    (v1, v2)
+}
+
+#[test]
+fn scan_sweeps_the_second_protocol_only_when_the_first_finds_nothing() {
+    // The collision spoils the broadcast ping, forcing the ID sweep.
+    let emulator = BootloaderEmulator::new(&[3, 7], APP_BAUD).duplicate_id(7);
+    let mut recorder = TracingTransport::new(emulator, Vec::new());
+    let report = scan_bus(&mut recorder, Deadline::NONE, &ScanOptions::default()).unwrap();
+    assert_eq!((report.found, report.collisions), (vec![3], vec![7]));
+    assert_eq!(unicast_pings(recorder), (254, 0));
+
+    let emulator = BootloaderEmulator::new(&[], APP_BAUD);
+    let mut recorder = TracingTransport::new(emulator, Vec::new());
+    let report = scan_bus(&mut recorder, Deadline::NONE, &ScanOptions::default()).unwrap();
+    assert!(report.found.is_empty());
+    assert_eq!(unicast_pings(recorder), (254, 254));
+}
+
+#[test]
+fn reverse_scan_reports_in_id_order() {
+    // The collision spoils the broadcast ping, forcing the ID sweep.
+    let mut emulator = BootloaderEmulator::new(&[3, 100, 200], APP_BAUD).duplicate_id(100);
+    let options = ScanOptions {
+        protocol: Some(ProtocolVersion::V1),
+        reverse: true,
+        attempts: 1,
+    };
+    let report = scan_bus(&mut emulator, Deadline::NONE, &options).unwrap();
+    assert_eq!(report.found, [3, 200]);
+    assert_eq!(report.collisions, [100]);
+}
+
+#[test]
+fn scan_attempts_find_a_device_that_drops_replies() {
+    // Id 4 answers every other ping; the collision on 9 forces the ID sweep.
+    let mut emulator = BootloaderEmulator::new(&[4, 9], APP_BAUD)
+        .intermittent_id(4)
+        .duplicate_id(9);
+    let v1 = ScanOptions {
+        protocol: Some(ProtocolVersion::V1),
+        ..ScanOptions::default()
+    };
+    assert_eq!(
+        probe_id(&mut emulator, 4, v1.protocol).unwrap(),
+        PingOutcome::Answered(ProtocolVersion::V1)
+    );
+    assert!(
+        scan_bus(&mut emulator, Deadline::NONE, &v1)
+            .unwrap()
+            .found
+            .is_empty()
+    );
+    let twice = ScanOptions { attempts: 2, ..v1 };
+    assert_eq!(
+        scan_bus(&mut emulator, Deadline::NONE, &twice)
+            .unwrap()
+            .found,
+        [4]
+    );
+    // Garbled replies aren't retried: they already show something is there.
+    assert_eq!(
+        probe_id_attempts(&mut emulator, 9, v1.protocol, 3).unwrap(),
+        PingOutcome::Garbled
+    );
+}
+
+#[test]
+fn flash_many_runs_jobs_independently() {
+    let firmware: Arc<[u8]> = synthetic_firmware(1000).into();
+    let job = |port_name: &str, model: u16| FlashJob {
+        port_name: port_name.to_string(),
+        app_baud: APP_BAUD,
+        id: 1,
+        firmware: firmware.clone(),
+        options: FlashOptions {
+            expected_models: Some(vec![model]),
+            ..FlashOptions::default()
+        },
+    };
+    let jobs = vec![job("pod0", 777), job("pod1", 1030), job("pod2", 777)];
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let sink = events.clone();
+    let outcomes = flash_many_with(
+        jobs,
+        |_| {
+            let emulator =
+                BootloaderEmulator::new(&[1], APP_BAUD).register(1, MODEL, &777u16.to_le_bytes());
+            Ok(Box::new(emulator) as Box<dyn Transport>)
+        },
+        move |index, event| sink.lock().unwrap().push((index, event)),
+    );
+
+    let summary: Vec<_> = outcomes
+        .iter()
+        .map(|o| {
+            (
+                o.port_name.as_str(),
+                o.result.as_ref().map(|s| s.frames).ok(),
+            )
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [("pod0", Some(16)), ("pod1", None), ("pod2", Some(16))]
+    );
+
+    let events = events.lock().unwrap();
+    for index in [0, 2] {
+        let frames = events
+            .iter()
+            .filter(|(i, e)| *i == index && matches!(e, FlashEvent::Frame { .. }))
+            .count();
+        assert_eq!(frames, 16);
+    }
+    assert!(!events.iter().any(|(i, _)| *i == 1));
+}
+
+#[test]
+fn flash_many_reports_a_panicked_job() {
+    let job = |port_name: &str| FlashJob {
+        port_name: port_name.to_string(),
+        app_baud: APP_BAUD,
+        id: 1,
+        firmware: synthetic_firmware(100).into(),
+        options: FlashOptions::default(),
+    };
+    let outcomes = flash_many_with(
+        vec![job("pod0"), job("pod1")],
+        |job| {
+            if job.port_name == "pod1" {
+                panic!("adapter unplugged");
+            }
+            Ok(Box::new(BootloaderEmulator::new(&[1], APP_BAUD)) as Box<dyn Transport>)
+        },
+        |_, _| {},
+    );
+
+    assert!(outcomes[0].result.is_ok());
+    assert_eq!(outcomes[1].port_name, "pod1");
+    let err = outcomes[1].result.as_ref().unwrap_err();
+    assert_eq!(err.to_string(), "Flash thread panicked: adapter unplugged");
+}
+
+#[test]
+fn watch_ping_reports_drops() {
+    // Id 6 answers every other ping.
+    let mut emulator = BootloaderEmulator::new(&[6], APP_BAUD).intermittent_id(6);
+    let mut events = Vec::new();
+    watch_ping(
+        &mut emulator,
+        6,
+        Duration::from_millis(10),
+        ProtocolVersion::V1,
+        Deadline::after(Duration::from_millis(55)),
+        |event| events.push(matches!(event, PingEvent::Up { .. })),
+    )
+    .unwrap();
+    assert!(events.len() >= 4, "{events:?}");
+    assert_eq!(events[..4], [true, false, true, false]);
+}
+
+#[test]
+fn soak_continues_past_a_failed_iteration() {
+    let firmware = synthetic_firmware(300);
+    // The first magic is refused; the bootloader then times out back into
+    // the application.
+    let mut emulator = BootloaderEmulator::new(&[3], APP_BAUD).magic_response(0x15, 1);
+    let options = FlashOptions::default();
+    let mut seen = 0;
+    let report = soak(&mut emulator, 3, &firmware, APP_BAUD, 3, &options, |_| {
+        seen += 1
+    })
+    .unwrap();
+
+    assert_eq!(seen, 3);
+    let outcomes: Vec<_> = report
+        .iterations
+        .iter()
+        .map(|i| (i.iteration, i.succeeded(), i.retries))
+        .collect();
+    assert_eq!(
+        outcomes,
+        [(1, false, None), (2, true, Some(0)), (3, true, Some(0))]
+    );
+    assert!(emulator.is_done());
+}
+
+#[test]
+fn bootloader_baud_and_reboot_delay_are_configurable() {
+    let firmware = synthetic_firmware(200);
+    let emulator = || BootloaderEmulator::new(&[4], APP_BAUD).bootloader_baud(250_000);
+
+    let err = flash_device(&mut emulator(), 4, &firmware, &FlashOptions::default()).unwrap_err();
+    assert!(err.to_string().contains("not in bootloader mode"), "{err}");
+
+    let options = FlashOptions {
+        bootloader_baud: 250_000,
+        reboot_delay: Duration::from_millis(50),
+        ..FlashOptions::default()
+    };
+    let mut bus = emulator();
+    flash_device(&mut bus, 4, &firmware, &options).unwrap();
+    assert_image_matches(&bus, &firmware);
+}
@@ -0,0 +1,129 @@
+//! Retry and error paths driven by the emulator's fault schedule.
+
+use std::io;
+use std::time::Duration;
+
+use feeflash::bootloader::{
+    BOOTLOADER_MAGIC, FlashOptions, init_bootloader, send_firmware, send_frame_with_retry,
+    wait_for_bootloader_magic_ack,
+};
+use feeflash::deadline::Deadline;
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::error::FeeflashError;
+use feeflash::frame::BootloaderFrame;
+use feeflash::transport::Transport;
+
+fn frame(index: u8) -> Vec<u8> {
+    BootloaderFrame {
+        index,
+        unknown_byte: 0,
+        data: [index; 64],
+        is_last: false,
+    }
+    .to_bytes()
+    .to_vec()
+}
+
+/// Bring an emulator that starts in its bootloader to the transfer state.
+fn ready(mut emulator: BootloaderEmulator) -> BootloaderEmulator {
+    emulator.write_all(BOOTLOADER_MAGIC).unwrap();
+    let mut ack = [0u8; 1];
+    emulator.read(&mut ack).unwrap();
+    assert_eq!(ack, [0x06]);
+    init_bootloader(&mut emulator, &FlashOptions::default()).unwrap();
+    emulator
+}
+
+#[test]
+fn retries_exactly_the_scheduled_naks() {
+    let mut emulator = ready(BootloaderEmulator::in_bootloader().nak_frame(1, 3));
+
+    let attempts = send_frame_with_retry(&mut emulator, &frame(1), 3, Deadline::NONE).unwrap();
+    assert_eq!(attempts, 4);
+    assert_eq!(emulator.naks_sent(), 3);
+    assert_eq!(emulator.frames_received(), 4);
+}
+
+#[test]
+fn exhausted_retries_name_the_frame() {
+    let mut emulator = ready(BootloaderEmulator::in_bootloader().nak_frame(1, 4));
+
+    let err = send_frame_with_retry(&mut emulator, &frame(1), 3, Deadline::NONE).unwrap_err();
+    assert_eq!(emulator.frames_received(), 4);
+    assert!(err.to_string().contains("frame index 1"), "{err}");
+}
+
+#[test]
+fn dropped_ack_times_out() {
+    let mut emulator = ready(BootloaderEmulator::in_bootloader().drop_ack(1));
+
+    let err = send_frame_with_retry(&mut emulator, &frame(1), 5, Deadline::NONE).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn recovery_skips_bad_magic_response() {
+    let mut emulator = BootloaderEmulator::in_bootloader().magic_response(0x00, 1);
+
+    wait_for_bootloader_magic_ack(
+        &mut emulator,
+        Duration::from_millis(1),
+        Some(Duration::from_secs(1)),
+        &FlashOptions::default(),
+    )
+    .unwrap();
+    init_bootloader(&mut emulator, &FlashOptions::default()).unwrap();
+}
+
+#[test]
+fn delayed_ack_within_timeout_succeeds() {
+    let mut emulator =
+        ready(BootloaderEmulator::in_bootloader().response_delay(Duration::from_millis(30)));
+    emulator.set_timeout(Duration::from_millis(500)).unwrap();
+
+    send_frame_with_retry(&mut emulator, &frame(1), 5, Deadline::NONE).unwrap();
+}
+
+#[test]
+fn delayed_ack_past_timeout_fails() {
+    let mut emulator =
+        ready(BootloaderEmulator::in_bootloader().response_delay(Duration::from_millis(30)));
+    emulator.set_timeout(Duration::from_millis(5)).unwrap();
+
+    let err = send_frame_with_retry(&mut emulator, &frame(1), 5, Deadline::NONE).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+}
+
+#[test]
+fn total_retry_budget_aborts_with_distribution() {
+    let firmware = vec![0x5A; 64 * 6];
+    let schedule = || {
+        BootloaderEmulator::in_bootloader()
+            .nak_frame(2, 2)
+            .nak_frame(4, 3)
+    };
+    let options = FlashOptions {
+        max_total_retries: Some(4),
+        ..FlashOptions::default()
+    };
+
+    let mut emulator = ready(schedule());
+    let err = send_firmware(&mut emulator, &firmware, &options).unwrap_err();
+    assert_eq!(
+        FeeflashError::from_io(&err),
+        Some(&FeeflashError::RetryBudgetExceeded {
+            budget: 4,
+            naks: vec![(2, 2), (4, 3)],
+        })
+    );
+    // Two resends of frame 2, then frame 4 only gets the two left.
+    assert_eq!(emulator.naks_sent(), 5);
+
+    let options = FlashOptions {
+        max_total_retries: Some(5),
+        ..options
+    };
+    let mut emulator = ready(schedule());
+    send_firmware(&mut emulator, &firmware, &options).unwrap();
+    assert!(emulator.is_done());
+}
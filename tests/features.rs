@@ -0,0 +1,94 @@
+//! Builds with other feature sets, in a separate target directory: the
+//! protocol core and the embedded HAL adapters without `std`, for updaters
+//! on targets without an OS, the browser, the serial code without the
+//! CLI's dependencies, and the library without output.
+
+use std::path::Path;
+use std::process::{Command, Output};
+
+fn cargo(args: &[&str]) -> Output {
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let output = Command::new(cargo)
+        .args(args)
+        .env(
+            "CARGO_TARGET_DIR",
+            Path::new(env!("CARGO_TARGET_TMPDIR")).join("features"),
+        )
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("failed to run cargo");
+    assert!(
+        output.status.success(),
+        "cargo {args:?}: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output
+}
+
+#[test]
+fn builds_without_default_features() {
+    cargo(&["build", "--lib", "--no-default-features"]);
+}
+
+#[test]
+fn web_does_not_need_std() {
+    cargo(&[
+        "check",
+        "--lib",
+        "--no-default-features",
+        "--features",
+        "web",
+    ]);
+}
+
+#[test]
+fn embedded_does_not_need_std() {
+    cargo(&[
+        "check",
+        "--lib",
+        "--no-default-features",
+        "--features",
+        "embedded",
+    ]);
+}
+
+#[test]
+fn serial_does_not_need_the_cli() {
+    cargo(&[
+        "check",
+        "--lib",
+        "--no-default-features",
+        "--features",
+        "serial",
+    ]);
+
+    let tree = cargo(&[
+        "tree",
+        "--no-default-features",
+        "--features",
+        "serial",
+        "--edges",
+        "normal",
+        "--prefix",
+        "none",
+    ]);
+    let tree = String::from_utf8(tree.stdout).unwrap();
+    assert!(tree.lines().any(|line| line.starts_with("serialport ")));
+    assert!(!tree.lines().any(|line| line.starts_with("clap")), "{tree}");
+}
+
+#[test]
+fn silent_builds() {
+    cargo(&["check", "--lib", "--features", "silent,tokio"]);
+}
+
+#[test]
+fn serde_does_not_need_std() {
+    cargo(&[
+        "check",
+        "--lib",
+        "--no-default-features",
+        "--features",
+        "serde",
+    ]);
+}
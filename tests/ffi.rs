@@ -0,0 +1,220 @@
+//! The C API: called in-process, and through the C example program linked
+//! against the shared library, flashing the bootloader emulator behind a
+//! TCP bridge.
+
+use std::ffi::{CStr, c_char, c_void};
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::ffi::{
+    FeeflashEventKind, FeeflashOptions, FeeflashResult, feeflash_flash,
+    feeflash_last_error_message, feeflash_options_default,
+};
+use feeflash::transport::Transport;
+
+const APP_BAUD: u32 = 1_000_000;
+
+/// Raw TCP bridge to an emulated servo whose bootloader runs at the
+/// application rate, like tests/tcp.rs. Returns the `tcp://` port name.
+fn bridge() -> (String, JoinHandle<BootloaderEmulator>) {
+    let mut servo = BootloaderEmulator::new(&[5], APP_BAUD).bootloader_baud(APP_BAUD);
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = format!("tcp://{}", listener.local_addr().unwrap());
+    let handle = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        let mut buf = [0u8; 512];
+        loop {
+            match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => servo.write_all(&buf[..n]).unwrap(),
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) => {}
+                Err(e) => panic!("bridge read failed: {e}"),
+            }
+            match servo.read(&mut buf) {
+                Ok(n) => socket.write_all(&buf[..n]).unwrap(),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("emulator read failed: {e}"),
+            }
+        }
+        servo
+    });
+    (port, handle)
+}
+
+fn options() -> FeeflashOptions {
+    FeeflashOptions {
+        bootloader_baud: APP_BAUD,
+        ..feeflash_options_default()
+    }
+}
+
+fn last_error() -> String {
+    let message = feeflash_last_error_message();
+    assert!(!message.is_null());
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+unsafe extern "C" fn count_frames(
+    user: *mut c_void,
+    kind: FeeflashEventKind,
+    frame: u32,
+    total: u32,
+    _message: *const c_char,
+) {
+    if kind == FeeflashEventKind::Frame {
+        let frames = unsafe { &mut *(user as *mut Vec<(u32, u32)>) };
+        frames.push((frame, total));
+    }
+}
+
+#[test]
+fn flashes_in_process_with_progress() {
+    let (port, bridge) = bridge();
+    let port = std::ffi::CString::new(port).unwrap();
+    let firmware: Vec<u8> = (0..200).map(|i| (i * 3) as u8).collect();
+    let options = options();
+    let mut frames: Vec<(u32, u32)> = Vec::new();
+
+    let result = unsafe {
+        feeflash_flash(
+            port.as_ptr(),
+            5,
+            firmware.as_ptr(),
+            firmware.len(),
+            &options,
+            Some(count_frames),
+            &mut frames as *mut _ as *mut c_void,
+        )
+    };
+    assert_eq!(result, FeeflashResult::Ok);
+    assert_eq!(frames, [(1, 4), (2, 4), (3, 4), (4, 4)]);
+
+    let servo = bridge.join().unwrap();
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn bad_arguments_set_the_last_error() {
+    let firmware = [0u8; 64];
+    let result = unsafe {
+        feeflash_flash(
+            std::ptr::null(),
+            1,
+            firmware.as_ptr(),
+            firmware.len(),
+            std::ptr::null(),
+            None,
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(result, FeeflashResult::InvalidArgument);
+    assert_eq!(last_error(), "port is NULL");
+
+    let result = unsafe {
+        feeflash_flash(
+            c"/dev/feeflash-no-such-port".as_ptr(),
+            1,
+            firmware.as_ptr(),
+            firmware.len(),
+            std::ptr::null(),
+            None,
+            std::ptr::null_mut(),
+        )
+    };
+    assert_eq!(result, FeeflashResult::Io);
+    assert!(!last_error().is_empty());
+}
+
+/// The checked-in header is what build.rs generates from the current
+/// src/ffi.rs.
+#[test]
+fn checked_in_header_is_up_to_date() {
+    let generated = include_str!(concat!(env!("OUT_DIR"), "/feeflash.h"));
+    let checked_in =
+        std::fs::read_to_string(Path::new(env!("CARGO_MANIFEST_DIR")).join("include/feeflash.h"))
+            .unwrap();
+    assert!(
+        generated == checked_in,
+        "include/feeflash.h is stale; regenerate it with the command in cbindgen.toml"
+    );
+}
+
+/// Build the cdylib, compile examples/ffi/flash.c against it and flash
+/// through the resulting program.
+#[cfg(target_os = "linux")]
+#[test]
+fn c_example_flashes_through_the_shared_library() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("ffi");
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args([
+            "rustc",
+            "--lib",
+            "--no-default-features",
+            "--features",
+            "ffi",
+        ])
+        .args(["--crate-type", "cdylib"])
+        .env("CARGO_TARGET_DIR", &target)
+        .current_dir(root)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success());
+
+    let lib_dir = target.join("debug");
+    let program = target.join("flash");
+    let status = Command::new("cc")
+        .arg("-I")
+        .arg(root.join("include"))
+        .arg(root.join("examples/ffi/flash.c"))
+        .arg("-L")
+        .arg(&lib_dir)
+        .arg("-lfeeflash")
+        .arg("-o")
+        .arg(&program)
+        .status()
+        .expect("failed to run cc");
+    assert!(status.success());
+
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 11) as u8).collect();
+    let image = target.join("fw.bin");
+    std::fs::write(&image, &firmware).unwrap();
+
+    let (port, bridge) = bridge();
+    let output = Command::new(&program)
+        .arg(&port)
+        .arg("5")
+        .arg(&image)
+        .arg(APP_BAUD.to_string())
+        .env("LD_LIBRARY_PATH", &lib_dir)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "{stdout}{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("frame 16/16"), "{stdout}");
+    assert!(stdout.ends_with("done\n"), "{stdout}");
+
+    let servo = bridge.join().unwrap();
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
@@ -0,0 +1,112 @@
+//! The sans-IO flasher against the bootloader emulator, through an
+//! `AsyncTransport` whose futures complete at once.
+
+use std::future::Future;
+use std::io;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::flasher::{
+    self, AsyncTransport, DriverError, Flasher, FlasherConfig, FlasherError, FlasherEvent, Stage,
+};
+use feeflash::transport::{Transport, clear_input};
+
+const APP_BAUD: u32 = 1_000_000;
+
+struct Blocking(BootloaderEmulator);
+
+impl AsyncTransport for Blocking {
+    type Error = io::Error;
+
+    async fn write_all(&mut self, data: &[u8]) -> io::Result<()> {
+        self.0.write_all(data)
+    }
+
+    async fn read(&mut self, buf: &mut [u8], timeout: Duration) -> io::Result<usize> {
+        self.0.set_timeout(timeout)?;
+        match self.0.read(buf) {
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
+            result => result,
+        }
+    }
+
+    async fn clear_input(&mut self) -> io::Result<()> {
+        clear_input(&mut self.0).map(drop)
+    }
+
+    async fn set_baud_rate(&mut self, baud: u32) -> io::Result<()> {
+        self.0.set_baud_rate(baud)
+    }
+
+    async fn sleep(&mut self, duration: Duration) {
+        std::thread::sleep(duration);
+    }
+}
+
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+fn config() -> FlasherConfig {
+    FlasherConfig {
+        app_baud: APP_BAUD,
+        reboot_delay: Duration::from_millis(10),
+        boot_settle: Duration::ZERO,
+        ..FlasherConfig::default()
+    }
+}
+
+#[test]
+fn flashes_the_emulator() {
+    let mut port = Blocking(BootloaderEmulator::new(&[5], APP_BAUD).nak_frame(3, 2));
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let mut flasher = Flasher::new(5, &firmware, config()).unwrap();
+
+    let mut events = Vec::new();
+    block_on(flasher::flash(&mut port, &mut flasher, |e| events.push(e))).unwrap();
+
+    assert!(port.0.is_done());
+    assert_eq!(&port.0.image()[..firmware.len()], firmware);
+    assert_eq!(flasher.progress(), (16, 16));
+    assert_eq!(flasher.retries(), 2);
+    assert_eq!(events[0], FlasherEvent::Stage(Stage::Ping));
+    assert_eq!(events.last(), Some(&FlasherEvent::Stage(Stage::Done)));
+    assert!(events.contains(&FlasherEvent::Frame {
+        frame: 3,
+        total: 16,
+        index: 3,
+        retries: 2
+    }));
+}
+
+#[test]
+fn reports_a_device_that_stays_in_its_application() {
+    let mut port = Blocking(BootloaderEmulator::new(&[5], APP_BAUD).reject_reboot());
+    let firmware = [0u8; 64];
+    let mut flasher = Flasher::new(5, &firmware, config()).unwrap();
+
+    let err = block_on(flasher::flash(&mut port, &mut flasher, drop)).unwrap_err();
+    assert!(matches!(
+        err,
+        DriverError::Flasher(FlasherError::NotInBootloader { reply: None })
+    ));
+}
+
+#[test]
+fn scans_and_pings() {
+    let mut port = Blocking(BootloaderEmulator::new(&[2, 7], APP_BAUD));
+    let timeout = Duration::from_millis(10);
+
+    let found = block_on(flasher::scan(&mut port, 0..=10, timeout)).unwrap();
+    assert_eq!(found, [2, 7]);
+    assert!(block_on(flasher::ping(&mut port, 7, timeout)).unwrap());
+    assert!(!block_on(flasher::ping(&mut port, 8, timeout)).unwrap());
+}
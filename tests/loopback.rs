@@ -0,0 +1,83 @@
+//! Full handshake and transfer against a fake bootloader running on a
+//! background thread, connected through an in-memory loopback pipe.
+
+use std::collections::VecDeque;
+use std::thread;
+
+use feeflash::bootloader::{
+    BOOTLOADER_BAUD, BOOTLOADER_MAGIC, FlashOptions, enter_bootloader, send_firmware,
+};
+use feeflash::crc::crc16_ccitt;
+use feeflash::dynamixel::{Instruction, build_dyn_packet};
+use feeflash::transport::{LoopbackTransport, Transport};
+
+fn read_exact(port: &mut LoopbackTransport, len: usize) -> Vec<u8> {
+    let mut out = vec![0u8; len];
+    let mut filled = 0;
+    while filled < len {
+        filled += port.read(&mut out[filled..]).expect("device read failed");
+    }
+    out
+}
+
+/// Act as a servo with id `id`: accept the reboot, ACK magic and init, then
+/// answer each frame with the next scripted byte (0x06 once exhausted).
+/// Returns every frame received, including resends.
+fn run_device(mut port: LoopbackTransport, id: u8, mut script: VecDeque<u8>) -> Vec<Vec<u8>> {
+    assert_eq!(
+        read_exact(&mut port, 6),
+        build_dyn_packet(id, Instruction::Reboot, &[]).unwrap()
+    );
+    assert_eq!(
+        read_exact(&mut port, BOOTLOADER_MAGIC.len()),
+        BOOTLOADER_MAGIC
+    );
+    port.write_all(&[0x06]).unwrap();
+    assert_eq!(read_exact(&mut port, 1), [0x01]);
+    port.write_all(&[0x06]).unwrap();
+
+    let mut frames = Vec::new();
+    loop {
+        let frame = read_exact(&mut port, 70);
+        let resp = script.pop_front().unwrap_or(0x06);
+        port.write_all(&[resp]).unwrap();
+        let last = frame[69] == 4 && resp == 0x06;
+        frames.push(frame);
+        if last {
+            return frames;
+        }
+    }
+}
+
+#[test]
+fn full_flow_against_fake_bootloader() {
+    let (mut host, device) = LoopbackTransport::pair();
+    // Frame 2 is NAKed once and must be resent.
+    let script = VecDeque::from([0x06, 0x15]);
+    let device = thread::spawn(move || run_device(device, 3, script));
+
+    let firmware: Vec<u8> = (0..200u8).collect();
+    let options = FlashOptions::default();
+    enter_bootloader(&mut host, 3, &options).unwrap();
+    send_firmware(&mut host, &firmware, &options).unwrap();
+    assert_eq!(host.baud_rate(), Some(BOOTLOADER_BAUD));
+
+    let frames = device.join().unwrap();
+    let indices: Vec<u8> = frames.iter().map(|f| f[0]).collect();
+    assert_eq!(indices, [1, 2, 2, 3, 4]);
+
+    for frame in &frames {
+        assert_eq!(frame[1], !frame[0]);
+        let crc = crc16_ccitt(&frame[..64]);
+        assert_eq!(frame[67], (crc >> 8) as u8);
+        assert_eq!(frame[68], (crc & 0xFF) as u8);
+    }
+    assert_eq!(frames.last().unwrap()[69], 4);
+
+    let payload: Vec<u8> = [&frames[0], &frames[2], &frames[3], &frames[4]]
+        .iter()
+        .flat_map(|f| f[3..67].to_vec())
+        .collect();
+    assert_eq!(&payload[..firmware.len()], &firmware[..]);
+    assert!(payload[firmware.len()..].iter().all(|&b| b == 0xFF));
+}
@@ -0,0 +1,43 @@
+//! Runs tests/python/test_feeflash.py against the Python module, built as
+//! an extension in a separate target directory.
+
+#![cfg(target_os = "linux")]
+
+use std::path::Path;
+use std::process::Command;
+
+#[test]
+fn python_module_passes_its_tests() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let target = Path::new(env!("CARGO_TARGET_TMPDIR")).join("python");
+    let cargo = std::env::var("CARGO").unwrap_or_else(|_| "cargo".to_string());
+    let status = Command::new(cargo)
+        .args(["rustc", "--lib", "--no-default-features"])
+        .args(["--features", "python,testing", "--crate-type", "cdylib"])
+        .env("CARGO_TARGET_DIR", &target)
+        .current_dir(root)
+        .status()
+        .expect("failed to run cargo");
+    assert!(status.success());
+
+    // Python imports `feeflash` from feeflash.so.
+    let module_dir = target.join("module");
+    std::fs::create_dir_all(&module_dir).unwrap();
+    std::fs::copy(
+        target.join("debug/libfeeflash.so"),
+        module_dir.join("feeflash.so"),
+    )
+    .unwrap();
+
+    let output = Command::new("python3")
+        .arg(root.join("tests/python/test_feeflash.py"))
+        .env("PYTHONPATH", &module_dir)
+        .output()
+        .expect("failed to run python3");
+    assert!(
+        output.status.success(),
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
@@ -0,0 +1,92 @@
+"""Tests of the feeflash Python module against the in-crate emulator.
+
+Run with pytest, or as a script, with the module built with the `python`
+and `testing` features on the path; tests/python.rs does both.
+"""
+
+import threading
+
+import feeflash
+
+FIRMWARE = bytes((i * 7) % 256 for i in range(1000))
+
+
+def test_scan_and_ping():
+    servo = feeflash.Emulator([3, 7])
+    assert feeflash.scan(servo) == [3, 7]
+    assert feeflash.ping(servo, 7)
+    assert not feeflash.ping(servo, 4)
+
+
+def test_flash_reports_every_frame():
+    servo = feeflash.Emulator([5])
+    frames = []
+    stats = feeflash.flash(servo, 5, FIRMWARE, lambda frame, total: frames.append((frame, total)))
+    assert frames == [(n, 16) for n in range(1, 17)]
+    assert stats["frames"] == 16
+    assert stats["retries"] == 0
+    assert servo.is_done()
+    assert servo.image()[: len(FIRMWARE)] == FIRMWARE
+
+
+def test_flash_releases_the_gil():
+    servo = feeflash.Emulator([5])
+    ticks = []
+    done = threading.Event()
+
+    def tick():
+        while not done.is_set():
+            ticks.append(1)
+            done.wait(0.001)
+
+    thread = threading.Thread(target=tick)
+    thread.start()
+    try:
+        feeflash.flash(servo, 5, FIRMWARE)
+    finally:
+        done.set()
+        thread.join()
+    assert len(ticks) > 10
+
+
+def test_errors_are_structured():
+    servo = feeflash.Emulator([5])
+    try:
+        feeflash.flash(servo, 5, b"")
+    except feeflash.EmptyFirmware as e:
+        assert isinstance(e, feeflash.FeeflashError)
+    else:
+        raise AssertionError("empty firmware was flashed")
+
+    try:
+        feeflash.flash(servo, 5, FIRMWARE, models=[2825])
+    except feeflash.IncompatibleModel as e:
+        assert "2825" in str(e)
+    else:
+        raise AssertionError("model check passed")
+
+    try:
+        feeflash.flash(servo, 9, FIRMWARE, max_duration=0.5)
+    except (feeflash.FeeflashError, TimeoutError):
+        pass
+    else:
+        raise AssertionError("absent servo was flashed")
+
+
+def test_progress_exceptions_propagate():
+    def fail(frame, total):
+        raise KeyError(frame)
+
+    try:
+        feeflash.flash(feeflash.Emulator([5]), 5, FIRMWARE, fail)
+    except KeyError as e:
+        assert e.args == (1,)
+    else:
+        raise AssertionError("progress exception was swallowed")
+
+
+if __name__ == "__main__":
+    for name, test in list(globals().items()):
+        if name.startswith("test_"):
+            test()
+            print(f"{name} ok")
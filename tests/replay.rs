@@ -0,0 +1,46 @@
+//! Recorded sessions replayed against the protocol code: a refactor that
+//! changes what goes on the wire fails here. Captures are `--trace-file`
+//! transcripts, from hardware or the emulator.
+
+use feeflash::bootloader::{FlashOptions, flash_device};
+use feeflash::dynamixel::registers::MODEL;
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::trace::{ReplayTransport, TracingTransport};
+
+const APP_BAUD: u32 = 1_000_000;
+
+/// Flash recorded from the emulator: model check, torque off, reboot into
+/// the bootloader and a 200-byte image on id 1.
+const FLASH_SESSION: &str = include_str!("data/flash_id1_200b.trace");
+
+fn firmware() -> Vec<u8> {
+    (0..200u32).map(|i| (i * 7) as u8).collect()
+}
+
+fn options() -> FlashOptions {
+    FlashOptions {
+        expected_models: Some(vec![777]),
+        ..FlashOptions::default()
+    }
+}
+
+#[test]
+fn recorded_session_replays_with_same_writes() {
+    let emulator =
+        BootloaderEmulator::new(&[1], APP_BAUD).register(1, MODEL, &777u16.to_le_bytes());
+    let mut recorder = TracingTransport::new(emulator, Vec::new());
+    flash_device(&mut recorder, 1, &firmware(), &options()).unwrap();
+    let (_, transcript) = recorder.into_parts().unwrap();
+    let transcript = String::from_utf8(transcript).unwrap();
+
+    let mut replay = ReplayTransport::from_transcript(&transcript).unwrap();
+    flash_device(&mut replay, 1, &firmware(), &options()).unwrap();
+    assert_eq!(replay.remaining(), 0);
+}
+
+#[test]
+fn known_good_capture_still_replays() {
+    let mut replay = ReplayTransport::from_transcript(FLASH_SESSION).unwrap();
+    flash_device(&mut replay, 1, &firmware(), &options()).unwrap();
+    assert_eq!(replay.remaining(), 0);
+}
@@ -0,0 +1,140 @@
+//! The report, option and device types through JSON and back, with the
+//! `serde` feature.
+
+use std::fmt::Debug;
+use std::time::Duration;
+
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+
+use feeflash::bootloader::{FlashEvent, FlashOptions, TransferStats};
+use feeflash::dynamixel::{LatencyStats, ProtocolVersion, ScanReport, StatusPacket};
+use feeflash::firmware::FirmwareVersion;
+use feeflash::flasher::{FlasherConfig, FlasherEvent, Stage};
+use feeflash::frame::ChecksumKind;
+use feeflash::info::{DeviceInfo, DeviceScan, ScannedDevice};
+use feeflash::soak::{SoakIteration, SoakReport};
+
+fn round_trip<T: Serialize + DeserializeOwned + PartialEq + Debug>(value: &T) {
+    let json = serde_json::to_string(value).unwrap();
+    let back: T = serde_json::from_str(&json).unwrap();
+    assert_eq!(&back, value, "{json}");
+}
+
+#[test]
+fn reports_round_trip() {
+    round_trip(&TransferStats {
+        frames: 16,
+        total_retries: 2,
+        delay_time: Duration::from_millis(30),
+        elapsed: Duration::from_millis(1234),
+    });
+    round_trip(&ScanReport {
+        found: vec![1, 7],
+        collisions: vec![3],
+    });
+    round_trip(&LatencyStats {
+        sent: 10,
+        received: 9,
+        min: Duration::from_micros(800),
+        max: Duration::from_micros(2100),
+        mean: Duration::from_micros(1000),
+    });
+    round_trip(&SoakReport {
+        iterations: vec![
+            SoakIteration {
+                iteration: 1,
+                duration: Duration::from_millis(2500),
+                retries: Some(1),
+                error: None,
+            },
+            SoakIteration {
+                iteration: 2,
+                duration: Duration::from_millis(900),
+                retries: None,
+                error: Some("Ping timed out".to_string()),
+            },
+        ],
+    });
+    round_trip(&FlashEvent::Frame {
+        frame: 3,
+        total: 16,
+        index: 3,
+        retries: 1,
+    });
+    round_trip(&FlasherEvent::Stage(Stage::Transfer));
+}
+
+#[test]
+fn devices_round_trip() {
+    let status = StatusPacket {
+        id: 3,
+        error: 0,
+        params: vec![],
+    };
+    round_trip(&DeviceScan {
+        devices: vec![ScannedDevice {
+            id: 3,
+            protocol: ProtocolVersion::V2,
+            status,
+            model: Some(777),
+            model_name: Some("STS3215"),
+            firmware: Some("3.10".to_string()),
+        }],
+        collisions: vec![],
+    });
+    round_trip(&DeviceInfo {
+        id: 1,
+        model: Some(777),
+        model_name: Some("STS3215"),
+        firmware: Some("3.10".to_string()),
+        baud_index: Some(0),
+        baud_rate: Some(1_000_000),
+        min_angle_limit_steps: Some(0),
+        max_angle_limit_steps: Some(4095),
+        max_temperature_c: Some(70),
+        present_temperature_c: None,
+        present_voltage_v: Some(7.4),
+        present_position_steps: Some(2048),
+    });
+    round_trip(&FirmwareVersion {
+        major: 3,
+        minor: 10,
+    });
+}
+
+#[test]
+fn unknown_model_name_reads_back_as_none() {
+    let json = r#"{"devices":[{"id":3,"protocol":"1","status":{"id":3,"error":0,"params":[]},
+        "model":1190,"model_name":"XL-320","firmware":null}],"collisions":[]}"#;
+    let scan: DeviceScan = serde_json::from_str(json).unwrap();
+    assert_eq!(scan.devices[0].model_name, None);
+}
+
+#[test]
+fn options_round_trip_and_default_missing_fields() {
+    round_trip(&FlasherConfig {
+        app_baud: 115_200,
+        checksum: ChecksumKind::Crc32,
+        ..FlasherConfig::default()
+    });
+
+    let options = FlashOptions {
+        max_retries: 9,
+        expected_models: Some(vec![777]),
+        checksum: ChecksumKind::Sum16,
+        flash_page_size: Some(256),
+        ..FlashOptions::default()
+    };
+    let json = serde_json::to_string(&options).unwrap();
+    let back: FlashOptions = serde_json::from_str(&json).unwrap();
+    assert_eq!(back.max_retries, 9);
+    assert_eq!(back.expected_models, Some(vec![777]));
+    assert_eq!(back.checksum, ChecksumKind::Sum16);
+    assert_eq!(back.flash_page_size, Some(256));
+    assert!(back.progress.is_none());
+
+    let back: FlashOptions = serde_json::from_str(r#"{"bootloader_baud": 115200}"#).unwrap();
+    assert_eq!(back.bootloader_baud, 115_200);
+    assert_eq!(back.max_retries, FlashOptions::default().max_retries);
+}
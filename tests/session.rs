@@ -0,0 +1,62 @@
+//! The typestate session against the bootloader emulator, and the
+//! out-of-order calls it rejects at compile time (tests/ui).
+
+use std::time::Duration;
+
+use feeflash::bootloader::FlashOptions;
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::session::BootloaderSession;
+
+const APP_BAUD: u32 = 1_000_000;
+
+fn synthetic_firmware(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 7 + i / 256) as u8).collect()
+}
+
+#[test]
+fn flashes_through_the_session() {
+    let firmware = synthetic_firmware(10 * 1024 + 17);
+    let mut emulator = BootloaderEmulator::new(&[7], APP_BAUD).nak_frame(5, 1);
+    let options = FlashOptions {
+        boot_settle: Duration::ZERO,
+        ..FlashOptions::default()
+    };
+
+    let ready = BootloaderSession::connect(&mut emulator, &options)
+        .enter(7)
+        .unwrap();
+    assert_eq!(ready.id(), Some(7));
+    let session = ready.init().unwrap().send_firmware(&firmware).unwrap();
+    let stats = session.stats().clone();
+    session.finish(APP_BAUD).unwrap();
+
+    assert_eq!(stats.frames, firmware.len().div_ceil(64));
+    assert_eq!(stats.total_retries, 1);
+    assert!(emulator.is_done());
+    let image = emulator.image();
+    assert_eq!(&image[..firmware.len()], firmware);
+    assert!(image[firmware.len()..].iter().all(|&b| b == 0xFF));
+}
+
+#[test]
+fn recovers_through_the_session() {
+    let firmware = synthetic_firmware(300);
+    let mut emulator = BootloaderEmulator::in_bootloader();
+    let options = FlashOptions::default();
+
+    let ready = BootloaderSession::connect(&mut emulator, &options)
+        .recover()
+        .unwrap();
+    assert_eq!(ready.id(), None);
+    let session = ready.init().unwrap().send_firmware(&firmware).unwrap();
+    session.finish(APP_BAUD).unwrap();
+
+    assert!(emulator.is_done());
+    assert_eq!(&emulator.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn out_of_order_calls_do_not_compile() {
+    let cases = trybuild::TestCases::new();
+    cases.compile_fail("tests/ui/*.rs");
+}
@@ -0,0 +1,412 @@
+//! Handshake and transfer through `TcpTransport`, against a bridge thread
+//! that passes socket bytes to the bootloader emulator the way ser2net
+//! passes them to a serial port.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::process::Command;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use feeflash::bootloader::{FlashOptions, enter_bootloader, jump_to_application, send_firmware};
+use feeflash::emulator::BootloaderEmulator;
+use feeflash::tcp::TcpTransport;
+use feeflash::transport::Transport;
+
+const APP_BAUD: u32 = 1_000_000;
+
+const IAC: u8 = 0xFF;
+const SB: u8 = 0xFA;
+const SE: u8 = 0xF0;
+
+/// Serve one connection on a local port, forwarding to `servo` until the
+/// client disconnects. With `rfc2217`, Telnet commands are stripped from
+/// the socket and SET-BAUDRATE reconfigures the servo's side of the line.
+fn bridge(
+    mut servo: BootloaderEmulator,
+    rfc2217: bool,
+) -> (String, JoinHandle<BootloaderEmulator>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let handle = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .unwrap();
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 512];
+        loop {
+            match socket.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => pending.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("bridge read failed: {e}"),
+            }
+            let data = if rfc2217 {
+                let mut replies = Vec::new();
+                let data = telnet_server(&mut pending, &mut servo, &mut replies);
+                socket.write_all(&replies).unwrap();
+                data
+            } else {
+                std::mem::take(&mut pending)
+            };
+            if !data.is_empty() {
+                servo.write_all(&data).unwrap();
+            }
+            match servo.read(&mut buf) {
+                Ok(n) if rfc2217 => socket.write_all(&escape(&buf[..n])).unwrap(),
+                Ok(n) => socket.write_all(&buf[..n]).unwrap(),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => panic!("emulator read failed: {e}"),
+            }
+        }
+        servo
+    });
+    (addr, handle)
+}
+
+fn escape(data: &[u8]) -> Vec<u8> {
+    data.iter()
+        .flat_map(|&b| if b == IAC { vec![IAC, IAC] } else { vec![b] })
+        .collect()
+}
+
+/// Take the data bytes out of `pending`, acting on complete commands and
+/// leaving an incomplete one for the next read. SET-BAUDRATE is confirmed
+/// in `replies`.
+fn telnet_server(
+    pending: &mut Vec<u8>,
+    servo: &mut BootloaderEmulator,
+    replies: &mut Vec<u8>,
+) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut i = 0;
+    while i < pending.len() {
+        if pending[i] != IAC {
+            data.push(pending[i]);
+            i += 1;
+            continue;
+        }
+        let Some(&command) = pending.get(i + 1) else {
+            break;
+        };
+        match command {
+            IAC => {
+                data.push(IAC);
+                i += 2;
+            }
+            SB => {
+                let Some(end) = pending[i..].windows(2).position(|w| w == [IAC, SE]) else {
+                    break;
+                };
+                let sub = &pending[i + 2..i + end];
+                if let [0x2C, 1, baud @ ..] = sub {
+                    servo
+                        .set_baud_rate(u32::from_be_bytes(baud.try_into().unwrap()))
+                        .unwrap();
+                    replies.extend([IAC, SB, 0x2C, 101]);
+                    replies.extend(baud);
+                    replies.extend([IAC, SE]);
+                }
+                i += end + 2;
+            }
+            // WILL, WONT, DO, DONT and their option.
+            0xFB..=0xFE if i + 2 < pending.len() => i += 3,
+            0xFB..=0xFE => break,
+            _ => i += 2,
+        }
+    }
+    pending.drain(..i);
+    data
+}
+
+/// Read `expected.len()` bytes and compare.
+fn expect(socket: &mut TcpStream, expected: &[u8]) {
+    let mut got = vec![0u8; expected.len()];
+    socket.read_exact(&mut got).unwrap();
+    assert_eq!(got, expected);
+}
+
+fn flash(port: &mut TcpTransport, firmware: &[u8], options: &FlashOptions) {
+    enter_bootloader(port, 5, options).unwrap();
+    send_firmware(port, firmware, options).unwrap();
+    jump_to_application(port, 5, APP_BAUD, options).unwrap();
+}
+
+#[test]
+fn flashes_through_a_raw_bridge() {
+    // A raw bridge can't switch rates, so the servo's bootloader runs at
+    // the application rate.
+    let servo = BootloaderEmulator::new(&[5], APP_BAUD).bootloader_baud(APP_BAUD);
+    let (addr, bridge) = bridge(servo, false);
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let options = FlashOptions {
+        bootloader_baud: APP_BAUD,
+        ..FlashOptions::default()
+    };
+
+    let mut port = TcpTransport::connect(&addr, APP_BAUD).unwrap();
+    flash(&mut port, &firmware, &options);
+    drop(port);
+
+    let servo = bridge.join().unwrap();
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn rfc2217_bridge_follows_baud_changes() {
+    let servo = BootloaderEmulator::new(&[5], APP_BAUD);
+    let (addr, bridge) = bridge(servo, true);
+    // 0xFF bytes must survive Telnet escaping both ways.
+    let firmware: Vec<u8> = (0..1000)
+        .map(|i| if i % 3 == 0 { 0xFF } else { i as u8 })
+        .collect();
+    let options = FlashOptions::default();
+
+    let mut port = TcpTransport::connect_rfc2217(&addr, APP_BAUD).unwrap();
+    flash(&mut port, &firmware, &options);
+    drop(port);
+
+    let servo = bridge.join().unwrap();
+    assert!(servo.is_done());
+    assert_eq!(&servo.image()[..firmware.len()], firmware);
+}
+
+#[test]
+fn rfc2217_negotiation_with_a_scripted_server() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        // WILL BINARY, DO BINARY, WILL COM-PORT-OPTION, then a signature
+        // request and SET-BAUDRATE 1000000.
+        expect(&mut socket, &[IAC, 0xFB, 0, IAC, 0xFD, 0, IAC, 0xFB, 0x2C]);
+        expect(&mut socket, &[IAC, SB, 0x2C, 0, IAC, SE]);
+        expect(
+            &mut socket,
+            &[IAC, SB, 0x2C, 1, 0x00, 0x0F, 0x42, 0x40, IAC, SE],
+        );
+        // Offer ECHO, which must be refused, then sign and confirm the rate
+        // with a data byte before the confirmation.
+        socket.write_all(&[IAC, 0xFB, 0x01]).unwrap();
+        socket.write_all(&[IAC, SB, 0x2C, 100]).unwrap();
+        socket.write_all(b"ser2net").unwrap();
+        socket.write_all(&[IAC, SE, 0x42]).unwrap();
+        socket
+            .write_all(&[IAC, SB, 0x2C, 101, 0x00, 0x0F, 0x42, 0x40, IAC, SE])
+            .unwrap();
+        expect(&mut socket, &[IAC, 0xFE, 0x01]);
+        // PURGE-DATA of the bridge's receive buffer.
+        expect(&mut socket, &[IAC, SB, 0x2C, 12, 1, IAC, SE]);
+        // Data is escaped both ways.
+        expect(&mut socket, &[0xFF, 0xFF, 0x01]);
+        socket.write_all(&[0x02, 0xFF, 0xFF]).unwrap();
+        // A rate the bridge can't do is reported back.
+        expect(
+            &mut socket,
+            &[IAC, SB, 0x2C, 1, 0x00, 0x07, 0xA1, 0x20, IAC, SE],
+        );
+        socket
+            .write_all(&[IAC, SB, 0x2C, 101, 0x00, 0x03, 0xD0, 0x90, IAC, SE])
+            .unwrap();
+    });
+
+    let mut port = TcpTransport::connect_rfc2217(addr, APP_BAUD).unwrap();
+    assert_eq!(port.bridge_signature(), Some("ser2net"));
+    port.write_all(&[0xFF, 0x01]).unwrap();
+    let mut buf = [0u8; 8];
+    let mut got = Vec::new();
+    while got.len() < 3 {
+        let n = port.read(&mut buf).unwrap();
+        got.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(got, [0x42, 0x02, 0xFF]);
+
+    let err = port.set_baud_rate(500_000).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+    server.join().unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_progress_json_reports_every_frame() {
+    let servo = BootloaderEmulator::new(&[5], APP_BAUD).bootloader_baud(APP_BAUD);
+    let (addr, bridge) = bridge(servo, false);
+    let firmware: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("progress-json.bin");
+    std::fs::write(&path, &firmware).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_feeflash"))
+        .arg(&path)
+        .args(["--port", &format!("tcp://{addr}"), "--id", "5", "--force"])
+        .args([
+            "--bootloader-baud",
+            &APP_BAUD.to_string(),
+            "--progress-json",
+        ])
+        .output()
+        .unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    bridge.join().unwrap();
+
+    // Nothing but JSON on stdout, one frame object per chunk in order.
+    let events: Vec<serde_json::Value> = String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    let (done, frames) = events.split_last().unwrap();
+    for (i, frame) in frames.iter().enumerate() {
+        assert_eq!(frame["event"], "frame");
+        assert_eq!(frame["chunk"], i + 1);
+        assert_eq!(frame["total"], frames.len());
+        assert_eq!(frame["retries"], 0);
+    }
+    assert_eq!(done["event"], "done");
+    assert_eq!(done["frames"], frames.len());
+    assert_eq!(done["retries"], 0);
+    assert_eq!(done["confirmed"], true);
+    assert!(!stderr.contains("Sending frame"), "{stderr}");
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_progress_json_reports_a_failed_confirmation() {
+    let servo = BootloaderEmulator::new(&[5], APP_BAUD).bootloader_baud(APP_BAUD);
+    let (addr, bridge) = bridge(servo, false);
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("progress-json-mismatch.bin");
+    std::fs::write(&path, [0x5A; 200]).unwrap();
+
+    // The transfer succeeds, but the device doesn't report 9.9 afterwards.
+    let output = Command::new(env!("CARGO_BIN_EXE_feeflash"))
+        .arg(&path)
+        .args(["--port", &format!("tcp://{addr}"), "--id", "5", "--force"])
+        .args([
+            "--bootloader-baud",
+            &APP_BAUD.to_string(),
+            "--expect-version",
+            "9.9",
+            "--progress-json",
+        ])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(4));
+    bridge.join().unwrap();
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let last: serde_json::Value = serde_json::from_str(stdout.lines().last().unwrap()).unwrap();
+    assert_eq!(last["event"], "error");
+    assert_eq!(last["code"], 4);
+    assert!(!stdout.contains(r#""done""#), "{stdout}");
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_monitor_json_stops_quietly_when_the_reader_goes() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+
+    let (addr, bridge) = bridge(BootloaderEmulator::new(&[5], APP_BAUD), false);
+    let mut child = Command::new(env!("CARGO_BIN_EXE_feeflash"))
+        .args(["--port", &format!("tcp://{addr}"), "monitor", "--id", "5"])
+        .args(["--interval-ms", "10", "--json"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Only telemetry on stdout, then hang up like `| head -n 1`.
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    serde_json::from_str::<serde_json::Value>(&line).unwrap();
+
+    let output = child.wait_with_output().unwrap();
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+    assert!(stderr.contains("Monitoring device id 5"), "{stderr}");
+    bridge.join().unwrap();
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_require_signature_refuses_unsigned_firmware() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let firmware = dir.join("require-signature.bin");
+    let pubkey = dir.join("require-signature.pub");
+    std::fs::write(&firmware, [0x5A; 200]).unwrap();
+    std::fs::write(&pubkey, [0x11; 32]).unwrap();
+    // Offline: --emit-frames stops before any port is opened.
+    let run = |env: &[(&str, &Path)], args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_feeflash"))
+            .arg(&firmware)
+            .args(["--force", "--emit-frames"])
+            .arg(dir.join("require-signature.frames"))
+            .env("FEEFLASH_REQUIRE_SIGNATURE", "true")
+            .envs(env.iter().copied())
+            .args(args)
+            .output()
+            .unwrap()
+    };
+
+    // No key to check against.
+    assert_eq!(run(&[], &[]).status.code(), Some(2));
+    // No way around it.
+    let with_key = [("FEEFLASH_PUBKEY", pubkey.as_path())];
+    assert_eq!(run(&with_key, &["--allow-unsigned"]).status.code(), Some(2));
+    let output = run(&with_key, &[]);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--require-signature"), "{stderr}");
+}
+
+#[cfg(feature = "cli")]
+#[test]
+fn cli_flash_batch_refuses_signature_options() {
+    let dir = Path::new(env!("CARGO_TARGET_TMPDIR"));
+    let manifest = dir.join("signed-batch.toml");
+    let pubkey = dir.join("signed-batch.pub");
+    std::fs::write(dir.join("signed-batch.bin"), [0x5A; 200]).unwrap();
+    std::fs::write(
+        &manifest,
+        r#"entries = [{ id = 1, firmware = "signed-batch.bin" }]"#,
+    )
+    .unwrap();
+    std::fs::write(&pubkey, [0x11; 32]).unwrap();
+
+    // Refused before the manifest is read or a port opened.
+    for (flag, value) in [("--pubkey", &pubkey), ("--signature", &pubkey)] {
+        let output = Command::new(env!("CARGO_BIN_EXE_feeflash"))
+            .args(["--port", "tcp://127.0.0.1:1", flag])
+            .arg(value)
+            .arg("flash-batch")
+            .arg(&manifest)
+            .output()
+            .unwrap();
+        assert_eq!(output.status.code(), Some(1));
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.contains("doesn't check signatures"), "{stderr}");
+    }
+}
+
+#[test]
+fn read_times_out_and_reports_a_closed_bridge() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server, _) = listener.accept().unwrap();
+    let mut port = TcpTransport::from_stream(client, APP_BAUD).unwrap();
+    port.set_timeout(Duration::from_millis(20)).unwrap();
+    assert_eq!(port.timeout(), Duration::from_millis(20));
+
+    let err = port.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+
+    drop(server);
+    let err = port.read(&mut [0u8; 8]).unwrap_err();
+    assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+}
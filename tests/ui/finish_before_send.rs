@@ -0,0 +1,12 @@
+use feeflash::bootloader::FlashOptions;
+use feeflash::session::BootloaderSession;
+use feeflash::transport::Transport;
+
+fn flash(port: &mut dyn Transport) -> std::io::Result<()> {
+    let options = FlashOptions::default();
+    let session = BootloaderSession::connect(port, &options).enter(1)?.init()?;
+    session.finish(1_000_000)?;
+    Ok(())
+}
+
+fn main() {}
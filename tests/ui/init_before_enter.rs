@@ -0,0 +1,11 @@
+use feeflash::bootloader::FlashOptions;
+use feeflash::session::BootloaderSession;
+use feeflash::transport::Transport;
+
+fn init(port: &mut dyn Transport) -> std::io::Result<()> {
+    let options = FlashOptions::default();
+    BootloaderSession::connect(port, &options).init()?;
+    Ok(())
+}
+
+fn main() {}
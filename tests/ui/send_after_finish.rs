@@ -0,0 +1,14 @@
+use feeflash::bootloader::FlashOptions;
+use feeflash::session::BootloaderSession;
+use feeflash::transport::Transport;
+
+fn flash(port: &mut dyn Transport, firmware: &[u8]) -> std::io::Result<()> {
+    let options = FlashOptions::default();
+    let session = BootloaderSession::connect(port, &options).enter(1)?.init()?;
+    let transferred = session.send_firmware(firmware)?;
+    transferred.finish(1_000_000)?;
+    transferred.send_firmware(firmware)?;
+    Ok(())
+}
+
+fn main() {}
@@ -0,0 +1,12 @@
+use feeflash::bootloader::FlashOptions;
+use feeflash::session::BootloaderSession;
+use feeflash::transport::Transport;
+
+fn flash(port: &mut dyn Transport, firmware: &[u8]) -> std::io::Result<()> {
+    let options = FlashOptions::default();
+    let mut session = BootloaderSession::connect(port, &options).enter(1)?;
+    session.send_firmware(firmware)?;
+    Ok(())
+}
+
+fn main() {}
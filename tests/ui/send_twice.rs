@@ -0,0 +1,13 @@
+use feeflash::bootloader::FlashOptions;
+use feeflash::session::BootloaderSession;
+use feeflash::transport::Transport;
+
+fn flash(port: &mut dyn Transport, firmware: &[u8]) -> std::io::Result<()> {
+    let options = FlashOptions::default();
+    let session = BootloaderSession::connect(port, &options).enter(1)?.init()?;
+    session.send_firmware(firmware)?;
+    session.send_firmware(firmware)?;
+    Ok(())
+}
+
+fn main() {}